@@ -0,0 +1,209 @@
+// -----------------------------------------------------------------------------
+
+use clap;
+use std::time;
+
+use super::error;
+use super::luks;
+use super::report;
+use super::signals;
+use super::traits::{CliCommand, Validate};
+use super::utils;
+
+// -----------------------------------------------------------------------------
+
+const ARG_KEY_SIZE: &str = "key-size";
+const ARG_TARGET: &str = "target";
+
+/// Memory costs (in KiB) evaluated by the benchmark
+const MEM_COSTS: &[u32] = &[65536, 262144, 1048576];
+
+// -----------------------------------------------------------------------------
+
+/// Command structure for benchmarking LUKS and Argon2 parameters
+#[derive(Debug)]
+pub struct Command {
+    /// Size in bytes of the key to be generated
+    key_size: u32,
+
+    /// Target unlock time in milliseconds
+    target: u64,
+}
+
+impl Validate for Command {
+    fn is_valid(&self) -> bool {
+        return
+            self.key_size > 0 &&
+            self.target > 0;
+    }
+}
+
+impl CliCommand for Command {
+    /// Get the name of the command
+    fn name(&self) -> &'static str {
+        return "bench";
+    }
+
+    /// Get command and its arguments
+    fn get<'a, 'b>(
+        &self,
+        version: &'b str,
+        author: &'b str) -> clap::App<'a, 'b> {
+
+        return clap::App::new(self.name())
+            .about("Benchmark LUKS and key file hashing parameters")
+            .version(version)
+            .author(author)
+            // Key size argument
+            .arg(clap::Arg::with_name(ARG_KEY_SIZE)
+                .long(ARG_KEY_SIZE)
+                .help("Size of the key (same as the `luks` command)")
+                .takes_value(true))
+            // Target argument
+            .arg(clap::Arg::with_name(ARG_TARGET)
+                .long(ARG_TARGET)
+                .help("Target unlock time in milliseconds (default: 1000)")
+                .takes_value(true));
+    }
+
+    /// Process command line arguments
+    fn process(&mut self, matches: &clap::ArgMatches) -> error::Return {
+        // Parse arguments
+        for arg in matches.args.iter() {
+            match arg.0 {
+                &ARG_KEY_SIZE => {
+                    let value = match matches.value_of(arg.0) {
+                        Some(s) => s.to_string(),
+                        None => return inval_error!(&ARG_KEY_SIZE),
+                    };
+
+                    self.key_size = match value.parse::<u32>() {
+                        Ok(i) => i,
+                        Err(_) => return inval_error!(&ARG_KEY_SIZE),
+                    };
+                },
+
+                &ARG_TARGET => {
+                    let value = match matches.value_of(arg.0) {
+                        Some(s) => s.to_string(),
+                        None => return inval_error!(&ARG_TARGET),
+                    };
+
+                    self.target = match value.parse::<u64>() {
+                        Ok(i) => i,
+                        Err(_) => return inval_error!(&ARG_TARGET),
+                    };
+                },
+
+                _ => {
+                    return inval_error!(arg.0);
+                }
+            }
+        }
+
+        log::debug!("{:#?}", self);
+
+        // Check validity
+        if !self.is_valid() {
+            return generic_error!("Invalid configuration");
+        }
+
+        self.bench_cryptsetup();
+        self.bench_argon2()?;
+
+        return Success!();
+    }
+}
+
+impl Command {
+    /// Create an instance of Command
+    pub fn new() -> Self {
+        Self {
+            key_size: 4096,
+            target: 1000,
+        }
+    }
+
+    /// Run `cryptsetup benchmark` and print its results
+    fn bench_cryptsetup(&self) {
+        let output = match utils::command_output("cryptsetup", &["benchmark"]) {
+            Ok(o) => o,
+            Err(e) => {
+                report::warning(
+                    &format!("Cannot run cryptsetup benchmark: {}", e));
+                return;
+            },
+        };
+
+        let stdout = match utils::command_stdout_to_string(&output) {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+
+        for line in stdout.lines() {
+            log::info!("{}", line);
+        }
+    }
+
+    /// Time the key file hashing and recommend parameters
+    fn bench_argon2(&self) -> error::Return {
+        let salt = [0u8; 16];
+        let target = time::Duration::from_millis(self.target);
+
+        let mut recommended: Option<(u32, u32)> = None;
+
+        log::info!(
+            "{:>10} {:>12} {:>12}",
+            "mem-cost",
+            "1 iteration",
+            "iterations");
+
+        for mem_cost in MEM_COSTS.iter() {
+            signals::check()?;
+
+            let start = time::Instant::now();
+
+            luks::hash_password(
+                b"benchmark",
+                &salt,
+                1,
+                *mem_cost,
+                self.key_size)?;
+
+            let elapsed = start.elapsed();
+
+            // Number of iterations to reach the target time
+            let iterations = (target.as_secs_f64() / elapsed.as_secs_f64())
+                .floor() as u32;
+
+            log::info!(
+                "{:>10} {:>11.0}ms {:>12}",
+                mem_cost,
+                elapsed.as_secs_f64() * 1000.0,
+                iterations);
+
+            // Keep the highest memory cost that still allows a few iterations
+            if iterations >= 2 {
+                recommended = Some((*mem_cost, iterations));
+            }
+
+            // Higher memory costs will be even slower
+            if elapsed > target {
+                break;
+            }
+        }
+
+        match recommended {
+            Some((mem_cost, iterations)) => log::info!(
+                "Recommended: --iterations {} --mem-cost {} (~{}ms)",
+                iterations,
+                mem_cost,
+                self.target),
+
+            None => report::warning(
+                "No parameters reach the target time, use the defaults"),
+        }
+
+        return Success!();
+    }
+}
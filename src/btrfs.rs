@@ -0,0 +1,209 @@
+// -----------------------------------------------------------------------------
+
+use serde::{Deserialize, Serialize};
+use std::path;
+
+use super::error;
+use super::traits::{Mountable, Validate};
+use super::utils;
+
+// -----------------------------------------------------------------------------
+
+/// Json configuration of a btrfs subvolume
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// Name of the subvolume (e.g. `@home`)
+    pub name: String,
+
+    /// Mountpoint of the subvolume
+    pub mountpoint: String,
+
+    /// Whether this subvolume is the root mount point
+    pub is_root: bool,
+}
+
+impl Validate for Config {
+    fn is_valid(&self) -> bool {
+        return
+            !self.name.is_empty() &&
+            !self.name.contains('/') &&
+            !self.mountpoint.is_empty();
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Subvolumes representation
+#[derive(Debug)]
+pub struct Subvolumes {
+    /// List of subvolumes
+    pub subvolumes: Vec<Subvolume>,
+}
+
+impl Subvolumes {
+    /// Create subvolumes entries from Json configuration
+    pub fn from_config(device: Option<&str>, configs: &Vec<Config>) -> Self {
+        let mut subvolumes: Vec<Subvolume> = Vec::new();
+
+        for config in configs.iter() {
+            subvolumes.push(Subvolume::from_config(device, config));
+        }
+
+        Self {
+            subvolumes: subvolumes,
+        }
+    }
+
+    /// Convert to Json configuration
+    pub fn config(&self) -> Result<Vec<Config>, error::Error> {
+        let mut config: Vec<Config> = Vec::new();
+
+        for subvolume in self.subvolumes.iter() {
+            config.push(subvolume.config.clone());
+        }
+
+        return Ok(config);
+    }
+
+    /// Identify the subvolumes created by a previous run on the device
+    pub fn identify(&mut self, device: &str) {
+        for subvolume in self.subvolumes.iter_mut() {
+            subvolume.device = Some(device.to_string());
+        }
+    }
+
+    /// Create the subvolumes (the top volume of the device is temporarily
+    /// mounted)
+    pub fn create(&mut self, device: &str) -> error::Return {
+        let temp_dir = match mktemp::Temp::new_dir() {
+            Ok(d) => d,
+            Err(e) => return io_error!("/tmp", e),
+        };
+
+        let top = match temp_dir.to_str() {
+            Some(t) => t.to_string(),
+            None => return generic_error!("No mountpoint"),
+        };
+
+        utils::command_output("mount", &["-t", "btrfs", device, &top])?;
+
+        let mut result = Success!();
+
+        for subvolume in self.subvolumes.iter_mut() {
+            result = subvolume_create(&top, &subvolume.config.name);
+
+            if result.is_err() {
+                break;
+            }
+
+            subvolume.device = Some(device.to_string());
+        }
+
+        utils::command_output("umount", &[&top])?;
+
+        return result;
+    }
+}
+
+impl Validate for Subvolumes {
+    fn is_valid(&self) -> bool {
+        return !self.subvolumes.is_empty();
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Subvolume representation
+#[derive(Debug)]
+pub struct Subvolume {
+    /// Json configuration
+    pub config: Config,
+
+    /// Block device holding the subvolume
+    device: Option<String>,
+
+    /// Where the subvolume is mounted (if mounted)
+    mountpoint: Option<String>,
+}
+
+impl Subvolume {
+    /// Create subvolume entry from Json configuration
+    pub fn from_config(device: Option<&str>, config: &Config) -> Self {
+        Self {
+            config: config.clone(),
+            device: device.map(|d| d.to_string()),
+            mountpoint: None,
+        }
+    }
+}
+
+impl Mountable for Subvolume {
+    /// Mount this subvolume
+    fn mount(&mut self, mountpoint: &path::PathBuf) -> error::Return {
+        if self.mountpoint.is_some() {
+            return Success!();
+        }
+
+        let device = match &self.device {
+            Some(d) => d,
+            None => return generic_error!("No device for subvolume"),
+        };
+
+        let mountpoint = match mountpoint.to_str() {
+            Some(m) => m,
+            None => return generic_error!("No mountpoint"),
+        };
+
+        utils::mount(&[
+            "-t", "btrfs",
+            "-o", &format!("subvol={}", self.config.name),
+            device,
+            mountpoint,
+        ])?;
+
+        self.mountpoint = Some(mountpoint.to_string());
+
+        log::info!(
+            "`{}` ({}) mounted to `{}`",
+            device,
+            self.config.name,
+            mountpoint);
+
+        return Success!();
+    }
+
+    /// Unmount this subvolume
+    fn unmount(&mut self) -> error::Return {
+        let mountpoint = match &self.mountpoint {
+            Some(m) => m.clone(),
+            None => return Success!(),
+        };
+
+        // Several subvolumes of the device can be mounted
+        utils::command_output("umount", &[&mountpoint])?;
+
+        self.mountpoint = None;
+
+        log::info!("{} unmounted", mountpoint);
+
+        return Success!();
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Create a subvolume in a mounted top volume
+pub fn subvolume_create(top: &str, name: &str) -> error::Return {
+    let path = path::Path::new(top).join(name);
+
+    let path = match path.to_str() {
+        Some(p) => p.to_string(),
+        None => return generic_error!("Invalid subvolume path"),
+    };
+
+    utils::command_output("btrfs", &["subvolume", "create", &path])?;
+
+    log::info!("btrfs subvolume `{}` created", name);
+
+    return Success!();
+}
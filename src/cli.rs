@@ -1,16 +1,30 @@
 // -----------------------------------------------------------------------------
 
 use clap;
+use std::path;
+use std::str::FromStr;
 
+use super::bench;
+use super::crypttab;
 use super::env;
 use super::error;
 use super::hardware;
+use super::initramfs;
 use super::filesystems;
+use super::gpt;
+use super::grow;
 use super::install;
 use super::luks;
+use super::migrate;
 use super::partitioning;
+use super::repair;
 use super::secrets;
+use super::status;
 use super::traits::CliCommand;
+use super::unmount;
+use super::utils;
+use super::verify;
+use super::wipe;
 
 // -----------------------------------------------------------------------------
 
@@ -18,48 +32,244 @@ type CommandList = Vec<Box<dyn CliCommand>>;
 
 // -----------------------------------------------------------------------------
 
-pub fn parse() -> error::Return {
-    let author = "Mathieu H. <mhardy2008@gmail.com>";
-    let version = "1.0";
+const ARG_COMMAND_TIMEOUT: &str = "command-timeout";
+const ARG_DRY_RUN: &str = "dry-run";
+const ARG_JSON_ERRORS: &str = "json-errors";
+const ARG_LOG_FILE: &str = "log-file";
+const ARG_LOG_LEVEL: &str = "log-level";
+const ARG_MAX_LOG_FILE: &str = "max-log-file";
+const ARG_OFFLINE: &str = "offline";
+const ARG_PROFILE: &str = "profile";
+const ARG_QUIET: &str = "quiet";
+const ARG_RETRIES: &str = "retries";
+const ARG_VERBOSE: &str = "verbose";
 
-    // Create command line parser
-    let mut app = clap::App::new("NixOS setup")
-        .version(version)
-        .author(author)
-        .about("Performs machine setup for installing NixOS");
+/// Possible levels of the console logs
+const LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
 
-    // Add commands
-    let mut commands = create_commands();
+/// Default size at which the log file is rotated
+const DEFAULT_MAX_LOG_FILE: &str = "10M";
 
-    for c in commands.iter() {
-        app = app.subcommand(c.get(version, author));
+// -----------------------------------------------------------------------------
+
+/// Command line interface
+pub struct Cli {
+    /// List of available commands
+    commands: CommandList,
+
+    /// Arguments provided on the command line
+    matches: clap::ArgMatches<'static>,
+}
+
+impl Default for Cli {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+impl Cli {
+    /// Parse the command line
+    pub fn new() -> Self {
+        let author = "Mathieu H. <mhardy2008@gmail.com>";
+        let version = "1.0";
+
+        // The defaults are the ones of utils
+        let command_timeout_help = format!(
+            "Seconds after which an external command is killed (0 to disable, \
+                builds and copies are never killed) [default: {}]",
+            utils::DEFAULT_COMMAND_TIMEOUT);
+
+        let retries_help = format!(
+            "Attempts of the operations failing while devices appear (pools \
+                import, mounts) [default: {}]",
+            utils::DEFAULT_RETRIES);
+
+        // Create command line parser
+        let mut app = clap::App::new("NixOS setup")
+            .version(version)
+            .author(author)
+            .about("Performs machine setup for installing NixOS")
+            // Command timeout argument
+            .arg(clap::Arg::with_name(ARG_COMMAND_TIMEOUT)
+                .long(ARG_COMMAND_TIMEOUT)
+                .help(&command_timeout_help)
+                .takes_value(true))
+            // Dry run argument
+            .arg(clap::Arg::with_name(ARG_DRY_RUN)
+                .long(ARG_DRY_RUN)
+                .help("Log the commands and files instead of running and \
+                    writing them"))
+            // JSON errors argument
+            .arg(clap::Arg::with_name(ARG_JSON_ERRORS)
+                .long(ARG_JSON_ERRORS)
+                .help("Print errors as JSON on stderr"))
+            // Log file argument
+            .arg(clap::Arg::with_name(ARG_LOG_FILE)
+                .long(ARG_LOG_FILE)
+                .help("File where all logs are written (at trace level)")
+                .takes_value(true))
+            // Log level argument
+            .arg(clap::Arg::with_name(ARG_LOG_LEVEL)
+                .long(ARG_LOG_LEVEL)
+                .help("Level of the console logs (info by default, raised by \
+                    -v and lowered by -q)")
+                .possible_values(LOG_LEVELS)
+                .takes_value(true))
+            // Max log file argument
+            .arg(clap::Arg::with_name(ARG_MAX_LOG_FILE)
+                .long(ARG_MAX_LOG_FILE)
+                .help("Size at which the log file is rotated (e.g. 10M, 0 to \
+                    disable)")
+                .default_value(DEFAULT_MAX_LOG_FILE)
+                .takes_value(true))
+            // Offline argument
+            .arg(clap::Arg::with_name(ARG_OFFLINE)
+                .long(ARG_OFFLINE)
+                .help("Skip or refuse any operation requiring network access"))
+            // Profile argument
+            .arg(clap::Arg::with_name(ARG_PROFILE)
+                .long(ARG_PROFILE)
+                .help("Base profile (profiles/<name>.env) the host .env is \
+                    layered on (command line > .env > profile)")
+                .takes_value(true))
+            // Quiet argument
+            .arg(clap::Arg::with_name(ARG_QUIET)
+                .short("q")
+                .long(ARG_QUIET)
+                .help("Less console logs (can be repeated)")
+                .multiple(true))
+            // Retries argument
+            .arg(clap::Arg::with_name(ARG_RETRIES)
+                .long(ARG_RETRIES)
+                .help(&retries_help)
+                .takes_value(true))
+            // Verbose argument
+            .arg(clap::Arg::with_name(ARG_VERBOSE)
+                .short("v")
+                .long(ARG_VERBOSE)
+                .help("More console logs (can be repeated)")
+                .multiple(true));
+
+        // Add commands
+        let commands = create_commands();
+
+        for c in commands.iter() {
+            app = app.subcommand(c.get(version, author));
+        }
+
+        Self {
+            commands: commands,
+            matches: app.get_matches(),
+        }
+    }
+
+    /// Whether errors must be printed as JSON
+    pub fn json_errors(&self) -> bool {
+        return self.matches.is_present(ARG_JSON_ERRORS);
+    }
+
+    /// Level of the console logs (shifted by the verbose and quiet flags)
+    pub fn log_level(&self) -> log::LevelFilter {
+        let level = match self.matches.value_of(ARG_LOG_LEVEL) {
+            Some(l) => log::LevelFilter::from_str(l)
+                .unwrap_or(log::LevelFilter::Info),
+
+            None => log::LevelFilter::Info,
+        };
+
+        let shift =
+            self.matches.occurrences_of(ARG_VERBOSE) as i64 -
+            self.matches.occurrences_of(ARG_QUIET) as i64;
+
+        return shift_level(level, shift);
+    }
+
+    /// File where all logs are written
+    pub fn log_file(&self) -> Option<&path::Path> {
+        return self.matches.value_of(ARG_LOG_FILE).map(path::Path::new);
     }
 
-    // Get and execute command provided
-    let command = match app.get_matches().subcommand {
-        Some(c) => c,
-        None => return generic_error!("No subcommand provided"),
-    };
+    /// Size at which the log file is rotated (in bytes)
+    pub fn max_log_file(&self) -> u64 {
+        let size = self.matches
+            .value_of(ARG_MAX_LOG_FILE)
+            .unwrap_or(DEFAULT_MAX_LOG_FILE);
+
+        return gpt::Bytesize::from(size).to_bytes();
+    }
+
+    /// Execute the command provided
+    pub fn process(&mut self) -> error::Return {
+        let command = match &self.matches.subcommand {
+            Some(c) => c,
+            None => return generic_error!("No subcommand provided"),
+        };
+
+        let timeout = match self.matches.value_of(ARG_COMMAND_TIMEOUT) {
+            Some(t) => match t.parse::<u64>() {
+                Ok(t) => t,
+                Err(_) => return inval_error!(&ARG_COMMAND_TIMEOUT),
+            },
 
-    for c in commands.iter_mut() {
-        if command.name.as_str() == c.name() {
-            return c.process(&command.matches);
+            None => utils::DEFAULT_COMMAND_TIMEOUT,
+        };
+
+        utils::set_command_timeout(timeout);
+
+        let retries = match self.matches.value_of(ARG_RETRIES) {
+            Some(r) => match r.parse::<u32>() {
+                Ok(r) if r > 0 => r,
+                _ => return inval_error!(&ARG_RETRIES),
+            },
+
+            None => utils::DEFAULT_RETRIES,
+        };
+
+        utils::set_retries(retries);
+
+        utils::set_offline(self.matches.is_present(ARG_OFFLINE));
+        utils::set_dry_run(self.matches.is_present(ARG_DRY_RUN));
+        env::set_profile(self.matches.value_of(ARG_PROFILE))?;
+
+        for c in self.commands.iter_mut() {
+            if command.name.as_str() == c.name() {
+                return c.process(&command.matches);
+            }
         }
+
+        return generic_error!("Command has not been processed");
     }
+}
+
+/// Shift a level (towards trace if positive, towards off if negative)
+fn shift_level(level: log::LevelFilter, shift: i64) -> log::LevelFilter {
+    let last = (LOG_LEVELS.len() - 1) as i64;
+    let index = (level as i64 + shift).clamp(0, last);
 
-    return generic_error!("Command has not been processed");
+    return log::LevelFilter::from_str(LOG_LEVELS[index as usize])
+        .unwrap_or(level);
 }
 
 fn create_commands() -> CommandList {
-    let mut commands: CommandList = Vec::new();
-
-    commands.push(Box::new(env::Command::new()));
-    commands.push(Box::new(filesystems::Command::new()));
-    commands.push(Box::new(hardware::Command::new()));
-    commands.push(Box::new(install::Command::new()));
-    commands.push(Box::new(luks::Command::new()));
-    commands.push(Box::new(partitioning::Command::new()));
-    commands.push(Box::new(secrets::Command::new()));
+    let commands: CommandList = vec![
+        Box::new(bench::Command::new()),
+        Box::new(crypttab::Command::new()),
+        Box::new(env::Command::new()),
+        Box::new(filesystems::Command::new()),
+        Box::new(grow::Command::new()),
+        Box::new(hardware::Command::new()),
+        Box::new(initramfs::Command::new()),
+        Box::new(install::Command::new()),
+        Box::new(luks::Command::new()),
+        Box::new(migrate::Command::new()),
+        Box::new(partitioning::Command::new()),
+        Box::new(repair::Command::new()),
+        Box::new(secrets::Command::new()),
+        Box::new(status::Command::new()),
+        Box::new(unmount::Command::new()),
+        Box::new(verify::Command::new()),
+        Box::new(wipe::Command::new()),
+    ];
 
     return commands;
 }
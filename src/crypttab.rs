@@ -0,0 +1,182 @@
+// -----------------------------------------------------------------------------
+
+use clap;
+
+use super::env;
+use super::error;
+use super::filesystem;
+use super::traits::{CliCommand, Validate};
+
+// -----------------------------------------------------------------------------
+
+const ARG_HOST: &str = "host";
+
+// -----------------------------------------------------------------------------
+
+/// Command structure for printing a crypttab-style summary of a layout
+#[derive(Debug)]
+pub struct Command {
+    /// Host name
+    host: String,
+
+    /// Name of the key file used to decrypt disks
+    key_filename: String,
+}
+
+impl Validate for Command {
+    fn is_valid(&self) -> bool {
+        return
+            !self.host.is_empty() &&
+            !self.key_filename.is_empty();
+    }
+}
+
+impl CliCommand for Command {
+    /// Get the name of the command
+    fn name(&self) -> &'static str {
+        return "crypttab";
+    }
+
+    /// Get command and its arguments
+    fn get<'a, 'b>(
+        &self,
+        version: &'b str,
+        author: &'b str) -> clap::App<'a, 'b> {
+
+        return clap::App::new(self.name())
+            .about("Print the crypttab equivalent of the encrypted devices")
+            .version(version)
+            .author(author)
+            // Host argument
+            .arg(clap::Arg::with_name(ARG_HOST)
+                .long(ARG_HOST)
+                .help("Host name (optional if a .env file is present)")
+                .takes_value(true));
+    }
+
+    /// Process command line arguments
+    fn process(&mut self, matches: &clap::ArgMatches) -> error::Return {
+        // Parse arguments
+        for arg in matches.args.iter() {
+            match arg.0 {
+                &ARG_HOST => {
+                    self.host = match matches.value_of(arg.0) {
+                        Some(s) => s.to_owned(),
+                        None => return inval_error!(&ARG_HOST),
+                    };
+                },
+
+                _ => {
+                    return inval_error!(arg.0);
+                }
+            }
+        }
+
+        if !self.is_valid() {
+            self.fill_with_env()?;
+        }
+
+        log::debug!("{:#?}", self);
+
+        // Check validity
+        env::check_values(&[
+            (ARG_HOST, &self.host),
+            ("key_filename", &self.key_filename),
+        ])?;
+
+        // Create filesystem from the layout
+        let layout = filesystem::layout(&self.host)?;
+
+        let fs = filesystem::Filesystem::from_file(&layout)?;
+
+        // Print entries
+        print!("{}", self.create_crypttab(&fs)?);
+
+        return Success!();
+    }
+}
+
+impl Command {
+    /// Create an instance of Command
+    pub fn new() -> Self {
+        Self {
+            host: String::from(""),
+            key_filename: String::from(""),
+        }
+    }
+
+    /// Use environment file to get needed values
+    fn fill_with_env(&mut self) -> error::Return {
+        let config = match env::read_for_host(&mut self.host)? {
+            Some(c) => c,
+            None => return Success!(),
+        };
+
+        self.key_filename = config.nixos.key_filename;
+
+        return Success!();
+    }
+
+    /// Create the crypttab lines (same data as `devices.nix`)
+    fn create_crypttab(
+        &self,
+        fs: &filesystem::Filesystem) -> Result<String, error::Error> {
+
+        let mut content = "# <name> <device> <keyfile> <options>\n".to_string();
+
+        for disk in fs.disks.iter() {
+            for partition in disk.partitions.iter() {
+                if !partition.config.encrypted {
+                    continue;
+                }
+
+                let device = match &partition.config.device_by_partlabel {
+                    Some(d) => d,
+                    None => return generic_error!("No path for partition"),
+                };
+
+                content += &entry(
+                    &partition.config.label,
+                    device,
+                    &format!("/{}", self.key_filename),
+                    "luks,discard")?;
+            }
+        }
+
+        return Ok(content);
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Create a crypttab line (fields can't be empty or contain blanks)
+pub fn entry(
+    name: &str,
+    device: &str,
+    key_file: &str,
+    options: &str) -> Result<String, error::Error> {
+
+    let fields = [name, device, key_file, options];
+
+    if fields.iter().any(|f| f.is_empty() || f.contains(char::is_whitespace)) {
+        return generic_error!(&format!(
+            "Invalid crypttab entry `{}`",
+            fields.join(" ")));
+    }
+
+    if !device.starts_with("/dev/") {
+        return generic_error!(&format!(
+            "Device `{}` of `{}` is not in /dev",
+            device,
+            name));
+    }
+
+    if !key_file.starts_with('/') || key_file.contains("/../") {
+        return generic_error!(&format!(
+            "Key file `{}` of `{}` is not an absolute path",
+            key_file,
+            name));
+    }
+
+    return Ok(format!("{} {} {} {}\n", name, device, key_file, options));
+}
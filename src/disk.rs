@@ -1,12 +1,31 @@
 // -----------------------------------------------------------------------------
 
 use serde::{Deserialize, Serialize};
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::path;
 use std::str::FromStr;
 
 use super::error;
 use super::gpt;
 use super::partition;
+use super::plan;
+use super::raid;
+use super::report;
 use super::traits::{Configurable, Mountable, Openable, Validate};
+use super::utils;
+use super::zfs;
+
+// -----------------------------------------------------------------------------
+
+/// Directory of the stable names of the disks
+const BY_ID_DIR: &str = "/dev/disk/by-id";
+
+/// Prefix of a disk given by serial number
+const SERIAL_PREFIX: &str = "serial:";
+
+/// Prefix of a disk given by model
+const MODEL_PREFIX: &str = "model:";
 
 // -----------------------------------------------------------------------------
 
@@ -17,15 +36,47 @@ pub struct Config {
     pub device: String,
 
     /// If ready-only: no write operation will be performed on this disk
+    #[serde(default)]
     pub read_only: bool,
 
-    /// Whether this disk contains the Linux system
-    pub contains_system: bool,
+    /// Whether the disk is only plugged occasionally (unlocked and mounted
+    /// when present, never needed to boot)
+    #[serde(default)]
+    pub external: bool,
+
+    /// Whether this disk contains the Linux system (inferred from the root
+    /// if not provided)
+    #[serde(default)]
+    pub contains_system: Option<bool>,
 
     /// List of partition configurations
     pub partitions: Vec<partition::Config>,
 }
 
+impl Config {
+    /// Check if this disk contains the Linux system
+    pub fn contains_system(&self) -> bool {
+        return self.contains_system.unwrap_or(false);
+    }
+
+    /// Check if a partition, volume, ZFS filesystem or Btrfs subvolume of the
+    /// disk is the root mount point
+    pub fn has_root(&self) -> bool {
+        return self.partitions.iter().any(|p|
+            p.is_root ||
+            p.lvm.iter().any(|v| v.is_root) ||
+            p.zfs.iter().any(|fs| fs.is_root) ||
+            p.btrfs.iter().any(|s| s.is_root));
+    }
+
+    /// Check if the disk holds a member of an array
+    fn has_member_of(&self, array: &raid::Config) -> bool {
+        return self.partitions
+            .iter()
+            .any(|p| array.members.contains(&p.label));
+    }
+}
+
 impl Validate for Config {
     fn is_valid(&self) -> bool {
         if self.device.is_empty() {
@@ -38,12 +89,208 @@ impl Validate for Config {
             }
         }
 
+        // GRUB mirrors the boot on one EFI partition per disk
+        let efi_count = self.partitions
+            .iter()
+            .filter(|p| matches!(
+                gpt::PartitionType::from_str(&p.partition_type),
+                Ok(gpt::PartitionType::Efi)))
+            .count();
+
+        if efi_count > 1 {
+            return false;
+        }
+
+        // Nothing needed to boot can be on an external disk
+        if self.external {
+            if self.contains_system() {
+                log::error!(
+                    "External disk `{}` cannot contain the system",
+                    self.device);
+
+                return false;
+            }
+
+            match self.partitions.iter().find(|p| !p.is_data()) {
+                Some(p) => {
+                    log::error!(
+                        "Partition `{}` of external disk `{}` must only \
+                            hold data",
+                        p.label,
+                        self.device);
+
+                    return false;
+                },
+
+                None => (),
+            }
+        }
+
         return true;
     }
 }
 
 // -----------------------------------------------------------------------------
 
+/// Check if a device is given by serial number or model
+pub fn is_device_spec(device: &str) -> bool {
+    return
+        device.starts_with(SERIAL_PREFIX) ||
+        device.starts_with(MODEL_PREFIX);
+}
+
+/// Resolve a device given by serial number or model (`serial:S1234567`,
+/// `model:Samsung_SSD_860`) to the real device, using `/dev/disk/by-id`
+pub fn resolve_device(device: &str) -> Result<String, error::Error> {
+    let dir = path::Path::new(BY_ID_DIR);
+
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => return fs_error!(dir.to_path_buf(), e),
+    };
+
+    let mut links = Vec::new();
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => return fs_error!(dir.to_path_buf(), e),
+        };
+
+        let target = match fs::canonicalize(entry.path()) {
+            Ok(t) => t.to_string_lossy().to_string(),
+            Err(e) => return fs_error!(entry.path(), e),
+        };
+
+        links.push((entry.file_name().to_string_lossy().to_string(), target));
+    }
+
+    let resolved = match_by_id(device, &links)?;
+
+    log::info!("Disk `{}` resolved to `{}`", device, resolved);
+
+    return Ok(resolved);
+}
+
+/// Find the disk matching a serial number or model among the links of
+/// `/dev/disk/by-id` (name and target), named `<bus>-<model>_<serial>`
+fn match_by_id(
+    device: &str,
+    links: &[(String, String)]) -> Result<String, error::Error> {
+
+    let matches = |name: &str| -> bool {
+        // Only the whole disks (a same disk is listed under several names)
+        let is_partition = match name.rsplit_once("-part") {
+            Some((_, n)) => n.parse::<u32>().is_ok(),
+            None => false,
+        };
+
+        let description = match name.split_once('-') {
+            Some((_, d)) if !is_partition => d,
+            _ => return false,
+        };
+
+        match device.strip_prefix(SERIAL_PREFIX) {
+            Some(s) => return description.ends_with(&format!("_{}", s)),
+            None => (),
+        }
+
+        return match device.strip_prefix(MODEL_PREFIX) {
+            Some(m) => description.starts_with(&format!("{}_", m)),
+            None => false,
+        };
+    };
+
+    let mut targets: Vec<&str> = links
+        .iter()
+        .filter(|l| matches(&l.0))
+        .map(|l| l.1.as_str())
+        .collect();
+
+    targets.sort();
+    targets.dedup();
+
+    return match targets.len() {
+        0 => generic_error!(&format!(
+            "No disk matches `{}` in {}",
+            device,
+            BY_ID_DIR)),
+
+        1 => Ok(targets[0].to_string()),
+
+        _ => generic_error!(&format!(
+            "Several disks match `{}`: {}",
+            device,
+            targets.join(", "))),
+    };
+}
+
+/// Infer whether the disks contain the system: the disk holding the root, or
+/// the first disk holding a member of the root array (the others are boot
+/// mirrors). Explicit values are checked against the layout.
+pub fn infer_contains_system(
+    disks: &mut [Config],
+    arrays: &[raid::Config]) -> error::Return {
+
+    let root_array = arrays.iter().find(|a| a.is_root);
+
+    let mut array_system_found =
+        disks.iter().any(|d| d.contains_system == Some(true));
+
+    for disk in disks.iter_mut() {
+        let holds_root_array = match root_array {
+            Some(a) => disk.has_member_of(a),
+            None => false,
+        };
+
+        match disk.contains_system {
+            Some(true) if !disk.has_root() && !holds_root_array => {
+                return generic_error!(&format!(
+                    "Disk `{}` is set to contain the system but holds no \
+                        root",
+                    disk.device));
+            },
+
+            Some(false) if disk.has_root() => {
+                return generic_error!(&format!(
+                    "Disk `{}` holds the root but is set not to contain the \
+                        system",
+                    disk.device));
+            },
+
+            Some(_) => (),
+
+            None => {
+                let inferred = disk.has_root() ||
+                    (holds_root_array && !array_system_found);
+
+                if inferred && holds_root_array {
+                    array_system_found = true;
+                }
+
+                log::debug!(
+                    "Disk `{}` contains the system: {} (inferred)",
+                    disk.device,
+                    inferred);
+
+                disk.contains_system = Some(inferred);
+            },
+        }
+    }
+
+    return Success!();
+}
+
+/// Resolve the links of a device path (kept as is if it cannot be resolved)
+fn canonical_device(device: &str) -> String {
+    return match fs::canonicalize(device) {
+        Ok(p) => p.to_string_lossy().to_string(),
+        Err(_) => device.to_string(),
+    };
+}
+
+// -----------------------------------------------------------------------------
+
 /// Disk representation
 #[derive(Debug)]
 pub struct Disk {
@@ -52,6 +299,16 @@ pub struct Disk {
 
     /// List of partitions
     pub partitions: Vec<partition::Partition>,
+
+    /// Whether the disk is selected for partitioning
+    pub selected: bool,
+
+    /// Whether an existing non-GPT partition table can be converted
+    pub force_gpt: bool,
+
+    /// Whether the existing partitions matching the layout are kept (the
+    /// disk isn't wiped), until one doesn't match
+    pub resume: bool,
 }
 
 impl Disk {
@@ -60,28 +317,203 @@ impl Disk {
         self.config.read_only
     }
 
+    /// Check if disk contains a ZFS partition
+    pub fn has_zfs(&self) -> bool {
+        for p in self.partitions.iter() {
+            match gpt::FsType::from_str(&p.config.fs_type) {
+                Ok(gpt::FsType::Zfs) => return true,
+                _ => continue,
+            }
+        }
+
+        return false;
+    }
+
+    /// Check the device of the disk exists and isn't in use (mounted or
+    /// member of an imported pool) before wiping it
+    pub fn check_device(&self) -> error::Return {
+        let device = &self.config.device;
+
+        // Nothing is touched: the layout can be checked on another machine
+        if utils::is_dry_run() {
+            return Success!();
+        }
+
+        let metadata = match fs::metadata(device) {
+            Ok(m) => m,
+            Err(_) => return generic_error!(&format!(
+                "Device `{}` doesn't exist (check the layout or --device)",
+                device)),
+        };
+
+        if !metadata.file_type().is_block_device() {
+            return generic_error!(
+                &format!("`{}` is not a block device", device));
+        }
+
+        // The disk and its children (partitions, mappers, volumes)
+        let output = utils::command_output(
+            "lsblk",
+            &["-n", "-r", "-p", "-o", "NAME,MOUNTPOINT", device])?;
+        let output = utils::command_stdout_to_string(&output)?;
+
+        let mut names = Vec::new();
+
+        for line in output.lines() {
+            let mut fields = line.split_whitespace();
+
+            let name = match fields.next() {
+                Some(n) => n,
+                None => continue,
+            };
+
+            match fields.next() {
+                Some(m) => return generic_error!(&format!(
+                    "`{}` is in use: `{}` is mounted on {}",
+                    device,
+                    name,
+                    m)),
+                None => (),
+            }
+
+            names.push(canonical_device(name));
+        }
+
+        for (pool, member) in zfs::pool_devices() {
+            if names.contains(&canonical_device(&member)) {
+                return generic_error!(&format!(
+                    "`{}` is in use: `{}` is a member of the imported ZFS \
+                        pool `{}` (export it first)",
+                    device,
+                    member,
+                    pool));
+            }
+        }
+
+        return Success!();
+    }
+
+    /// Check the partition table of the disk before wiping it (converting a
+    /// non-GPT table must be forced)
+    pub fn check_partition_table(&self) -> error::Return {
+        let device = &self.config.device;
+
+        let table = gpt::partition_table(device)?;
+
+        log::info!(
+            "`{}` has {} partition table",
+            device,
+            table.to_string());
+
+        if !table.is_foreign() {
+            return Success!();
+        }
+
+        if !self.force_gpt {
+            return generic_error!(&format!(
+                "`{}` has an existing {} partition table, use --force-gpt to \
+                    convert it to GPT (its data will be lost)",
+                device,
+                table.to_string()));
+        }
+
+        report::warning(&format!(
+            "`{}` had an {} partition table, converted to GPT",
+            device,
+            table.to_string()));
+
+        return Success!();
+    }
+
     /// Wipeout the disk
     pub fn wipeout(&self) -> error::Return {
+        report::device(&self.config.device);
+
         return gpt::wipeout(&self.config.device);
     }
 
-    /// Create the disk from its configuration
-    pub fn create(
-        &mut self,
-        key_file: &str,
-        passphrase: &str) -> error::Return {
+    /// Run a step of the plan creating the disk (only touches this disk so
+    /// disks can be created in parallel)
+    pub fn run(&mut self, step: &plan::PlanStep) -> error::Return {
+        return match (step.action, &step.target) {
+            (plan::Action::WipeDisk, _) => self.wipeout(),
+            (plan::Action::CreatePartition, Some(t)) => {
+                self.create_partition(t)
+            },
+            _ => generic_error!(&format!(
+                "Invalid step for disk `{}`",
+                self.config.device)),
+        };
+    }
 
-        // Create
-        for partition in self.partitions.iter_mut() {
-            partition.create(&self.config.device)?;
+    /// Skip a step of the plan completed by a previous run (the created
+    /// partitions are identified)
+    pub fn skip(&mut self, step: &plan::PlanStep) -> error::Return {
+        log::info!("Step `{}` already completed", step);
+
+        let label = match (step.action, &step.target) {
+            (plan::Action::CreatePartition, Some(t)) => t,
+            _ => return Success!(),
+        };
+
+        let device = self.config.device.clone();
+
+        return match self.partitions
+            .iter_mut()
+            .find(|p| &p.config.label == label) {
+
+            Some(p) => p.identify_existing(&device),
+            None => generic_error!(&format!(
+                "No partition `{}` on `{}`",
+                label,
+                device)),
+        };
+    }
+
+    /// Create a partition of the disk
+    fn create_partition(&mut self, label: &str) -> error::Return {
+        let device = self.config.device.clone();
+
+        let index = match self.partitions
+            .iter()
+            .position(|p| p.config.label == label) {
+
+            Some(i) => i,
+            None => return generic_error!(&format!(
+                "No partition `{}` on `{}`",
+                label,
+                device)),
+        };
+
+        let number = (index + 1) as u32;
+        let partition = &mut self.partitions[index];
+
+        // Partitions are kept until the first one not matching the layout
+        if self.resume && partition.matches(&device, number)? {
+            return partition.keep(&device);
         }
 
-        // Format
-        for partition in self.partitions.iter_mut() {
-            partition.format(key_file, passphrase)?;
+        // Following partitions are created again
+        if self.resume {
+            for n in gpt::partition_numbers(&device)? {
+                if n >= number {
+                    gpt::delete_partition(&device, n)?;
+                }
+            }
+
+            self.resume = false;
         }
 
-        return Success!();
+        return partition.create(&device);
+    }
+
+    /// Tear down what has been set up on the partitions (see
+    /// `Partition::rollback`)
+    pub fn rollback(&mut self) -> Vec<String> {
+        return self.partitions
+            .iter_mut()
+            .flat_map(|p| p.rollback())
+            .collect();
     }
 
     /// Find root partition/lvm/zfs
@@ -108,42 +540,15 @@ impl Disk {
                     return Ok(fs);
                 }
             }
-        }
-
-        return generic_error!("Root partition not found");
-    }
-
-    /// Find EFI partition/lvm/zfs
-    pub fn find_efi_partition(&mut self)
-        -> Result<&mut dyn Mountable, error::Error> {
-
-        for p in self.partitions.iter_mut() {
-            let partition_type =
-                gpt::PartitionType::from_str(&p.config.partition_type)?;
-
-            match partition_type {
-                gpt::PartitionType::Efi => return Ok(p),
-                _ => (),
-            }
 
-            if !p.config.is_system {
-                continue;
-            }
-
-            for volume in p.lvm.volumes.iter_mut() {
-                let volume_type =
-                    gpt::PartitionType::from_str(&volume.config.volume_type)?;
-
-                match volume_type {
-                    gpt::PartitionType::Efi => return Ok(volume),
-                    _ => (),
+            for subvolume in p.btrfs.subvolumes.iter_mut() {
+                if subvolume.config.is_root {
+                    return Ok(subvolume);
                 }
             }
-
-            // Cannot be ZFS so no need to check
         }
 
-        return generic_error!("EFI partition not found");
+        return generic_error!("Root partition not found");
     }
 }
 
@@ -187,6 +592,9 @@ impl Configurable<Config> for Disk {
         Self {
             config: c,
             partitions: partitions,
+            selected: true,
+            force_gpt: false,
+            resume: false,
         }
     }
 
@@ -203,8 +611,52 @@ impl Configurable<Config> for Disk {
         return Ok(Config {
             device: self.config.device.clone(),
             read_only: self.config.read_only.clone(),
+            external: self.config.external,
             contains_system: self.config.contains_system.clone(),
             partitions: partitions,
         });
     }
 }
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn by_id() -> Vec<(String, String)> {
+        return [
+            ("ata-Samsung_SSD_860_EVO_S3Z1NB0K123456", "/dev/sda"),
+            ("ata-Samsung_SSD_860_EVO_S3Z1NB0K123456-part1", "/dev/sda1"),
+            ("wwn-0x5002538e40a1b2c3", "/dev/sda"),
+            ("ata-Samsung_SSD_860_EVO_S3Z1NB0K654321", "/dev/sdb"),
+            ("nvme-WD_BLACK_SN850X_2000GB_23123A456789", "/dev/nvme0n1"),
+            ("nvme-WD_BLACK_SN850X_2000GB_23123A456789_1", "/dev/nvme0n1"),
+        ]
+            .iter()
+            .map(|(n, t)| (n.to_string(), t.to_string()))
+            .collect();
+    }
+
+    #[test]
+    fn resolves_disks_by_serial_and_model() {
+        let links = by_id();
+
+        assert_eq!(
+            match_by_id("serial:S3Z1NB0K654321", &links).unwrap(),
+            "/dev/sdb");
+        assert_eq!(
+            match_by_id("serial:23123A456789", &links).unwrap(),
+            "/dev/nvme0n1");
+        assert_eq!(
+            match_by_id("model:WD_BLACK_SN850X_2000GB", &links).unwrap(),
+            "/dev/nvme0n1");
+
+        // Partitions of the disk are not candidates
+        assert!(match_by_id("serial:S3Z1NB0K123456", &links).is_ok());
+
+        assert!(match_by_id("serial:UNKNOWN", &links).is_err());
+        assert!(match_by_id("model:Samsung_SSD_860_EVO", &links).is_err());
+        assert!(match_by_id("model:Samsung", &links).is_err());
+    }
+}
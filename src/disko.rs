@@ -0,0 +1,431 @@
+// -----------------------------------------------------------------------------
+
+use std::str::FromStr;
+
+use super::btrfs;
+use super::disk;
+use super::error;
+use super::filesystem;
+use super::gpt;
+use super::luks;
+use super::lvm;
+use super::nix;
+use super::partition;
+use super::raid;
+use super::zfs;
+
+// -----------------------------------------------------------------------------
+
+/// Backend emitting the layout in the disko schema (`disko.devices`)
+pub struct Backend {
+    /// Key file unlocking the LUKS devices (path in the initrd)
+    key_file: String,
+
+    /// Arrays of the RAID members (label of the member and name of the array)
+    members: Vec<(String, String)>,
+
+    /// Disks (attribute name, device and partitions)
+    disks: Vec<(String, String, nix::AttrSet)>,
+
+    /// Volume groups (`lvm_vg`)
+    volume_groups: nix::AttrSet,
+
+    /// ZFS pools (`zpool`)
+    pools: nix::AttrSet,
+
+    /// Software RAID arrays (`mdadm`)
+    arrays: nix::AttrSet,
+}
+
+impl Backend {
+    /// Create a backend for a layout
+    pub fn new(fs: &filesystem::Filesystem, key_file: &str) -> Self {
+        let members = fs.raids
+            .iter()
+            .flat_map(|r| r.config.members
+                .iter()
+                .map(move |m| (m.clone(), r.config.name.clone())))
+            .collect();
+
+        Self {
+            key_file: key_file.to_string(),
+            members: members,
+            disks: Vec::new(),
+            volume_groups: nix::AttrSet::new(),
+            pools: nix::AttrSet::new(),
+            arrays: nix::AttrSet::new(),
+        }
+    }
+
+    /// Render `disko.nix`
+    pub fn render(&self, indent: usize) -> String {
+        let mut devices = nix::AttrSet::new();
+
+        for (name, device, partitions) in self.disks.iter() {
+            devices.push_blank();
+            devices.push(
+                &format!("disk.{}", nix::attr_name(name)),
+                nix::AttrSet::new()
+                    .set("type", "disk")
+                    .set("device", device.as_str())
+                    .set(
+                        "content",
+                        nix::AttrSet::new()
+                            .set("type", "gpt")
+                            .set("partitions", partitions.clone())));
+        }
+
+        let sections = [
+            ("lvm_vg", &self.volume_groups),
+            ("zpool", &self.pools),
+            ("mdadm", &self.arrays),
+        ];
+
+        for (name, section) in sections.iter() {
+            if section.is_empty() {
+                continue;
+            }
+
+            devices.push_blank();
+            devices.push(name, (*section).clone());
+        }
+
+        return nix::module(
+            &[],
+            &nix::AttrSet::new().set("disko.devices", devices),
+            indent);
+    }
+
+    /// Get the content of a LUKS device (unlocked with the key file)
+    fn luks(
+        &self,
+        label: &str,
+        config: &Option<luks::Config>,
+        content: nix::AttrSet) -> nix::AttrSet {
+
+        let luks_config = match config {
+            Some(c) => c.clone(),
+            None => luks::Config::default(),
+        };
+
+        let args = luks::header_args(&luks_config);
+
+        return nix::AttrSet::new()
+            .set("type", "luks")
+            .set("name", label)
+            .set(
+                "extraFormatArgs",
+                nix::strings(
+                    &args.iter().map(|a| a.as_str()).collect::<Vec<&str>>()))
+            .set(
+                "settings",
+                nix::AttrSet::new()
+                    .set("keyFile", self.key_file.as_str())
+                    .set("allowDiscards", true))
+            .set("content", content);
+    }
+
+    /// Declare the volume group of a partition and get its physical volume
+    fn volume_group(
+        &mut self,
+        config: &partition::Config) -> Result<nix::AttrSet, error::Error> {
+
+        let vg = format!("vg-{}", config.label);
+
+        let mut lvs = nix::AttrSet::new();
+
+        for volume in config.lvm.iter() {
+            lvs.push(&nix::attr_name(&volume.label), logical_volume(volume)?);
+        }
+
+        self.volume_groups.push(
+            &nix::attr_name(&vg),
+            nix::AttrSet::new()
+                .set("type", "lvm_vg")
+                .set("lvs", lvs));
+
+        return Ok(nix::AttrSet::new()
+            .set("type", "lvm_pv")
+            .set("vg", vg));
+    }
+
+    /// Declare the pool of a partition and get its vdev
+    fn pool(&mut self, partition: &partition::Partition) -> nix::AttrSet {
+        let config = &partition.config;
+
+        let mut options = nix::AttrSet::new()
+            .set("ashift", partition.zfs_ashift().to_string())
+            .set("cachefile", "none");
+
+        match partition.zfs_compatibility() {
+            Some(c) => options.push("compatibility", c),
+            None => (),
+        }
+
+        let mut root_options = nix::AttrSet::new()
+            .set("compression", "lz4")
+            .set("mountpoint", "none");
+
+        if config.zfs_encrypted {
+            root_options.push("encryption", zfs::ENCRYPTION);
+            root_options.push("keyformat", "passphrase");
+            root_options.push("keylocation", "prompt");
+        }
+
+        let mut datasets = nix::AttrSet::new();
+
+        for fs in config.zfs.iter() {
+            datasets.push(
+                &nix::attr_name(&fs.name),
+                nix::AttrSet::new()
+                    .set("type", "zfs_fs")
+                    .set("mountpoint", fs.mountpoint.as_str())
+                    .set("options.mountpoint", "legacy"));
+        }
+
+        self.pools.push(
+            &nix::attr_name(&config.label),
+            nix::AttrSet::new()
+                .set("type", "zpool")
+                .set("options", options)
+                .set("rootFsOptions", root_options)
+                .set("datasets", datasets));
+
+        return nix::AttrSet::new()
+            .set("type", "zfs")
+            .set("pool", config.label.as_str());
+    }
+
+    /// Get the content of a RAID member
+    fn member(
+        &self,
+        config: &partition::Config) -> Result<nix::AttrSet, error::Error> {
+
+        let array = match self.members.iter().find(|m| m.0 == config.label) {
+            Some(m) => &m.1,
+            None => return generic_error!(&format!(
+                "Partition `{}` is not a member of an array",
+                config.label)),
+        };
+
+        return Ok(nix::AttrSet::new()
+            .set("type", "mdraid")
+            .set("name", array.as_str()));
+    }
+}
+
+impl nix::Backend for Backend {
+    fn disk(&mut self, disk: &disk::Disk) -> error::Return {
+        let device = &disk.config.device;
+
+        // Disko would wipe every declared disk
+        if disk.read_only() {
+            log::warn!("Read-only disk `{}` not declared", device);
+            return Success!();
+        }
+
+        let name = match device.rsplit('/').next() {
+            Some(n) if !n.is_empty() => n.to_string(),
+            _ => return generic_error!(&format!("Invalid device {}", device)),
+        };
+
+        self.disks.push((name, device.clone(), nix::AttrSet::new()));
+
+        return Success!();
+    }
+
+    fn partition(
+        &mut self,
+        disk: &disk::Disk,
+        partition: &partition::Partition) -> error::Return {
+
+        if disk.read_only() {
+            return Success!();
+        }
+
+        let config = &partition.config;
+
+        let partition_type =
+            gpt::PartitionType::from_str(&config.partition_type)?;
+
+        let mountpoint = match partition_type {
+            gpt::PartitionType::Efi => {
+                Some(config.efi_mountpoint(disk.config.contains_system()))
+            },
+
+            gpt::PartitionType::Linux if config.is_root => {
+                Some("/".to_string())
+            },
+
+            gpt::PartitionType::Linux => None,
+        };
+
+        let mut content = match gpt::FsType::from_str(&config.fs_type)? {
+            gpt::FsType::Lvm => self.volume_group(config)?,
+            gpt::FsType::Zfs => self.pool(partition),
+            gpt::FsType::Raid => self.member(config)?,
+            gpt::FsType::Btrfs => {
+                btrfs(&config.label, &config.btrfs, mountpoint)
+            },
+            gpt::FsType::Swap => swap(&config.label, config.is_resume),
+            t => filesystem(&t, &config.label, mountpoint)?,
+        };
+
+        if config.encrypted {
+            content = self.luks(&config.label, &config.luks, content);
+        }
+
+        // Remaining space of the disk
+        let size = match config.size.is_null() {
+            true => "100%".to_string(),
+            false => config.size.to_unit_string(),
+        };
+
+        // Partitions are created by priority, in the order of the layout
+        let entry = nix::AttrSet::new()
+            .set("priority", config.id as i64)
+            .set("size", size)
+            .set("type", partition_type.to_gpt_string().to_uppercase())
+            .set("content", content);
+
+        match self.disks.last_mut() {
+            Some(d) => d.2.push(&nix::attr_name(&config.label), entry),
+            None => return generic_error!("Partition without disk"),
+        }
+
+        return Success!();
+    }
+
+    fn array(&mut self, array: &raid::Array) -> error::Return {
+        let config = &array.config;
+
+        let level = match config.level.parse::<i64>() {
+            Ok(l) => l,
+            Err(_) => return generic_error!(&format!(
+                "Invalid RAID level `{}` of array `{}`",
+                config.level,
+                config.name)),
+        };
+
+        let mountpoint = Some(config.mountpoint.clone());
+
+        let mut content = match gpt::FsType::from_str(&config.fs_type)? {
+            gpt::FsType::Btrfs => btrfs(&config.label, &[], mountpoint),
+            t => filesystem(&t, &config.label, mountpoint)?,
+        };
+
+        if config.encrypted {
+            content = self.luks(&config.label, &config.luks, content);
+        }
+
+        self.arrays.push(
+            &nix::attr_name(&config.name),
+            nix::AttrSet::new()
+                .set("type", "mdadm")
+                .set("level", level)
+                .set("metadata", raid::METADATA)
+                .set("content", content));
+
+        return Success!();
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Get the content of a logical volume (the last one can take the rest of the
+/// volume group)
+fn logical_volume(volume: &lvm::Config) -> Result<nix::AttrSet, error::Error> {
+    let size = match volume.size.is_null() {
+        true => volume.extent().to_string(),
+        false => volume.size.to_unit_string(),
+    };
+
+    let mountpoint = match volume.is_root {
+        true => Some("/".to_string()),
+        false => None,
+    };
+
+    let content = match gpt::FsType::from_str(&volume.fs_type)? {
+        gpt::FsType::Swap => swap(&volume.label, volume.is_resume),
+        t => filesystem(&t, &volume.label, mountpoint)?,
+    };
+
+    return Ok(nix::AttrSet::new()
+        .set("size", size)
+        .set("content", content));
+}
+
+/// Get the content of a filesystem formatted by disko
+fn filesystem(
+    fs_type: &gpt::FsType,
+    label: &str,
+    mountpoint: Option<String>) -> Result<nix::AttrSet, error::Error> {
+
+    let (format, label_arg) = match fs_type {
+        gpt::FsType::Ext4 => ("ext4", "-L"),
+        gpt::FsType::F2fs => ("f2fs", "-l"),
+        gpt::FsType::Fat32 => ("vfat", "-n"),
+        gpt::FsType::Xfs => ("xfs", "-L"),
+        _ => return generic_error!(&format!(
+            "`{}` cannot be formatted as a filesystem by disko",
+            label)),
+    };
+
+    let mut content = nix::AttrSet::new()
+        .set("type", "filesystem")
+        .set("format", format)
+        .set("extraArgs", nix::strings(&[label_arg, label]));
+
+    match mountpoint {
+        Some(m) => content.push("mountpoint", m),
+        None => (),
+    }
+
+    return Ok(content);
+}
+
+/// Get the content of a btrfs filesystem (and its subvolumes)
+fn btrfs(
+    label: &str,
+    subvolumes: &[btrfs::Config],
+    mountpoint: Option<String>) -> nix::AttrSet {
+
+    let mut content = nix::AttrSet::new()
+        .set("type", "btrfs")
+        .set("extraArgs", nix::strings(&["-L", label, "-f"]));
+
+    if subvolumes.is_empty() {
+        match mountpoint {
+            Some(m) => content.push("mountpoint", m),
+            None => (),
+        }
+
+        return content;
+    }
+
+    let mut set = nix::AttrSet::new();
+
+    for subvolume in subvolumes.iter() {
+        set.push(
+            &nix::attr_name(&subvolume.name),
+            nix::AttrSet::new()
+                .set("mountpoint", subvolume.mountpoint.as_str()));
+    }
+
+    content.push("subvolumes", set);
+
+    return content;
+}
+
+/// Get the content of a swap
+fn swap(label: &str, is_resume: bool) -> nix::AttrSet {
+    let mut content = nix::AttrSet::new()
+        .set("type", "swap")
+        .set("extraArgs", nix::strings(&["-L", label]));
+
+    if is_resume {
+        content.push("resumeDevice", true);
+    }
+
+    return content;
+}
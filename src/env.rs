@@ -1,10 +1,16 @@
 // -----------------------------------------------------------------------------
 
 use clap;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::fs;
 use std::path;
+use std::sync::Mutex;
 
 use super::error;
+use super::filesystem;
+use super::logger;
+use super::report;
 use super::traits::{CliCommand, Validate};
 use super::utils;
 
@@ -14,9 +20,46 @@ const ARG_HARDWARE: &str = "hardware";
 const ARG_HOST: &str = "host";
 const ARG_KEY_FILENAME: &str = "key-name";
 const ARG_KEY_FILEPATH: &str = "key-path";
+const ARG_KEYMAP: &str = "keymap";
+const ARG_LOCALE: &str = "locale";
+const ARG_SECRETS_DIR: &str = "secrets-dir";
+const ARG_TIMEZONE: &str = "timezone";
+const ARG_WPA_EAP: &str = "wpa-eap";
+const ARG_WPA_IDENTITY: &str = "wpa-identity";
 const ARG_WPA_PASSWORD: &str = "wpa-password";
+const ARG_WPA_PHASE2: &str = "wpa-phase2";
 const ARG_WPA_SSID: &str = "wpa-ssid";
 
+const CMD_RECONSTRUCT: &str = "reconstruct";
+
+/// Default directory of the generated key file
+const DEFAULT_KEY_PATH: &str = "/tmp";
+
+/// Default keymap (console and X11)
+const DEFAULT_KEYMAP: &str = "fr";
+
+/// Default EAP method of the enterprise WiFi networks
+const DEFAULT_WPA_EAP: &str = "PEAP";
+
+/// EAP methods authenticating with an identity and a password
+const WPA_EAP_METHODS: [&str; 3] = ["PEAP", "PWD", "TTLS"];
+
+/// Default directory of the key file on the installed system
+pub const DEFAULT_SECRETS_DIR: &str = "/etc/secrets/disks";
+
+/// Directories holding the tz database
+const ZONEINFO_DIRS: &[&str] = &["/etc/zoneinfo", "/usr/share/zoneinfo"];
+
+/// Version of the .env schema understood and written by this tool (files
+/// without `schema_version` are at version 0 and read as is)
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// Directory holding the base profiles (relative to the current directory)
+const PROFILES_DIR: &str = "profiles";
+
+/// Profile selected on the command line
+static PROFILE: Mutex<Option<String>> = Mutex::new(None);
+
 // -----------------------------------------------------------------------------
 
 /// Structure reprensenting the hierarchy of the Json file
@@ -37,6 +80,10 @@ impl Validate for Config {
 /// Structure representing the `nixos` node in Json file
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NixOSConfig {
+    /// The version of the schema of the file
+    #[serde(default)]
+    pub schema_version: u64,
+
     /// The host name to be setup
     pub host: String,
 
@@ -48,6 +95,41 @@ pub struct NixOSConfig {
 
     /// The filename of the key file
     pub key_filename: String,
+
+    /// The console keymap of the installed system
+    #[serde(default)]
+    pub keymap: Option<String>,
+
+    /// The default locale of the installed system
+    #[serde(default)]
+    pub locale: Option<String>,
+
+    /// The time zone of the installed system
+    #[serde(default)]
+    pub timezone: Option<String>,
+
+    /// The base profile the host environment is layered on
+    #[serde(default)]
+    pub profile: Option<String>,
+
+    /// The directory of the key file on the installed system
+    #[serde(default)]
+    pub secrets_dir: Option<String>,
+}
+
+impl NixOSConfig {
+    /// Get the directory of the key file on the installed system
+    pub fn secrets_dir(&self) -> Result<String, error::Error> {
+        return match &self.secrets_dir {
+            Some(d) => {
+                check_secrets_dir(d)?;
+
+                Ok(d.trim_end_matches('/').to_string())
+            },
+
+            None => Ok(DEFAULT_SECRETS_DIR.to_string()),
+        };
+    }
 }
 
 impl Validate for NixOSConfig {
@@ -56,7 +138,8 @@ impl Validate for NixOSConfig {
             !self.host.is_empty() &&
             !self.hardware.is_empty() &&
             !self.key_file.is_empty() &&
-            !self.key_filename.is_empty();
+            !self.key_filename.is_empty() &&
+            self.keymap.as_ref().is_none_or(|k| !k.trim().is_empty());
     }
 }
 
@@ -71,6 +154,15 @@ pub struct Command {
     /// The password of the WiFi network
     wpa_password: String,
 
+    /// The identity of the enterprise WiFi network (WPA-EAP)
+    wpa_identity: Option<String>,
+
+    /// The EAP method of the enterprise WiFi network
+    wpa_eap: Option<String>,
+
+    /// The inner authentication of the enterprise WiFi network
+    wpa_phase2: Option<String>,
+
     /// The Json configuration
     config: Config,
 }
@@ -97,11 +189,15 @@ impl CliCommand for Command {
             .about("Prepare environment (variables, WiFi, ...)")
             .version(version)
             .author(author)
+            .setting(clap::AppSettings::SubcommandsNegateReqs)
+            // Reconstruct command
+            .subcommand(clap::App::new(CMD_RECONSTRUCT)
+                .about("Regenerate .env from the layouts, the hardware \
+                    directory and the generated files"))
             // Hardware argument
             .arg(clap::Arg::with_name(ARG_HARDWARE)
                 .long(ARG_HARDWARE)
-                .help("Hardware name")
-                .required(true)
+                .help("Hardware name (taken from the profile if omitted)")
                 .takes_value(true))
             // Host argument
             .arg(clap::Arg::with_name(ARG_HOST)
@@ -120,11 +216,56 @@ impl CliCommand for Command {
                 .long(ARG_KEY_FILEPATH)
                 .help("Key filepath (where the key will be generated)")
                 .takes_value(true))
+            // Keymap argument
+            .arg(clap::Arg::with_name(ARG_KEYMAP)
+                .long(ARG_KEYMAP)
+                .help("Keymap of the installer session and of the installed \
+                    system (default: fr)")
+                .takes_value(true))
+            // Locale argument
+            .arg(clap::Arg::with_name(ARG_LOCALE)
+                .long(ARG_LOCALE)
+                .help("Default locale of the installed system (e.g. \
+                    fr_FR.UTF-8)")
+                .takes_value(true))
+            // Secrets directory argument
+            .arg(clap::Arg::with_name(ARG_SECRETS_DIR)
+                .long(ARG_SECRETS_DIR)
+                .help("Absolute directory of the key file on the installed \
+                    system (default: /etc/secrets/disks)")
+                .takes_value(true))
+            // Timezone argument
+            .arg(clap::Arg::with_name(ARG_TIMEZONE)
+                .long(ARG_TIMEZONE)
+                .help("Time zone of the installed system (e.g. Europe/Paris, \
+                    detected from /etc/localtime by default)")
+                .takes_value(true))
+            // WPA EAP argument
+            .arg(clap::Arg::with_name(ARG_WPA_EAP)
+                .long(ARG_WPA_EAP)
+                .help("EAP method of an enterprise WiFi (PEAP, PWD or TTLS, \
+                    default: PEAP)")
+                .requires(ARG_WPA_IDENTITY)
+                .takes_value(true))
+            // WPA identity argument
+            .arg(clap::Arg::with_name(ARG_WPA_IDENTITY)
+                .long(ARG_WPA_IDENTITY)
+                .help("Identity of an enterprise WiFi (WPA-EAP instead of \
+                    WPA-PSK)")
+                .requires(ARG_WPA_PASSWORD)
+                .takes_value(true))
             // WPA password argument
             .arg(clap::Arg::with_name(ARG_WPA_PASSWORD)
                 .long(ARG_WPA_PASSWORD)
                 .help("WiFi password")
                 .takes_value(true))
+            // WPA phase 2 argument
+            .arg(clap::Arg::with_name(ARG_WPA_PHASE2)
+                .long(ARG_WPA_PHASE2)
+                .help("Inner authentication of an enterprise WiFi (e.g. \
+                    MSCHAPV2)")
+                .requires(ARG_WPA_IDENTITY)
+                .takes_value(true))
             // WPA SSID argument
             .arg(clap::Arg::with_name(ARG_WPA_SSID)
                 .long(ARG_WPA_SSID)
@@ -134,7 +275,12 @@ impl CliCommand for Command {
 
     /// Process command line arguments
     fn process(&mut self, matches: &clap::ArgMatches) -> error::Return {
-        let mut key_path = "/tmp".to_string();
+        match matches.subcommand_name() {
+            Some(CMD_RECONSTRUCT) => return self.reconstruct(),
+            _ => (),
+        }
+
+        let mut key_path = DEFAULT_KEY_PATH.to_string();
 
         // Parse arguments
         for arg in matches.args.iter() {
@@ -168,11 +314,75 @@ impl CliCommand for Command {
                     };
                 },
 
+                &ARG_KEYMAP => {
+                    self.config.nixos.keymap = match matches.value_of(arg.0) {
+                        Some(s) if !s.is_empty() => Some(s.to_string()),
+                        _ => return inval_error!(&ARG_KEYMAP),
+                    };
+                },
+
+                &ARG_LOCALE => {
+                    let locale = match matches.value_of(arg.0) {
+                        Some(s) => s.to_string(),
+                        None => return inval_error!(&ARG_LOCALE),
+                    };
+
+                    check_locale(&locale)?;
+
+                    self.config.nixos.locale = Some(locale);
+                },
+
+                &ARG_SECRETS_DIR => {
+                    let dir = match matches.value_of(arg.0) {
+                        Some(s) => s.to_string(),
+                        None => return inval_error!(&ARG_SECRETS_DIR),
+                    };
+
+                    check_secrets_dir(&dir)?;
+
+                    self.config.nixos.secrets_dir =
+                        Some(dir.trim_end_matches('/').to_string());
+                },
+
+                &ARG_TIMEZONE => {
+                    let timezone = match matches.value_of(arg.0) {
+                        Some(s) => s.to_string(),
+                        None => return inval_error!(&ARG_TIMEZONE),
+                    };
+
+                    check_timezone(&timezone)?;
+
+                    self.config.nixos.timezone = Some(timezone);
+                },
+
+                &ARG_WPA_EAP => {
+                    self.wpa_eap = match matches.value_of(arg.0) {
+                        Some(s) => Some(s.to_uppercase()),
+                        None => return inval_error!(&ARG_WPA_EAP),
+                    };
+                },
+
+                &ARG_WPA_IDENTITY => {
+                    self.wpa_identity = match matches.value_of(arg.0) {
+                        Some(s) => Some(s.to_string()),
+                        None => return inval_error!(&ARG_WPA_IDENTITY),
+                    };
+                },
+
                 &ARG_WPA_PASSWORD => {
                     self.wpa_password = match matches.value_of(arg.0) {
                         Some(s) => s.to_string(),
                         None => return inval_error!(&ARG_WPA_PASSWORD),
                     };
+
+                    logger::add_secret(&self.wpa_password);
+                },
+
+                &ARG_WPA_PHASE2 => {
+                    self.wpa_phase2 = match matches.value_of(arg.0) {
+                        Some(s) => Some(s.to_string()),
+                        None => return inval_error!(&ARG_WPA_PHASE2),
+                    };
                 },
 
                 &ARG_WPA_SSID => {
@@ -193,6 +403,12 @@ impl CliCommand for Command {
             return inval_error!(&key_path);
         }
 
+        if !path::Path::new(&key_path).starts_with("/tmp") {
+            report::warning(&format!(
+                "Key file will be generated in a persistent location `{}`",
+                key_path));
+        }
+
         self.config.nixos.key_file = match path::Path::new(&key_path)
             .join(&self.config.nixos.key_filename)
             .to_str() {
@@ -200,6 +416,20 @@ impl CliCommand for Command {
                 None => return generic_error!("Cannot build key filepath"),
             };
 
+        // Fill missing values with the base profile
+        match profile() {
+            Some(p) => self.fill_with_profile(&p)?,
+            None => (),
+        }
+
+        if self.config.nixos.keymap.is_none() {
+            self.config.nixos.keymap = Some(DEFAULT_KEYMAP.to_string());
+        }
+
+        if self.config.nixos.timezone.is_none() {
+            self.config.nixos.timezone = detect_timezone();
+        }
+
         log::debug!("{:#?}", self);
 
         // Check validity
@@ -222,18 +452,138 @@ impl Command {
         Self {
             wpa_ssid: "".to_string(),
             wpa_password: "".to_string(),
+            wpa_identity: None,
+            wpa_eap: None,
+            wpa_phase2: None,
 
             config: Config {
                 nixos: NixOSConfig {
+                    schema_version: SCHEMA_VERSION,
                     host: "".to_string(),
                     hardware: "".to_string(),
                     key_file: "".to_string(),
                     key_filename: "".to_string(),
+                    keymap: None,
+                    locale: None,
+                    timezone: None,
+                    profile: None,
+                    secrets_dir: None,
                 }
             }
         }
     }
 
+    /// Fill the values not provided on the command line with the ones of the
+    /// base profile
+    fn fill_with_profile(&mut self, profile: &str) -> error::Return {
+        let base = read_profile(profile)?;
+
+        let value = |key: &str| -> Option<String> {
+            return base["nixos"][key].as_str().map(|s| s.to_string());
+        };
+
+        let nixos = &mut self.config.nixos;
+
+        if nixos.hardware.is_empty() {
+            nixos.hardware = value("hardware").unwrap_or_default();
+        }
+
+        if nixos.keymap.is_none() {
+            nixos.keymap = value("keymap");
+        }
+
+        if nixos.locale.is_none() {
+            nixos.locale = value("locale");
+        }
+
+        if nixos.timezone.is_none() {
+            nixos.timezone = value("timezone");
+        }
+
+        if nixos.secrets_dir.is_none() {
+            nixos.secrets_dir = value("secrets_dir");
+        }
+
+        nixos.profile = Some(profile.to_string());
+
+        return Success!();
+    }
+
+    /// Rebuild the environment file from the layouts, the hardware directory
+    /// and the files generated for the host
+    fn reconstruct(&mut self) -> error::Return {
+        let dir = utils::current_dir()?;
+        let mut missing = Vec::new();
+
+        // Host from the layout filename
+        match infer_host(&dir.join("layouts"))? {
+            Some(h) => self.config.nixos.host = h,
+            None => missing.push("host"),
+        }
+
+        // Hardware from the hardware directory
+        match infer_hardware(&dir.join("hardware"))? {
+            Some(h) => self.config.nixos.hardware = h,
+            None => missing.push("hardware"),
+        }
+
+        // Key file from the generated files
+        let generated = dir.join("filesystems").join(&self.config.nixos.host);
+
+        let devices = read_generated(&generated.join("devices.nix"));
+
+        match capture(&devices, r#"keyFile = "/([^"/]+)";"#)? {
+            Some(k) => {
+                let key_file = path::Path::new(DEFAULT_KEY_PATH).join(&k);
+
+                if !key_file.exists() {
+                    report::warning(&format!(
+                        "Key file assumed to be {:?} (not found)",
+                        key_file));
+                }
+
+                // Secrets directory from the initrd secret of the key
+                let pattern = format!(
+                    r#""/{0}" = "(/[^"]+)/{0}";"#,
+                    regex::escape(&k));
+
+                self.config.nixos.secrets_dir =
+                    match capture(&devices, &pattern)? {
+                        Some(d) if d != DEFAULT_SECRETS_DIR => Some(d),
+                        _ => None,
+                    };
+
+                self.config.nixos.key_file =
+                    key_file.to_string_lossy().to_string();
+                self.config.nixos.key_filename = k;
+            },
+
+            None => missing.push("key_filename"),
+        }
+
+        // Localization from the generated files
+        let base = read_generated(&generated.join("base.nix"));
+
+        self.config.nixos.keymap =
+            capture(&base, r#"console\.keyMap = "([^"]*)";"#)?;
+        self.config.nixos.locale =
+            capture(&base, r#"i18n\.defaultLocale = "([^"]*)";"#)?;
+        self.config.nixos.timezone =
+            capture(&base, r#"time\.timeZone = "([^"]*)";"#)?;
+
+        for field in missing.iter() {
+            report::warning(&format!("`{}` could not be inferred", field));
+        }
+
+        log::debug!("{:#?}", self.config);
+
+        if !self.is_valid() {
+            return generic_error!("Reconstructed configuration is not valid");
+        }
+
+        return self.setup_environment();
+    }
+
     /// Create an environment file named `.env`, in the current directory, that
     /// contains Json data describing the setup environement.
     fn setup_environment(&self) -> error::Return {
@@ -253,12 +603,17 @@ impl Command {
         return Success!();
     }
 
-    /// Setup the keyboard layout to french
+    /// Setup the keyboard layout of the installer session
     fn setup_keyboard_layout(&self) -> error::Return {
-        let output = utils::command_output("loadkeys", &["fr"])?;
+        let keymap = match &self.config.nixos.keymap {
+            Some(k) if !k.trim().is_empty() => k.as_str(),
+            _ => return generic_error!("Keymap cannot be empty"),
+        };
+
+        let output = utils::command_output("loadkeys", &[keymap])?;
 
         match output.status.success() {
-            true => log::info!("Keyboard layout configured"),
+            true => log::info!("Keyboard layout `{}` configured", keymap),
             false => return process_error!("loadkeys", output.status),
         }
 
@@ -271,26 +626,36 @@ impl Command {
             return Success!();
         }
 
+        if utils::is_offline() {
+            report::warning("WiFi setup skipped (--offline is set)");
+            return Success!();
+        }
+
         // Generate configuration
-        let output = utils::command_output(
-            "wpa_passphrase",
-            &[
-                &self.wpa_ssid,
-                &self.wpa_password,
-            ])?;
+        let content = match &self.wpa_identity {
+            Some(identity) => {
+                let eap = match &self.wpa_eap {
+                    Some(e) => e.as_str(),
+                    None => DEFAULT_WPA_EAP,
+                };
 
-        if !output.status.success() {
-            return process_error!("wpa_passphrase", output.status);
-        }
+                eap_network(
+                    &self.wpa_ssid,
+                    identity,
+                    &self.wpa_password,
+                    eap,
+                    self.wpa_phase2.as_deref())?
+            },
 
-        let stdout = utils::command_stdout_to_string(&output)?;
+            None => self.psk_network()?,
+        };
 
-        log::debug!("{}", stdout);
+        log::debug!("{}", content);
 
         // Write to file
         let path = path::Path::new("/").join("etc").join("wpa_supplicant.conf");
 
-        utils::write_to_file(stdout.as_bytes(), &path)?;
+        utils::write_to_file(content.as_bytes(), &path)?;
 
         log::info!("WPA configuration written to {:?}", path);
 
@@ -309,13 +674,500 @@ impl Command {
 
         return Success!();
     }
+
+    /// Generate the network block of a WPA-PSK network
+    fn psk_network(&self) -> Result<String, error::Error> {
+        let output = utils::command_output(
+            "wpa_passphrase",
+            &[
+                &self.wpa_ssid,
+                &self.wpa_password,
+            ])?;
+
+        if !output.status.success() {
+            return process_error!("wpa_passphrase", output.status);
+        }
+
+        return utils::command_stdout_to_string(&output);
+    }
 }
 
 // -----------------------------------------------------------------------------
 
-/// Method used to load environment configuraition from Json file `.env`
+/// Select the base profile (`profiles/<name>.env`) to layer the host
+/// environment on
+pub fn set_profile(profile: Option<&str>) -> error::Return {
+    match profile {
+        Some(p) => check_profile(p)?,
+        None => (),
+    }
+
+    match PROFILE.lock() {
+        Ok(mut guard) => *guard = profile.map(|p| p.to_string()),
+        Err(_) => return generic_error!("Cannot select profile"),
+    }
+
+    return Success!();
+}
+
+/// Base profile selected on the command line
+fn profile() -> Option<String> {
+    return match PROFILE.lock() {
+        Ok(guard) => guard.clone(),
+        Err(_) => None,
+    };
+}
+
+/// Method used to load environment configuraition from Json file `.env`.
+///
+/// The values are layered (later values win): base profile, then host file
+/// `.env`, then command line options of each command. The profile is the one
+/// selected with `--profile` or, by default, the one recorded in `.env`.
 pub fn read() -> Result<Config, error::Error> {
     let path = utils::current_dir()?.join(".env");
 
+    let host: serde_json::Value = utils::load_json(&path)?;
+
+    let profile = match profile() {
+        Some(p) => Some(p),
+        None => host["nixos"]["profile"].as_str().map(|s| s.to_string()),
+    };
+
+    let value = match profile {
+        Some(p) => {
+            let mut base = read_profile(&p)?;
+            merge(&mut base, host);
+            base["nixos"]["profile"] = serde_json::Value::from(p);
+            base
+        },
+
+        None => host,
+    };
+
+    let config: Config = match serde_json::from_value(value) {
+        Ok(c) => c,
+        Err(e) => return json_error!(path.to_str().unwrap_or(".env"), e),
+    };
+
+    if config.nixos.schema_version > SCHEMA_VERSION {
+        return generic_error!(&format!(
+            "{:?} schema version {} is newer than the supported one ({}), \
+                please upgrade nixos-setup",
+            path,
+            config.nixos.schema_version,
+            SCHEMA_VERSION));
+    }
+
+    return Ok(config);
+}
+
+/// Load the base profile named `profile`
+fn read_profile(profile: &str) -> Result<serde_json::Value, error::Error> {
+    let path = utils::current_dir()?
+        .join(PROFILES_DIR)
+        .join(format!("{}.env", profile));
+
+    if !path.exists() {
+        return generic_error!(&format!(
+            "Profile `{}` not found ({:?})",
+            profile,
+            path));
+    }
+
     return utils::load_json(&path);
 }
+
+/// Merge `layer` into `base`: objects are merged recursively and other values
+/// replace the ones of `base` (null values are ignored)
+fn merge(base: &mut serde_json::Value, layer: serde_json::Value) {
+    match (base, layer) {
+        (_, serde_json::Value::Null) => (),
+
+        (serde_json::Value::Object(b), serde_json::Value::Object(l)) => {
+            for (key, value) in l {
+                merge(b.entry(key).or_insert(serde_json::Value::Null), value);
+            }
+        },
+
+        (b, l) => *b = l,
+    }
+}
+
+/// Check that a profile name can be used as a filename
+fn check_profile(profile: &str) -> error::Return {
+    let re = match Regex::new(r"^[A-Za-z0-9_-]+$") {
+        Ok(r) => r,
+        Err(e) => return generic_error!(
+            &format!("Cannot build regex: {}", e)),
+    };
+
+    if !re.is_match(profile) {
+        return generic_error!(&format!("Invalid profile `{}`", profile));
+    }
+
+    return Success!();
+}
+
+/// Infer the host from the layouts (`<host>.json` or `<host>.toml`), the
+/// system hostname is used if there are several
+fn infer_host(layouts: &path::Path) -> Result<Option<String>, error::Error> {
+    let entries = match fs::read_dir(layouts) {
+        Ok(e) => e,
+        Err(_) => return Ok(None),
+    };
+
+    let mut hosts = Vec::new();
+
+    for entry in entries {
+        let filename = match entry {
+            Ok(e) => e.file_name().to_string_lossy().to_string(),
+            Err(e) => return fs_error!(layouts.to_path_buf(), e),
+        };
+
+        if filename.ends_with(".in.json") || filename.ends_with(".in.toml") {
+            continue;
+        }
+
+        let host = filename
+            .strip_suffix(".json")
+            .or_else(|| filename.strip_suffix(".toml"));
+
+        // A host may have both a Json and a Toml layout
+        match host {
+            Some(h) if !hosts.iter().any(|x| x == h) => {
+                hosts.push(h.to_string());
+            },
+            _ => continue,
+        }
+    }
+
+    return match hosts.len() {
+        0 => Ok(None),
+        1 => Ok(hosts.pop()),
+        _ => Ok(detect_host().ok()),
+    };
+}
+
+/// Infer the hardware from the hardware directory: `<a>_<b>` is described by
+/// `hardware/<a>/<b>.nix`, next to the generated `-readonly.nix`
+fn infer_hardware(hardware: &path::Path)
+    -> Result<Option<String>, error::Error> {
+
+    let mut found = Vec::new();
+    let mut dirs = vec![hardware.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+
+        let mut files = Vec::new();
+        let mut generated = false;
+
+        for entry in entries {
+            let path = match entry {
+                Ok(e) => e.path(),
+                Err(e) => return fs_error!(dir, e),
+            };
+
+            if path.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+
+            match path.file_name().and_then(|f| f.to_str()) {
+                Some("-readonly.nix") => generated = true,
+                Some(f) if f.ends_with(".nix") => files.push(path.clone()),
+                _ => (),
+            }
+        }
+
+        if !generated {
+            continue;
+        }
+
+        for file in files.iter() {
+            let stem = file.with_extension("");
+
+            let relative = match stem.strip_prefix(hardware) {
+                Ok(r) => r.to_path_buf(),
+                Err(_) => continue,
+            };
+
+            let tokens: Vec<String> = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect();
+
+            found.push(tokens.join("_"));
+        }
+    }
+
+    if found.len() > 1 {
+        report::warning(&format!(
+            "Several hardware found: {}",
+            found.join(", ")));
+
+        return Ok(None);
+    }
+
+    return Ok(found.pop());
+}
+
+/// Read a generated file (empty if it doesn't exist)
+fn read_generated(path: &path::Path) -> String {
+    return match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(_) => {
+            report::warning(&format!("{:?} not found", path));
+            "".to_string()
+        },
+    };
+}
+
+/// Get the first group captured by a pattern in a content
+fn capture(content: &str, pattern: &str)
+    -> Result<Option<String>, error::Error> {
+
+    let re = match Regex::new(pattern) {
+        Ok(r) => r,
+        Err(e) => return generic_error!(
+            &format!("Cannot build regex: {}", e)),
+    };
+
+    return Ok(re.captures(content).map(|c| c[1].to_string()));
+}
+
+/// Method used to infer the host from the system hostname (a layout must exist
+/// for this host)
+pub fn detect_host() -> Result<String, error::Error> {
+    let path = path::Path::new("/proc/sys/kernel/hostname");
+
+    let hostname = match fs::read_to_string(path) {
+        Ok(h) => h.trim().to_string(),
+        Err(e) => return fs_error!(path.to_path_buf(), e),
+    };
+
+    if hostname.is_empty() {
+        return generic_error!("No system hostname");
+    }
+
+    let layout = filesystem::layout(&hostname)?;
+
+    if !layout.exists() {
+        return generic_error!(
+            &format!("No layout found for system hostname `{}`", hostname));
+    }
+
+    log::info!("Host `{}` inferred from the system hostname", hostname);
+
+    return Ok(hostname);
+}
+
+/// Read the environment file of a command working on a host, and resolve the
+/// host: the one given on the command line, else the one of `.env`, else the
+/// system hostname (there's no environment to return then). An existing `.env`
+/// that cannot be read is an error, the hostname isn't used in that case.
+pub fn read_for_host(host: &mut String)
+    -> Result<Option<Config>, error::Error> {
+
+    // Without environment file, use the system hostname
+    if host.is_empty() && !utils::current_dir()?.join(".env").exists() {
+        *host = detect_host()?;
+
+        return Ok(None);
+    }
+
+    let config = read()?;
+
+    if host.is_empty() {
+        *host = config.nixos.host.clone();
+    }
+
+    return Ok(Some(config));
+}
+
+/// Check that the values needed by a command (from the command line or
+/// `.env`) are set, the missing ones are reported by name
+pub fn check_values(values: &[(&str, &str)]) -> error::Return {
+    let missing: Vec<String> = values
+        .iter()
+        .filter(|(_, value)| value.is_empty())
+        .map(|(name, _)| format!("`{}`", name))
+        .collect();
+
+    if missing.is_empty() {
+        return Success!();
+    }
+
+    return generic_error!(&format!(
+        "Invalid configuration, missing {} (from the command line or .env)",
+        missing.join(", ")));
+}
+
+/// Check that a locale looks like `lang[_TERRITORY][.codeset][@modifier]`
+fn check_locale(locale: &str) -> error::Return {
+    let pattern =
+        r"^([a-z]{2,3}(_[A-Z]{2})?|C|POSIX)(\.[A-Za-z0-9-]+)?(@[a-z]+)?$";
+
+    let re = match Regex::new(pattern) {
+        Ok(r) => r,
+        Err(e) => return generic_error!(
+            &format!("Cannot build regex: {}", e)),
+    };
+
+    if !re.is_match(locale) {
+        return generic_error!(&format!("Invalid locale `{}`", locale));
+    }
+
+    return Success!();
+}
+
+/// Check that the secrets directory is an absolute path (not the root)
+fn check_secrets_dir(dir: &str) -> error::Return {
+    let trimmed = dir.trim_end_matches('/');
+
+    if !dir.starts_with('/') ||
+        trimmed.is_empty() ||
+        trimmed.split('/').any(|c| c == "..") {
+
+        return generic_error!(&format!(
+            "Invalid secrets directory `{}` (must be absolute)",
+            dir));
+    }
+
+    return Success!();
+}
+
+/// Check that a time zone exists in the tz database
+fn check_timezone(timezone: &str) -> error::Return {
+    if timezone.is_empty() ||
+        timezone.starts_with('/') ||
+        timezone.split('/').any(|c| c == "..") {
+
+        return generic_error!(&format!("Invalid time zone `{}`", timezone));
+    }
+
+    for dir in ZONEINFO_DIRS.iter() {
+        if path::Path::new(dir).join(timezone).is_file() {
+            return Success!();
+        }
+    }
+
+    return generic_error!(&format!("Unknown time zone `{}`", timezone));
+}
+
+/// Infer the time zone from the target of the `/etc/localtime` symlink
+fn detect_timezone() -> Option<String> {
+    let target = match fs::read_link("/etc/localtime") {
+        Ok(t) => t,
+        Err(_) => return None,
+    };
+
+    let target = match target.to_str() {
+        Some(t) => t.to_string(),
+        None => return None,
+    };
+
+    let timezone = match target.split_once("zoneinfo/") {
+        Some((_, tz)) => tz.to_string(),
+        None => return None,
+    };
+
+    match check_timezone(&timezone) {
+        Ok(_) => log::info!("Time zone `{}` detected", timezone),
+        Err(_) => return None,
+    }
+
+    return Some(timezone);
+}
+
+/// Generate the network block of an enterprise (WPA-EAP) network, quoting the
+/// values as wpa_supplicant expects them
+fn eap_network(
+    ssid: &str,
+    identity: &str,
+    password: &str,
+    eap: &str,
+    phase2: Option<&str>) -> Result<String, error::Error> {
+
+    if !WPA_EAP_METHODS.contains(&eap) {
+        return generic_error!(&format!(
+            "Unsupported EAP method `{}` (expected one of {})",
+            eap,
+            WPA_EAP_METHODS.join(", ")));
+    }
+
+    // Quoted strings of wpa_supplicant cannot be escaped
+    for (name, value) in [
+        ("SSID", ssid),
+        ("identity", identity),
+        ("password", password)].iter() {
+
+        if value.is_empty() || value.contains(&['"', '\n'][..]) {
+            return generic_error!(&format!("Invalid WiFi {}", name));
+        }
+    }
+
+    let mut lines = vec![
+        format!("ssid=\"{}\"", ssid),
+        "key_mgmt=WPA-EAP".to_string(),
+        format!("eap={}", eap),
+        format!("identity=\"{}\"", identity),
+        format!("password=\"{}\"", password),
+    ];
+
+    match phase2 {
+        // `auth=MSCHAPV2` or simply `MSCHAPV2`
+        Some(p) if p.contains('=') => lines.push(format!("phase2=\"{}\"", p)),
+        Some(p) => lines.push(format!("phase2=\"auth={}\"", p)),
+        None => (),
+    }
+
+    let body: Vec<String> = lines.iter().map(|l| format!("\t{}", l)).collect();
+
+    return Ok(format!("network={{\n{}\n}}\n", body.join("\n")));
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_eap_networks() {
+        let network = eap_network(
+            "corp",
+            "jdoe",
+            "secret",
+            "PEAP",
+            Some("MSCHAPV2")).unwrap();
+
+        assert_eq!(
+            network,
+            "network={\n\
+            \tssid=\"corp\"\n\
+            \tkey_mgmt=WPA-EAP\n\
+            \teap=PEAP\n\
+            \tidentity=\"jdoe\"\n\
+            \tpassword=\"secret\"\n\
+            \tphase2=\"auth=MSCHAPV2\"\n\
+            }\n");
+
+        assert!(eap_network("corp", "jdoe", "secret", "TLS", None).is_err());
+        assert!(eap_network("corp", "jdoe", "se\"cret", "PEAP", None).is_err());
+    }
+    #[test]
+    fn missing_values_are_reported_by_name() {
+        assert!(check_values(&[("host", "laptop"), ("key_file", "k")]).is_ok());
+
+        let error = check_values(&[("host", "laptop"), ("key_file", "")])
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("missing `key_file`"));
+        assert!(!error.contains("`host`"));
+    }
+}
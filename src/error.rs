@@ -2,6 +2,7 @@
 
 use std::fmt;
 use std::io;
+use std::os::unix::process::ExitStatusExt;
 use std::path;
 
 // -----------------------------------------------------------------------------
@@ -29,7 +30,8 @@ pub enum ErrorKind {
     InvalidValue(String),
     Io(String),
     Json(String),
-    Process(std::process::ExitStatus),
+    Process(std::process::ExitStatus, String),
+    Toml(String),
 }
 
 impl Error {
@@ -75,12 +77,91 @@ impl Error {
         }
     }
 
-    pub fn process(status: std::process::ExitStatus, name: &str) -> Self {
+    pub fn process(
+        status: std::process::ExitStatus,
+        name: &str,
+        stderr: &str) -> Self {
+
         Self {
             description: name.to_string(),
-            kind: ErrorKind::Process(status),
+            kind: ErrorKind::Process(status, stderr.trim().to_string()),
         }
     }
+
+    pub fn toml(source: &str, error: impl std::error::Error) -> Self {
+        Self {
+            description: error.to_string(),
+            kind: ErrorKind::Toml(source.to_string()),
+        }
+    }
+
+    /// Get the exit code of a failed process (if the error comes from one)
+    pub fn exit_code(&self) -> Option<i32> {
+        return match &self.kind {
+            ErrorKind::Process(status, _) => status.code(),
+            _ => None,
+        };
+    }
+
+    /// Get the stderr of a failed process (if the error comes from one)
+    pub fn stderr(&self) -> Option<&str> {
+        return match &self.kind {
+            ErrorKind::Process(_, stderr) => Some(stderr),
+            _ => None,
+        };
+    }
+
+    /// Convert the error to a Json value (for tooling)
+    pub fn to_json(&self) -> serde_json::Value {
+        return match &self.kind {
+            ErrorKind::Command(command_name) => serde_json::json!({
+                "kind": "Command",
+                "command": command_name,
+                "description": self.description,
+            }),
+
+            ErrorKind::Filesystem(path) => serde_json::json!({
+                "kind": "Filesystem",
+                "path": path,
+                "description": self.description,
+            }),
+
+            ErrorKind::Generic => serde_json::json!({
+                "kind": "Generic",
+                "description": self.description,
+            }),
+
+            ErrorKind::InvalidValue(field) => serde_json::json!({
+                "kind": "InvalidValue",
+                "field": field,
+            }),
+
+            ErrorKind::Io(error) => serde_json::json!({
+                "kind": "Io",
+                "description": self.description,
+                "error": error,
+            }),
+
+            ErrorKind::Json(source) => serde_json::json!({
+                "kind": "Json",
+                "source": source,
+                "description": self.description,
+            }),
+
+            ErrorKind::Process(status, stderr) => serde_json::json!({
+                "kind": "Process",
+                "command": self.description,
+                "code": status.code(),
+                "stderr": stderr,
+            }),
+
+            ErrorKind::Toml(source) => serde_json::json!({
+                "kind": "Toml",
+                "source": source,
+                "description": self.description,
+            }),
+        };
+    }
 }
 
 impl fmt::Display for Error {
@@ -110,20 +191,45 @@ impl fmt::Display for Error {
                 write!(f, "(JSON) {} => {}", source, self.description)
             },
 
-            ErrorKind::Process(status) => {
-                match status.code() {
-                    Some(c) => write!(
+            ErrorKind::Process(status, stderr) => {
+                match (status.code(), status.signal()) {
+                    (Some(c), _) => write!(
                         f,
                         "(PROCESS) `{}` returned {}",
                         self.description,
-                        c),
+                        c)?,
+
+                    (None, Some(s)) => write!(
+                        f,
+                        "(PROCESS) `{}` killed by signal {}",
+                        self.description,
+                        s)?,
 
-                    None => write!(
+                    (None, None) => write!(
                         f,
                         "(PROCESS) `{}`: no error code",
-                        self.description),
+                        self.description)?,
+                }
+
+                // Several lines are indented below the command
+                match stderr.lines().count() {
+                    0 => Ok(()),
+                    1 => write!(f, " => {}", stderr),
+                    _ => {
+                        write!(f, " =>")?;
+
+                        for line in stderr.lines() {
+                            write!(f, "\n    {}", line)?;
+                        }
+
+                        Ok(())
+                    },
                 }
             },
+
+            ErrorKind::Toml(source) => {
+                write!(f, "(TOML) {} => {}", source, self.description)
+            },
         }
     }
 }
@@ -169,7 +275,18 @@ macro_rules! json_error {
 #[macro_export]
 macro_rules! process_error {
     ($name: expr, $status: expr) => {
-        Err(error::Error::process($status, $name))
+        Err(error::Error::process($status, $name, ""))
+    };
+
+    ($name: expr, $status: expr, $stderr: expr) => {
+        Err(error::Error::process($status, $name, $stderr))
+    };
+}
+
+#[macro_export]
+macro_rules! toml_error {
+    ($source: expr, $error: expr) => {
+        Err(error::Error::toml($source, $error))
     }
 }
 
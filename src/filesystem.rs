@@ -3,20 +3,48 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path;
+use std::str::FromStr;
+use std::thread;
 
 use super::disk;
 use super::error;
-use super::traits::{Configurable, Openable, Validate};
+use super::gpt;
+use super::luks;
+use super::lvm;
+use super::partition;
+use super::plan;
+use super::raid;
+use super::report;
+use super::signals;
+use super::traits::{Configurable, Mountable, Openable, Validate};
 use super::utils;
 use super::zfs;
 
 // -----------------------------------------------------------------------------
 
+/// Version of the layout schema understood and written by this tool, raised
+/// with each transform of the `migrate` command (layouts without
+/// `schema_version` are at version 0)
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// Oldest layout schema still loaded (older layouts must be migrated first)
+pub const MIN_SCHEMA_VERSION: u64 = 0;
+
+// -----------------------------------------------------------------------------
+
 /// Json configuration of the filesystem
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
+    /// Version of the schema of the layout
+    #[serde(default)]
+    schema_version: u64,
+
     /// List of disks configurations
     disks: Vec<disk::Config>,
+
+    /// List of software RAID arrays configurations
+    #[serde(default)]
+    raids: Vec<raid::Config>,
 }
 
 impl Validate for Config {
@@ -27,6 +55,12 @@ impl Validate for Config {
             }
         }
 
+        for r in self.raids.iter() {
+            if !r.is_valid() {
+                return false;
+            }
+        }
+
         return true;
     }
 }
@@ -38,6 +72,12 @@ impl Validate for Config {
 pub struct Filesystem {
     /// List of disks i the filesystem
     pub disks: Vec<disk::Disk>,
+
+    /// List of software RAID arrays
+    pub raids: Vec<raid::Array>,
+
+    /// Version of the schema of the layout (kept when it's written back)
+    schema_version: u64,
 }
 
 impl Filesystem {
@@ -45,32 +85,454 @@ impl Filesystem {
     pub fn create(
         &mut self,
         key_file: &str,
-        passphrase: &str) -> error::Return {
+        passphrase: &str,
+        progress: &plan::Progress) -> error::Return {
+
+        // Refuse missing or busy devices and the conversion of existing
+        // non-GPT tables before wiping anything
+        for disk in self.writable_disks() {
+            disk.check_device()?;
+            disk.check_partition_table()?;
+        }
 
-        zfs::wipeout()?;
+        for disk in self.disks.iter().filter(|d| !d.selected) {
+            log::info!("Disk `{}` skipped", disk.config.device);
+        }
 
-        for disk in self.disks.iter_mut() {
-            if !disk.read_only() {
-                disk.wipeout()?;
-                disk.create(key_file, passphrase)?;
+        for array in self.raids.iter() {
+            if !self.raid_selected(&array.config) {
+                log::info!("RAID array `{}` skipped", array.config.name);
             }
         }
 
+        let plan = self.plan();
+
+        plan::log(&plan);
+
+        self.execute(&plan, key_file, passphrase, progress)?;
+
         log::info!("{:#?}", self.to_config());
 
         return Success!();
     }
 
+    /// Get the steps of the partitioning, in order of execution
+    pub fn plan(&self) -> Vec<plan::PlanStep> {
+        let mut steps = Vec::new();
+
+        let disks: Vec<&disk::Disk> = self.disks
+            .iter()
+            .filter(|d| d.selected && !d.read_only())
+            .collect();
+
+        // ZFS pools are global: when only some disks are selected, only wipe
+        // them if a ZFS disk is concerned (kept partitions keep their pools)
+        let resume = self.disks.iter().any(|d| d.resume);
+
+        if !resume && (self.disks.iter().all(|d| d.selected) ||
+            self.disks.iter().any(|d| d.selected && d.has_zfs())) {
+
+            steps.push(
+                plan::PlanStep::new(plan::Action::WipePools, None, None));
+        }
+
+        for disk in disks.iter() {
+            let device = Some(disk.config.device.as_str());
+
+            if !disk.resume {
+                steps.push(
+                    plan::PlanStep::new(plan::Action::WipeDisk, device, None));
+            }
+
+            for p in disk.partitions.iter() {
+                let size = match p.config.size.is_null() {
+                    true => "rest of the disk".to_string(),
+                    false => p.config.size.to_string(),
+                };
+
+                steps.push(
+                    plan::PlanStep::new(
+                        plan::Action::CreatePartition,
+                        device,
+                        Some(&p.config.label))
+                    .with_detail(
+                        &format!("{}, {}", size, p.config.partition_type)));
+            }
+        }
+
+        for disk in disks.iter() {
+            let device = Some(disk.config.device.as_str());
+
+            for p in disk.partitions.iter() {
+                let label = Some(p.config.label.as_str());
+
+                if p.config.encrypted {
+                    steps.push(plan::PlanStep::new(
+                        plan::Action::LuksFormat,
+                        device,
+                        label));
+                }
+
+                let step = match p.format_action() {
+                    Some(a) => plan::PlanStep::new(a, device, label),
+                    None => continue,
+                };
+
+                steps.push(match step.action {
+                    plan::Action::CreateVolumeGroup => step.with_detail(
+                        &format!("{} volumes", p.lvm.volumes.len())),
+                    plan::Action::Format => step.with_detail(&p.config.fs_type),
+                    _ => step,
+                });
+            }
+        }
+
+        for array in self.raids.iter() {
+            if !self.raid_selected(&array.config) {
+                continue;
+            }
+
+            steps.push(
+                plan::PlanStep::new(
+                    plan::Action::CreateArray,
+                    None,
+                    Some(&array.config.name))
+                .with_detail(&match array.config.encrypted {
+                    true => format!("RAID{}, encrypted", array.config.level),
+                    false => format!("RAID{}", array.config.level),
+                }));
+        }
+
+        return steps;
+    }
+
+    /// Execute the steps of the partitioning (the ones completed by a
+    /// previous run are skipped, only identifying their devices)
+    fn execute(
+        &mut self,
+        plan: &[plan::PlanStep],
+        key_file: &str,
+        passphrase: &str,
+        progress: &plan::Progress) -> error::Return {
+
+        let steps = |actions: &[plan::Action]| -> Vec<&plan::PlanStep> {
+            return plan
+                .iter()
+                .filter(|s| actions.contains(&s.action))
+                .collect();
+        };
+
+        for step in steps(&[plan::Action::WipePools]) {
+            match progress.is_completed(step) {
+                true => log::info!("Step `{}` already completed", step),
+                false => {
+                    progress.start(step)?;
+                    report::phase("wipe", zfs::wipeout)?;
+                    progress.complete(step)?;
+                },
+            }
+        }
+
+        // Disks are independent until formatting: wipe them, then partition
+        // them, in parallel (a thread per disk)
+        let wipes = steps(&[plan::Action::WipeDisk]);
+
+        report::phase("wipe", || self.create_disks(&wipes, progress))?;
+
+        let creations = steps(&[plan::Action::CreatePartition]);
+
+        report::phase("create", || self.create_disks(&creations, progress))?;
+
+        // Pools can span several disks: format sequentially
+        let formats = steps(&[
+            plan::Action::LuksFormat,
+            plan::Action::CreateVolumeGroup,
+            plan::Action::CreatePool,
+            plan::Action::Format,
+        ]);
+
+        report::phase("format", || -> error::Return {
+            for step in formats.iter() {
+                signals::check()?;
+
+                let completed = progress.is_completed(step);
+
+                if !completed {
+                    progress.start(step)?;
+                }
+
+                self.run_partition_step(step, key_file, passphrase, completed)?;
+
+                if !completed {
+                    progress.complete(step)?;
+                }
+            }
+
+            return Success!();
+        })?;
+
+        for step in steps(&[plan::Action::CreateArray]) {
+            signals::check()?;
+
+            let completed = progress.is_completed(step);
+
+            if !completed {
+                progress.start(step)?;
+            }
+
+            self.create_array(step, key_file, passphrase, completed)?;
+
+            if !completed {
+                progress.complete(step)?;
+            }
+        }
+
+        return Success!();
+    }
+
+    /// Run a step of the plan formatting a partition
+    fn run_partition_step(
+        &mut self,
+        step: &plan::PlanStep,
+        key_file: &str,
+        passphrase: &str,
+        completed: bool) -> error::Return {
+
+        let partition = self.disks
+            .iter_mut()
+            .filter(|d| Some(&d.config.device) == step.disk.as_ref())
+            .flat_map(|d| d.partitions.iter_mut())
+            .find(|p| Some(&p.config.label) == step.target.as_ref());
+
+        return match partition {
+            Some(p) if completed => p.skip(step.action),
+            Some(p) => p.run(step.action, key_file, passphrase),
+            None => generic_error!(
+                &format!("No partition for step `{}`", step)),
+        };
+    }
+
+    /// Tear down everything that has been set up after a failure, going on
+    /// whatever fails, then report the LUKS devices left opened
+    pub fn rollback(&mut self) {
+        let mut cleaned = Vec::new();
+
+        for array in self.raids.iter_mut().filter(|a| a.is_opened()) {
+            match array.close() {
+                Ok(_) => cleaned.push(format!(
+                    "RAID array `{}`",
+                    array.config.name)),
+                Err(e) => log::error!("{}", e),
+            }
+        }
+
+        if self.disks.iter().any(|d| d.has_zfs()) {
+            match zfs::pool_export_all() {
+                Ok(_) => cleaned.push("ZFS pools".to_string()),
+                Err(e) => log::error!("{}", e),
+            }
+        }
+
+        for disk in self.disks.iter_mut() {
+            cleaned.extend(disk.rollback());
+        }
+
+        match cleaned.is_empty() {
+            true => log::info!("Nothing to clean up"),
+            false => log::warn!("Cleaned up: {}", cleaned.join(", ")),
+        }
+
+        // Nothing has been opened
+        if utils::is_dry_run() {
+            return;
+        }
+
+        let encrypted = self.disks
+            .iter()
+            .flat_map(|d| d.partitions.iter())
+            .filter(|p| p.config.encrypted);
+
+        for partition in encrypted {
+            if luks::is_opened(&partition.config.label) {
+                report::warning(&format!(
+                    "LUKS device `{}` is still opened",
+                    partition.config.label));
+            }
+        }
+    }
+
+    /// Get the disks selected for partitioning and not read-only
+    fn writable_disks(&mut self) -> impl Iterator<Item = &mut disk::Disk> {
+        return self.disks
+            .iter_mut()
+            .filter(|d| d.selected && !d.read_only());
+    }
+
+    /// Wipe and partition the disks, one thread per disk running its steps
+    /// (the first error encountered is returned once all the threads are
+    /// done)
+    fn create_disks(
+        &mut self,
+        steps: &[&plan::PlanStep],
+        progress: &plan::Progress) -> error::Return {
+
+        // The disk threads run the commands like the current one
+        let context = utils::ThreadContext::current();
+
+        let results: Vec<error::Return> = thread::scope(|scope| {
+            let handles: Vec<_> = self.writable_disks()
+                .map(|disk| {
+                    let steps: Vec<&plan::PlanStep> = steps
+                        .iter()
+                        .filter(|s| {
+                            s.disk.as_ref() == Some(&disk.config.device)
+                        })
+                        .copied()
+                        .collect();
+
+                    let context = context.clone();
+
+                    return scope.spawn(move || -> error::Return {
+                        context.install();
+
+                        for step in steps.iter() {
+                            signals::check()?;
+
+                            if progress.is_completed(step) {
+                                disk.skip(step)?;
+                                continue;
+                            }
+
+                            progress.start(step)?;
+                            disk.run(step)?;
+                            progress.complete(step)?;
+                        }
+
+                        return Success!();
+                    });
+                })
+                .collect();
+
+            return handles
+                .into_iter()
+                .map(|h| match h.join() {
+                    Ok(r) => r,
+                    Err(_) => generic_error!("Disk creation thread panicked"),
+                })
+                .collect();
+        });
+
+        for result in results.into_iter() {
+            result?;
+        }
+
+        return Success!();
+    }
+
+    /// Load a layout file (Json or Toml, from its extension) and create
+    /// filesystem objects
+    pub fn from_file(layout: &path::PathBuf) -> Result<Self, error::Error> {
+        return match utils::is_toml(layout) {
+            true => Self::from_toml(layout),
+            false => Self::from_json(layout),
+        };
+    }
+
     /// Load Json file and create filesystem objects
     pub fn from_json(json: &path::PathBuf) -> Result<Self, error::Error> {
+        return Self::from_checked_config(utils::load_json(json)?);
+    }
 
-        let config: Config = match utils::load_json(json) {
-            Ok(j) => j,
-            Err(e) => return Err(e),
-        };
+    /// Load Toml file and create filesystem objects
+    pub fn from_toml(toml: &path::PathBuf) -> Result<Self, error::Error> {
+        return Self::from_checked_config(utils::load_toml(toml)?);
+    }
 
+    /// Check a loaded configuration and create filesystem objects
+    fn from_checked_config(mut config: Config) -> Result<Self, error::Error> {
         log::info!("{:#?}", config);
 
+        check_schema_version(config.schema_version, MIN_SCHEMA_VERSION)?;
+
+        let mut partitions = Vec::new();
+        let mut resume = Vec::new();
+
+        for disk in config.disks.iter() {
+            for partition in disk.partitions.iter() {
+                partition::check_types(partition)?;
+                lvm::check_volumes(&partition.lvm, &partition.label)?;
+
+                if partition.auto_resize {
+                    gpt::check_auto_resize(
+                        &partition.label,
+                        &partition.fs_type,
+                        partition.is_root)?;
+                }
+
+                for volume in partition.lvm.iter().filter(|v| v.auto_resize) {
+                    gpt::check_auto_resize(
+                        &volume.label,
+                        &volume.fs_type,
+                        volume.is_root)?;
+                }
+
+                if partition.is_resume {
+                    resume.push((&partition.label, &partition.fs_type));
+                }
+
+                for volume in partition.lvm.iter().filter(|v| v.is_resume) {
+                    resume.push((&volume.label, &volume.fs_type));
+                }
+
+                partitions.push((
+                    partition.label.clone(),
+                    partition.fs_type.clone()));
+            }
+        }
+
+        raid::check_arrays(&config.raids, &partitions)?;
+
+        // Hibernation image is written to a single swap device
+        match resume.iter().find(|r| r.1 != "swap") {
+            Some(r) => return generic_error!(&format!(
+                "`{}` cannot be the resume device (not a swap)",
+                r.0)),
+            None => (),
+        }
+
+        if resume.len() > 1 {
+            return generic_error!(&format!(
+                "Only one resume device allowed: {}",
+                resume
+                    .iter()
+                    .map(|r| r.0.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")));
+        }
+
+        disk::infer_contains_system(&mut config.disks, &config.raids)?;
+
+        // Each EFI partition needs its own mount point
+        let mut efi_mountpoints: Vec<String> = Vec::new();
+
+        for disk in config.disks.iter() {
+            let efi = disk.partitions
+                .iter()
+                .filter(|p| p.partition_type == "efi");
+
+            for p in efi {
+                let mountpoint = p.efi_mountpoint(disk.contains_system());
+
+                if efi_mountpoints.contains(&mountpoint) {
+                    return generic_error!(&format!(
+                        "EFI mount point `{}` is used twice",
+                        mountpoint));
+                }
+
+                efi_mountpoints.push(mountpoint);
+            }
+        }
+
         if !config.is_valid() {
             return generic_error!("Filesystem configuration is not valid");
         }
@@ -78,6 +540,14 @@ impl Filesystem {
         return Ok(Self::from_config(config));
     }
 
+    /// Export filesystem to a layout file (Json or Toml, from its extension)
+    pub fn to_file(&self, layout: &path::PathBuf) -> error::Return {
+        return match utils::is_toml(layout) {
+            true => self.to_toml(layout),
+            false => self.to_json(layout),
+        };
+    }
+
     /// Export filesystem to Json file
     pub fn to_json(&self, json: &path::PathBuf) -> error::Return {
         let value = utils::json_to_string(&self.to_config()?)?;
@@ -89,6 +559,17 @@ impl Filesystem {
         return Success!();
     }
 
+    /// Export filesystem to Toml file
+    pub fn to_toml(&self, toml: &path::PathBuf) -> error::Return {
+        let value = utils::toml_to_string(&self.to_config()?)?;
+
+        utils::write_to_file(value.as_bytes(), toml)?;
+
+        log::info!("Configuration has been written to {:?}", toml);
+
+        return Success!();
+    }
+
     /// Provide the device mapping
     pub fn set_device_mapping(&mut self, mapping: &HashMap<String, String>) {
         for disk in self.disks.iter_mut() {
@@ -108,6 +589,220 @@ impl Filesystem {
         }
     }
 
+    /// Resolve the disks given by serial number or model to their device
+    pub fn resolve_devices(&mut self) -> error::Return {
+        for disk in self.disks.iter_mut() {
+            if disk::is_device_spec(&disk.config.device) {
+                disk.config.device = disk::resolve_device(&disk.config.device)?;
+            }
+        }
+
+        return Success!();
+    }
+
+    /// Restrict partitioning to the given devices
+    pub fn select_disks(&mut self, devices: &[String]) -> error::Return {
+        for device in devices.iter() {
+            if !self.disks.iter().any(|d| &d.config.device == device) {
+                return generic_error!(
+                    &format!("Disk `{}` not found in layout", device));
+            }
+        }
+
+        for disk in self.disks.iter_mut() {
+            disk.selected = devices.contains(&disk.config.device);
+        }
+
+        return Success!();
+    }
+
+    /// Keep the existing partitions matching the layout instead of wiping
+    /// the disks
+    pub fn set_resume(&mut self, resume: bool) {
+        for disk in self.disks.iter_mut() {
+            disk.resume = resume;
+        }
+    }
+
+    /// Allow the conversion of existing non-GPT partition tables
+    pub fn set_force_gpt(&mut self, force: bool) {
+        for disk in self.disks.iter_mut() {
+            disk.force_gpt = force;
+        }
+    }
+
+    /// Prefix the labels of partitions, volumes and arrays (LUKS mappers,
+    /// volume groups and pools are named after them)
+    pub fn set_label_prefix(&mut self, prefix: &str) -> error::Return {
+        let mut config = self.to_config()?;
+
+        let prefixed = |label: &str| format!("{}{}", prefix, label);
+
+        for disk in config.disks.iter_mut() {
+            for partition in disk.partitions.iter_mut() {
+                partition.label = prefixed(&partition.label);
+
+                gpt::check_label(&partition.label, &partition.fs_type)?;
+
+                if partition.label.len() > gpt::PARTLABEL_MAX {
+                    return generic_error!(&format!(
+                        "Partition label `{}` is longer than {} characters",
+                        partition.label,
+                        gpt::PARTLABEL_MAX));
+                }
+
+                for volume in partition.lvm.iter_mut() {
+                    volume.label = prefixed(&volume.label);
+
+                    gpt::check_label(&volume.label, &volume.fs_type)?;
+                }
+            }
+        }
+
+        for array in config.raids.iter_mut() {
+            array.name = prefixed(&array.name);
+            array.label = prefixed(&array.label);
+
+            for member in array.members.iter_mut() {
+                *member = prefixed(member);
+            }
+
+            gpt::check_label(&array.label, &array.fs_type)?;
+        }
+
+        log::info!("Labels prefixed with `{}`", prefix);
+
+        *self = Self::from_config(config);
+
+        return Success!();
+    }
+
+    /// Set the LUKS keyslot iteration time of encrypted partitions and arrays
+    /// that don't provide one
+    pub fn set_luks_iter_time(&mut self, iter_time: u32) {
+        for disk in self.disks.iter_mut() {
+            for partition in disk.partitions.iter_mut() {
+                if !partition.config.encrypted {
+                    continue;
+                }
+
+                let luks = partition.config.luks
+                    .get_or_insert_with(luks::Config::default);
+
+                if luks.iter_time.is_none() {
+                    luks.iter_time = Some(iter_time);
+                }
+            }
+        }
+
+        for array in self.raids.iter_mut().filter(|a| a.config.encrypted) {
+            let luks =
+                array.config.luks.get_or_insert_with(luks::Config::default);
+
+            if luks.iter_time.is_none() {
+                luks.iter_time = Some(iter_time);
+            }
+        }
+    }
+
+    /// Set the keyslot of the key file of encrypted partitions that don't
+    /// provide one (fails if out of range for the LUKS version)
+    pub fn set_luks_key_slot(&mut self, key_slot: u32) -> error::Return {
+        for disk in self.disks.iter_mut() {
+            for partition in disk.partitions.iter_mut() {
+                if !partition.config.encrypted {
+                    continue;
+                }
+
+                let luks = partition.config.luks
+                    .get_or_insert_with(luks::Config::default);
+
+                if luks.key_slot.is_some() {
+                    continue;
+                }
+
+                if key_slot >= luks.version().key_slots() {
+                    return generic_error!(&format!(
+                        "Keyslot {} out of range for {} `{}`",
+                        key_slot,
+                        luks.version().to_string(),
+                        partition.config.label));
+                }
+
+                luks.key_slot = Some(key_slot);
+            }
+        }
+
+        return Success!();
+    }
+
+    /// Allow wiping the occupied keyslots of the key files
+    pub fn set_kill_key_slot(&mut self, kill: bool) {
+        for disk in self.disks.iter_mut() {
+            for partition in disk.partitions.iter_mut() {
+                partition.kill_key_slot = kill;
+            }
+        }
+    }
+
+    /// Set the time to wait for the devices of the created partitions (in
+    /// seconds)
+    pub fn set_disk_timeout(&mut self, seconds: u64) {
+        for disk in self.disks.iter_mut() {
+            for partition in disk.partitions.iter_mut() {
+                partition.disk_timeout = seconds;
+            }
+        }
+    }
+
+    /// Set the boundary the start of the created partitions is checked
+    /// against (in bytes)
+    pub fn set_alignment_boundary(&mut self, boundary: u64) {
+        for disk in self.disks.iter_mut() {
+            for partition in disk.partitions.iter_mut() {
+                partition.alignment_boundary = Some(boundary);
+            }
+        }
+    }
+
+    /// Set the LUKS version of encrypted partitions and arrays that don't
+    /// provide one
+    pub fn set_luks_version(&mut self, version: luks::LuksVersion) {
+        for disk in self.disks.iter_mut() {
+            for partition in disk.partitions.iter_mut() {
+                if !partition.config.encrypted {
+                    continue;
+                }
+
+                let luks = partition.config.luks
+                    .get_or_insert_with(luks::Config::default);
+
+                if luks.version.is_none() {
+                    luks.version = Some(version);
+                }
+            }
+        }
+
+        for array in self.raids.iter_mut().filter(|a| a.config.encrypted) {
+            let luks =
+                array.config.luks.get_or_insert_with(luks::Config::default);
+
+            if luks.version.is_none() {
+                luks.version = Some(version);
+            }
+        }
+    }
+
+    /// Get the natively encrypted ZFS pools
+    fn encrypted_pools(&self) -> Vec<String> {
+        return self.disks
+            .iter()
+            .flat_map(|d| d.partitions.iter())
+            .filter(|p| p.config.zfs_encrypted)
+            .map(|p| p.config.label.clone())
+            .collect();
+    }
+
     /// Create configuration from filesystem
     pub fn to_config(&self) -> Result<Config, error::Error> {
         let mut disks = Vec::new();
@@ -116,8 +811,16 @@ impl Filesystem {
             disks.push(disk.config()?);
         }
 
+        let mut raids = Vec::new();
+
+        for array in &self.raids {
+            raids.push(array.config()?);
+        }
+
         let config = Config {
+            schema_version: self.schema_version,
             disks: disks,
+            raids: raids,
         };
 
         return Ok(config);
@@ -129,7 +832,7 @@ impl Filesystem {
         -> Result<&mut disk::Disk, error::Error> {
 
         for disk in self.disks.iter_mut() {
-            if disk.config.contains_system {
+            if disk.config.contains_system() {
                 return Ok(disk);
             }
         }
@@ -137,6 +840,132 @@ impl Filesystem {
         return generic_error!("System disk not found");
     }
 
+    /// Find the root partition/lvm/zfs/btrfs/array
+    pub fn find_root(&mut self)
+        -> Result<&mut dyn Mountable, error::Error> {
+
+        match self.raids.iter().position(|r| r.config.is_root) {
+            Some(i) => return Ok(&mut self.raids[i]),
+            None => (),
+        }
+
+        return self.find_system_disk()?.find_root_partition();
+    }
+
+    /// Get the member devices of an array
+    fn raid_members(&self, array: &raid::Config)
+        -> Result<Vec<String>, error::Error> {
+
+        let mut members = Vec::new();
+
+        for member in array.members.iter() {
+            let partition = self.disks
+                .iter()
+                .flat_map(|d| d.partitions.iter())
+                .find(|p| &p.config.label == member);
+
+            let device = match partition {
+                Some(p) => partition::filesystem_device(&p.config),
+                None => None,
+            };
+
+            match device {
+                Some(d) => members.push(d.to_string()),
+                None => return generic_error!(&format!(
+                    "No device for member `{}` of array `{}`",
+                    member,
+                    array.name)),
+            }
+        }
+
+        return Ok(members);
+    }
+
+    /// Check if all the members of an array are kept partitions
+    fn raid_members_kept(&self, array: &raid::Config) -> bool {
+        return array.members.iter().all(|member| {
+            self.disks
+                .iter()
+                .flat_map(|d| d.partitions.iter())
+                .any(|p| &p.config.label == member && p.kept)
+        });
+    }
+
+    /// Check if all the members of an array are on selected disks that can be
+    /// written
+    fn raid_selected(&self, array: &raid::Config) -> bool {
+        return array.members.iter().all(|member| {
+            return self.disks.iter().any(|d|
+                d.selected &&
+                !d.read_only() &&
+                d.partitions.iter().any(|p| &p.config.label == member));
+        });
+    }
+
+    /// Create a software RAID array (after its members), only assembled if
+    /// completed by a previous run
+    fn create_array(
+        &mut self,
+        step: &plan::PlanStep,
+        key_file: &str,
+        passphrase: &str,
+        completed: bool) -> error::Return {
+
+        let i = match self.raids
+            .iter()
+            .position(|a| Some(&a.config.name) == step.target.as_ref()) {
+
+            Some(i) => i,
+            None => return generic_error!(
+                &format!("No array for step `{}`", step)),
+        };
+
+        let config = self.raids[i].config.clone();
+
+        let members = self.raid_members(&config)?;
+
+        // Created by a previous run
+        if completed || self.raid_members_kept(&config) {
+            log::info!("RAID array `{}` kept", config.name);
+            return self.raids[i].reuse(&members, passphrase);
+        }
+
+        return report::phase("raid", || {
+            self.raids[i].create(&members, key_file, passphrase)
+        });
+    }
+
+    /// Find the EFI partitions and their mount points (the one of the system
+    /// disk first, then the boot mirrors)
+    pub fn find_efi_partitions(&mut self)
+        -> Result<Vec<(String, &mut partition::Partition)>, error::Error> {
+
+        let mut partitions = Vec::new();
+
+        // The system disk is sorted first
+        let mut disks: Vec<&mut disk::Disk> = self.disks
+            .iter_mut()
+            .filter(|d| !d.config.external)
+            .collect();
+
+        disks.sort_by_key(|d| !d.config.contains_system());
+
+        for disk in disks {
+            let is_system = disk.config.contains_system();
+
+            for p in disk.partitions.iter_mut() {
+                match gpt::PartitionType::from_str(&p.config.partition_type)? {
+                    gpt::PartitionType::Efi => (),
+                    _ => continue,
+                }
+
+                partitions.push((p.config.efi_mountpoint(is_system), p));
+            }
+        }
+
+        return Ok(partitions);
+    }
+
     /// Create filesystem from configuration
     fn from_config(config: Config) -> Self {
         let mut disks = Vec::new();
@@ -145,8 +974,16 @@ impl Filesystem {
             disks.push(disk::Disk::from_config(d));
         }
 
+        let mut raids = Vec::new();
+
+        for r in config.raids.iter() {
+            raids.push(raid::Array::from_config(r));
+        }
+
         Self {
             disks: disks,
+            raids: raids,
+            schema_version: config.schema_version,
         }
     }
 }
@@ -161,11 +998,32 @@ impl Openable for Filesystem {
         // Open all ZFS
         zfs::pool_import_all()?;
 
+        for pool in self.encrypted_pools() {
+            zfs::load_key(&pool, passphrase)?;
+        }
+
+        // Assemble arrays
+        for i in 0..self.raids.len() {
+            let members = self.raid_members(&self.raids[i].config.clone())?;
+
+            self.raids[i].assemble(&members)?;
+            self.raids[i].open(passphrase)?;
+        }
+
         return Success!();
     }
 
     fn close(&mut self) -> error::Return {
+        // Stop arrays
+        for array in self.raids.iter_mut() {
+            array.close()?;
+        }
+
         // Close all ZFS
+        for pool in self.encrypted_pools() {
+            zfs::unload_key(&pool)?;
+        }
+
         zfs::pool_export_all()?;
 
         // Close each disk
@@ -176,3 +1034,349 @@ impl Openable for Filesystem {
         return Success!();
     }
 }
+
+// -----------------------------------------------------------------------------
+
+/// Check that a layout can be loaded from its schema version (the ones older
+/// than the current version and not older than `min_version` still load but
+/// should be migrated)
+fn check_schema_version(version: u64, min_version: u64) -> error::Return {
+    if version > SCHEMA_VERSION {
+        return generic_error!(&format!(
+            "Layout schema version {} is newer than the supported one ({}), \
+                please upgrade nixos-setup",
+            version,
+            SCHEMA_VERSION));
+    }
+
+    if version < min_version {
+        return generic_error!(&format!(
+            "Layout schema version {} is no longer supported (minimum {}), \
+                please run `nixos-setup migrate` first",
+            version,
+            min_version));
+    }
+
+    if version < SCHEMA_VERSION {
+        log::warn!(
+            "Layout schema version {} is outdated (current {}), \
+                `nixos-setup migrate` upgrades it",
+            version,
+            SCHEMA_VERSION);
+    }
+
+    return Success!();
+}
+
+/// Locate the layout `layouts/<name>.json` or `layouts/<name>.toml` (Json is
+/// kept if both exist, the Json path is returned if none exists)
+pub fn layout(name: &str) -> Result<path::PathBuf, error::Error> {
+    let layouts = utils::current_dir()?.join("layouts");
+
+    let json = layouts.join(format!("{}.json", name));
+    let toml = layouts.join(format!("{}.toml", name));
+
+    if !toml.exists() {
+        return Ok(json);
+    }
+
+    if json.exists() {
+        log::warn!("Both {:?} and {:?} exist, using the Json one", json, toml);
+        return Ok(json);
+    }
+
+    return Ok(toml);
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync;
+
+    use super::*;
+
+    /// Load a golden layout as Json value
+    fn golden_layout(name: &str) -> serde_json::Value {
+        let golden = path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("golden")
+            .join(format!("{}.json", name));
+
+        return serde_json::from_str(&fs::read_to_string(&golden).unwrap())
+            .unwrap();
+    }
+
+    /// Load a filesystem from a Json value
+    fn load(value: &serde_json::Value) -> Result<Filesystem, error::Error> {
+        let input = mktemp::Temp::new_file().unwrap();
+        fs::write(&input, value.to_string()).unwrap();
+
+        return Filesystem::from_json(&input.to_path_buf());
+    }
+
+    #[test]
+    fn disk_threads_run_commands_with_the_caller_runner() {
+        let mut fs = load(&golden_layout("ext4")).unwrap();
+
+        let runner = sync::Arc::new(utils::MockRunner::new(Vec::new()));
+
+        utils::set_runner(runner.clone());
+
+        let plan = fs.plan();
+        let wipes: Vec<&plan::PlanStep> = plan
+            .iter()
+            .filter(|s| s.action == plan::Action::WipeDisk)
+            .collect();
+
+        let progress_file = mktemp::Temp::new_file().unwrap();
+        let progress = plan::Progress::new(&progress_file);
+
+        fs.create_disks(&wipes, &progress).unwrap();
+
+        let calls = runner.calls.lock().unwrap();
+
+        assert!(!wipes.is_empty());
+        assert!(calls.iter().any(|c| c.0 == "sgdisk" && c.1[0] == "-Z"));
+    }
+
+    #[test]
+    fn plans_the_partitioning_in_order() {
+        let fs = load(&golden_layout("ext4")).unwrap();
+
+        let steps: Vec<String> =
+            fs.plan().iter().map(|s| s.to_string()).collect();
+
+        assert_eq!(steps, [
+            "destroy the ZFS pools",
+            "wipe `/dev/sda`",
+            "create partition `uefi` on `/dev/sda` (512M, efi)",
+            "create partition `data` on `/dev/sda` (1G, linux)",
+            "create partition `system` on `/dev/sda` (rest of the disk, linux)",
+            "format `uefi` (fat32)",
+            "luksFormat `data`",
+            "format `data` (ext4)",
+            "luksFormat `system`",
+            "create volume group `vg-system` (2 volumes)",
+        ]);
+    }
+
+    #[test]
+    fn fractional_size_survives_json() {
+        let mut value = golden_layout("ext4");
+
+        value["disks"][0]["partitions"][1]["size"] =
+            serde_json::Value::from("1.5G");
+
+        let output = mktemp::Temp::new_file().unwrap();
+
+        load(&value)
+            .unwrap()
+            .to_json(&output.to_path_buf())
+            .unwrap();
+
+        let fs = Filesystem::from_json(&output.to_path_buf()).unwrap();
+        let size = &fs.disks[0].partitions[1].config.size;
+
+        assert_eq!(size.to_string(), "1.5G");
+        assert_eq!(size.to_bytes(), 3 << 29);
+    }
+
+    #[test]
+    fn layouts_survive_toml() {
+        for name in ["autoresize", "btrfs", "ext4", "raid", "swap", "zfs"] {
+            let fs = load(&golden_layout(name)).unwrap();
+
+            let output = mktemp::Temp::new_file().unwrap();
+            let toml = output.to_path_buf().with_extension("toml");
+
+            fs.to_file(&toml).unwrap();
+
+            let reloaded = Filesystem::from_file(&toml).unwrap();
+            fs::remove_file(&toml).unwrap();
+
+            assert_eq!(
+                serde_json::to_value(reloaded.to_config().unwrap()).unwrap(),
+                serde_json::to_value(fs.to_config().unwrap()).unwrap(),
+                "{}",
+                name);
+        }
+    }
+
+    #[test]
+    fn infers_contains_system() {
+        for (layout, expected) in [
+            ("ext4", vec![true]),
+            ("raid", vec![true, false]),
+        ] {
+            let mut value = golden_layout(layout);
+
+            for disk in value["disks"].as_array_mut().unwrap() {
+                disk.as_object_mut().unwrap().remove("contains_system");
+            }
+
+            let inferred: Vec<bool> = load(&value)
+                .unwrap()
+                .disks
+                .iter()
+                .map(|d| d.config.contains_system())
+                .collect();
+
+            assert_eq!(inferred, expected, "{}", layout);
+        }
+    }
+
+    #[test]
+    fn rejects_contradicting_contains_system() {
+        let mut value = golden_layout("ext4");
+
+        value["disks"][0]["contains_system"] = serde_json::Value::from(false);
+
+        let error = load(&value).unwrap_err();
+
+        assert!(error.to_string().ends_with(
+            "holds the root but is set not to contain the system"));
+    }
+
+    #[test]
+    fn minimal_layouts_are_accepted() {
+        let mut value = serde_json::json!({
+            "disks": [{
+                "device": "/dev/sda",
+                "partitions": [
+                    {
+                        "id": 1,
+                        "size": "512M",
+                        "partition_type": "efi",
+                        "fs_type": "fat32",
+                        "label": "uefi",
+                    },
+                    {
+                        "id": 2,
+                        "size": "0",
+                        "partition_type": "linux",
+                        "fs_type": "lvm",
+                        "label": "system",
+                        "encrypted": true,
+                        "lvm": [{
+                            "id": 1,
+                            "size": "0",
+                            "volume_type": "linux",
+                            "fs_type": "ext4",
+                            "label": "root",
+                            "is_root": true,
+                        }],
+                    },
+                ],
+            }],
+        });
+
+        let fs = load(&value).unwrap();
+
+        assert!(fs.disks[0].config.contains_system());
+        assert!(!fs.disks[0].read_only());
+
+        // Defaults don't stand for the required fields
+        value["disks"][0]["partitions"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("label");
+
+        assert!(load(&value).is_err());
+    }
+
+    #[test]
+    fn rejects_partitions_with_lvm_and_zfs() {
+        let mut value = golden_layout("ext4");
+
+        value["disks"][0]["partitions"][2]["zfs"] = serde_json::json!([{
+            "name": "root",
+            "mountpoint": "/",
+            "is_root": true,
+        }]);
+
+        let error = load(&value).unwrap_err();
+
+        assert!(error.to_string().ends_with(
+            "`system` declares both LVM volumes and ZFS filesystems (at most \
+            one of them)"));
+
+        let mut value = golden_layout("ext4");
+
+        value["disks"][0]["partitions"][2]["lvm"][0]["encrypted"] =
+            serde_json::Value::Bool(true);
+
+        let error = load(&value).unwrap_err();
+
+        assert!(error.to_string().contains("cannot be encrypted on its own"));
+    }
+
+    #[test]
+    fn rejects_nonsensical_fs_types() {
+        let cases = [
+            (0, "fs_type", serde_json::json!("ext4"), "as fat32"),
+            (1, "fs_type", serde_json::json!("zfs"), "has no filesystem"),
+            (1, "is_system", serde_json::json!(true), "fat32 cannot hold"),
+            (2, "lvm", serde_json::json!([]), "has no volume"),
+        ];
+
+        for (index, field, data, message) in cases.iter() {
+            let mut value = golden_layout("ext4");
+            let partition = &mut value["disks"][0]["partitions"][*index];
+
+            partition[*field] = data.clone();
+
+            // An ESP mistakenly declared as a Linux partition
+            if *field == "is_system" {
+                partition["fs_type"] = serde_json::json!("fat32");
+                value["disks"][0]["partitions"][2]["is_system"] =
+                    serde_json::json!(false);
+            }
+
+            let error = load(&value).unwrap_err().to_string();
+
+            assert!(error.contains("Invalid partition"), "{}", error);
+            assert!(error.contains(message), "{}", error);
+        }
+    }
+
+    #[test]
+    fn schema_version_is_checked_and_kept() {
+        let mut value = golden_layout("ext4");
+
+        // Layouts written before the versioning load as version 0
+        let fs = load(&value).unwrap();
+
+        assert_eq!(fs.to_config().unwrap().schema_version, 0);
+
+        value["schema_version"] = serde_json::Value::from(SCHEMA_VERSION);
+
+        let fs = load(&value).unwrap();
+
+        assert_eq!(fs.to_config().unwrap().schema_version, SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn schema_versions_newer_than_supported_are_rejected() {
+        let mut value = golden_layout("ext4");
+
+        value["schema_version"] = serde_json::Value::from(SCHEMA_VERSION + 1);
+
+        let error = load(&value).unwrap_err().to_string();
+
+        assert!(error.contains("newer than the supported one"), "{}", error);
+        assert!(error.contains("upgrade nixos-setup"), "{}", error);
+    }
+
+    #[test]
+    fn schema_versions_below_the_minimum_are_rejected() {
+        // Minimum raised once version 0 is no longer supported
+        let error = check_schema_version(0, 1).unwrap_err().to_string();
+
+        assert!(error.contains("no longer supported (minimum 1)"), "{}", error);
+        assert!(error.contains("nixos-setup migrate"), "{}", error);
+
+        assert!(check_schema_version(1, 1).is_ok());
+    }
+}
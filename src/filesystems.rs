@@ -4,18 +4,50 @@ use clap;
 use std::fs;
 use std::path;
 
+use super::crypttab;
+use super::disk;
+use super::disko;
 use super::env;
 use super::error;
 use super::filesystem;
 use super::gpt;
+use super::logger;
+use super::lvm;
+use super::nix;
 use super::partition;
+use super::raid;
+use super::report;
 use std::str::FromStr;
 use super::traits::{CliCommand, Validate};
 use super::utils;
+use super::zfs;
 
 // -----------------------------------------------------------------------------
 
+const ARG_BOOTLOADER: &str = "bootloader";
+const ARG_CRYPTTAB: &str = "crypttab";
+const ARG_FORMAT: &str = "format";
+const ARG_FORMAT_NIX: &str = "format-nix";
 const ARG_HOST: &str = "host";
+const ARG_INDENT: &str = "indent";
+const ARG_SECURE_BOOT_PKI: &str = "secure-boot-pki";
+const ARG_USER: &str = "user";
+const ARG_USER_GROUPS: &str = "user-groups";
+const ARG_USER_PASSWORD_FILE: &str = "user-password-file";
+
+/// Room taken on the ESP by a generation booted as a Unified Kernel Image
+/// (kernel, initrd and command line)
+const UKI_SIZE: u64 = 96 << 20;
+
+/// Generations of UKIs the ESP must be able to hold
+const UKI_MIN_GENERATIONS: u64 = 3;
+
+/// Generations of UKIs kept on the ESP at most
+const UKI_MAX_GENERATIONS: u64 = 10;
+
+/// Files of the signature database key of a lanzaboote PKI bundle (sbctl
+/// layout)
+const SECURE_BOOT_DB_KEYS: &[&str] = &["keys/db/db.key", "keys/db/db.pem"];
 
 // -----------------------------------------------------------------------------
 
@@ -27,6 +59,48 @@ pub struct Command {
 
     /// Name of the key file used to decrypt disks
     key_filename: String,
+
+    /// Directory of the key file on the installed system
+    secrets_dir: String,
+
+    /// Console keymap of the installed system
+    keymap: Option<String>,
+
+    /// Default locale of the installed system
+    locale: Option<String>,
+
+    /// Time zone of the installed system
+    timezone: Option<String>,
+
+    /// Name of the initial user account
+    user: Option<String>,
+
+    /// Groups of the initial user account
+    user_groups: Vec<String>,
+
+    /// File containing the password of the initial user account
+    user_password_file: Option<String>,
+
+    /// Indentation width of the generated files
+    indent: usize,
+
+    /// Formatter run on the generated files
+    format_nix: Option<String>,
+
+    /// Whether a disko layout is generated instead of the NixOS modules
+    disko: bool,
+
+    /// Whether systemd-boot boots UKIs from the ESP instead of GRUB reading
+    /// the encrypted `/boot`
+    systemd_boot: bool,
+
+    /// PKI bundle of lanzaboote signing the UKIs for Secure Boot (on the
+    /// installed system)
+    secure_boot_pki: Option<String>,
+
+    /// Whether the encrypted data partitions are unlocked after boot
+    /// (`/etc/crypttab`) instead of in the initrd
+    crypttab: bool,
 }
 
 impl Validate for Command {
@@ -53,10 +127,67 @@ impl CliCommand for Command {
             .about("Create filesystems configurations for NixOS")
             .version(version)
             .author(author)
+            // Bootloader argument
+            .arg(clap::Arg::with_name(ARG_BOOTLOADER)
+                .long(ARG_BOOTLOADER)
+                .help("Bootloader: GRUB unlocking /boot (default) or \
+                    systemd-boot with UKIs on the ESP (everything else \
+                    encrypted)")
+                .possible_values(&["grub", "systemd-boot"])
+                .takes_value(true))
+            // Crypttab argument
+            .arg(clap::Arg::with_name(ARG_CRYPTTAB)
+                .long(ARG_CRYPTTAB)
+                .help("Unlock the encrypted data partitions (not needed to \
+                    boot) from /etc/crypttab after boot"))
+            // Format argument
+            .arg(clap::Arg::with_name(ARG_FORMAT)
+                .long(ARG_FORMAT)
+                .help("Generated configuration: NixOS modules (default) or a \
+                    single disko.nix layout")
+                .possible_values(&["nixos", "disko"])
+                .takes_value(true))
+            // Format Nix argument
+            .arg(clap::Arg::with_name(ARG_FORMAT_NIX)
+                .long(ARG_FORMAT_NIX)
+                .help("Formatter run on the generated files")
+                .possible_values(nix::FORMATTERS)
+                .takes_value(true))
             // Host argument
             .arg(clap::Arg::with_name(ARG_HOST)
                 .long(ARG_HOST)
                 .help("Host name (optional if a .env file is present)")
+                .takes_value(true))
+            // Indent argument
+            .arg(clap::Arg::with_name(ARG_INDENT)
+                .long(ARG_INDENT)
+                .help("Indentation width of the generated files (default: 2)")
+                .takes_value(true))
+            // Secure Boot PKI argument
+            .arg(clap::Arg::with_name(ARG_SECURE_BOOT_PKI)
+                .long(ARG_SECURE_BOOT_PKI)
+                .help("PKI bundle of the Secure Boot keys on the installed \
+                    system (e.g. /var/lib/sbctl): UKIs are signed by \
+                    lanzaboote (implies --bootloader systemd-boot)")
+                .takes_value(true))
+            // User argument
+            .arg(clap::Arg::with_name(ARG_USER)
+                .long(ARG_USER)
+                .help("Name of an initial user account to create")
+                .requires(ARG_USER_PASSWORD_FILE)
+                .takes_value(true))
+            // User groups argument
+            .arg(clap::Arg::with_name(ARG_USER_GROUPS)
+                .long(ARG_USER_GROUPS)
+                .help("Comma separated groups of the initial user (e.g. \
+                    wheel,networkmanager)")
+                .requires(ARG_USER)
+                .takes_value(true))
+            // User password file argument
+            .arg(clap::Arg::with_name(ARG_USER_PASSWORD_FILE)
+                .long(ARG_USER_PASSWORD_FILE)
+                .help("File containing the password of the initial user")
+                .requires(ARG_USER)
                 .takes_value(true));
     }
 
@@ -72,12 +203,112 @@ impl CliCommand for Command {
                     };
                 },
 
+                &ARG_BOOTLOADER => {
+                    self.systemd_boot = match matches.value_of(arg.0) {
+                        Some(s) => s == "systemd-boot",
+                        None => return inval_error!(&ARG_BOOTLOADER),
+                    };
+                },
+
+                &ARG_CRYPTTAB => {
+                    self.crypttab = true;
+                },
+
+                &ARG_FORMAT => {
+                    self.disko = match matches.value_of(arg.0) {
+                        Some(s) => s == "disko",
+                        None => return inval_error!(&ARG_FORMAT),
+                    };
+                },
+
+                &ARG_FORMAT_NIX => {
+                    let formatter = match matches.value_of(arg.0) {
+                        Some(s) => s.to_owned(),
+                        None => return inval_error!(&ARG_FORMAT_NIX),
+                    };
+
+                    if !utils::find_program(&formatter) {
+                        return generic_error!(&format!(
+                            "Formatter `{}` not found",
+                            formatter));
+                    }
+
+                    self.format_nix = Some(formatter);
+                },
+
+                &ARG_INDENT => {
+                    let value = match matches.value_of(arg.0) {
+                        Some(s) => s.to_string(),
+                        None => return inval_error!(&ARG_INDENT),
+                    };
+
+                    self.indent = match value.parse::<usize>() {
+                        Ok(i) if i > 0 && i <= 8 => i,
+                        _ => return inval_error!(&ARG_INDENT),
+                    };
+                },
+
+                &ARG_SECURE_BOOT_PKI => {
+                    let pki = match matches.value_of(arg.0) {
+                        Some(s) => s.trim_end_matches('/').to_string(),
+                        None => return inval_error!(&ARG_SECURE_BOOT_PKI),
+                    };
+
+                    check_secure_boot_pki(&pki)?;
+
+                    self.secure_boot_pki = Some(pki);
+                },
+
+                &ARG_USER => {
+                    let user = match matches.value_of(arg.0) {
+                        Some(s) => s.to_owned(),
+                        None => return inval_error!(&ARG_USER),
+                    };
+
+                    utils::check_name(&user)?;
+
+                    self.user = Some(user);
+                },
+
+                &ARG_USER_GROUPS => {
+                    let groups = match matches.value_of(arg.0) {
+                        Some(s) => s.to_owned(),
+                        None => return inval_error!(&ARG_USER_GROUPS),
+                    };
+
+                    for group in groups.split(',') {
+                        utils::check_name(group)?;
+
+                        self.user_groups.push(group.to_string());
+                    }
+                },
+
+                &ARG_USER_PASSWORD_FILE => {
+                    self.user_password_file = match matches.value_of(arg.0) {
+                        Some(s) => Some(s.to_owned()),
+                        None => return inval_error!(&ARG_USER_PASSWORD_FILE),
+                    };
+                },
+
                 _ => {
                     return inval_error!(arg.0);
                 }
             }
         }
 
+        // Lanzaboote signs the UKIs booted by systemd-boot
+        if self.secure_boot_pki.is_some() {
+            if matches.value_of(ARG_BOOTLOADER) == Some("grub") {
+                return generic_error!("Secure Boot is not supported with GRUB");
+            }
+
+            self.systemd_boot = true;
+
+            report::warning(
+                "Secure Boot keys must be enrolled in the firmware manually \
+                (Setup Mode, then `sbctl enroll-keys`)");
+        }
+
         if !self.is_valid() {
             self.fill_with_env()?;
         }
@@ -85,21 +316,20 @@ impl CliCommand for Command {
         log::info!("{:#?}", self);
 
         // Check validity
-        if !self.is_valid() {
-            return generic_error!("Invalid configuration");
-        }
+        env::check_values(&[
+            (ARG_HOST, &self.host),
+            ("key_filename", &self.key_filename),
+        ])?;
 
-        // Create filesystem from Json description
-        let path = utils::current_dir()?
-            .join("layouts")
-            .join(format!("{}.json", self.host));
+        // Create filesystem from the layout
+        let layout = filesystem::layout(&self.host)?;
 
-        let fs = filesystem::Filesystem::from_json(&path)?;
+        let fs = filesystem::Filesystem::from_file(&layout)?;
 
         // Create output directories
         let output = utils::current_dir()?
             .join("filesystems")
-            .join(format!("{}", self.host));
+            .join(&self.host);
 
         match fs::create_dir_all(&output) {
             Ok(_) => (),
@@ -107,10 +337,18 @@ impl CliCommand for Command {
         }
 
         // Create configurations
-        self.create_default(&output)?;
-        self.create_bootloader(&output)?;
-        self.create_devices(&fs, &output)?;
-        self.create_filesystems(&fs, &output)?;
+        self.warn_discards(&fs);
+
+        let files = match self.disko {
+            true => vec![("disko.nix", self.disko_nix(&fs)?)],
+            false => self.nixos_files(&fs)?,
+        };
+
+        for (filename, content) in files.iter() {
+            let path = write_nix(&output, filename, content)?;
+
+            self.format_file(&path)?;
+        }
 
         return Success!();
     }
@@ -122,320 +360,817 @@ impl Command {
         Self {
             host: String::from(""),
             key_filename: String::from(""),
+            secrets_dir: env::DEFAULT_SECRETS_DIR.to_string(),
+            keymap: None,
+            locale: None,
+            timezone: None,
+            user: None,
+            user_groups: Vec::new(),
+            user_password_file: None,
+            indent: nix::DEFAULT_INDENT,
+            format_nix: None,
+            disko: false,
+            systemd_boot: false,
+            secure_boot_pki: None,
+            crypttab: false,
         }
     }
 
     /// Use environment file to get needed values
     fn fill_with_env(&mut self) -> error::Return {
-        let config = env::read()?;
+        let config = match env::read_for_host(&mut self.host)? {
+            Some(c) => c,
+            None => return Success!(),
+        };
+
+        self.secrets_dir = config.nixos.secrets_dir()?;
 
-        self.host = config.nixos.host;
         self.key_filename = config.nixos.key_filename;
+        self.keymap = config.nixos.keymap;
+        self.locale = config.nixos.locale;
+        self.timezone = config.nixos.timezone;
 
         return Success!();
     }
 
-    /// Create the `default.nix` file in provided directory
-    fn create_default(&self, path: &path::PathBuf) -> error::Return {
-        let content =
-r"# Auto-generated, do not edit !
-{ ... }:
+    /// Create the NixOS modules (file name and content)
+    fn nixos_files(
+        &self,
+        fs: &filesystem::Filesystem)
+        -> Result<Vec<(&'static str, String)>, error::Error> {
+
+        let host_id = self.get_host_id()?;
+
+        let mut files = vec![
+            ("default.nix", self.default_nix()),
+            ("base.nix", self.base_nix()),
+            ("bootloader.nix", self.bootloader_nix(fs)?),
+            ("devices.nix", self.devices_nix(fs)?),
+            ("filesystems.nix", self.filesystems_nix(fs, &host_id)?),
+        ];
+
+        if self.user.is_some() {
+            files.push(("users.nix", self.users_nix()?));
+        }
 
-{
-  imports = [
-    ./bootloader.nix
-    ./devices.nix
-    ./filesystems.nix
-  ];
-}";
+        return Ok(files);
+    }
+
+    /// Create the content of `disko.nix` (LUKS devices are unlocked with the
+    /// key file of the initrd)
+    fn disko_nix(
+        &self,
+        fs: &filesystem::Filesystem) -> Result<String, error::Error> {
 
-        let output = path.join("default.nix");
+        let (initrd_key_file, _) = self.key_file_paths();
 
-        utils::write_to_file(content.as_bytes(), &output)?;
+        let mut backend = disko::Backend::new(fs, &initrd_key_file);
 
-        log::info!("{}", content);
-        log::info!("Configuration written to {}", output.to_str().unwrap());
+        nix::traverse(fs, &mut backend)?;
 
-        return Success!();
+        return Ok(backend.render(self.indent));
     }
 
-    /// Create the `bootloader.nix` file in provided directory
-    fn create_bootloader(&self, path: &path::PathBuf) -> error::Return {
-        //TODO: remove zfsSupport ?
-        let content =
-r#"# Auto-generated, do not edit !
-{ config, ... }:
+    /// Create the content of `default.nix`
+    fn default_nix(&self) -> String {
+        let mut imports = vec![
+            nix::Value::Raw("./base.nix".to_string()),
+            nix::Value::Raw("./bootloader.nix".to_string()),
+            nix::Value::Raw("./devices.nix".to_string()),
+            nix::Value::Raw("./filesystems.nix".to_string()),
+        ];
+
+        if self.user.is_some() {
+            imports.push(nix::Value::Raw("./users.nix".to_string()));
+        }
 
-{
-  boot.loader = {
-    timeout = 1;
+        // SSH keys are provisioned by the `install` command
+        let ssh = nix::Value::Raw(
+            "lib.optional (builtins.pathExists ./ssh.nix) ./ssh.nix"
+                .to_string());
 
-    efi = {
-      canTouchEfiVariables = true;
-      efiSysMountPoint = "/boot/efi";
-    };
+        let content = nix::AttrSet::new().set(
+            "imports",
+            nix::Value::Concat(vec![nix::Value::List(imports), ssh]));
 
-    grub = {
-      enable = true;
-      device = "nodev";
-      version = 2;
-      efiSupport = true;
-      enableCryptodisk = true;
-      copyKernels = true;
-      zfsSupport = true;
-    };
-  };
-}"#;
+        return nix::module(&["lib"], &content, self.indent);
+    }
 
-        let output = path.join("bootloader.nix");
+    /// Create the content of `base.nix` (keyboard, locale, time zone)
+    fn base_nix(&self) -> String {
+        let mut content = nix::AttrSet::new();
+
+        // Same layout for the console and X11
+        match &self.keymap {
+            Some(k) => {
+                content.push("console.keyMap", k.as_str());
+                content.push("services.xserver.xkb.layout", k.as_str());
+            },
+            None => (),
+        }
 
-        utils::write_to_file(content.as_bytes(), &output)?;
+        match &self.locale {
+            Some(l) => content.push("i18n.defaultLocale", l.as_str()),
+            None => (),
+        }
 
-        log::info!("{}", content);
-        log::info!("Configuration written to {}", output.to_str().unwrap());
+        match &self.timezone {
+            Some(t) => content.push("time.timeZone", t.as_str()),
+            None => (),
+        }
 
-        return Success!();
+        return nix::module(&[], &content, self.indent);
     }
 
-    /// Create `devices.nix` file in provided directory
-    fn create_devices(
+    /// Create the content of `bootloader.nix`
+    fn bootloader_nix(
         &self,
-        fs: &filesystem::Filesystem,
-        path: &path::PathBuf) -> error::Return {
+        fs: &filesystem::Filesystem) -> Result<String, error::Error> {
+
+        let loader = match (self.systemd_boot, &self.secure_boot_pki) {
+            // Replaced by lanzaboote
+            (true, Some(_)) => (
+                "systemd-boot.enable",
+                nix::Value::Raw("lib.mkForce false".to_string())),
 
-        let mut content = "# Auto-generated, do not edit !\n".to_string();
-        content += "{ config, ... }:\n\n";
-        content += "{\n";
-        content += "  boot = {";
+            (true, None) => (
+                "systemd-boot",
+                nix::Value::from(self.systemd_boot_nix(fs)?)),
 
-        if self.has_zfs(fs) {
-            content += "\n";
-            content += r#"    supportedFilesystems = ["zfs"];"#;
-            content += "\n";
+            (false, _) => ("grub", nix::Value::from(self.grub_nix(fs)?)),
+        };
+
+        let mut content = nix::AttrSet::new().set(
+            "boot.loader",
+            nix::AttrSet::new()
+                .set("timeout", 1)
+                .blank()
+                .set(
+                    "efi",
+                    nix::AttrSet::new()
+                        .set("canTouchEfiVariables", true)
+                        .set(
+                            "efiSysMountPoint",
+                            self.system_efi_mountpoint(fs)?))
+                .blank()
+                .set(loader.0, loader.1));
+
+        match &self.secure_boot_pki {
+            Some(pki) => {
+                content.push_blank();
+                content.push(
+                    "boot.lanzaboote",
+                    nix::AttrSet::new()
+                        .set("enable", true)
+                        .set("pkiBundle", pki.as_str())
+                        .set(
+                            "configurationLimit",
+                            self.uki_generations(fs)? as i64));
+            },
+
+            None => (),
         }
 
-        content += "\n";
-        content += "    initrd = {";
+        // Generations described by bootspec, unlocked by the systemd initrd
+        if self.systemd_boot {
+            content.push_blank();
+            content.push("boot.bootspec.enable", true);
+            content.push("boot.initrd.systemd.enable", true);
+        }
 
-        if self.is_root_zfs(fs) {
-            content += "\n";
-            content += r#"      supportedFilesystems = ["zfs"];"#;
-            content += "\n";
+        let args: &[&str] = match self.secure_boot_pki {
+            Some(_) => &["config", "lib"],
+            None => &["config"],
+        };
+
+        return Ok(nix::module(args, &content, self.indent));
+    }
+
+    /// Create the configuration of GRUB (reading `/boot` from the encrypted
+    /// system partition)
+    fn grub_nix(
+        &self,
+        fs: &filesystem::Filesystem) -> Result<nix::AttrSet, error::Error> {
+
+        //TODO: remove zfsSupport ?
+        let mut grub = nix::AttrSet::new()
+            .set("enable", true)
+            .set("device", "nodev")
+            .set("version", 2)
+            .set("efiSupport", true)
+            .set("enableCryptodisk", true)
+            .set("copyKernels", true)
+            .set("zfsSupport", true);
+
+        // Keep the EFI partitions of the other disks in sync
+        let mirrors = self.mirrored_efi_partitions(fs)?;
+
+        if !mirrors.is_empty() {
+            let mut boots = Vec::new();
+
+            for config in mirrors.iter() {
+                let boot = partition::mirrored_boot_path(&config.label);
+
+                boots.push(nix::Value::from(nix::AttrSet::new()
+                    .set("devices", nix::strings(&["nodev"]))
+                    .set("path", boot.as_str())
+                    .set("efiSysMountPoint", config.efi_mountpoint(false))));
+            }
+
+            grub.push("mirroredBoots", boots);
         }
 
-        for disk in fs.disks.iter() {
-            for partition in disk.partitions.iter() {
-                if !partition.config.encrypted {
-                    continue;
+        return Ok(grub);
+    }
+
+    /// Create the configuration of systemd-boot (kernels and initrds are
+    /// copied to the ESP, as many generations as it can hold are kept)
+    fn systemd_boot_nix(
+        &self,
+        fs: &filesystem::Filesystem) -> Result<nix::AttrSet, error::Error> {
+
+        // The kernel command line must not be editable at boot
+        return Ok(nix::AttrSet::new()
+            .set("enable", true)
+            .set("editor", false)
+            .set("configurationLimit", self.uki_generations(fs)? as i64));
+    }
+
+    /// Get the number of generations of UKIs kept on the ESP of the system
+    /// disk (fails if it cannot hold enough of them)
+    fn uki_generations(
+        &self,
+        fs: &filesystem::Filesystem) -> Result<u64, error::Error> {
+
+        if !self.mirrored_efi_partitions(fs)?.is_empty() {
+            return generic_error!(
+                "Mirrored EFI partitions are only supported with GRUB");
+        }
+
+        let mut esp = None;
+
+        for disk in fs.disks.iter().filter(|d| d.config.contains_system()) {
+            for p in disk.partitions.iter() {
+                match gpt::PartitionType::from_str(&p.config.partition_type)? {
+                    gpt::PartitionType::Efi => esp = Some(&p.config),
+                    _ => (),
                 }
+            }
+        }
 
-                let device = match &partition.config.device_by_partlabel {
-                    Some(d) => d,
-                    None => return generic_error!("No path for partition"),
-                };
+        let esp = match esp {
+            Some(e) => e,
+            None => return generic_error!(
+                "systemd-boot needs an EFI partition on the system disk"),
+        };
+
+        // The ESP may take the rest of the disk
+        let generations = match esp.size.is_null() {
+            true => UKI_MAX_GENERATIONS,
+            false => esp.size.to_bytes() / UKI_SIZE,
+        };
+
+        if generations < UKI_MIN_GENERATIONS {
+            return generic_error!(&format!(
+                "EFI partition `{}` ({}) cannot hold {} generations of UKIs \
+                (at least {}M)",
+                esp.label,
+                esp.size.to_string(),
+                UKI_MIN_GENERATIONS,
+                (UKI_MIN_GENERATIONS * UKI_SIZE) >> 20));
+        }
+
+        return Ok(generations.min(UKI_MAX_GENERATIONS));
+    }
+
+    /// Create the content of `devices.nix`
+    fn devices_nix(
+        &self,
+        fs: &filesystem::Filesystem) -> Result<String, error::Error> {
+
+        let mut boot = nix::AttrSet::new();
 
-                content += "\n";
-                content += &format!(
-                    r#"      luks.devices."{}" = {{"#,
-                    partition.config.label);
+        let supported = self.supported_filesystems(fs);
 
-                content += "\n";
-                content += &format!(r#"        device = "{}";"#, device);
+        if !supported.is_empty() {
+            boot.push("supportedFilesystems", nix::strings(&supported));
+        }
+
+        if supported.contains(&"zfs") {
+            boot.push("zfs.devNodes", zfs::DEV_NODES);
+        }
+
+        let mut initrd = nix::AttrSet::new();
+
+        match self.root_supported_filesystem(fs) {
+            Some(f) => initrd.push("supportedFilesystems", nix::strings(&[f])),
+            None => (),
+        }
+
+        // Sorted by label, whatever the order of the layout
+        let mut encrypted: Vec<(&disk::Disk, &partition::Partition)> = fs.disks
+            .iter()
+            .flat_map(|d| d.partitions.iter().map(move |p| (d, p)))
+            .filter(|(_, p)| p.config.encrypted)
+            .collect();
+
+        encrypted.sort_by(|a, b| a.1.config.label.cmp(&b.1.config.label));
 
-                content += "\n";
-                content += &format!(
-                    r#"        keyFile = "/{}";"#,
-                    self.key_filename);
+        let (initrd_key_file, system_key_file) = self.key_file_paths();
 
-                content += "\n";
-                content += "        allowDiscards = true;";
+        let mut crypttab = String::new();
+        let mut udev_rules = String::new();
 
-                content += "\n";
-                content += "        preLVM = true;";
+        for (disk, partition) in encrypted.iter() {
+            let device = match &partition.config.device_by_partlabel {
+                Some(d) => d.as_str(),
+                None => return generic_error!("No path for partition"),
+            };
 
-                content += "\n";
-                content += "      };\n";
+            // Unlocked with the key file of the (already unlocked) root
+            if self.is_unlocked_after_boot(&disk.config, &partition.config) {
+                let options = match disk.config.external {
+                    true => "luks,discard,noauto,nofail",
+                    false => "luks,discard",
+                };
+
+                crypttab += &crypttab::entry(
+                    &partition.config.label,
+                    device,
+                    &system_key_file,
+                    options)?;
+
+                // External disks are unlocked when plugged
+                if disk.config.external {
+                    udev_rules += &unlock_rule(&partition.config.label);
+                }
+
+                continue;
             }
+
+            initrd.push_blank();
+            initrd.push(
+                &format!(
+                    "luks.devices.{}",
+                    nix::attr_name(&partition.config.label)),
+                self.initrd_luks_device(device, &initrd_key_file));
         }
 
-        content += "\n";
-        content += "      secrets = {";
+        // Encrypted arrays are assembled in the initrd before being unlocked
+        for array in fs.raids.iter().filter(|a| a.config.encrypted) {
+            initrd.push_blank();
+            initrd.push(
+                &format!(
+                    "luks.devices.{}",
+                    nix::attr_name(&array.config.label)),
+                self.initrd_luks_device(
+                    &array.config.device(),
+                    &initrd_key_file));
+        }
 
-        content += "\n";
-        content += &format!(
-            r#"        "/{0}" = "/etc/secrets/disks/{0}";"#,
-            &self.key_filename);
+        if !self.systemd_boot {
+            initrd.push_blank();
+            initrd.push(
+                "secrets",
+                nix::AttrSet::new().set(
+                    &nix::attr_name(&initrd_key_file),
+                    system_key_file.as_str()));
+        }
 
-        content += "\n";
-        content += "      };";
+        boot.push_blank();
+        boot.push("initrd", initrd);
 
-        content += "\n";
-        content += "    };";
+        if !fs.raids.is_empty() {
+            boot.push_blank();
+            boot.push(
+                "swraid",
+                nix::AttrSet::new()
+                    .set("enable", true)
+                    .set("mdadmConf", mdadm_conf(fs)?));
+        }
 
-        content += "\n";
-        content += "  };";
+        let mut content = nix::AttrSet::new().set("boot", boot);
 
-        content += "\n";
-        content += "}";
+        if !crypttab.is_empty() {
+            content.push_blank();
+            content.push("environment.etc.\"crypttab\".text", crypttab);
+        }
 
-        log::info!("{}", content);
+        if !udev_rules.is_empty() {
+            content.push_blank();
+            content.push("services.udev.extraRules", udev_rules);
+        }
 
-        // Write to file
-        let output = path.join("devices.nix");
+        let discard = fs.disks
+            .iter()
+            .flat_map(|d| d.partitions.iter())
+            .any(|p| p.config.lvm_discard);
+
+        // Merged with the settings of the LVM module
+        if discard {
+            content.push_blank();
+            content.push(
+                "environment.etc.\"lvm/lvm.conf\".text",
+                lvm::ISSUE_DISCARDS.replace('=', " = "));
+        }
 
-        utils::write_to_file(content.as_bytes(), &output)?;
+        return Ok(nix::module(&["config"], &content, self.indent));
+    }
 
-        log::info!("Configuration written to {:?}", &output);
+    /// Check if an encrypted partition is unlocked after boot (see
+    /// `--crypttab`, always for external disks)
+    fn is_unlocked_after_boot(
+        &self,
+        disk: &disk::Config,
+        config: &partition::Config) -> bool {
 
-        return Success!();
+        return disk.external || (self.crypttab && config.is_data());
     }
 
-    /// Create `filesystems.nix` file in provided directory
-    fn create_filesystems(
+    /// Create the content of `filesystems.nix`
+    fn filesystems_nix(
         &self,
         fs: &filesystem::Filesystem,
-        path: &path::PathBuf) -> error::Return {
+        host_id: &str) -> Result<String, error::Error> {
 
-        let host_id = self.get_host_id()?;
+        let mut backend = Mounts {
+            command: self,
+            mounts: Vec::new(),
+        };
 
-        let mut content = "# Auto-generated, do not edit !\n".to_string();
-        content += "{ config, ... }:\n\n";
-        content += "{\n";
-        content += &format!(r#"  networking.hostId = "{}";"#, host_id);
+        nix::traverse(fs, &mut backend)?;
 
-        for disk in fs.disks.iter() {
-            for partition in disk.partitions.iter() {
-                match partition.config.partition_type.as_str() {
-                    "linux" => {
-                        content += &self.create_fs_from_partition(&partition)?;
-                    },
+        let mut mounts = backend.mounts;
 
-                    "efi" => {
-                        content +=
-                            &self.create_fs_from_efi_partition(&partition)?;
-                    }
+        // Root first then by mountpoint, whatever the order of the layout
+        mounts.sort_by(|a, b| (a.0 != "/", &a.0).cmp(&(b.0 != "/", &b.0)));
 
-                    _ => {},
-                }
-            }
-        }
+        let mut content = nix::AttrSet::new()
+            .set("networking.hostId", host_id);
 
-        content += "\n}";
-
-        log::info!("{}", content);
+        for (mountpoint, entry) in mounts {
+            content.push_blank();
+            content.push(
+                &format!("fileSystems.{}", nix::attr_name(&mountpoint)),
+                entry);
+        }
 
-        // Write to file
-        let output = path.join("filesystems.nix");
+        let (swaps, resume) = self.swap_devices(fs)?;
+
+        if !swaps.is_empty() {
+            content.push_blank();
+            content.push(
+                "swapDevices",
+                swaps
+                    .iter()
+                    .map(|s| nix::AttrSet::new().set("device", s.as_str()))
+                    .map(nix::Value::from)
+                    .collect::<Vec<nix::Value>>());
+        }
 
-        utils::write_to_file(content.as_bytes(), &output)?;
+        match resume {
+            Some(r) => {
+                content.push_blank();
+                content.push("boot.resumeDevice", r);
+            },
 
-        log::info!("Configuration written to {:?}", &output);
+            None => (),
+        }
 
-        return Success!();
+        return Ok(nix::module(&["config"], &content, self.indent));
     }
 
-    /// Create filesystem entry from partition
-    fn create_fs_from_partition(
+    /// Collect filesystem entries of a partition
+    fn fs_from_partition(
         &self,
-        partition: &partition::Partition) -> Result<String, error::Error> {
+        disk: &disk::Config,
+        partition: &partition::Partition,
+        mounts: &mut Vec<(String, nix::AttrSet)>) -> error::Return {
 
         return match gpt::FsType::from_str(&partition.config.fs_type)? {
-            gpt::FsType::Zfs => self.create_fs_from_zfs_partition(partition),
-            _ => self.create_fs_from_basic_partition(partition),
+            gpt::FsType::Zfs => self.fs_from_zfs_partition(partition, mounts),
+            gpt::FsType::Btrfs if !partition.config.btrfs.is_empty() => {
+                self.fs_from_btrfs_partition(partition, mounts)
+            },
+            // Members are mounted through their array
+            gpt::FsType::Raid => Success!(),
+            // Declared in `swapDevices`
+            gpt::FsType::Swap => Success!(),
+            _ => self.fs_from_basic_partition(disk, partition, mounts),
         }
     }
 
-    /// Create filesystem entry from EFI partition
-    fn create_fs_from_efi_partition(
+    /// Collect filesystem entry of an EFI partition (mirrors of the system disk
+    /// one are mounted in their boot directory)
+    fn fs_from_efi_partition(
         &self,
-        partition: &partition::Partition) -> Result<String, error::Error> {
+        partition: &partition::Partition,
+        is_system: bool,
+        mounts: &mut Vec<(String, nix::AttrSet)>) -> error::Return {
 
-        let mut content = "\n\n".to_string();
-        content += r#"  fileSystems."/boot/efi" = {"#;
-        content += "\n";
-        content += &format!(
-            r#"    device = "{}";"#,
-            partition.config.device_by_partlabel.as_ref().unwrap());
-        content += "\n";
-        content += r#"    fsType = "vfat";"#;
-        content += "\n";
-        content += "  };";
+        let mountpoint = partition.config.efi_mountpoint(is_system);
 
-        return Ok(content);
+        let device = match &partition.config.device_by_partlabel {
+            Some(d) => d.as_str(),
+            None => return generic_error!("No path for partition"),
+        };
+
+        let mut entry = nix::AttrSet::new()
+            .set("device", device)
+            .set("fsType", "vfat");
+
+        let mut options = mount_options(&partition.config.mount_options);
+
+        // The system must boot even if a mirror disk is missing
+        if !is_system {
+            options.push("nofail");
+        }
+
+        if !options.is_empty() {
+            entry.push("options", nix::strings(&options));
+        }
+
+        mounts.push((mountpoint, entry));
+
+        return Success!();
     }
 
-    /// Create filesystem entry from non-ZFS partition
-    fn create_fs_from_basic_partition(
+    /// Collect filesystem entry of a non-ZFS partition
+    fn fs_from_basic_partition(
         &self,
-        p: &partition::Partition) -> Result<String, error::Error> {
+        disk: &disk::Config,
+        p: &partition::Partition,
+        mounts: &mut Vec<(String, nix::AttrSet)>) -> error::Return {
 
         let device = match p.config.encrypted {
-            true => p.config.luks_mapper.as_ref().unwrap(),
-            false => p.config.device_by_partlabel.as_ref().unwrap(),
+            true => &p.config.luks_mapper,
+            false => &p.config.device_by_partlabel,
         };
 
-        let mut content = "\n\n".to_string();
-        content += &format!(r#"  fileSystems."{}" = {{"#, &p.config.label);
-
-        content += "\n";
-        content += &format!(r#"    device = "{}";"#, &device);
+        let device = match device {
+            Some(d) => d.as_str(),
+            None => return generic_error!("No device for partition"),
+        };
 
-        if p.config.encrypted {
-            let blk_dev = p.config.device_by_partlabel.as_ref().unwrap();
+        let mut entry = nix::AttrSet::new().set("device", device);
 
-            content += "\n\n";
-            content += "    encrypted = {";
+        match gpt::FsType::from_str(&p.config.fs_type)? {
+            _ if p.config.is_root => {
+                entry.push("fsType", p.config.fs_type.as_str())
+            },
+            gpt::FsType::F2fs => entry.push("fsType", "f2fs"),
+            gpt::FsType::Xfs => entry.push("fsType", "xfs"),
+            _ => (),
+        }
 
-            content += "\n";
-            content += "      enable = true;";
+        if p.config.auto_resize {
+            auto_resize(&mut entry);
+        }
 
-            content += "\n";
-            content += &format!(r#"      blkdev = "{}";"#, &blk_dev);
+        let mut options = mount_options(&p.config.mount_options);
 
-            content += "\n";
-            content += &format!(
-                r#"      label = "{}";"#,
-                &p.config.label);
+        // Mounted on access, when plugged
+        if disk.external {
+            options.extend(["noauto", "nofail", "x-systemd.automount"]);
+        }
 
-            content += "\n";
-            content += &format!(
-                r#"      keyFile = "/etc/secrets/disks/{}";"#,
-                &self.key_filename);
+        if !options.is_empty() {
+            entry.push("options", nix::strings(&options));
+        }
 
-            content += "\n";
-            content += "    };";
+        if p.config.encrypted && !self.is_unlocked_after_boot(disk, &p.config) {
+            let blk_dev = match &p.config.device_by_partlabel {
+                Some(d) => d.as_str(),
+                None => return generic_error!("No path for partition"),
+            };
+
+            let (_, system_key_file) = self.key_file_paths();
+
+            entry.push_blank();
+            entry.push(
+                "encrypted",
+                nix::AttrSet::new()
+                    .set("enable", true)
+                    .set("blkdev", blk_dev)
+                    .set("label", p.config.label.as_str())
+                    .set("keyFile", system_key_file));
         }
 
-        content += "\n";
-        content += "  };";
+        mounts.push((mount_key(&p.config.label, p.config.is_root), entry));
+
+        // Volumes are only declared to be grown or given mount options
+        let volumes = p.lvm.volumes
+            .iter()
+            .filter(|v| {
+                v.config.auto_resize || !v.config.mount_options.is_empty()
+            });
+
+        for volume in volumes {
+            let device = match &volume.config.device {
+                Some(d) => d.as_str(),
+                None => return generic_error!("No device for volume"),
+            };
+
+            let mut entry = nix::AttrSet::new().set("device", device);
 
-        return Ok(content);
+            if volume.config.is_root {
+                entry.push("fsType", volume.config.fs_type.as_str());
+            }
+
+            if !volume.config.mount_options.is_empty() {
+                entry.push(
+                    "options",
+                    nix::strings(&mount_options(&volume.config.mount_options)));
+            }
+
+            if volume.config.auto_resize {
+                auto_resize(&mut entry);
+            }
+
+            mounts.push((
+                mount_key(&volume.config.label, volume.config.is_root),
+                entry));
+        }
+
+        return Success!();
     }
 
-    /// Create filesystem entry from ZFS partition
-    fn create_fs_from_zfs_partition(
+    /// Get the swap devices (sorted) and the resume device, encrypted swaps
+    /// are unlocked with the key file in the initrd (see `devices.nix`)
+    fn swap_devices(
         &self,
-        p: &partition::Partition) -> Result<String, error::Error> {
+        fs: &filesystem::Filesystem)
+        -> Result<(Vec<String>, Option<String>), error::Error> {
+
+        let mut swaps = Vec::new();
+        let mut resume = None;
+
+        for p in fs.disks.iter().flat_map(|d| d.partitions.iter()) {
+            if p.config.fs_type == "swap" {
+                let device = match p.config.encrypted {
+                    true => &p.config.luks_mapper,
+                    false => &p.config.device_by_partlabel,
+                };
+
+                let device = match device {
+                    Some(d) => d.clone(),
+                    None => return generic_error!("No device for swap"),
+                };
+
+                if p.config.is_resume {
+                    resume = Some(device.clone());
+                }
+
+                swaps.push(device);
+            }
+
+            for volume in p.lvm.volumes.iter() {
+                if volume.config.fs_type != "swap" {
+                    continue;
+                }
+
+                let device = match &volume.config.device {
+                    Some(d) => d.clone(),
+                    None => return generic_error!("No device for swap"),
+                };
+
+                if volume.config.is_resume {
+                    resume = Some(device.clone());
+                }
 
-        let mut content = "".to_string();
+                swaps.push(device);
+            }
+        }
+
+        swaps.sort();
+
+        return Ok((swaps, resume));
+    }
+
+    /// Collect filesystem entries of a ZFS partition
+    fn fs_from_zfs_partition(
+        &self,
+        p: &partition::Partition,
+        mounts: &mut Vec<(String, nix::AttrSet)>) -> error::Return {
 
         for fs in p.config.zfs.iter() {
-            let device = format!("{}/{}", p.config.label, fs.name);
+            let mut entry = nix::AttrSet::new()
+                .set("device", format!("{}/{}", p.config.label, fs.name))
+                .set("fsType", "zfs");
+
+            if !fs.mount_options.is_empty() {
+                entry.push(
+                    "options",
+                    nix::strings(&mount_options(&fs.mount_options)));
+            }
+
+            mounts.push((fs.mountpoint.clone(), entry));
+        }
+
+        return Success!();
+    }
+
+    /// Collect filesystem entries of a btrfs partition (one per subvolume, the
+    /// LUKS device is unlocked in the initrd)
+    fn fs_from_btrfs_partition(
+        &self,
+        p: &partition::Partition,
+        mounts: &mut Vec<(String, nix::AttrSet)>) -> error::Return {
 
-            content += "\n\n";
-            content += &format!(r#"  fileSystems."{}" = {{"#, &fs.mountpoint);
+        let device = match p.config.encrypted {
+            true => &p.config.luks_mapper,
+            false => &p.config.device_by_partlabel,
+        };
 
-            content += "\n";
-            content += &format!(r#"    device = "{}";"#, &device);
+        let device = match device {
+            Some(d) => d.as_str(),
+            None => return generic_error!("No device for partition"),
+        };
 
-            content += "\n";
-            content += r#"    fsType = "zfs";"#;
+        for subvolume in p.config.btrfs.iter() {
+            let option = format!("subvol={}", subvolume.name);
 
-            content += "\n";
-            content += "  };";
+            let mut options = vec![option.as_str()];
+            options.extend(mount_options(&p.config.mount_options));
+
+            mounts.push((
+                subvolume.mountpoint.clone(),
+                nix::AttrSet::new()
+                    .set("device", device)
+                    .set("fsType", "btrfs")
+                    .set("options", nix::strings(&options))));
         }
 
-        return Ok(content);
+        return Success!();
+    }
+
+    /// Create the content of `users.nix` (initial user account)
+    fn users_nix(&self) -> Result<String, error::Error> {
+        let user = match &self.user {
+            Some(u) => u,
+            None => return generic_error!("No user"),
+        };
+
+        let hashed_password = self.hash_user_password()?;
+
+        let groups = self.user_groups
+            .iter()
+            .map(|g| g.as_str())
+            .collect::<Vec<&str>>();
+
+        let content = nix::AttrSet::new().set(
+            &format!("users.users.{}", nix::attr_name(user)),
+            nix::AttrSet::new()
+                .set("isNormalUser", true)
+                .set("extraGroups", nix::strings(&groups))
+                .set("hashedPassword", hashed_password));
+
+        return Ok(nix::module(&[], &content, self.indent));
+    }
+
+    /// Hash the password of the initial user (crypt format)
+    fn hash_user_password(&self) -> Result<String, error::Error> {
+        let file = match &self.user_password_file {
+            Some(f) => path::PathBuf::from(f),
+            None => return generic_error!("No user password file"),
+        };
+
+        let password = match fs::read_to_string(&file) {
+            Ok(p) => p.trim_end_matches('\n').to_string(),
+            Err(e) => return fs_error!(file, e),
+        };
+
+        if password.is_empty() {
+            return generic_error!("Empty user password");
+        }
+
+        logger::add_secret(&password);
+
+        let output = utils::spawn_command(
+            "mkpasswd",
+            &[
+                "--method=sha-512",
+                "--stdin",
+            ],
+            Some(password.as_bytes()))?;
+
+        let hashed_password = utils::command_stdout_to_string(&output)?
+            .trim()
+            .to_string();
+
+        if !hashed_password.starts_with('$') {
+            return generic_error!("Cannot hash the user password");
+        }
+
+        return Ok(hashed_password);
+    }
+
+    /// Get the paths of the key file in the initrd and on the installed system
+    /// (the initrd secret is copied from the latter)
+    fn key_file_paths(&self) -> (String, String) {
+        return (
+            format!("/{}", self.key_filename),
+            format!("{}/{}", self.secrets_dir, self.key_filename));
     }
 
     /// Create a unique host identifier
@@ -452,46 +1187,826 @@ r#"# Auto-generated, do not edit !
         return Ok(id);
     }
 
-    /// Check if the filesystem contains at least one ZFS
-    fn has_zfs(&self, fs: &filesystem::Filesystem) -> bool {
+    /// Get the entry of a LUKS device unlocked in the initrd (the initrd of
+    /// systemd-boot lies on the unencrypted ESP: the passphrase is asked, once
+    /// for all the devices)
+    fn initrd_luks_device(
+        &self,
+        device: &str,
+        initrd_key_file: &str) -> nix::AttrSet {
+
+        return match self.systemd_boot {
+            true => nix::AttrSet::new()
+                .set("device", device)
+                .set("allowDiscards", true),
+
+            false => nix::AttrSet::new()
+                .set("device", device)
+                .set("keyFile", initrd_key_file)
+                .set("allowDiscards", true)
+                .set("preLVM", true),
+        };
+    }
+
+    /// Run the formatter (if any) on a generated file
+    fn format_file(&self, path: &path::Path) -> error::Return {
+        let formatter = match &self.format_nix {
+            Some(f) => f.as_str(),
+            None => return Success!(),
+        };
+
+        let file = match path.to_str() {
+            Some(f) => f,
+            None => return generic_error!("Invalid path"),
+        };
+
+        let args = match formatter {
+            "alejandra" => vec!["--quiet", file],
+            _ => vec![file],
+        };
+
+        utils::command_output(formatter, &args)?;
+
+        log::info!("`{}` formatted with {}", file, formatter);
+
+        return Success!();
+    }
+
+    /// Warn about the LUKS devices with discards enabled
+    fn warn_discards(&self, fs: &filesystem::Filesystem) {
         for disk in fs.disks.iter() {
-            for p in disk.partitions.iter() {
-                let fs_type = match gpt::FsType::from_str(&p.config.fs_type) {
-                    Ok(t) => t,
-                    Err(_) => continue,
-                };
+            for partition in disk.partitions.iter() {
+                if partition.config.encrypted {
+                    report::warning(&format!(
+                        "Discards are enabled on LUKS device `{}`",
+                        partition.config.label));
+                }
+            }
+        }
+    }
 
-                match fs_type {
-                    gpt::FsType::Zfs => return true,
+    /// Get the EFI partitions of the non-system disks
+    fn mirrored_efi_partitions<'a>(
+        &self,
+        fs: &'a filesystem::Filesystem)
+        -> Result<Vec<&'a partition::Config>, error::Error> {
+
+        let mut partitions = Vec::new();
+        let mut has_system_efi = false;
+
+        for disk in fs.disks.iter().filter(|d| !d.config.external) {
+            for p in disk.partitions.iter() {
+                match gpt::PartitionType::from_str(&p.config.partition_type)? {
+                    gpt::PartitionType::Efi => (),
                     _ => continue,
                 }
+
+                match disk.config.contains_system() {
+                    true => has_system_efi = true,
+                    false => partitions.push(&p.config),
+                }
             }
         }
 
-        return false;
+        if !partitions.is_empty() && !has_system_efi {
+            return generic_error!(
+                "Mirrored EFI partitions need one on the system disk");
+        }
+
+        return Ok(partitions);
     }
 
-    /// Check if the root partition/filesystem is a ZFS
-    fn is_root_zfs(&self, fs: &filesystem::Filesystem) -> bool {
+    /// Get the mount point of the EFI partition of the system disk
+    fn system_efi_mountpoint(
+        &self,
+        fs: &filesystem::Filesystem) -> Result<String, error::Error> {
+
+        for disk in fs.disks.iter().filter(|d| d.config.contains_system()) {
+            for p in disk.partitions.iter() {
+                match gpt::PartitionType::from_str(&p.config.partition_type)? {
+                    gpt::PartitionType::Efi => {
+                        return Ok(p.config.efi_mountpoint(true));
+                    },
+
+                    _ => (),
+                }
+            }
+        }
+
+        return Ok(partition::EFI_MOUNTPOINT.to_string());
+    }
+
+    /// Get the filesystems needing kernel support (in the order of the
+    /// partitions)
+    fn supported_filesystems(
+        &self,
+        fs: &filesystem::Filesystem) -> Vec<&'static str> {
+
+        let mut supported = Vec::new();
+
+        let partitions = fs.disks.iter().flat_map(|d| d.partitions.iter());
+
+        let fs_types = partitions.clone()
+            .map(|p| &p.config.fs_type)
+            .chain(partitions
+                .flat_map(|p| p.config.lvm.iter().map(|v| &v.fs_type)))
+            .chain(fs.raids.iter().map(|r| &r.config.fs_type));
+
+        for fs_type in fs_types {
+            let name = match supported_filesystem(fs_type) {
+                Some(n) => n,
+                None => continue,
+            };
+
+            if !supported.contains(&name) {
+                supported.push(name);
+            }
+        }
+
+        return supported;
+    }
+
+    /// Get the filesystem the initrd needs to support to mount the root (if
+    /// the root partition, volume, dataset, subvolume or array needs it)
+    fn root_supported_filesystem(
+        &self,
+        fs: &filesystem::Filesystem) -> Option<&'static str> {
+
+        for array in fs.raids.iter() {
+            if array.config.is_root {
+                return supported_filesystem(&array.config.fs_type);
+            }
+        }
+
         for disk in fs.disks.iter() {
             for p in disk.partitions.iter() {
-                let fs_type = match gpt::FsType::from_str(&p.config.fs_type) {
-                    Ok(t) => t,
-                    Err(_) => continue,
+                let is_root =
+                    p.config.is_root ||
+                    p.config.zfs.iter().any(|f| f.is_root) ||
+                    p.config.btrfs.iter().any(|s| s.is_root);
+
+                let fs_type = match p.config.lvm.iter().find(|v| v.is_root) {
+                    Some(v) => &v.fs_type,
+                    None if is_root => &p.config.fs_type,
+                    None => continue,
                 };
 
-                if fs_type != gpt::FsType::Zfs {
-                    continue;
+                match supported_filesystem(fs_type) {
+                    Some(n) => return Some(n),
+                    None => continue,
                 }
+            }
+        }
 
-                for fs in p.config.zfs.iter() {
-                    if fs.is_root {
-                        return true;
-                    }
-                }
+        return None;
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Backend collecting the `fileSystems` entries (mountpoint and entry)
+struct Mounts<'a> {
+    /// Command generating the entries
+    command: &'a Command,
+
+    /// Entries collected
+    mounts: Vec<(String, nix::AttrSet)>,
+}
+
+impl nix::Backend for Mounts<'_> {
+    fn disk(&mut self, _disk: &disk::Disk) -> error::Return {
+        return Success!();
+    }
+
+    fn partition(
+        &mut self,
+        disk: &disk::Disk,
+        partition: &partition::Partition) -> error::Return {
+
+        return match partition.config.partition_type.as_str() {
+            "linux" => {
+                self.command.fs_from_partition(
+                    &disk.config,
+                    partition,
+                    &mut self.mounts)
+            },
+
+            "efi" => {
+                self.command.fs_from_efi_partition(
+                    partition,
+                    disk.config.contains_system(),
+                    &mut self.mounts)
+            },
+
+            _ => Success!(),
+        };
+    }
+
+    fn array(&mut self, array: &raid::Array) -> error::Return {
+        self.mounts.push((
+            array.config.mountpoint.clone(),
+            nix::AttrSet::new()
+                .set("device", array.config.filesystem_device())
+                .set("fsType", array.config.fs_type.as_str())));
+
+        return Success!();
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Get the mount options of the layout as string slices
+fn mount_options(options: &[String]) -> Vec<&str> {
+    return options.iter().map(|o| o.as_str()).collect();
+}
+
+/// Create the content of `mdadm.conf` (arrays are identified by UUID)
+fn mdadm_conf(fs: &filesystem::Filesystem) -> Result<String, error::Error> {
+    let mut lines = Vec::new();
+
+    for array in fs.raids.iter() {
+        let uuid = match &array.config.uuid {
+            Some(u) => u,
+            None => return generic_error!(&format!(
+                "No UUID for array `{}`",
+                array.config.name)),
+        };
+
+        lines.push(format!(
+            "ARRAY {} metadata={} UUID={}",
+            array.config.device(),
+            raid::METADATA,
+            uuid));
+    }
+
+    lines.push("MAILADDR root".to_string());
+
+    return Ok(lines.join("\n"));
+}
+
+/// Check the PKI bundle of lanzaboote: an absolute path, holding the keys of
+/// the signature database if it exists on this machine
+fn check_secure_boot_pki(pki: &str) -> error::Return {
+    if !pki.starts_with('/') || pki.split('/').any(|c| c == "..") {
+        return generic_error!(&format!(
+            "Invalid Secure Boot PKI bundle `{}` (must be absolute)",
+            pki));
+    }
+
+    let dir = path::Path::new(pki);
+
+    if !dir.exists() {
+        log::info!("PKI bundle `{}` not found on this machine", pki);
+        return Success!();
+    }
+
+    let missing: Vec<&str> = SECURE_BOOT_DB_KEYS
+        .iter()
+        .filter(|k| !dir.join(k).is_file())
+        .copied()
+        .collect();
+
+    if !missing.is_empty() {
+        return generic_error!(&format!(
+            "PKI bundle `{}` lacks {} (created by `sbctl create-keys`)",
+            pki,
+            missing.join(", ")));
+    }
+
+    return Success!();
+}
+
+/// Get the name of a filesystem type needing kernel support
+fn supported_filesystem(fs_type: &str) -> Option<&'static str> {
+    return match gpt::FsType::from_str(fs_type) {
+        Ok(t) => t.nixos_support(),
+        Err(_) => None,
+    };
+}
+
+/// Create the udev rule unlocking a partition of an external disk when it's
+/// plugged (starts its `systemd-cryptsetup` unit, see `/etc/crypttab`)
+fn unlock_rule(label: &str) -> String {
+    // Dashes are escaped in unit names
+    let unit = format!(
+        "systemd-cryptsetup@{}.service",
+        label.replace('-', "\\x2d"));
+
+    return format!(
+        "ACTION==\"add\", SUBSYSTEM==\"block\", \
+        ENV{{ID_PART_ENTRY_NAME}}==\"{}\", ENV{{SYSTEMD_WANTS}}+=\"{}\"\n",
+        label,
+        unit);
+}
+
+/// Write a generated Nix file in provided directory
+fn write_nix(
+    path: &path::PathBuf,
+    filename: &str,
+    content: &str) -> Result<path::PathBuf, error::Error> {
+
+    log::info!("{}", content);
+
+    let output = path.join(filename);
+
+    utils::write_to_file(content.as_bytes(), &output)?;
+
+    log::info!("Configuration written to {:?}", &output);
+
+    return Ok(output);
+}
+
+/// Grow the filesystem of an entry at boot (only done for filesystems mounted
+/// in the initrd, where NixOS adds the resize tools)
+fn auto_resize(entry: &mut nix::AttrSet) {
+    entry.push("autoResize", true);
+}
+
+/// Get the key of the entry of a partition or a volume in `fileSystems` (the
+/// root is mounted on `/`, the others are only named after their label)
+fn mount_key(label: &str, is_root: bool) -> String {
+    return match is_root {
+        true => "/".to_string(),
+        false => label.to_string(),
+    };
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::env;
+    use std::fs;
+    use std::path;
+
+    use super::super::error;
+    use super::super::filesystem;
+
+    /// Get a path in the golden files directory
+    fn golden(name: &str) -> path::PathBuf {
+        return path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("golden")
+            .join(name);
+    }
+
+    /// Load a golden layout edited by a test (written to a temporary file)
+    pub(crate) fn golden_layout(
+        name: &str,
+        edit: impl FnOnce(&mut serde_json::Value))
+        -> Result<filesystem::Filesystem, error::Error> {
+
+        let json = fs::read_to_string(golden(name)).unwrap();
+        let mut value: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        edit(&mut value);
+
+        let layout = mktemp::Temp::new_file().unwrap();
+        fs::write(&layout, value.to_string()).unwrap();
+
+        return filesystem::Filesystem::from_json(&layout.to_path_buf());
+    }
+
+    /// Compare generated files with the golden ones (`UPDATE_GOLDEN=1`
+    /// rewrites them)
+    fn check(layout: &str) {
+        let json = golden(&format!("{}.json", layout));
+        let layout_fs = filesystem::Filesystem::from_json(&json).unwrap();
+
+        let mut command = super::Command::new();
+        command.host = "test".to_string();
+        command.key_filename = "key_file".to_string();
+
+        let generated = [
+            ("bootloader.nix", command.bootloader_nix(&layout_fs).unwrap()),
+            ("devices.nix", command.devices_nix(&layout_fs).unwrap()),
+            (
+                "filesystems.nix",
+                command.filesystems_nix(&layout_fs, "01234567").unwrap(),
+            ),
+            ("disko.nix", command.disko_nix(&layout_fs).unwrap()),
+        ];
+
+        for (filename, content) in generated.iter() {
+            let path = golden(layout).join(filename);
+
+            if env::var("UPDATE_GOLDEN").is_ok() {
+                fs::write(&path, content).unwrap();
             }
+
+            let expected = fs::read_to_string(&path).unwrap();
+
+            assert_eq!(content, &expected, "{}/{}", layout, filename);
         }
+    }
+
+    /// Reverse the order of the disks and of their content in a layout
+    fn reverse(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Array(a) => {
+                a.reverse();
+                a.iter_mut().for_each(reverse);
+            },
+
+            serde_json::Value::Object(o) => o.values_mut().for_each(reverse),
+
+            _ => (),
+        }
+    }
+
+    #[test]
+    fn output_does_not_depend_on_layout_order() {
+        let layouts = ["ext4", "zfs", "btrfs", "raid", "autoresize", "swap"];
+
+        for layout in layouts.iter() {
+            let json = golden(&format!("{}.json", layout));
+
+            let original = filesystem::Filesystem::from_json(&json).unwrap();
+            let shuffled =
+                golden_layout(&format!("{}.json", layout), reverse).unwrap();
+
+            let mut command = super::Command::new();
+            command.key_filename = "key_file".to_string();
+
+            assert_eq!(
+                command.filesystems_nix(&original, "01234567").unwrap(),
+                command.filesystems_nix(&shuffled, "01234567").unwrap(),
+                "{}/filesystems.nix",
+                layout);
+
+            assert_eq!(
+                command.devices_nix(&original).unwrap(),
+                command.devices_nix(&shuffled).unwrap(),
+                "{}/devices.nix",
+                layout);
+        }
+    }
+
+    #[test]
+    fn key_file_references_agree() {
+        let json = golden("ext4.json");
+        let layout_fs = filesystem::Filesystem::from_json(&json).unwrap();
+
+        let mut command = super::Command::new();
+        command.key_filename = "key_file".to_string();
+
+        let devices = command.devices_nix(&layout_fs).unwrap();
+        let filesystems =
+            command.filesystems_nix(&layout_fs, "01234567").unwrap();
+
+        let captures = |content: &str, pattern: &str| -> Vec<String> {
+            return regex::Regex::new(pattern)
+                .unwrap()
+                .captures_iter(content)
+                .map(|c| c[1].to_string())
+                .collect();
+        };
+
+        // Unlocked in the initrd with the secret copied from the system
+        let unlocked = captures(&devices, r#"keyFile = "([^"]+)";"#);
+        let secrets = regex::Regex::new(r#""(/[^"]+)" = "([^"]+)";"#)
+            .unwrap()
+            .captures(&devices)
+            .unwrap();
+        let mounted = captures(&filesystems, r#"keyFile = "([^"]+)";"#);
+
+        assert!(!unlocked.is_empty() && !mounted.is_empty());
+        assert!(unlocked.iter().all(|k| k == &secrets[1]));
+        assert!(mounted.iter().all(|k| k == &secrets[2]));
+    }
+
+    #[test]
+    fn systemd_boot_keeps_the_key_file_off_the_esp() {
+        let json = golden("ext4.json");
+        let layout_fs = filesystem::Filesystem::from_json(&json).unwrap();
+
+        let mut command = super::Command::new();
+        command.key_filename = "key_file".to_string();
+        command.systemd_boot = true;
+
+        let bootloader = command.bootloader_nix(&layout_fs).unwrap();
+        let devices = command.devices_nix(&layout_fs).unwrap();
+
+        // 512M ESP
+        assert!(bootloader.contains("systemd-boot = {"));
+        assert!(bootloader.contains("configurationLimit = 5;"));
+        assert!(bootloader.contains("boot.initrd.systemd.enable = true;"));
+        assert!(!bootloader.contains("grub"));
+
+        assert!(devices.contains("luks.devices.\"system\""));
+        assert!(!devices.contains("key_file"));
+    }
+
+    #[test]
+    fn lanzaboote_replaces_systemd_boot() {
+        let json = golden("ext4.json");
+        let layout_fs = filesystem::Filesystem::from_json(&json).unwrap();
+
+        let mut command = super::Command::new();
+        command.systemd_boot = true;
+        command.secure_boot_pki = Some("/var/lib/sbctl".to_string());
+
+        let bootloader = command.bootloader_nix(&layout_fs).unwrap();
+
+        assert!(bootloader.starts_with("# Auto-generated"));
+        assert!(bootloader.contains("{ config, lib, ... }:"));
+        assert!(
+            bootloader.contains("systemd-boot.enable = lib.mkForce false;"));
+        assert!(bootloader.contains("pkiBundle = \"/var/lib/sbctl\";"));
+
+        assert!(super::check_secure_boot_pki("var/lib/sbctl").is_err());
+        assert!(super::check_secure_boot_pki("/var/../sbctl").is_err());
+    }
+
+    #[test]
+    fn systemd_boot_rejects_small_esp() {
+        let layout_fs = golden_layout("ext4.json", |value| {
+            value["disks"][0]["partitions"][0]["size"] =
+                serde_json::Value::from("256M");
+        }).unwrap();
+
+        let mut command = super::Command::new();
+        command.systemd_boot = true;
+
+        assert!(command.bootloader_nix(&layout_fs).is_err());
+    }
+
+    #[test]
+    fn crypttab_unlocks_data_partitions_after_boot() {
+        let json = golden("ext4.json");
+        let layout_fs = filesystem::Filesystem::from_json(&json).unwrap();
+
+        let mut command = super::Command::new();
+        command.key_filename = "key_file".to_string();
+        command.crypttab = true;
+
+        let devices = command.devices_nix(&layout_fs).unwrap();
+        let filesystems =
+            command.filesystems_nix(&layout_fs, "01234567").unwrap();
+
+        assert!(devices.contains("luks.devices.\"system\""));
+        assert!(!devices.contains("luks.devices.\"data\""));
+        assert!(devices.contains(
+            "environment.etc.\"crypttab\".text = \"data \
+            /dev/disk/by-partlabel/data /etc/secrets/disks/key_file \
+            luks,discard\\n\";"));
+
+        assert!(filesystems.contains("label = \"system\";"));
+        assert!(!filesystems.contains("label = \"data\";"));
+
+        let entry = super::crypttab::entry;
+
+        assert!(entry("data", "sda2", "/key", "luks").is_err());
+        assert!(entry("data", "/dev/sda2", "key", "luks").is_err());
+    }
+
+    #[test]
+    fn lvm_discards_are_enabled_in_the_lvm_configuration() {
+        let layout_fs = golden_layout("ext4.json", |value| {
+            value["disks"][0]["partitions"][2]["lvm_discard"] =
+                serde_json::Value::Bool(true);
+        }).unwrap();
+
+        let mut command = super::Command::new();
+        command.key_filename = "key_file".to_string();
+
+        assert!(command.devices_nix(&layout_fs).unwrap().contains(
+            "environment.etc.\"lvm/lvm.conf\".text = \
+            \"devices/issue_discards = 1\";"));
+
+        assert!(golden_layout("ext4.json", |value| {
+            value["disks"][0]["partitions"][1]["lvm_discard"] =
+                serde_json::Value::Bool(true);
+        }).is_err());
+    }
+
+    #[test]
+    fn mount_options_are_declared() {
+        let layout_fs = golden_layout("ext4.json", |value| {
+            value["disks"][0]["partitions"][1]["mount_options"] =
+                serde_json::json!(["noatime", "discard"]);
+            value["disks"][0]["partitions"][2]["lvm"][0]["mount_options"] =
+                serde_json::json!(["noatime"]);
+        }).unwrap();
+
+        let mut command = super::Command::new();
+        command.key_filename = "key_file".to_string();
+
+        let filesystems =
+            command.filesystems_nix(&layout_fs, "01234567").unwrap();
+
+        assert!(filesystems.contains("options = [ \"noatime\" \"discard\" ];"));
+        assert!(filesystems.contains("options = [ \"noatime\" ];"));
+
+        // Volume groups aren't formatted by mkfs
+        assert!(golden_layout("ext4.json", |value| {
+            value["disks"][0]["partitions"][2]["mkfs_options"] =
+                serde_json::json!(["-O", "metadata_csum"]);
+        }).is_err());
+    }
+
+    #[test]
+    fn labels_are_escaped() {
+        let layout_fs = golden_layout("ext4.json", |value| {
+            value["disks"][0]["partitions"][1]["label"] =
+                serde_json::json!("my\"disk");
+            value["disks"][0]["partitions"][2]["lvm"][0]["label"] =
+                serde_json::json!("a${b}");
+            value["disks"][0]["partitions"][2]["lvm"][0]["device"] =
+                serde_json::json!("/dev/vg-system/a${b}");
+        }).unwrap();
+
+        let mut command = super::Command::new();
+        command.key_filename = "key_file".to_string();
+
+        let devices = command.devices_nix(&layout_fs).unwrap();
+        let filesystems =
+            command.filesystems_nix(&layout_fs, "01234567").unwrap();
+
+        assert!(devices.contains("luks.devices.\"my\\\"disk\""));
+        assert!(filesystems.contains("\"/dev/vg-system/a\\${b}\""));
+
+        // Nothing is interpolated nor closes a string early
+        for content in [devices, filesystems].iter() {
+            let unescaped = content.replace("\\\"", "").replace("\\$", "");
+
+            assert!(!unescaped.contains("${"));
+            assert!(!unescaped.contains("my\"disk"));
+        }
+    }
+
+    #[test]
+    fn external_disks_are_unlocked_when_plugged() {
+        let layout_fs = golden_layout("ext4.json", |value| {
+            let mut backup = value["disks"][0].clone();
+            let mut partition = backup["partitions"][1].clone();
+
+            partition["id"] = serde_json::Value::from(1);
+            partition["label"] = serde_json::Value::from("backup-usb");
+            partition["device_by_partlabel"] =
+                serde_json::Value::from("/dev/disk/by-partlabel/backup-usb");
+            partition["luks_mapper"] =
+                serde_json::Value::from("/dev/mapper/backup-usb");
+
+            backup["device"] = serde_json::Value::from("/dev/sdb");
+            backup["external"] = serde_json::Value::Bool(true);
+            backup["contains_system"] = serde_json::Value::Bool(false);
+            backup["partitions"] = serde_json::Value::from(vec![partition]);
+
+            value["disks"].as_array_mut().unwrap().push(backup);
+        }).unwrap();
+
+        let mut command = super::Command::new();
+        command.key_filename = "key_file".to_string();
+
+        let devices = command.devices_nix(&layout_fs).unwrap();
+        let filesystems =
+            command.filesystems_nix(&layout_fs, "01234567").unwrap();
+
+        assert!(devices.contains("luks.devices.\"data\""));
+        assert!(!devices.contains("luks.devices.\"backup-usb\""));
+        assert!(devices.contains(
+            "/etc/secrets/disks/key_file luks,discard,noauto,nofail\\n"));
+        assert!(devices.contains("systemd-cryptsetup@backup\\\\x2dusb"));
+        assert!(filesystems.contains("\"x-systemd.automount\""));
+        assert!(!filesystems.contains("label = \"backup-usb\";"));
+
+        // The system can't be on an external disk
+        assert!(golden_layout("ext4.json", |value| {
+            value["disks"][0]["external"] = serde_json::Value::Bool(true);
+        }).is_err());
+    }
+
+    #[test]
+    fn efi_partitions_can_have_their_own_mountpoint() {
+        let layout_fs = golden_layout("raid.json", |value| {
+            value["disks"][0]["partitions"][0]["efi_mountpoint"] =
+                serde_json::Value::from("/boot/efi0");
+            value["disks"][1]["partitions"][0]["efi_mountpoint"] =
+                serde_json::Value::from("/boot/efi1");
+        }).unwrap();
+
+        let command = super::Command::new();
+
+        let bootloader = command.bootloader_nix(&layout_fs).unwrap();
+        let filesystems =
+            command.filesystems_nix(&layout_fs, "01234567").unwrap();
+
+        assert!(bootloader.contains("efiSysMountPoint = \"/boot/efi0\";"));
+        assert!(bootloader.contains("path = \"/boot-uefi-b\";"));
+        assert!(bootloader.contains("efiSysMountPoint = \"/boot/efi1\";"));
+        assert!(filesystems.contains("fileSystems.\"/boot/efi0\""));
+        assert!(filesystems.contains("fileSystems.\"/boot/efi1\""));
+
+        // Mount points can't be shared
+        assert!(golden_layout("raid.json", |value| {
+            for disk in 0..2 {
+                value["disks"][disk]["partitions"][0]["efi_mountpoint"] =
+                    serde_json::Value::from("/boot/efi0");
+            }
+        }).is_err());
+    }
+
+    #[test]
+    fn f2fs_root_is_supported_by_the_initrd() {
+        let layout_fs = golden_layout("ext4.json", |value| {
+            value["disks"][0]["partitions"][1]["fs_type"] =
+                serde_json::Value::from("f2fs");
+            value["disks"][0]["partitions"][2]["lvm"][1]["fs_type"] =
+                serde_json::Value::from("f2fs");
+        }).unwrap();
+
+        let mut command = super::Command::new();
+        command.key_filename = "key_file".to_string();
+
+        let devices = command.devices_nix(&layout_fs).unwrap();
+        let filesystems =
+            command.filesystems_nix(&layout_fs, "01234567").unwrap();
+
+        assert!(devices.contains("    supportedFilesystems = [ \"f2fs\" ];"));
+        assert!(
+            devices.contains("      supportedFilesystems = [ \"f2fs\" ];"));
+        assert!(filesystems.contains("fsType = \"f2fs\";"));
+    }
+
+    #[test]
+    fn generates_encrypted_ext4_and_lvm() {
+        check("ext4");
+    }
+
+    #[test]
+    fn generates_zfs_root() {
+        check("zfs");
+    }
+
+    #[test]
+    fn generates_btrfs_subvolumes() {
+        check("btrfs");
+    }
+
+    #[test]
+    fn generates_raid1_root() {
+        check("raid");
+    }
+
+    #[test]
+    fn encrypted_arrays_are_unlocked_in_the_initrd() {
+        let layout_fs = golden_layout("raid.json", |value| {
+            value["raids"][0]["encrypted"] = serde_json::Value::Bool(true);
+        }).unwrap();
+
+        let mut command = super::Command::new();
+        command.key_filename = "key_file".to_string();
+
+        let devices = command.devices_nix(&layout_fs).unwrap();
+        let filesystems =
+            command.filesystems_nix(&layout_fs, "01234567").unwrap();
+        let disko = command.disko_nix(&layout_fs).unwrap();
+
+        assert!(devices.contains(
+            "luks.devices.\"nixos\" = {\n        \
+            device = \"/dev/md/system\";"));
+        assert!(devices.contains("swraid"));
+        assert!(filesystems.contains("device = \"/dev/mapper/nixos\";"));
+        assert!(disko.contains("type = \"luks\";"));
+
+        // The LUKS device would take the name of a partition
+        assert!(golden_layout("raid.json", |value| {
+            value["raids"][0]["encrypted"] = serde_json::Value::Bool(true);
+            value["raids"][0]["label"] = serde_json::json!("system-a");
+        }).is_err());
+    }
+
+    #[test]
+    fn generates_swap_and_resume_devices() {
+        check("swap");
+    }
+
+    #[test]
+    fn generates_auto_resize() {
+        check("autoresize");
+    }
+
+    #[test]
+    fn rejects_auto_resize_of_swap() {
+        let error = golden_layout("autoresize.json", |value| {
+            value["disks"][0]["partitions"][2]["lvm"][0]["auto_resize"] =
+                serde_json::Value::Bool(true);
+        }).unwrap_err();
+
+        assert!(error.to_string().ends_with(
+            "`swap` cannot be resized at boot (swap is not supported, ext4 or \
+                f2fs only)"));
+    }
+
+    #[test]
+    fn rejects_auto_resize_without_mount_point() {
+        let error = golden_layout("autoresize.json", |value| {
+            value["disks"][0]["partitions"][1]["auto_resize"] =
+                serde_json::Value::Bool(true);
+        }).unwrap_err();
 
-        return false;
+        assert!(error.to_string().ends_with(
+            "`data` cannot be resized at boot (only the root filesystem can)"));
     }
 }
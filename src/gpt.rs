@@ -3,7 +3,10 @@
 use regex::Regex;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde::de::{Visitor};
+use std::convert::TryFrom;
 use std::fmt;
+use std::fs;
+use std::path;
 use std::str::FromStr;
 use std::thread;
 use std::time;
@@ -14,6 +17,20 @@ use super::zfs;
 
 // -----------------------------------------------------------------------------
 
+/// Size in bytes of a logical sector
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Default alignment of partitions (in sectors, i.e. 1MiB)
+pub const DEFAULT_ALIGNMENT: u64 = 2048;
+
+/// Maximal length of a GPT partition name
+pub const PARTLABEL_MAX: usize = 36;
+
+/// Size taking the space left on the device (same as `0`)
+pub const SIZE_REST: &str = "rest";
+
+// -----------------------------------------------------------------------------
+
 #[derive(Clone, Debug)]
 pub enum SizeUnit {
     Byte,
@@ -38,6 +55,20 @@ impl From<&str> for SizeUnit {
     }
 }
 
+impl SizeUnit {
+    /// Get the number of bytes of the unit
+    pub fn multiplier(&self) -> u64 {
+        return match self {
+            SizeUnit::Byte => 1,
+            SizeUnit::Kilo => 1 << 10,
+            SizeUnit::Mega => 1 << 20,
+            SizeUnit::Giga => 1 << 30,
+            SizeUnit::Tera => 1 << 40,
+            SizeUnit::Peta => 1 << 50,
+        }
+    }
+}
+
 impl ToString for SizeUnit {
     fn to_string(&self) -> String {
         return match self {
@@ -70,21 +101,100 @@ impl<'de> Visitor<'de> for BytesizeVisitor {
 
 // -----------------------------------------------------------------------------
 
+/// Size written as an integer or a decimal fraction followed by an optional
+/// unit (e.g. `512M`, `1.5G`, `512MiB`), units are always binary, or as
+/// `rest` (same as `0`) for the space left on the device
 #[derive(Clone, Debug)]
 pub struct Bytesize {
-    unit: SizeUnit,
-    value: u64,
+    /// Size as written in the layout
+    text: String,
+
+    /// Size in bytes (truncated to the byte for fractional values)
+    bytes: u64,
 }
 
 impl Bytesize {
+    /// Create a size from a number of bytes
+    pub fn from_bytes(bytes: u64) -> Self {
+        let size = Self {
+            text: String::new(),
+            bytes: bytes,
+        };
+
+        return Self {
+            text: size.to_unit_string(),
+            bytes: bytes,
+        };
+    }
+
     pub fn is_null(&self) -> bool {
-        return self.value == 0;
+        return self.bytes == 0;
+    }
+
+    /// Get the size in bytes
+    pub fn to_bytes(&self) -> u64 {
+        return self.bytes;
+    }
+
+    /// Get the size in the largest unit dividing it (e.g. `1536M` for `1.5G`)
+    /// or in bytes with a `B` suffix, understood by sgdisk and lvcreate
+    pub fn to_unit_string(&self) -> String {
+        if self.bytes == 0 {
+            return "0".to_string();
+        }
+
+        let units = [
+            SizeUnit::Peta,
+            SizeUnit::Tera,
+            SizeUnit::Giga,
+            SizeUnit::Mega,
+            SizeUnit::Kilo,
+        ];
+
+        for unit in units.iter() {
+            if self.bytes.is_multiple_of(unit.multiplier()) {
+                return format!(
+                    "{}{}",
+                    self.bytes / unit.multiplier(),
+                    unit.to_string());
+            }
+        }
+
+        return format!("{}B", self.bytes);
+    }
+
+    /// Get the size in the largest unit below it, rounded to one decimal
+    /// (e.g. `465.8G`), for logs
+    pub fn to_human(&self) -> String {
+        let units = [
+            SizeUnit::Peta,
+            SizeUnit::Tera,
+            SizeUnit::Giga,
+            SizeUnit::Mega,
+            SizeUnit::Kilo,
+        ];
+
+        for unit in units.iter() {
+            if self.bytes < unit.multiplier() {
+                continue;
+            }
+
+            let value = self.bytes as f64 / unit.multiplier() as f64;
+            let value = format!("{:.1}", value);
+
+            return format!(
+                "{}{}",
+                value.trim_end_matches(".0"),
+                unit.to_string());
+        }
+
+        return format!("{}B", self.bytes);
     }
 
     fn to_gpt_string(&self) -> String {
-        return match self.value {
+        return match self.bytes {
             0 => "0".to_string(),
-            _ => format!("+{}", self.to_string()),
+            _ => format!("+{}", self.to_unit_string()),
         }
     }
 }
@@ -105,7 +215,14 @@ impl<'de> Deserialize<'de> for Bytesize {
 
 impl From<&str> for Bytesize {
     fn from(s: &str) -> Self {
-        let pattern = r"^([0-9]+)([BKMGTP])*$";
+        if s == SIZE_REST {
+            return Self {
+                text: s.to_string(),
+                bytes: 0,
+            };
+        }
+
+        let pattern = r"^([0-9]+)(?:\.([0-9]+))?(?:([KMGTP])(?:i?B)?|B)?$";
 
         let re = match Regex::new(pattern) {
             Ok(r) => r,
@@ -117,32 +234,101 @@ impl From<&str> for Bytesize {
             None => return Self::from("0"),
         };
 
-        let value = captures.get(1).map_or("", |m| m.as_str());
-        let value = match value.parse::<u64>() {
+        let integer = captures.get(1).map_or("", |m| m.as_str());
+        let integer = match integer.parse::<u128>() {
             Ok(v) => v,
             Err(_) => return Self::from("0"),
         };
 
-        let unit = captures.get(2).map_or("", |m| m.as_str());
+        let unit = captures.get(3).map_or("", |m| m.as_str());
+        let multiplier = SizeUnit::from(unit).multiplier() as u128;
+
+        // Fraction of the unit (e.g. `5` / 10 for `1.5G`)
+        let fraction = match captures.get(2) {
+            Some(f) if f.as_str().len() <= 18 => {
+                let digits = f.as_str();
+
+                match digits.parse::<u128>() {
+                    Ok(v) => v * multiplier / 10u128.pow(digits.len() as u32),
+                    Err(_) => return Self::from("0"),
+                }
+            },
+            Some(_) => return Self::from("0"),
+            None => 0,
+        };
+
+        let bytes = integer
+            .saturating_mul(multiplier)
+            .saturating_add(fraction);
 
         return Self {
-            value: value,
-            unit: SizeUnit::from(unit),
+            text: s.to_string(),
+            bytes: u64::try_from(bytes).unwrap_or(u64::MAX),
         };
     }
 }
 
 impl ToString for Bytesize {
     fn to_string(&self) -> String {
-        return match self.value {
+        return match self.bytes {
+            0 if self.text == SIZE_REST => self.text.clone(),
             0 => "0".to_string(),
-            _ => format!("{}{}", self.value, self.unit.to_string()),
+            _ => self.text.clone(),
         }
     }
 }
 
 // -----------------------------------------------------------------------------
 
+/// Partition table found on a device
+#[derive(Debug, PartialEq)]
+pub enum PartitionTable {
+    /// GUID partition table
+    Gpt,
+
+    /// MBR (DOS) partition table
+    Mbr,
+
+    /// Other kind of partition table (as named by blkid)
+    Other(String),
+
+    /// No partition table
+    None,
+}
+
+impl PartitionTable {
+    /// Whether the table must be converted to GPT (data may be lost)
+    pub fn is_foreign(&self) -> bool {
+        return matches!(self, PartitionTable::Mbr | PartitionTable::Other(_));
+    }
+}
+
+impl ToString for PartitionTable {
+    fn to_string(&self) -> String {
+        return match self {
+            PartitionTable::Gpt => "GPT".to_string(),
+            PartitionTable::Mbr => "MBR".to_string(),
+            PartitionTable::Other(t) => t.clone(),
+            PartitionTable::None => "no".to_string(),
+        };
+    }
+}
+
+impl From<&str> for PartitionTable {
+    /// Create from the `PTTYPE` reported by blkid
+    fn from(pttype: &str) -> Self {
+        return match pttype.trim() {
+            "" => PartitionTable::None,
+            // Protective MBR of a GPT whose headers cannot be read
+            "gpt" | "PMBR" => PartitionTable::Gpt,
+            "dos" => PartitionTable::Mbr,
+            t => PartitionTable::Other(t.to_string()),
+        };
+    }
+}
+
+// -----------------------------------------------------------------------------
+
 pub enum PartitionType {
     Efi,
     Linux,
@@ -155,6 +341,14 @@ impl PartitionType {
             PartitionType::Linux => "8300".to_string(),
         }
     }
+
+    /// Get the type GUID (as reported by `sgdisk -i`)
+    pub fn to_gpt_guid(&self) -> &'static str {
+        return match self {
+            PartitionType::Efi => "C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+            PartitionType::Linux => "0FC63DAF-8483-4772-8E79-3D69D8477DE4",
+        }
+    }
 }
 
 impl FromStr for PartitionType {
@@ -183,11 +377,15 @@ impl ToString for PartitionType {
 /// Enumeration of filesystem types
 #[derive(PartialEq)]
 pub enum FsType {
+    Btrfs,
     Ext4,
+    F2fs,
     Fat32,
     Zfs,
     Lvm,
+    Raid,
     Swap,
+    Xfs,
 }
 
 impl FromStr for FsType {
@@ -195,42 +393,285 @@ impl FromStr for FsType {
 
     fn from_str(input: &str) -> Result<Self, Self::Err> {
         match input {
+            "btrfs" => Ok(Self::Btrfs),
             "ext4" => Ok(Self::Ext4),
+            "f2fs" => Ok(Self::F2fs),
             "fat32" => Ok(Self::Fat32),
             "zfs" => Ok(Self::Zfs),
             "lvm" => Ok(Self::Lvm),
+            "raid" => Ok(Self::Raid),
             "swap" => Ok(Self::Swap),
+            "xfs" => Ok(Self::Xfs),
             _ => generic_error!(&format!("Invalid enum value {}", input)),
         }
     }
 }
 
+impl FsType {
+    /// Get the maximal length of a label (volume groups are named `vg-<label>`
+    /// and limited to 127 characters)
+    pub fn max_label_len(&self) -> usize {
+        return match self {
+            FsType::Fat32 => 11,
+            FsType::Swap => 15,
+            FsType::Xfs => 12,
+            FsType::Ext4 => 16,
+            FsType::Lvm => 124,
+            FsType::Btrfs | FsType::F2fs | FsType::Zfs | FsType::Raid => 255,
+        };
+    }
+
+    /// Get the name NixOS needs in `boot.supportedFilesystems` (filesystems
+    /// whose tools and modules aren't available by default)
+    pub fn nixos_support(&self) -> Option<&'static str> {
+        return match self {
+            FsType::Btrfs => Some("btrfs"),
+            FsType::F2fs => Some("f2fs"),
+            FsType::Zfs => Some("zfs"),
+            _ => None,
+        };
+    }
+
+    /// Whether the filesystem can be grown at boot (NixOS `autoResize`)
+    pub fn can_auto_resize(&self) -> bool {
+        return matches!(self, FsType::Ext4 | FsType::F2fs);
+    }
+}
+
 // -----------------------------------------------------------------------------
 
+/// Check that a label can be used by a filesystem and to name devices (LUKS
+/// mappers, volume groups, pools)
+pub fn check_label(label: &str, fs_type: &str) -> error::Return {
+    let re = match Regex::new(r"^[A-Za-z][A-Za-z0-9_.+-]*$") {
+        Ok(r) => r,
+        Err(e) => return generic_error!(
+            &format!("Cannot build regex: {}", e)),
+    };
+
+    if !re.is_match(label) {
+        return generic_error!(&format!(
+            "Label `{}` must start with a letter and only contain letters, \
+            digits and `_.+-`",
+            label));
+    }
+
+    let max = FsType::from_str(fs_type)?.max_label_len();
+
+    if label.len() > max {
+        return generic_error!(&format!(
+            "Label `{}` is longer than {} characters ({})",
+            label,
+            max,
+            fs_type));
+    }
+
+    return Success!();
+}
+
+/// Check that the filesystem of a partition/volume can be grown at boot (a
+/// supported filesystem holding the root, the only one mounted by path)
+pub fn check_auto_resize(
+    label: &str,
+    fs_type: &str,
+    is_root: bool) -> error::Return {
+
+    if !FsType::from_str(fs_type)?.can_auto_resize() {
+        return generic_error!(&format!(
+            "`{}` cannot be resized at boot ({} is not supported, ext4 or \
+                f2fs only)",
+            label,
+            fs_type));
+    }
+
+    // Other filesystems are declared without mount point
+    if !is_root {
+        return generic_error!(&format!(
+            "`{}` cannot be resized at boot (only the root filesystem can)",
+            label));
+    }
+
+    return Success!();
+}
+
+/// Detect the partition table of a device (probed by blkid, which exits with
+/// code 2 when nothing is found)
+pub fn partition_table(device: &str) -> Result<PartitionTable, error::Error> {
+    let output = match utils::command_output(
+        "blkid",
+        &["-p", "-o", "value", "-s", "PTTYPE", device]) {
+
+        Ok(o) => o,
+        Err(e) if e.exit_code() == Some(2) => return Ok(PartitionTable::None),
+        Err(e) => return Err(e),
+    };
+
+    let pttype = utils::command_stdout_to_string(&output)?;
+
+    return Ok(PartitionTable::from(pttype.as_str()));
+}
+
 /// Wipeout a device
 pub fn wipeout(device: &str) -> error::Return {
+    // Only logged: an unknown size doesn't prevent the wipe (nothing is
+    // queried in dry-run)
+    let size = match utils::is_dry_run() {
+        true => None,
+        false => device_size(device).ok(),
+    };
+
     utils::command_output("sgdisk", &["-Z", device])?;
 
-    log::info!("`{}` has been wiped out", device);
+    match size {
+        Some(s) => log::info!(
+            "`{}` has been wiped out ({}, {} bytes)",
+            device,
+            Bytesize::from_bytes(s).to_human(),
+            s),
+
+        None => log::info!("`{}` has been wiped out", device),
+    }
+
+    return Success!();
+}
+
+/// Verify the GPT of a device and return the problems found
+pub fn verify(device: &str) -> Result<Vec<String>, error::Error> {
+    let output = utils::command_output("sgdisk", &["--verify", device])?;
+    let output = utils::command_stdout_to_string(&output)?;
+
+    let problems = output
+        .lines()
+        .filter(|l| l.starts_with("Problem:"))
+        .map(|l| l.trim_start_matches("Problem:").trim().to_string())
+        .collect();
+
+    return Ok(problems);
+}
+
+/// Move the GPT backup header to the end of the device
+pub fn fix_backup_header(device: &str) -> error::Return {
+    utils::command_output("sgdisk", &["-e", device])?;
+
+    log::info!("GPT backup header of `{}` moved to the end", device);
 
     return Success!();
 }
 
-/// Create a partition
+/// Existing partition of a GPT
+#[derive(Debug, PartialEq)]
+pub struct PartitionInfo {
+    /// Type GUID
+    pub type_guid: String,
+
+    /// Name (label)
+    pub name: String,
+
+    /// Size in bytes
+    pub size: u64,
+}
+
+/// Get an existing partition of a disk (from `sgdisk -i`), none if the
+/// partition doesn't exist
+pub fn partition_info(device: &str, number: u32)
+    -> Result<Option<PartitionInfo>, error::Error> {
+
+    let output = utils::command_output(
+        "sgdisk",
+        &["-i", &number.to_string(), device])?;
+    let output = utils::command_stdout_to_string(&output)?;
+
+    return Ok(parse_partition_info(&output, logical_sector_size(device)));
+}
+
+/// Parse the output of `sgdisk -i`
+fn parse_partition_info(
+    output: &str,
+    sector_size: u64) -> Option<PartitionInfo> {
+
+    let mut type_guid = None;
+    let mut name = None;
+    let mut size = None;
+
+    for line in output.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => continue,
+        };
+
+        match key {
+            "Partition GUID code" => {
+                type_guid = value.split_whitespace().next();
+            },
+
+            "Partition name" => {
+                name = Some(value.trim_matches('\''));
+            },
+
+            "Partition size" => {
+                size = value
+                    .split_whitespace()
+                    .next()
+                    .and_then(|s| s.parse::<u64>().ok());
+            },
+
+            _ => (),
+        }
+    }
+
+    return match (type_guid, name, size) {
+        (Some(t), Some(n), Some(s)) => Some(PartitionInfo {
+            type_guid: t.to_uppercase(),
+            name: n.to_string(),
+            size: s * sector_size,
+        }),
+        _ => None,
+    };
+}
+
+/// Get the numbers of the existing partitions of a disk (from `sgdisk -p`)
+pub fn partition_numbers(device: &str) -> Result<Vec<u32>, error::Error> {
+    let output = utils::command_output("sgdisk", &["-p", device])?;
+    let output = utils::command_stdout_to_string(&output)?;
+
+    // Partitions are listed below the `Number  Start (sector) ...` header
+    let numbers = output
+        .lines()
+        .skip_while(|l| !l.trim_start().starts_with("Number"))
+        .skip(1)
+        .filter_map(|l| l.split_whitespace().next())
+        .filter_map(|n| n.parse::<u32>().ok())
+        .collect();
+
+    return Ok(numbers);
+}
+
+/// Delete a partition
+pub fn delete_partition(device: &str, number: u32) -> error::Return {
+    utils::command_output("sgdisk", &["-d", &number.to_string(), device])?;
+
+    log::info!("Partition {} of `{}` has been deleted", number, device);
+
+    return Success!();
+}
+
+/// Create a partition (aligned on the given number of sectors)
 pub fn create_partition(
     device: &str,
     size: &Bytesize,
     partition_type: &PartitionType,
-    label: &str) -> error::Return {
+    label: &str,
+    alignment: u64) -> error::Return {
 
     // Create
     utils::command_output(
         "sgdisk",
         &[
+            "-a", &alignment.to_string(),
             "-n", &format!("0:0:{}", size.to_gpt_string()),
             "-t", &format!("0:{}", partition_type.to_gpt_string()),
             "-c", &format!("0:{}", label),
-            &device,
+            device,
         ])?;
 
     log::info!("Partition `{}` has been created", label);
@@ -244,15 +685,26 @@ pub fn create_partition(
 pub fn format_partition(
     device: &str,
     format: &str,
-    label: &str) -> error::Return {
+    label: &str,
+    options: &[String]) -> error::Return {
 
     let fs_type = FsType::from_str(format)?;
 
     match fs_type {
-        FsType::Fat32 => format_fat32(device, label)?,
-        FsType::Ext4 => format_ext4(device, label)?,
-        FsType::Zfs => format_zfs(device, label)?,
-        FsType::Swap => format_swap(device, label)?,
+        FsType::Btrfs => format_btrfs(device, label, options)?,
+        FsType::Fat32 => format_fat32(device, label, options)?,
+        FsType::Ext4 => format_ext4(device, label, options)?,
+        FsType::F2fs => format_f2fs(device, label, options)?,
+        // Pools are created with the options of their partition (ashift...)
+        FsType::Zfs => {
+            return generic_error!(&format!(
+                "`{}` cannot be a ZFS pool (partitions only)",
+                label));
+        },
+        FsType::Swap => format_swap(device, label, options)?,
+        FsType::Xfs => format_xfs(device, label, options)?,
+        // Members are formatted by mdadm when the array is created
+        FsType::Raid => return Success!(),
         _ => return generic_error!("Invalid partition format"),
     }
 
@@ -262,28 +714,84 @@ pub fn format_partition(
 }
 
 /// Format a partition in FAT32
-pub fn format_fat32(device: &str, label: &str) -> error::Return {
-    utils::command_output(
-        "mkfs.fat",
-        &[
-            "-F", "32",
-            "-n", label,
-            device,
-        ])?;
+pub fn format_fat32(
+    device: &str,
+    label: &str,
+    options: &[String]) -> error::Return {
+
+    let mut args = vec!["-F", "32", "-n", label];
+    args.extend(options.iter().map(|o| o.as_str()));
+    args.push(device);
+
+    utils::command_output("mkfs.fat", &args)?;
 
     log::info!("Partition `{}` has been formatted in fat32", label);
 
     return Success!();
 }
 
+/// Format a partition in BTRFS
+pub fn format_btrfs(
+    device: &str,
+    label: &str,
+    options: &[String]) -> error::Return {
+
+    let mut args = vec!["-L", label, "-f"];
+    args.extend(options.iter().map(|o| o.as_str()));
+    args.push(device);
+
+    utils::command_output("mkfs.btrfs", &args)?;
+
+    log::info!("Partition `{}` has been formatted in btrfs", label);
+
+    return Success!();
+}
+
+/// Format a partition in F2FS
+pub fn format_f2fs(
+    device: &str,
+    label: &str,
+    options: &[String]) -> error::Return {
+
+    let mut args = vec!["-l", label, "-f"];
+    args.extend(options.iter().map(|o| o.as_str()));
+    args.push(device);
+
+    utils::command_output("mkfs.f2fs", &args)?;
+
+    log::info!("Partition `{}` has been formatted in f2fs", label);
+
+    return Success!();
+}
+
+/// Format a partition in XFS
+pub fn format_xfs(
+    device: &str,
+    label: &str,
+    options: &[String]) -> error::Return {
+
+    let mut args = vec!["-L", label, "-f"];
+    args.extend(options.iter().map(|o| o.as_str()));
+    args.push(device);
+
+    utils::command_output("mkfs.xfs", &args)?;
+
+    log::info!("Partition `{}` has been formatted in xfs", label);
+
+    return Success!();
+}
+
 /// Format a partition in EXT4
-pub fn format_ext4(device: &str, label: &str) -> error::Return {
-    utils::command_output(
-        "mkfs.ext4",
-        &[
-            "-L", label,
-            device,
-        ])?;
+pub fn format_ext4(
+    device: &str,
+    label: &str,
+    options: &[String]) -> error::Return {
+
+    let mut args = vec!["-L", label];
+    args.extend(options.iter().map(|o| o.as_str()));
+    args.push(device);
+
+    utils::command_output("mkfs.ext4", &args)?;
 
     log::info!("Partition `{}` has been formatted in ext4", label);
 
@@ -291,8 +799,14 @@ pub fn format_ext4(device: &str, label: &str) -> error::Return {
 }
 
 /// Format a partition in ZFS
-pub fn format_zfs(device: &str, label: &str) -> error::Return {
-    zfs::pool_create(label, device)?;
+pub fn format_zfs(
+    device: &str,
+    label: &str,
+    ashift: u32,
+    compatibility: Option<&str>,
+    passphrase: Option<&str>) -> error::Return {
+
+    zfs::pool_create(label, device, ashift, compatibility, passphrase)?;
 
     log::info!("Partition `{}` has been added to zfs pool `{}`", device, label);
 
@@ -300,15 +814,291 @@ pub fn format_zfs(device: &str, label: &str) -> error::Return {
 }
 
 /// Format a swap partition
-pub fn format_swap(device: &str, label: &str) -> error::Return {
-    utils::command_output(
-        "mkswap",
-        &[
-            "-L", label,
-            device,
-        ])?;
+pub fn format_swap(
+    device: &str,
+    label: &str,
+    options: &[String]) -> error::Return {
+
+    let mut args = vec!["-L", label];
+    args.extend(options.iter().map(|o| o.as_str()));
+    args.push(device);
+
+    utils::command_output("mkswap", &args)?;
 
     log::info!("Partition `{}` has been formatted in swap", label);
 
     return Success!();
 }
+
+/// Get the sysfs directory of a block device
+fn sysfs_path(device: &str) -> Result<path::PathBuf, error::Error> {
+    // Mapper names are links to the kernel names
+    let kname = match path::Path::new(device).canonicalize() {
+        Ok(p) => match p.file_name() {
+            Some(n) => n.to_string_lossy().to_string(),
+            None => return generic_error!(
+                &format!("No kernel name for `{}`", device)),
+        },
+
+        Err(e) => return fs_error!(path::PathBuf::from(device), e),
+    };
+
+    return Ok(path::Path::new("/sys/class/block").join(kname));
+}
+
+/// Get the logical sector size of a disk (512 bytes if unknown)
+fn logical_sector_size(device: &str) -> u64 {
+    let file = match sysfs_path(device) {
+        Ok(p) => p.join("queue").join("logical_block_size"),
+        Err(_) => return 512,
+    };
+
+    return match fs::read_to_string(file) {
+        Ok(v) => v.trim().parse::<u64>().unwrap_or(512),
+        Err(_) => 512,
+    };
+}
+
+/// Check if a block device lies on a rotational disk (partitions use the
+/// queue of their disk), none if unknown
+pub fn is_rotational(device: &str) -> Option<bool> {
+    let sysfs = match sysfs_path(device) {
+        Ok(p) => p,
+        Err(_) => return None,
+    };
+
+    let files = [
+        sysfs.join("queue").join("rotational"),
+        sysfs.join("..").join("queue").join("rotational"),
+    ];
+
+    for file in files.iter() {
+        match fs::read_to_string(file) {
+            Ok(v) => return Some(v.trim() == "1"),
+            Err(_) => continue,
+        }
+    }
+
+    return None;
+}
+
+/// Get the region of a partition on its disk (offset and length in bytes),
+/// from sysfs (always in 512 bytes units)
+pub fn partition_region(device: &str) -> Result<(u64, u64), error::Error> {
+    let sysfs = sysfs_path(device)?;
+
+    let mut values = Vec::new();
+
+    for name in ["start", "size"] {
+        let file = sysfs.join(name);
+
+        let value = match fs::read_to_string(&file) {
+            Ok(v) => v,
+            Err(e) => return fs_error!(file, e),
+        };
+
+        match value.trim().parse::<u64>() {
+            Ok(v) => values.push(v * 512),
+            Err(_) => return generic_error!(
+                &format!("Invalid value in {:?}", file)),
+        }
+    }
+
+    return Ok((values[0], values[1]));
+}
+
+/// Get the size in bytes of a block device
+pub fn device_size(device: &str) -> Result<u64, error::Error> {
+    // Nothing has been created: sizes are only checked against the layout
+    if utils::is_dry_run() {
+        return Ok(u64::MAX);
+    }
+
+    let output = utils::command_output("blockdev", &["--getsize64", device])?;
+    let output = utils::command_stdout_to_string(&output)?;
+
+    return match output.trim().parse::<u64>() {
+        Ok(s) => Ok(s),
+        Err(_) => generic_error!(&format!("Cannot get size of `{}`", device)),
+    };
+}
+
+/// Grow the filesystem of a partition to the size of its device
+pub fn grow_filesystem(device: &str, format: &str) -> error::Return {
+    let fs_type = FsType::from_str(format)?;
+
+    match fs_type {
+        FsType::Ext4 => grow_ext4(device)?,
+        FsType::Xfs => grow_xfs(device)?,
+        _ => return generic_error!(
+            &format!("Cannot grow a `{}` filesystem", format)),
+    }
+
+    return Success!();
+}
+
+/// Grow an EXT4 filesystem to the size of its device
+pub fn grow_ext4(device: &str) -> error::Return {
+    // Get current size of the filesystem
+    let output = utils::command_output("dumpe2fs", &["-h", device])?;
+    let output = utils::command_stdout_to_string(&output)?;
+
+    let mut block_count: u64 = 0;
+    let mut block_size: u64 = 0;
+
+    for line in output.lines() {
+        let value = match line.split(':').nth(1) {
+            Some(v) => v.trim().parse::<u64>().unwrap_or(0),
+            None => continue,
+        };
+
+        if line.starts_with("Block count:") {
+            block_count = value;
+        } else if line.starts_with("Block size:") {
+            block_size = value;
+        }
+    }
+
+    // Never shrink the filesystem
+    if device_size(device)? < block_count * block_size {
+        return generic_error!(
+            &format!("Device `{}` is smaller than its filesystem", device));
+    }
+
+    utils::command_output("resize2fs", &[device])?;
+
+    log::info!("Filesystem on `{}` has been grown", device);
+
+    return Success!();
+}
+
+/// Grow a XFS filesystem to the size of its device (XFS is only grown once
+/// mounted, it's temporarily mounted)
+pub fn grow_xfs(device: &str) -> error::Return {
+    let temp_dir = match mktemp::Temp::new_dir() {
+        Ok(d) => d,
+        Err(e) => return io_error!("/tmp", e),
+    };
+
+    let mountpoint = match temp_dir.to_str() {
+        Some(m) => m.to_string(),
+        None => return generic_error!("No mountpoint"),
+    };
+
+    utils::mount(&["-t", "xfs", device, &mountpoint])?;
+
+    let result = utils::command_output("xfs_growfs", &[&mountpoint]);
+
+    utils::command_output("umount", &[&mountpoint])?;
+
+    result?;
+
+    log::info!("Filesystem on `{}` has been grown", device);
+
+    return Success!();
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::sync;
+
+    use super::*;
+
+    #[test]
+    fn xfs_is_grown_once_mounted() {
+        let runner = sync::Arc::new(utils::MockRunner::new(Vec::new()));
+
+        utils::set_runner(runner.clone());
+
+        grow_filesystem("/dev/vg-system/root", "xfs").unwrap();
+
+        let calls = runner.calls.lock().unwrap();
+        let names: Vec<&str> = calls.iter().map(|c| c.0.as_str()).collect();
+
+        assert_eq!(names, ["mount", "xfs_growfs", "umount"]);
+        assert_eq!(calls[1].1, calls[2].1);
+    }
+
+    #[test]
+    fn pools_are_not_created_without_their_ashift() {
+        let runner = sync::Arc::new(utils::MockRunner::new(Vec::new()));
+
+        utils::set_runner(runner.clone());
+
+        assert!(format_partition("/dev/vg-data/pool", "zfs", "pool", &[])
+            .is_err());
+        assert!(runner.calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn parses_existing_partitions() {
+        let output = "Partition GUID code: \
+            C12A7328-F81F-11D2-BA4B-00A0C93EC93B (EFI system partition)\n\
+            Partition unique GUID: 6F3C1E2A-0B7D-4C55-9B0E-2C1D5A8E7F10\n\
+            First sector: 2048 (at 1024.0 KiB)\n\
+            Last sector: 1050623 (at 513.0 MiB)\n\
+            Partition size: 1048576 sectors (512.0 MiB)\n\
+            Attribute flags: 0000000000000000\n\
+            Partition name: 'uefi'\n";
+
+        assert_eq!(parse_partition_info(output, 512), Some(PartitionInfo {
+            type_guid: PartitionType::Efi.to_gpt_guid().to_string(),
+            name: "uefi".to_string(),
+            size: 512 << 20,
+        }));
+
+        assert_eq!(
+            parse_partition_info("Partition #3 does not exist.\n", 512),
+            None);
+    }
+
+    #[test]
+    fn parses_fractional_and_iec_sizes() {
+        assert_eq!(Bytesize::from("1.5G").to_bytes(), 3 << 29);
+        assert_eq!(Bytesize::from("1.5GiB").to_bytes(), 3 << 29);
+        assert_eq!(Bytesize::from("512MiB").to_bytes(), 512 << 20);
+        assert_eq!(Bytesize::from("512M").to_bytes(), 512 << 20);
+        assert_eq!(Bytesize::from("100B").to_bytes(), 100);
+        assert!(Bytesize::from("1.5X").is_null());
+
+        assert_eq!(Bytesize::from("1.5G").to_gpt_string(), "+1536M");
+        assert_eq!(Bytesize::from("512MiB").to_gpt_string(), "+512M");
+        assert_eq!(Bytesize::from("1.3K").to_gpt_string(), "+1331B");
+        assert_eq!(Bytesize::from("0").to_gpt_string(), "0");
+
+        assert!(Bytesize::from("rest").is_null());
+        assert_eq!(Bytesize::from("rest").to_gpt_string(), "0");
+        assert_eq!(Bytesize::from("rest").to_string(), "rest");
+    }
+
+    #[test]
+    fn sizes_are_humanized() {
+        assert_eq!(Bytesize::from_bytes(500107862016).to_human(), "465.8G");
+        assert_eq!(Bytesize::from_bytes(512 << 20).to_human(), "512M");
+        assert_eq!(Bytesize::from_bytes(1000).to_human(), "1000B");
+        assert_eq!(Bytesize::from_bytes(3 << 29).to_string(), "1536M");
+    }
+
+    #[test]
+    fn fractional_size_round_trips() {
+        let json = serde_json::to_string(&Bytesize::from("1.5G")).unwrap();
+
+        assert_eq!(json, "\"1.5G\"");
+
+        let size: Bytesize = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(size.to_string(), "1.5G");
+        assert_eq!(size.to_bytes(), 3 << 29);
+    }
+
+    #[test]
+    fn only_non_gpt_tables_are_foreign() {
+        assert_eq!(PartitionTable::from("gpt\n"), PartitionTable::Gpt);
+        assert_eq!(PartitionTable::from(""), PartitionTable::None);
+        assert!(PartitionTable::from("dos").is_foreign());
+        assert!(PartitionTable::from("atari").is_foreign());
+        assert!(!PartitionTable::from("PMBR").is_foreign());
+    }
+}
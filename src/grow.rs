@@ -0,0 +1,316 @@
+// -----------------------------------------------------------------------------
+
+use clap;
+use std::str::FromStr;
+
+use super::env;
+use super::error;
+use super::filesystem;
+use super::gpt;
+use super::logger;
+use super::luks;
+use super::lvm;
+use super::partition;
+use super::traits::{CliCommand, Openable, Validate};
+use super::zfs;
+
+// -----------------------------------------------------------------------------
+
+const ARG_HOST: &str = "host";
+const ARG_LABEL: &str = "label";
+const ARG_PASSWORD: &str = "password";
+
+// -----------------------------------------------------------------------------
+
+/// Command structure for growing a filesystem after its partition grew
+#[derive(Debug)]
+pub struct Command {
+    /// Host name
+    host: String,
+
+    /// Label of the partition or logical volume to grow
+    label: String,
+
+    /// Password used to decrypt disks
+    password: String,
+}
+
+impl Validate for Command {
+    fn is_valid(&self) -> bool {
+        return
+            !self.host.is_empty() &&
+            !self.label.is_empty();
+    }
+}
+
+impl CliCommand for Command {
+    /// Get the name of the command
+    fn name(&self) -> &'static str {
+        return "grow";
+    }
+
+    /// Get command and its arguments
+    fn get<'a, 'b>(
+        &self,
+        version: &'b str,
+        author: &'b str) -> clap::App<'a, 'b> {
+
+        return clap::App::new(self.name())
+            .about("Grow a filesystem after its partition has been resized")
+            .version(version)
+            .author(author)
+            // Host argument
+            .arg(clap::Arg::with_name(ARG_HOST)
+                .long(ARG_HOST)
+                .help("Host name (optional if a .env file is present)")
+                .takes_value(true))
+            // Label argument
+            .arg(clap::Arg::with_name(ARG_LABEL)
+                .long(ARG_LABEL)
+                .help("Label of the partition or logical volume to grow")
+                .required(true)
+                .takes_value(true))
+            // Password argument
+            .arg(clap::Arg::with_name(ARG_PASSWORD)
+                .long(ARG_PASSWORD)
+                .help("Password used to decrypt filesystems")
+                .takes_value(true));
+    }
+
+    /// Process command line arguments
+    fn process(&mut self, matches: &clap::ArgMatches) -> error::Return {
+        // Parse arguments
+        for arg in matches.args.iter() {
+            match arg.0 {
+                &ARG_HOST => {
+                    self.host = match matches.value_of(arg.0) {
+                        Some(s) => s.to_owned(),
+                        None => return inval_error!(&ARG_HOST),
+                    };
+                },
+
+                &ARG_LABEL => {
+                    self.label = match matches.value_of(arg.0) {
+                        Some(s) => s.to_owned(),
+                        None => return inval_error!(&ARG_LABEL),
+                    };
+                },
+
+                &ARG_PASSWORD => {
+                    self.password = match matches.value_of(arg.0) {
+                        Some(s) => s.to_owned(),
+                        None => return inval_error!(&ARG_PASSWORD),
+                    };
+
+                    logger::add_secret(&self.password);
+                },
+
+                _ => {
+                    return inval_error!(arg.0);
+                }
+            }
+        }
+
+        if !self.is_valid() {
+            self.fill_with_env()?;
+        }
+
+        log::info!("{:#?}", self);
+
+        // Check validity
+        if !self.is_valid() {
+            return generic_error!("Invalid configuration");
+        }
+
+        // Create filesystem
+        let layout = filesystem::layout(&self.host)?;
+
+        let mut fs = filesystem::Filesystem::from_file(&layout)?;
+
+        // Open filesystem
+        fs.open(&self.password)?;
+
+        // Grow
+        let result = self.grow(&mut fs);
+
+        // Close filesystem (the grow error prevails)
+        match fs.close() {
+            Ok(_) => (),
+            Err(e) => log::error!("Cannot close the filesystem: {}", e),
+        }
+
+        return result;
+    }
+}
+
+impl Command {
+    /// Create an instance of Command
+    pub fn new() -> Self {
+        Self {
+            host: "".to_string(),
+            label: "".to_string(),
+            password: "".to_string(),
+        }
+    }
+
+    /// Use environment file to get needed values
+    fn fill_with_env(&mut self) -> error::Return {
+        env::read_for_host(&mut self.host)?;
+
+        return Success!();
+    }
+
+    /// Find the target and grow it
+    fn grow(&self, fs: &mut filesystem::Filesystem) -> error::Return {
+        for disk in fs.disks.iter() {
+            for partition in disk.partitions.iter() {
+                if partition.config.label == self.label {
+                    return self.grow_partition(partition);
+                }
+
+                for volume in partition.lvm.volumes.iter() {
+                    if volume.config.label == self.label {
+                        return self.grow_volume(partition, volume);
+                    }
+                }
+            }
+        }
+
+        return generic_error!(
+            &format!("No partition or volume labelled `{}`", self.label));
+    }
+
+    /// Grow the filesystem of a partition
+    fn grow_partition(
+        &self,
+        partition: &partition::Partition) -> error::Return {
+
+        let device = partition_device(partition)?;
+        let config = &partition.config;
+
+        if config.encrypted {
+            luks::resize(&config.label)?;
+        }
+
+        return match gpt::FsType::from_str(&config.fs_type)? {
+            gpt::FsType::Zfs => zfs::pool_expand(&config.label, &device),
+            _ => gpt::grow_filesystem(&device, &config.fs_type),
+        };
+    }
+
+    /// Grow a logical volume and its filesystem
+    fn grow_volume(
+        &self,
+        partition: &partition::Partition,
+        volume: &lvm::Volume) -> error::Return {
+
+        let device = match &volume.config.device {
+            Some(d) => d,
+            None => return generic_error!("No volume device"),
+        };
+
+        if partition.config.encrypted {
+            luks::resize(&partition.config.label)?;
+        }
+
+        partition.lvm.pv_resize(&partition_device(partition)?)?;
+
+        volume.extend()?;
+
+        return gpt::grow_filesystem(device, &volume.config.fs_type);
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Get the device holding the data of a partition
+fn partition_device(
+    partition: &partition::Partition) -> Result<String, error::Error> {
+
+    let device = match partition.config.encrypted {
+        true => &partition.config.luks_mapper,
+        false => &partition.config.device_by_id,
+    };
+
+    return match device {
+        Some(d) => Ok(d.clone()),
+        None => generic_error!("No device for partition"),
+    };
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::sync;
+
+    use super::*;
+    use super::super::traits::Configurable;
+    use super::super::utils;
+
+    #[test]
+    fn encrypted_volumes_grow_from_luks_to_filesystem() {
+        let config: partition::Config = serde_json::from_value(
+            serde_json::json!({
+                "id": 2,
+                "size": "rest",
+                "partition_type": "linux",
+                "encrypted": true,
+                "fs_type": "lvm",
+                "label": "system",
+                "luks_mapper": "/dev/mapper/system",
+                "lvm": [{
+                    "id": 1,
+                    "size": "rest",
+                    "volume_type": "linux",
+                    "fs_type": "ext4",
+                    "label": "root",
+                    "device": "/dev/vg-system/root",
+                }],
+            })).unwrap();
+
+        let partition = partition::Partition::from_config(&config);
+
+        let runner = sync::Arc::new(utils::MockRunner::new(vec![
+            // cryptsetup status
+            utils::MockRunner::output(0, "/dev/mapper/system is active.\n"),
+            // cryptsetup resize
+            utils::MockRunner::output(0, ""),
+            // pvresize
+            utils::MockRunner::output(0, ""),
+            // lvextend
+            utils::MockRunner::output(0, ""),
+            // dumpe2fs
+            utils::MockRunner::output(
+                0,
+                "Block count:              262144\n\
+                Block size:               4096\n"),
+            // blockdev
+            utils::MockRunner::output(0, "2147483648\n"),
+        ]));
+
+        utils::set_runner(runner.clone());
+
+        Command::new()
+            .grow_volume(&partition, &partition.lvm.volumes[0])
+            .unwrap();
+
+        let calls = runner.calls.lock().unwrap();
+        let calls: Vec<(&str, Vec<&str>)> = calls
+            .iter()
+            .map(|c| (
+                c.0.as_str(),
+                c.1.iter().map(|a| a.as_str()).collect()))
+            .collect();
+
+        assert_eq!(calls, [
+            ("cryptsetup", vec!["status", "/dev/mapper/system"]),
+            ("cryptsetup", vec!["resize", "system"]),
+            ("pvresize", vec!["/dev/mapper/system"]),
+            ("lvextend", vec!["-l", "+100%FREE", "/dev/vg-system/root"]),
+            ("dumpe2fs", vec!["-h", "/dev/vg-system/root"]),
+            ("blockdev", vec!["--getsize64", "/dev/vg-system/root"]),
+            ("resize2fs", vec!["/dev/vg-system/root"]),
+        ]);
+    }
+}
@@ -1,50 +1,67 @@
+// -----------------------------------------------------------------------------
+
 use clap;
 use std::fs;
 use std::io::Write;
 use std::path;
 use std::process;
-use std::thread;
-use std::time;
 
 use super::env;
 use super::filesystem;
 use super::error;
-use super::lvm;
-use super::partition;
-use super::traits::{CliCommand, Validate};
+use super::logger;
+use super::traits::{CliCommand, Openable, Validate};
+use super::utils;
+
+// -----------------------------------------------------------------------------
 
 const ARG_HOST: &str = "host";
 const ARG_PASSWORD: &str = "password";
 
+/// Name of the generated initramfs (in `/boot` of the root filesystem)
+const INITRAMFS_NAME: &str = "initrd.keys.gz";
+
+// -----------------------------------------------------------------------------
+
 /// Command structure for creating initramfs on generated filesystem
 #[derive(Debug)]
 pub struct Command {
+    /// Host name
     host: String,
+
+    /// Password used to decrypt disks
     password: String,
+
+    /// Key file to embed
     key_file: String,
+
+    /// File name of the key (path of the key in the initramfs)
     key_filename: String,
 }
 
 impl Validate for Command {
     fn is_valid(&self) -> bool {
-        return !self.host.is_empty() &&
+        return
+            !self.host.is_empty() &&
             !self.key_file.is_empty() &&
             !self.key_filename.is_empty();
     }
 }
 
 impl CliCommand for Command {
+    /// Get the name of the command
     fn name(&self) -> &'static str {
         return "initramfs";
     }
 
+    /// Get command and its arguments
     fn get<'a, 'b>(
         &self,
         version: &'b str,
         author: &'b str) -> clap::App<'a, 'b> {
 
         return clap::App::new(self.name())
-            .about("Create initramfs")
+            .about("Create an initramfs embedding the key file")
             .version(version)
             .author(author)
             // Host argument
@@ -56,9 +73,11 @@ impl CliCommand for Command {
             .arg(clap::Arg::with_name(ARG_PASSWORD)
                 .long(ARG_PASSWORD)
                 .help("Password used to decrypt filesystems")
+                .required(true)
                 .takes_value(true));
     }
 
+    /// Process command line arguments
     fn process(&mut self, matches: &clap::ArgMatches) -> error::Return {
         // Parse arguments
         for arg in matches.args.iter() {
@@ -75,6 +94,8 @@ impl CliCommand for Command {
                         Some(s) => s.to_owned(),
                         None => return inval_error!(&ARG_PASSWORD),
                     };
+
+                    logger::add_secret(&self.password);
                 },
 
                 _ => {
@@ -89,9 +110,12 @@ impl CliCommand for Command {
 
         log::info!("{:#?}", self);
 
-        if !self.is_valid() {
-            return generic_error!("Invalid configuration");
-        }
+        // Check validity
+        env::check_values(&[
+            (ARG_HOST, &self.host),
+            ("key_file", &self.key_file),
+            ("key_filename", &self.key_filename),
+        ])?;
 
         // Create root
         let root = path::Path::new("/").join("mnt").join("root");
@@ -101,43 +125,34 @@ impl CliCommand for Command {
             Err(e) => return io_error!("Error creating directory", e),
         }
 
-        // Create efi path
-        let efi = root.join("boot").join("efi");
-
-        // Create initramfs path
-        let initramfs = root.join("boot").join("initrd.keys.gz");
-
         // Create filesystem
-        let current_dir = match std::env::current_dir() {
-            Ok(d) => d,
-            Err(e) => return io_error!("env::current_dir()", e)
-        };
+        let layout = filesystem::layout(&self.host)?;
 
-        let path = current_dir
-            .join("layouts")
-            .join(format!("{}.json", self.host));
-
-        let mut fs = filesystem::Filesystem::from_json(&path)?;
+        let mut fs = filesystem::Filesystem::from_file(&layout)?;
 
         // Open filesystem
         fs.open(&self.password)?;
 
-        thread::sleep(time::Duration::from_secs(1));
+        // Generate initramfs (close what has been opened on failure)
+        match self.generate_initramfs(&root, &mut fs) {
+            Ok(_) => (),
+            Err(e) => {
+                log::warn!("Initramfs generation failed, closing devices");
 
-        // Create EFI directory
-        match fs::create_dir_all(&efi) {
-            Ok(_) => log::info!("`{:?}` created", &efi),
-            Err(e) => return io_error!("Error creating directory", e),
-        }
+                match fs.close() {
+                    Ok(_) => (),
+                    Err(c) => log::error!("{}", c),
+                }
 
-        // Generate initramfs
-        self.generate_initramfs(&root, initramfs, &mut fs)?;
+                return Err(e);
+            },
+        }
 
         // Close filesystem
         fs.close()?;
 
         return Success!();
-     }
+    }
 }
 
 impl Command {
@@ -153,90 +168,78 @@ impl Command {
 
     /// Use environment file to get needed values
     fn fill_with_env(&mut self) -> error::Return {
-        let config = env::read()?;
+        let config = match env::read_for_host(&mut self.host)? {
+            Some(c) => c,
+            None => return Success!(),
+        };
 
-        self.host = config.nixos.host;
         self.key_file = config.nixos.key_file;
         self.key_filename = config.nixos.key_filename;
 
         return Success!();
     }
 
+    /// Generate the initramfs in `/boot` of the root filesystem (fails if
+    /// there's no system disk or root partition)
     fn generate_initramfs(
         &self,
         root: &path::PathBuf,
-        initramfs: path::PathBuf,
         fs: &mut filesystem::Filesystem) -> error::Return {
 
-        for disk in fs.disks.iter_mut() {
-            if !disk.config.contains_system {
-                continue;
-            }
+        let root_fs = fs.find_root()?;
 
-            for partition in disk.partitions.iter_mut() {
-                if partition.config.is_root {
-                    return self.generate_initramfs_in_partition(
-                        root,
-                        initramfs,
-                        partition);
-                }
+        root_fs.mount(root)?;
 
-                if !partition.config.is_system {
-                    continue;
-                }
+        let result = self.generate_initramfs_to(root);
 
-                for volume in partition.lvm.volumes.iter_mut() {
-                    if volume.config.is_root {
-                        return self.generate_initramfs_in_volume(
-                            root,
-                            initramfs,
-                            volume);
-                    }
-                }
-            }
-        }
+        root_fs.unmount()?;
 
-        return Success!();
+        return result;
     }
 
-    fn generate_initramfs_in_partition(
-        &self,
-        root: &path::PathBuf,
-        initramfs: path::PathBuf,
-        partition: &mut partition::Partition) -> error::Return {
-
-        partition.mount(root)?;
-
-        self.generate_initramfs_to(initramfs)?;
-
-        partition.unmount()?;
+    /// Archive the key file (at the root of the archive) in a compressed cpio
+    /// written to `/boot` of the mounted root
+    fn generate_initramfs_to(&self, root: &path::PathBuf) -> error::Return {
+        let boot = root.join("boot");
+        let output = boot.join(INITRAMFS_NAME);
 
-        return Success!();
-    }
-
-    fn generate_initramfs_in_volume(
-        &self,
-        root: &path::PathBuf,
-        initramfs: path::PathBuf,
-        volume: &mut lvm::Volume) -> error::Return {
+        if utils::is_dry_run() {
+            log::info!("[dry-run] write {:?}", output);
+            return Success!();
+        }
 
-        volume.mount(root)?;
+        match fs::create_dir_all(&boot) {
+            Ok(_) => (),
+            Err(e) => return io_error!("Error creating directory", e),
+        }
 
-        self.generate_initramfs_to(initramfs)?;
+        // The key is archived from its directory to be named after its file
+        // name in the initramfs
+        let key_dir = match path::Path::new(&self.key_file).parent() {
+            Some(d) => d.to_path_buf(),
+            None => return generic_error!("No directory for the key file"),
+        };
 
-        volume.unmount()?;
+        let key_name = match path::Path::new(&self.key_file).file_name() {
+            Some(n) => n.to_string_lossy().to_string(),
+            None => return generic_error!("No name for the key file"),
+        };
 
-        return Success!();
-    }
+        if key_name != self.key_filename {
+            return generic_error!(&format!(
+                "Key file `{}` is not named `{}`",
+                self.key_file,
+                self.key_filename));
+        }
 
-    fn generate_initramfs_to(&self, output: path::PathBuf) -> error::Return {
-        // Cpio
+        // Cpio (file names are NUL terminated)
         let mut cpio = match process::Command::new("cpio")
             .arg("-o")
             .arg("-H").arg("newc")
             .arg("-R").arg("+0:+0")
             .arg("--reproducible")
             .arg("--null")
+            .current_dir(&key_dir)
             .stdin(process::Stdio::piped())
             .stdout(process::Stdio::piped())
             .stderr(process::Stdio::piped())
@@ -250,7 +253,7 @@ impl Command {
             None => return generic_error!("Cannot obtain access to stdin"),
         };
 
-        match cpio_stdin.write_all(self.key_file.as_bytes()) {
+        match cpio_stdin.write_all(format!("{}\0", key_name).as_bytes()) {
             Ok(_) => (),
             Err(_) => return generic_error!("Cannot write key_file to stdin"),
         }
@@ -263,7 +266,7 @@ impl Command {
         };
 
         if !cpio_output.status.success() {
-            return generic_error!("cpio command returned an error");
+            return Err(utils::command_error("cpio", &cpio_output));
         }
 
         // Gzip
@@ -271,6 +274,7 @@ impl Command {
             .arg("-9")
             .stdin(process::Stdio::piped())
             .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
             .spawn() {
                 Ok(p) => p,
                 Err(e) => return cmd_error!("gzip", e),
@@ -283,7 +287,7 @@ impl Command {
 
         match gzip_stdin.write_all(&cpio_output.stdout) {
             Ok(_) => (),
-            Err(_) => return generic_error!("Cannot write key_file to stdin"),
+            Err(_) => return generic_error!("Cannot write archive to stdin"),
         }
 
         drop(gzip_stdin);
@@ -294,19 +298,13 @@ impl Command {
         };
 
         if !gzip_output.status.success() {
-            return generic_error!("gzip command returned an error");
+            return Err(utils::command_error("gzip", &gzip_output));
         }
 
         // Write to file
-        let mut file = match fs::File::create(&output) {
-            Ok(f) => f,
-            Err(e) => return fs_error!(output, e),
-        };
+        utils::write_to_file(&gzip_output.stdout, &output)?;
 
-        match file.write_all(&gzip_output.stdout) {
-            Ok(_) => log::info!("initrd written to {:?}", output),
-            Err(e) => return fs_error!(output, e),
-        }
+        log::info!("initrd written to {:?}", output);
 
         return Success!();
     }
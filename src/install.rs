@@ -1,23 +1,52 @@
 // -----------------------------------------------------------------------------
 
 use clap;
+use regex::Regex;
 use std::fs;
 use std::os::unix;
 use std::path;
-use std::thread;
-use std::time;
 
 use super::env;
 use super::filesystem;
 use super::error;
-use super::traits::{CliCommand, Openable, Validate};
+use super::gpt;
+use super::logger;
+use super::report;
+use super::traits::{CliCommand, Mountable, Openable, Validate};
 use super::utils;
 
 // -----------------------------------------------------------------------------
 
+const ARG_CLOSURE: &str = "closure";
+const ARG_CORES: &str = "cores";
+const ARG_EXTRA_SUBSTITUTERS: &str = "extra-substituters";
+const ARG_FORCE_UNMOUNT: &str = "force-unmount";
 const ARG_HOST: &str = "host";
+const ARG_KEEP_GOING: &str = "keep-going";
+const ARG_LEAVE_MOUNTED: &str = "leave-mounted";
+const ARG_MAX_JOBS: &str = "max-jobs";
+const ARG_MIN_FREE_SPACE: &str = "min-free-space";
+const ARG_NIXOS_INSTALL_ARG: &str = "nixos-install-arg";
+const ARG_NO_CHANNEL_COPY: &str = "no-channel-copy";
 const ARG_PASSWORD: &str = "password";
 const ARG_REPO: &str = "repository";
+const ARG_SSH_AUTHORIZED_KEY: &str = "ssh-authorized-key";
+const ARG_SUBSTITUTERS: &str = "substituters";
+const ARG_USER: &str = "user";
+
+/// Types of SSH public keys
+const SSH_KEY_TYPES: &[&str] = &[
+    "ssh-ed25519",
+    "ssh-rsa",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+/// Minimal free space on the root filesystem before installing
+const DEFAULT_MIN_FREE_SPACE: &str = "8G";
 
 // -----------------------------------------------------------------------------
 
@@ -35,6 +64,36 @@ pub struct Command {
 
     /// Key file to install
     key_file: String,
+
+    /// Additional arguments given to `nixos-install`
+    nixos_install_args: Vec<String>,
+
+    /// Pre-built system closure to install (instead of building it)
+    closure: Option<String>,
+
+    /// Number of cores used by each build job (`auto` for all)
+    cores: Option<String>,
+
+    /// Number of build jobs run in parallel (`auto` for one per CPU)
+    max_jobs: Option<String>,
+
+    /// Whether the other builds continue when one fails
+    keep_going: bool,
+
+    /// Free space required on the root filesystem (`0` to skip the check)
+    min_free_space: gpt::Bytesize,
+
+    /// Whether the filesystem is left mounted (and opened) after install
+    leave_mounted: bool,
+
+    /// Whether existing mounts under the target are unmounted first
+    force_unmount: bool,
+
+    /// SSH public keys authorized on the installed system
+    ssh_authorized_keys: Vec<String>,
+
+    /// User owning the SSH keys (root if empty)
+    user: String,
 }
 
 impl Validate for Command {
@@ -62,21 +121,94 @@ impl CliCommand for Command {
             .about("Install NixOS")
             .version(version)
             .author(author)
+            // Closure argument
+            .arg(clap::Arg::with_name(ARG_CLOSURE)
+                .long(ARG_CLOSURE)
+                .help("Pre-built system closure to copy and install (offline)")
+                .takes_value(true))
+            // Cores argument
+            .arg(clap::Arg::with_name(ARG_CORES)
+                .long(ARG_CORES)
+                .help("Number of cores used by each build job (or auto)")
+                .takes_value(true))
+            // Extra substituters argument
+            .arg(clap::Arg::with_name(ARG_EXTRA_SUBSTITUTERS)
+                .long(ARG_EXTRA_SUBSTITUTERS)
+                .help("Extra binary caches used by `nixos-install`")
+                .takes_value(true))
+            // Force unmount argument
+            .arg(clap::Arg::with_name(ARG_FORCE_UNMOUNT)
+                .long(ARG_FORCE_UNMOUNT)
+                .help("Unmount what is already mounted under /mnt/root \
+                    instead of refusing to install"))
             // Host argument
             .arg(clap::Arg::with_name(ARG_HOST)
                 .long(ARG_HOST)
                 .help("Host name (optional if a .env file is present)")
                 .takes_value(true))
+            // Keep going argument
+            .arg(clap::Arg::with_name(ARG_KEEP_GOING)
+                .long(ARG_KEEP_GOING)
+                .help("Keep building the other derivations when one fails"))
+            // Leave mounted argument
+            .arg(clap::Arg::with_name(ARG_LEAVE_MOUNTED)
+                .long(ARG_LEAVE_MOUNTED)
+                .help("Leave the installed system mounted in /mnt/root (and \
+                    its devices opened) for inspection"))
+            // Max jobs argument
+            .arg(clap::Arg::with_name(ARG_MAX_JOBS)
+                .long(ARG_MAX_JOBS)
+                .help("Number of build jobs run in parallel (or auto)")
+                .takes_value(true))
+            // Min free space argument
+            .arg(clap::Arg::with_name(ARG_MIN_FREE_SPACE)
+                .long(ARG_MIN_FREE_SPACE)
+                .help("Free space required on the root filesystem, or the \
+                    size of the closure if larger (default: 8G, 0 to skip)")
+                .takes_value(true))
+            // NixOS install argument
+            .arg(clap::Arg::with_name(ARG_NIXOS_INSTALL_ARG)
+                .long(ARG_NIXOS_INSTALL_ARG)
+                .help("Argument given as is to `nixos-install`")
+                .multiple(true)
+                .number_of_values(1)
+                .allow_hyphen_values(true)
+                .takes_value(true))
+            // No channel copy argument
+            .arg(clap::Arg::with_name(ARG_NO_CHANNEL_COPY)
+                .long(ARG_NO_CHANNEL_COPY)
+                .help("Don't copy the NixOS channel to the target"))
             // Password argument
             .arg(clap::Arg::with_name(ARG_PASSWORD)
                 .long(ARG_PASSWORD)
                 .help("Password used to decrypt filesystems")
                 .takes_value(true))
+            // Password age and sops arguments
+            .args(&utils::password_file_args(ARG_PASSWORD))
             // Repo argument
             .arg(clap::Arg::with_name(ARG_REPO)
                 .long(ARG_REPO)
                 .help("Path to the NixOS configuration directory or repository")
                 .required(true)
+                .takes_value(true))
+            // SSH authorized key argument
+            .arg(clap::Arg::with_name(ARG_SSH_AUTHORIZED_KEY)
+                .long(ARG_SSH_AUTHORIZED_KEY)
+                .help("SSH public key (or file of keys) authorized on the \
+                    installed system")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true))
+            // Substituters argument
+            .arg(clap::Arg::with_name(ARG_SUBSTITUTERS)
+                .long(ARG_SUBSTITUTERS)
+                .help("Binary caches used by `nixos-install`")
+                .takes_value(true))
+            // User argument
+            .arg(clap::Arg::with_name(ARG_USER)
+                .long(ARG_USER)
+                .help("User owning the SSH authorized keys (default: root)")
+                .requires(ARG_SSH_AUTHORIZED_KEY)
                 .takes_value(true));
     }
 
@@ -97,6 +229,12 @@ impl CliCommand for Command {
                         Some(s) => s.to_owned(),
                         None => return inval_error!(&ARG_PASSWORD),
                     };
+
+                    logger::add_secret(&self.password);
+                },
+
+                &utils::ARG_PASSWORD_AGE | &utils::ARG_PASSWORD_SOPS => {
+                    self.password = utils::decrypt_password(arg.0, matches)?;
                 },
 
                 &ARG_REPO => {
@@ -106,6 +244,115 @@ impl CliCommand for Command {
                     };
                 },
 
+                &ARG_NIXOS_INSTALL_ARG => {
+                    let values = match matches.values_of(arg.0) {
+                        Some(v) => v,
+                        None => return inval_error!(&ARG_NIXOS_INSTALL_ARG),
+                    };
+
+                    for value in values {
+                        if value.is_empty() {
+                            return inval_error!(&ARG_NIXOS_INSTALL_ARG);
+                        }
+
+                        self.nixos_install_args.push(value.to_owned());
+                    }
+                },
+
+                &ARG_CLOSURE => {
+                    let closure = match matches.value_of(arg.0) {
+                        Some(s) => s.to_owned(),
+                        None => return inval_error!(&ARG_CLOSURE),
+                    };
+
+                    if !path::Path::new(&closure).exists() {
+                        return generic_error!(&format!(
+                            "Closure `{}` doesn't exist",
+                            closure));
+                    }
+
+                    self.closure = Some(closure);
+                },
+
+                &ARG_CORES | &ARG_MAX_JOBS => {
+                    let value = match matches.value_of(arg.0) {
+                        Some(s) => s,
+                        None => return inval_error!(arg.0),
+                    };
+
+                    check_jobs(arg.0, value)?;
+
+                    match arg.0 {
+                        &ARG_CORES => self.cores = Some(value.to_owned()),
+                        _ => self.max_jobs = Some(value.to_owned()),
+                    }
+                },
+
+                &ARG_FORCE_UNMOUNT => {
+                    self.force_unmount = true;
+                },
+
+                &ARG_KEEP_GOING => {
+                    self.keep_going = true;
+                },
+
+                &ARG_LEAVE_MOUNTED => {
+                    self.leave_mounted = true;
+                },
+
+                &ARG_MIN_FREE_SPACE => {
+                    let value = match matches.value_of(arg.0) {
+                        Some(s) => s,
+                        None => return inval_error!(&ARG_MIN_FREE_SPACE),
+                    };
+
+                    self.min_free_space = gpt::Bytesize::from(value);
+
+                    // Invalid sizes are parsed as `0`
+                    if self.min_free_space.is_null() && value != "0" {
+                        return inval_error!(&ARG_MIN_FREE_SPACE);
+                    }
+                },
+
+                &ARG_NO_CHANNEL_COPY => {
+                    self.nixos_install_args.push(
+                        format!("--{}", ARG_NO_CHANNEL_COPY));
+                },
+
+                &ARG_SSH_AUTHORIZED_KEY => {
+                    let values = match matches.values_of(arg.0) {
+                        Some(v) => v,
+                        None => return inval_error!(&ARG_SSH_AUTHORIZED_KEY),
+                    };
+
+                    for value in values {
+                        self.ssh_authorized_keys
+                            .extend(read_ssh_keys(value)?);
+                    }
+                },
+
+                &ARG_USER => {
+                    self.user = match matches.value_of(arg.0) {
+                        Some(s) => s.to_owned(),
+                        None => return inval_error!(&ARG_USER),
+                    };
+
+                    utils::check_name(&self.user)?;
+                },
+
+                &ARG_SUBSTITUTERS | &ARG_EXTRA_SUBSTITUTERS => {
+                    let value = match matches.value_of(arg.0) {
+                        Some(s) if !s.is_empty() => s.to_owned(),
+                        _ => return inval_error!(arg.0),
+                    };
+
+                    utils::require_network(&format!("--{}", arg.0))?;
+
+                    self.nixos_install_args.push("--option".to_string());
+                    self.nixos_install_args.push(arg.0.to_string());
+                    self.nixos_install_args.push(value);
+                },
+
                 _ => {
                     return inval_error!(arg.0);
                 }
@@ -119,27 +366,58 @@ impl CliCommand for Command {
         log::info!("{:#?}", self);
 
         // Check validity
-        if !self.is_valid() {
-            return generic_error!("Invalid configuration");
+        env::check_values(&[
+            (ARG_HOST, &self.host),
+            (ARG_REPO, &self.repo),
+            ("key_file", &self.key_file),
+        ])?;
+
+        // An offline install needs a local repository
+        if self.repo.contains("://") || self.repo.starts_with("git@") {
+            utils::require_network(&format!("Repository `{}`", self.repo))?;
         }
 
+        // Refuse to install over a previous (interrupted) run
+        utils::check_unmounted(
+            &path::Path::new("/").join("mnt").join("root"),
+            self.force_unmount)?;
+
+        report::begin();
+
         // Create filesystem
-        let json = utils::current_dir()?
-            .join("layouts")
-            .join(format!("{}.json", self.host));
+        let layout = filesystem::layout(&self.host)?;
 
-        let mut fs = filesystem::Filesystem::from_json(&json)?;
+        let mut fs = filesystem::Filesystem::from_file(&layout)?;
 
         // Open filesystem
-        fs.open(&self.password)?;
+        report::phase("open", || fs.open(&self.password))?;
 
-        thread::sleep(time::Duration::from_secs(1));
+        // Install NixOS (close what has been opened on failure)
+        match report::phase(
+            "install",
+            || self.install_nixos(&self.host, &self.repo, &mut fs)) {
 
-        // Install NixOS
-        self.install_nixos(&self.host, &self.repo, &mut fs)?;
+            Ok(_) => (),
+            Err(e) => {
+                log::warn!("Installation failed, closing devices");
+
+                match fs.close() {
+                    Ok(_) => (),
+                    Err(c) => log::error!("{}", c),
+                }
+
+                return Err(e);
+            },
+        }
 
         // Close filesystem
-        fs.close()?;
+        match self.leave_mounted {
+            true => report::warning(
+                "Filesystem left mounted, devices are still opened"),
+            false => report::phase("close", || fs.close())?,
+        }
+
+        report::summary();
 
         return Success!();
     }
@@ -153,21 +431,33 @@ impl Command {
             password: "".to_string(),
             key_file: "".to_string(),
             repo: "".to_string(),
+            nixos_install_args: Vec::new(),
+            closure: None,
+            cores: None,
+            max_jobs: None,
+            keep_going: false,
+            min_free_space: gpt::Bytesize::from(DEFAULT_MIN_FREE_SPACE),
+            leave_mounted: false,
+            force_unmount: false,
+            ssh_authorized_keys: Vec::new(),
+            user: "".to_string(),
         }
     }
 
     /// Use environment file to get needed values
     fn fill_with_env(&mut self) -> error::Return {
-        let config = env::read()?;
+        let config = match env::read_for_host(&mut self.host)? {
+            Some(c) => c,
+            None => return Success!(),
+        };
 
-        self.host = config.nixos.host;
         self.key_file = config.nixos.key_file;
 
         return Success!();
     }
 
     /// Install NixOS
-    fn install_nixos(
+    pub fn install_nixos(
         &self,
         host: &str,
         repo: &str,
@@ -175,7 +465,6 @@ impl Command {
 
         // Create paths
         let root = path::Path::new("/").join("mnt").join("root");
-        let efi = root.join("boot").join("efi");
         let etc = root.join("etc");
 
         match fs::create_dir_all(&root) {
@@ -184,30 +473,121 @@ impl Command {
         }
 
         // Root partition
-        fs.find_system_disk()?.find_root_partition()?.mount(&root)?;
+        fs.find_root()?.mount(&root)?;
 
         match fs::create_dir_all(&etc) {
             Ok(_) => log::info!("`{:?}` created", etc),
             Err(e) => return io_error!("Error creating directory", e),
         }
 
-        // EFI partition
-        match fs::create_dir_all(&efi) {
-            Ok(_) => log::info!("`{:?}` created", efi),
-            Err(e) => return io_error!("Error creating directory", e),
+        // EFI partitions (the system one and the mirrors)
+        let efi_partitions = fs.find_efi_partitions()?;
+
+        if efi_partitions.is_empty() {
+            return generic_error!("EFI partition not found");
         }
 
-        fs.find_system_disk()?.find_efi_partition()?.mount(&efi)?;
+        for (mountpoint, p) in efi_partitions {
+            let efi = root.join(mountpoint.trim_start_matches('/'));
+
+            match fs::create_dir_all(&efi) {
+                Ok(_) => log::info!("`{:?}` created", efi),
+                Err(e) => return io_error!("Error creating directory", e),
+            }
+
+            p.mount(&efi)?;
+        }
 
         // Install NixOS configuration
         self.install_nixos_repository(host, repo, &etc)?;
+        self.install_ssh_keys(host, &etc)?;
 
-        // Run installer
+        // Run installer (fail early if the store cannot fit)
+        self.check_free_space(&root)?;
         self.run_nixos_installer(&root)?;
 
+        // Leave everything mounted for inspection
+        if self.leave_mounted {
+            return self.report_left_mounted(&root, fs);
+        }
+
         // Unmount partitions
-        fs.find_system_disk()?.find_efi_partition()?.unmount()?;
-        fs.find_system_disk()?.find_root_partition()?.unmount()?;
+        for (_, p) in fs.find_efi_partitions()? {
+            p.unmount()?;
+        }
+
+        fs.find_root()?.unmount()?;
+
+        return Success!();
+    }
+
+    /// Log what has been left mounted and opened, and how to clean up
+    fn report_left_mounted(
+        &self,
+        root: &path::PathBuf,
+        fs: &mut filesystem::Filesystem) -> error::Return {
+
+        let mut mounts = vec![root.clone()];
+
+        for (mountpoint, _) in fs.find_efi_partitions()? {
+            mounts.push(root.join(mountpoint.trim_start_matches('/')));
+        }
+
+        log::info!("Left mounted:");
+
+        for mount in mounts.iter() {
+            log::info!("  {:?}", mount);
+        }
+
+        // Devices to close once done (in this order)
+        let mut cleanup = vec![format!("umount -R {}", root.display())];
+
+        for array in fs.raids.iter() {
+            let device = array.config.device();
+
+            report::warning(
+                &format!("RAID array `{}` is still assembled", device));
+
+            if array.config.encrypted {
+                cleanup.push(
+                    format!("cryptsetup close {}", array.config.label));
+            }
+
+            cleanup.push(format!("mdadm --stop {}", device));
+        }
+
+        for disk in fs.disks.iter() {
+            for p in disk.partitions.iter() {
+                let label = &p.config.label;
+
+                match p.config.fs_type.as_str() {
+                    "zfs" => {
+                        report::warning(
+                            &format!("ZFS pool `{}` is still imported", label));
+
+                        cleanup.push(format!("zpool export {}", label));
+                    },
+
+                    "lvm" => cleanup.push(format!("vgchange -an vg-{}", label)),
+
+                    _ => (),
+                }
+
+                if p.config.encrypted {
+                    report::warning(
+                        &format!("LUKS device `{}` is still opened", label));
+
+                    cleanup.push(format!("cryptsetup close {}", label));
+                }
+            }
+        }
+
+        log::info!("Inspect with `nixos-enter --root {}`", root.display());
+        log::info!("Clean up with:");
+
+        for command in cleanup.iter() {
+            log::info!("  {}", command);
+        }
 
         return Success!();
     }
@@ -228,11 +608,15 @@ impl Command {
 
         // Check if it's a repository to clone
         if repo.starts_with("https://github.com") {
+            utils::require_network(&format!("Cloning `{}`", repo))?;
+
             let local_repo = "/tmp/repo-nixos";
 
             log::info!("Cloning {} to {}", repo, local_repo);
 
-            utils::command_output("git", &["clone", repo, local_repo])?;
+            utils::without_timeout(|| {
+                utils::command_output("git", &["clone", repo, local_repo])
+            })?;
 
             log::info!("{} cloned to {}", repo, local_repo);
 
@@ -262,6 +646,161 @@ impl Command {
         return Success!();
     }
 
+    /// Create the `ssh.nix` module authorizing SSH keys on the installed system
+    fn install_ssh_keys(
+        &self,
+        host: &str,
+        etc: &path::PathBuf) -> error::Return {
+
+        if self.ssh_authorized_keys.is_empty() {
+            return Success!();
+        }
+
+        let dir = etc.join("nixos").join("filesystems").join(host);
+
+        if !dir.is_dir() {
+            return generic_error!(&format!(
+                "No generated configuration in {:?} (run `filesystems`)",
+                dir));
+        }
+
+        let user = match self.user.is_empty() {
+            true => "root",
+            false => &self.user,
+        };
+
+        let mut content = "# Auto-generated, do not edit !\n".to_string();
+        content += "{ ... }:\n\n";
+        content += "{\n";
+        content += "  services.openssh.enable = true;";
+
+        content += "\n\n";
+        content += &format!(
+            r#"  users.users."{}".openssh.authorizedKeys.keys = ["#,
+            user);
+
+        for key in self.ssh_authorized_keys.iter() {
+            content += "\n";
+            content += &format!(r#"    "{}""#, key);
+        }
+
+        content += "\n";
+        content += "  ];";
+
+        content += "\n";
+        content += "}";
+
+        log::info!("{}", content);
+
+        // Write to file
+        let output = dir.join("ssh.nix");
+
+        utils::write_to_file(content.as_bytes(), &output)?;
+
+        log::info!("Configuration written to {:?}", &output);
+
+        return Success!();
+    }
+
+    /// Copy a system closure into the store of the target
+    fn copy_closure(&self, root: &str, closure: &str) -> error::Return {
+        utils::without_timeout(|| utils::command_output(
+            "nix",
+            &[
+                "--extra-experimental-features", "nix-command",
+                "copy",
+                "--no-check-sigs",
+                "--to", root,
+                closure,
+            ]))?;
+
+        log::info!("Closure `{}` copied to `{}`", closure, root);
+
+        return Success!();
+    }
+
+    /// Check that the root filesystem has enough free space for the system
+    /// (the closure size if known, the minimal free space otherwise)
+    fn check_free_space(&self, root: &path::PathBuf) -> error::Return {
+        if self.min_free_space.is_null() {
+            return Success!();
+        }
+
+        // Nothing is mounted
+        if utils::is_dry_run() {
+            return Success!();
+        }
+
+        let mut required = self.min_free_space.to_bytes();
+
+        match &self.closure {
+            Some(c) => required = required.max(closure_size(c)?),
+            None => (),
+        }
+
+        let available = free_space(root)?;
+
+        log::info!(
+            "{} MiB available on {:?} ({} MiB required)",
+            available >> 20,
+            root,
+            required >> 20);
+
+        if available < required {
+            return generic_error!(&format!(
+                "Not enough free space on {:?}: {} MiB available, {} MiB \
+                    required (see --{})",
+                root,
+                available >> 20,
+                required >> 20,
+                ARG_MIN_FREE_SPACE));
+        }
+
+        return Success!();
+    }
+
+    /// Get the `nixos-install` arguments of the build settings
+    fn build_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+
+        match self.cores.as_deref() {
+            Some(c) => {
+                args.push("--cores".to_string());
+
+                // Nix uses all the cores with `0`
+                args.push(match c {
+                    "auto" => "0".to_string(),
+                    _ => c.to_string(),
+                });
+            },
+
+            None => (),
+        }
+
+        match &self.max_jobs {
+            Some(j) => {
+                args.push("--max-jobs".to_string());
+                args.push(j.clone());
+            },
+
+            None => (),
+        }
+
+        if self.keep_going {
+            args.push("--option".to_string());
+            args.push("keep-going".to_string());
+            args.push("true".to_string());
+        }
+
+        log::info!(
+            "Build settings: cores {}, max-jobs {}, keep-going {}",
+            self.cores.as_deref().unwrap_or("default"),
+            self.max_jobs.as_deref().unwrap_or("default"),
+            self.keep_going);
+
+        return args;
+    }
+
     /// Run NixOS installer
     fn run_nixos_installer(&self, root: &path::PathBuf) -> error::Return {
         let root = match root.to_str() {
@@ -269,13 +808,260 @@ impl Command {
             None => return generic_error!("No root"),
         };
 
-        utils::command_output(
-            "nixos-install",
-            &[
-                "--no-root-passwd",
-                "--root", root
-            ])?;
+        let mut args = vec!["--no-root-passwd", "--root", root];
+
+        // Install the pre-built closure (it must be in the target store)
+        match &self.closure {
+            Some(c) => {
+                self.copy_closure(root, c)?;
+
+                args.extend(&["--system", c.as_str(), "--no-channel-copy"]);
+            },
+
+            None => (),
+        }
+
+        let build_args = self.build_args();
+
+        args.extend(build_args.iter().map(|a| a.as_str()));
+        args.extend(self.nixos_install_args.iter().map(|a| a.as_str()));
+
+        // Never fetch anything from binary caches
+        if utils::is_offline() {
+            args.extend(&["--option", "substituters", ""]);
+        }
+
+        log::info!("Running: nixos-install {}", args.join(" "));
+
+        let mut progress = Progress::new();
+
+        utils::stream_command("nixos-install", &args, &mut |line| {
+            match progress.update(line) {
+                Some(p) => log::info!("Installation progress: {}%", p),
+                None => (),
+            }
+        })?;
 
         return Success!();
     }
 }
+
+// -----------------------------------------------------------------------------
+
+/// Coarse progress of `nixos-install`, from the paths announced to be built or
+/// fetched and the ones built or copied so far
+struct Progress {
+    /// Number of paths to build or fetch
+    total: usize,
+
+    /// Number of paths built or copied
+    done: usize,
+
+    /// Last reported percentage
+    reported: usize,
+}
+
+impl Progress {
+    /// Create an instance of Progress
+    fn new() -> Self {
+        Self {
+            total: 0,
+            done: 0,
+            reported: 0,
+        }
+    }
+
+    /// Account for a line of output: get the percentage if it reached a new
+    /// step of 10%
+    fn update(&mut self, line: &str) -> Option<usize> {
+        let line = line.trim();
+
+        // `these 12 derivations will be built:`, `this path will be fetched
+        // (...)`
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        let announced = match words[..] {
+            ["this", _, "will", "be", "built:" | "fetched", ..] => Some(1),
+            ["these", n, _, "will", "be", "built:" | "fetched", ..] => {
+                n.parse::<usize>().ok()
+            },
+            _ => None,
+        };
+
+        match announced {
+            Some(n) => {
+                self.total += n;
+                return None;
+            },
+            None => (),
+        }
+
+        if !line.starts_with("building '") &&
+            !line.starts_with("copying path '") {
+
+            return None;
+        }
+
+        if self.total == 0 {
+            return None;
+        }
+
+        self.done += 1;
+
+        let step = (self.done * 10 / self.total).min(10) * 10;
+
+        if step <= self.reported {
+            return None;
+        }
+
+        self.reported = step;
+
+        return Some(step);
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Read SSH public keys from a file, or a single key given as a string
+fn read_ssh_keys(value: &str) -> Result<Vec<String>, error::Error> {
+    let path = path::Path::new(value);
+
+    let content = match path.is_file() {
+        true => match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) => return fs_error!(path.to_path_buf(), e),
+        },
+
+        false => value.to_string(),
+    };
+
+    let mut keys = Vec::new();
+
+    for line in content.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        check_ssh_key(line)?;
+
+        keys.push(line.to_string());
+    }
+
+    if keys.is_empty() {
+        return generic_error!(&format!("No SSH key in `{}`", value));
+    }
+
+    return Ok(keys);
+}
+
+/// Check that a string looks like an SSH public key (`type base64 [comment]`)
+fn check_ssh_key(key: &str) -> error::Return {
+    let re = match Regex::new(r#"^(\S+) ([A-Za-z0-9+/]+={0,2})( [^"\\$]*)?$"#) {
+        Ok(r) => r,
+        Err(e) => return generic_error!(
+            &format!("Cannot build regex: {}", e)),
+    };
+
+    let valid = match re.captures(key) {
+        Some(c) => SSH_KEY_TYPES.contains(&&c[1]),
+        None => false,
+    };
+
+    if !valid {
+        return generic_error!(&format!("Invalid SSH public key `{}`", key));
+    }
+
+    return Success!();
+}
+
+/// Get the size in bytes of a closure and its dependencies
+fn closure_size(closure: &str) -> Result<u64, error::Error> {
+    let output = utils::command_output(
+        "nix",
+        &[
+            "--extra-experimental-features", "nix-command",
+            "path-info",
+            "-S",
+            closure,
+        ])?;
+
+    let output = utils::command_stdout_to_string(&output)?;
+
+    // `<path> <closure size>`
+    return match output.split_whitespace().last().map(|s| s.parse::<u64>()) {
+        Some(Ok(s)) => Ok(s),
+        _ => generic_error!(&format!("Cannot get size of `{}`", closure)),
+    };
+}
+
+/// Get the free space in bytes of a mounted filesystem
+fn free_space(mountpoint: &path::PathBuf) -> Result<u64, error::Error> {
+    let path = match mountpoint.to_str() {
+        Some(p) => p,
+        None => return generic_error!("Invalid mountpoint"),
+    };
+
+    let output = utils::command_output(
+        "df",
+        &["--output=avail", "-B1", path])?;
+
+    let output = utils::command_stdout_to_string(&output)?;
+
+    // Header line then the value
+    return match output.lines().nth(1).map(|l| l.trim().parse::<u64>()) {
+        Some(Ok(s)) => Ok(s),
+        _ => generic_error!(&format!("Cannot get free space of `{}`", path)),
+    };
+}
+
+/// Check a number of cores/jobs (a positive integer or `auto`)
+fn check_jobs(arg: &str, value: &str) -> error::Return {
+    match value.parse::<u32>() {
+        Ok(n) if n > 0 => return Success!(),
+        _ if value == "auto" => return Success!(),
+        _ => return generic_error!(&format!(
+            "Invalid --{} `{}` (positive integer or auto)",
+            arg,
+            value)),
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_progress_by_steps() {
+        let mut progress = Progress::new();
+
+        assert_eq!(progress.update("these 3 derivations will be built:"), None);
+        assert_eq!(
+            progress.update(
+                "this path will be fetched (0.10 MiB download, 0.50 MiB \
+                unpacked):"),
+            None);
+
+        assert_eq!(
+            progress.update("copying path '/nix/store/abc-foo' from 'https://\
+                cache.nixos.org'..."),
+            Some(20));
+
+        assert_eq!(progress.update("unpacking sources"), None);
+        assert_eq!(
+            progress.update("building '/nix/store/def-bar.drv'..."),
+            Some(50));
+        assert_eq!(
+            progress.update("building '/nix/store/ghi-baz.drv'..."),
+            Some(70));
+        assert_eq!(
+            progress.update("building '/nix/store/jkl-qux.drv'..."),
+            Some(100));
+
+        // Nothing announced beyond
+        assert_eq!(
+            progress.update("building '/nix/store/mno-extra.drv'..."),
+            None);
+    }
+}
@@ -0,0 +1,99 @@
+// -----------------------------------------------------------------------------
+
+use std::path;
+
+#[macro_use]
+pub mod error;
+
+mod bench;
+mod btrfs;
+pub mod cli;
+mod crypttab;
+pub mod disk;
+mod disko;
+mod env;
+pub mod filesystem;
+mod filesystems;
+mod gpt;
+mod grow;
+mod hardware;
+mod initramfs;
+mod install;
+pub mod logger;
+mod luks;
+mod lvm;
+mod migrate;
+mod nix;
+pub mod partition;
+mod partitioning;
+pub mod plan;
+mod raid;
+mod repair;
+pub mod report;
+mod secrets;
+pub mod signals;
+mod status;
+pub mod traits;
+mod unmount;
+mod utils;
+mod verify;
+mod wipe;
+mod zfs;
+
+use traits::Openable;
+
+// -----------------------------------------------------------------------------
+
+/// Create the partitions, LUKS devices, volumes, pools and filesystems of a
+/// layout file, as the `partitioning` command does with its default options
+/// (what has been opened is closed on failure)
+///
+/// The completed steps are recorded next to the layout
+/// (`<layout>.steps.json`, removed on success), the returned filesystem can be
+/// saved with `Filesystem::to_file`.
+pub fn create_filesystem(
+    layout: &path::Path,
+    key_file: &str,
+    passphrase: &str) -> Result<filesystem::Filesystem, error::Error> {
+
+    let mut fs = filesystem::Filesystem::from_file(&layout.to_path_buf())?;
+
+    fs.resolve_devices()?;
+
+    let progress = plan::Progress::new(&layout.with_extension("steps.json"));
+
+    match fs.create(key_file, passphrase, &progress) {
+        Ok(_) => (),
+        Err(e) => {
+            fs.rollback();
+            return Err(e);
+        },
+    }
+
+    fs.close()?;
+
+    progress.clear()?;
+
+    return Ok(fs);
+}
+
+/// Install NixOS on the filesystem of a host from a repository of
+/// configurations, as the `install` command does with its default options
+/// (the filesystem is opened, mounted in `/mnt/root`, then closed)
+pub fn install(
+    host: &str,
+    repo: &str,
+    fs: &mut filesystem::Filesystem,
+    passphrase: &str) -> error::Return {
+
+    fs.open(passphrase)?;
+
+    let result = install::Command::new().install_nixos(host, repo, fs);
+
+    match fs.close() {
+        Ok(_) => (),
+        Err(e) => log::error!("{}", e),
+    }
+
+    return result;
+}
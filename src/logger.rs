@@ -0,0 +1,183 @@
+// -----------------------------------------------------------------------------
+
+use env_logger;
+use std::fs;
+use std::io::Write;
+use std::path;
+use std::sync::Mutex;
+
+use super::error;
+
+// -----------------------------------------------------------------------------
+
+/// List of secrets that must never appear in logs
+static SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Replacement of secrets in logs
+const REDACTED: &str = "********";
+
+// -----------------------------------------------------------------------------
+
+/// File where all logs are written
+struct LogFile {
+    /// Path of the file
+    path: path::PathBuf,
+
+    /// Opened file
+    file: fs::File,
+
+    /// Number of bytes written in the file
+    size: u64,
+
+    /// Size at which the file is rotated
+    max_size: u64,
+}
+
+impl LogFile {
+    /// Open (and truncate) the log file
+    fn open(path: &path::Path, max_size: u64) -> Result<Self, error::Error> {
+        let file = match fs::File::create(path) {
+            Ok(f) => f,
+            Err(e) => return fs_error!(path.to_path_buf(), e),
+        };
+
+        return Ok(Self {
+            path: path.to_path_buf(),
+            file: file,
+            size: 0,
+            max_size: max_size,
+        });
+    }
+
+    /// Write a line, rotating the file (to `<path>.1`) when it's full
+    fn write(&mut self, line: &str) {
+        if self.max_size > 0 && self.size + line.len() as u64 > self.max_size {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+
+            match fs::rename(&self.path, &rotated) {
+                Ok(_) => (),
+                Err(_) => return,
+            }
+
+            match fs::File::create(&self.path) {
+                Ok(f) => self.file = f,
+                Err(_) => return,
+            }
+
+            self.size = 0;
+        }
+
+        match self.file.write_all(line.as_bytes()) {
+            Ok(_) => self.size += line.len() as u64,
+            Err(_) => (),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Logger writing to the console and optionally to a file
+struct Logger {
+    /// Console logger
+    console: env_logger::Logger,
+
+    /// Optional log file (captures everything)
+    file: Option<Mutex<LogFile>>,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        return self.file.is_some() || self.console.enabled(metadata);
+    }
+
+    fn log(&self, record: &log::Record) {
+        let message = redact(&record.args().to_string());
+
+        if self.console.matches(record) {
+            self.console.log(&log::Record::builder()
+                .args(format_args!("{}", message))
+                .level(record.level())
+                .target(record.target())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build());
+        }
+
+        match &self.file {
+            Some(f) => f.lock().unwrap().write(
+                &format!("[{:<5}] {}\n", record.level(), message)),
+
+            None => (),
+        }
+    }
+
+    fn flush(&self) {
+        match &self.file {
+            Some(f) => match f.lock().unwrap().file.flush() {
+                Ok(_) => (),
+                Err(_) => (),
+            },
+
+            None => (),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Configure logs
+pub fn init(
+    level: log::LevelFilter,
+    log_file: Option<&path::Path>,
+    max_size: u64) -> error::Return {
+
+    let console = env_logger::Builder::new()
+        .filter(None, level)
+        .format_timestamp(None)
+        .format_module_path(false)
+        .build();
+
+    let file = match log_file {
+        Some(p) => Some(Mutex::new(LogFile::open(p, max_size)?)),
+        None => None,
+    };
+
+    let max_level = match file {
+        Some(_) => log::LevelFilter::Trace,
+        None => level,
+    };
+
+    let logger = Logger {
+        console: console,
+        file: file,
+    };
+
+    match log::set_boxed_logger(Box::new(logger)) {
+        Ok(_) => log::set_max_level(max_level),
+        Err(_) => return generic_error!("Logger already initialized"),
+    }
+
+    return Success!();
+}
+
+/// Register a secret that must be redacted from logs
+pub fn add_secret(secret: &str) {
+    if secret.is_empty() {
+        return;
+    }
+
+    SECRETS.lock().unwrap().push(secret.to_string());
+}
+
+/// Replace registered secrets in a message
+fn redact(message: &str) -> String {
+    let mut message = message.to_string();
+
+    for secret in SECRETS.lock().unwrap().iter() {
+        message = message.replace(secret.as_str(), REDACTED);
+    }
+
+    return message;
+}
@@ -2,22 +2,227 @@
 
 use argon2;
 use clap;
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path;
+use std::str::FromStr;
 
 use super::env;
 use super::error;
+use super::logger;
+use super::report;
 use super::traits::{CliCommand, Validate};
 use super::utils;
 
 // -----------------------------------------------------------------------------
 
+const ARG_DEVICE: &str = "device";
+const ARG_GENERATE_SALT: &str = "generate-salt";
 const ARG_ITERATIONS: &str = "iterations";
 const ARG_KEY_SIZE: &str = "key-size";
+const ARG_MEM_COST: &str = "mem-cost";
 const ARG_OUTPUT: &str = "output";
 const ARG_PASSWORD: &str = "password";
 const ARG_SALT: &str = "salt";
 
+const CMD_SLOTS: &str = "slots";
+
+/// Ciphers supported for LUKS format with their allowed key sizes (in bits)
+const SUPPORTED_CIPHERS: &[(&str, &[u32])] = &[
+    ("aes-xts-plain64", &[256, 512]),
+    ("aes-cbc-essiv:sha256", &[128, 192, 256]),
+    ("serpent-xts-plain64", &[256, 512]),
+    ("twofish-xts-plain64", &[256, 512]),
+    ("xchacha12,aes-adiantum-plain64", &[256]),
+    ("xchacha20,aes-adiantum-plain64", &[256]),
+];
+
+/// Size in bytes of a generated salt
+const SALT_SIZE: usize = 32;
+
+/// Default memory used to hash the password (in KiB)
+pub const DEFAULT_MEM_COST: u32 = 65536;
+
+/// Hashes supported for LUKS format
+const SUPPORTED_HASHES: &[&str] = &["sha1", "sha256", "sha512"];
+
+// -----------------------------------------------------------------------------
+
+/// Version of the LUKS header
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LuksVersion {
+    /// LUKS1 with PBKDF2 (readable by GRUB)
+    Luks1,
+
+    /// LUKS2 with argon2id
+    Luks2,
+}
+
+impl LuksVersion {
+    /// Get the number of keyslots of the header
+    pub fn key_slots(&self) -> u32 {
+        return match self {
+            LuksVersion::Luks1 => 8,
+            LuksVersion::Luks2 => 32,
+        };
+    }
+}
+
+impl FromStr for LuksVersion {
+    type Err = error::Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        return match input {
+            "luks1" => Ok(Self::Luks1),
+            "luks2" => Ok(Self::Luks2),
+            _ => generic_error!(&format!("Invalid LUKS version {}", input)),
+        };
+    }
+}
+
+impl ToString for LuksVersion {
+    fn to_string(&self) -> String {
+        return match self {
+            LuksVersion::Luks1 => String::from("luks1"),
+            LuksVersion::Luks2 => String::from("luks2"),
+        };
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Json configuration of the LUKS format parameters
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// Cipher used to encrypt the device
+    pub cipher: String,
+
+    /// Size of the master key in bits
+    pub key_size: u32,
+
+    /// Hash used for the key derivation (LUKS1 only)
+    pub hash: String,
+
+    /// Time spent on keyslot PBKDF in milliseconds (cryptsetup default if not
+    /// provided). Lowering it speeds up unlocking at boot but also makes
+    /// brute-forcing the passphrase cheaper.
+    pub iter_time: Option<u32>,
+
+    /// Version of the header (LUKS1 if not provided)
+    #[serde(default)]
+    pub version: Option<LuksVersion>,
+
+    /// Keyslot of the key file (next free one if not provided)
+    #[serde(default)]
+    pub key_slot: Option<u32>,
+}
+
+impl Config {
+    /// Get the version of the header
+    pub fn version(&self) -> LuksVersion {
+        return self.version.unwrap_or(LuksVersion::Luks1);
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            cipher: "aes-xts-plain64".to_string(),
+            key_size: 256,
+            hash: "sha512".to_string(),
+            iter_time: None,
+            version: None,
+            key_slot: None,
+        }
+    }
+}
+
+impl Validate for Config {
+    fn is_valid(&self) -> bool {
+        let key_sizes = match SUPPORTED_CIPHERS
+            .iter()
+            .find(|c| c.0 == self.cipher) {
+                Some(c) => c.1,
+                None => return false,
+            };
+
+        if self.iter_time == Some(0) {
+            return false;
+        }
+
+        match self.key_slot {
+            Some(s) if s >= self.version().key_slots() => return false,
+            _ => (),
+        }
+
+        return
+            key_sizes.contains(&self.key_size) &&
+            SUPPORTED_HASHES.contains(&self.hash.as_str());
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Header of a LUKS device (from `cryptsetup luksDump`)
+#[derive(Debug)]
+struct Header {
+    /// Version of the header
+    version: LuksVersion,
+
+    /// Enabled keyslots
+    slots: Vec<KeySlot>,
+}
+
+/// Enabled keyslot of a LUKS header
+#[derive(Debug)]
+struct KeySlot {
+    /// Number of the keyslot
+    id: u32,
+
+    /// Key derivation function
+    pbkdf: String,
+
+    /// Parameters of the key derivation (name and value)
+    parameters: Vec<(String, String)>,
+
+    /// Types of the tokens bound to the keyslot (LUKS2: TPM2, FIDO2, ...)
+    tokens: Vec<String>,
+}
+
+impl KeySlot {
+    /// Create an instance of KeySlot
+    fn new(id: u32, pbkdf: &str) -> Self {
+        Self {
+            id: id,
+            pbkdf: pbkdf.to_string(),
+            parameters: Vec::new(),
+            tokens: Vec::new(),
+        }
+    }
+
+    /// Set a field of the keyslot read from the header dump (only the key
+    /// derivation ones are kept)
+    fn set_field(&mut self, key: &str, value: &str) {
+        match key {
+            "PBKDF" => self.pbkdf = value.to_string(),
+
+            "Hash" | "Iterations" | "Memory" | "Threads" | "Time cost" => {
+                self.parameters.push((key.to_string(), value.to_string()));
+            },
+
+            _ => (),
+        }
+    }
+}
+
+/// Entry of a header dump being read
+enum DumpEntry {
+    None,
+    Slot,
+    Token,
+}
+
 // -----------------------------------------------------------------------------
 
 /// Command structure for creating luks key file
@@ -29,6 +234,9 @@ pub struct Command {
     /// Size in bytes of the key to be generated
     key_size: u32,
 
+    /// Memory used by the algorithm (in KiB)
+    mem_cost: u32,
+
     /// Output file
     output: String,
 
@@ -37,6 +245,9 @@ pub struct Command {
 
     /// Random salt data
     salt: String,
+
+    /// Whether the salt file must be generated
+    generate_salt: bool,
 }
 
 impl Validate for Command {
@@ -44,6 +255,7 @@ impl Validate for Command {
         return
             self.iterations > 0 &&
             self.key_size > 0 &&
+            self.mem_cost > 0 &&
             !self.output.is_empty() &&
             !self.password.is_empty() &&
             !self.salt.is_empty();
@@ -66,17 +278,31 @@ impl CliCommand for Command {
             .about("Create LUKS key file")
             .version(version)
             .author(author)
+            .setting(clap::AppSettings::SubcommandsNegateReqs)
+            // Slots command
+            .subcommand(clap::App::new(CMD_SLOTS)
+                .about("List the keyslots of a LUKS device (key derivation \
+                    and tokens), read-only")
+                .arg(clap::Arg::with_name(ARG_DEVICE)
+                    .help("LUKS device")
+                    .required(true)
+                    .index(1)))
             // Iterations argument
             .arg(clap::Arg::with_name(ARG_ITERATIONS)
                 .long(ARG_ITERATIONS)
                 .help("Number of iterations to perform")
                 .required(true)
                 .takes_value(true))
-            // Iterations argument
+            // Key size argument
             .arg(clap::Arg::with_name(ARG_KEY_SIZE)
                 .long(ARG_KEY_SIZE)
                 .help("Size of the key")
                 .takes_value(true))
+            // Memory cost argument
+            .arg(clap::Arg::with_name(ARG_MEM_COST)
+                .long(ARG_MEM_COST)
+                .help("Memory used to hash the password (in KiB)")
+                .takes_value(true))
             // Password argument
             .arg(clap::Arg::with_name(ARG_OUTPUT)
                 .long(ARG_OUTPUT)
@@ -92,12 +318,26 @@ impl CliCommand for Command {
             .arg(clap::Arg::with_name(ARG_SALT)
                 .long(ARG_SALT)
                 .help("File path containing some salt data")
-                .required(true)
+                .required_unless(ARG_GENERATE_SALT)
+                .conflicts_with(ARG_GENERATE_SALT)
+                .takes_value(true))
+            // Generate salt argument
+            .arg(clap::Arg::with_name(ARG_GENERATE_SALT)
+                .long(ARG_GENERATE_SALT)
+                .help("File path where to generate random salt data")
                 .takes_value(true));
     }
 
     /// Process command line arguments
     fn process(&mut self, matches: &clap::ArgMatches) -> error::Return {
+        match matches.subcommand_matches(CMD_SLOTS) {
+            Some(m) => return match m.value_of(ARG_DEVICE) {
+                Some(d) => print_slots(d),
+                None => inval_error!(&ARG_DEVICE),
+            },
+            None => (),
+        }
+
         // Parse arguments
         for arg in matches.args.iter() {
             match arg.0 {
@@ -125,6 +365,18 @@ impl CliCommand for Command {
                     };
                 },
 
+                &ARG_MEM_COST => {
+                    let value = match matches.value_of(arg.0) {
+                        Some(s) => s.to_string(),
+                        None => return inval_error!(&ARG_MEM_COST),
+                    };
+
+                    self.mem_cost = match value.parse::<u32>() {
+                        Ok(i) => i,
+                        Err(_) => return inval_error!(&ARG_MEM_COST),
+                    };
+                },
+
                 &ARG_OUTPUT => {
                     self.output = match matches.value_of(arg.0) {
                         Some(s) => s.to_string(),
@@ -137,6 +389,8 @@ impl CliCommand for Command {
                         Some(s) => s.to_string(),
                         None => return inval_error!(&ARG_PASSWORD),
                     };
+
+                    logger::add_secret(&self.password);
                 },
 
                 &ARG_SALT => {
@@ -146,6 +400,15 @@ impl CliCommand for Command {
                     };
                 },
 
+                &ARG_GENERATE_SALT => {
+                    self.salt = match matches.value_of(arg.0) {
+                        Some(s) => s.to_string(),
+                        None => return inval_error!(&ARG_GENERATE_SALT),
+                    };
+
+                    self.generate_salt = true;
+                },
+
                 _ => {
                     return inval_error!(arg.0);
                 }
@@ -163,35 +426,23 @@ impl CliCommand for Command {
             return generic_error!("Invalid configuration");
         }
 
-        // Load data from salt file
-        let content = match fs::read(&self.salt) {
-            Ok(c) => c,
-            Err(e) => return io_error!("Cannot read salt data", e),
+        // Generate salt file (kept in memory, it's not written in dry run) or
+        // load data from salt file
+        let content = match self.generate_salt {
+            true => self.create_salt()?,
+            false => match fs::read(&self.salt) {
+                Ok(c) => c,
+                Err(e) => return io_error!("Cannot read salt data", e),
+            },
         };
 
         // Hash password
-        let hash_config = argon2::Config {
-            variant: argon2::Variant::Argon2id,
-            version: argon2::Version::Version13,
-            mem_cost: 65536,
-            time_cost: self.iterations,
-            thread_mode: argon2::ThreadMode::Parallel,
-            lanes: 4,
-            secret: &[],
-            ad: &[],
-            hash_length: self.key_size
-        };
-
-        let hash = match argon2::hash_raw(
+        let hash = hash_password(
             self.password.as_bytes(),
             &content,
-            &hash_config) {
-
-            Ok(h) => h,
-            Err(_) => {
-                return generic_error!("Error while trying to hash password");
-            },
-        };
+            self.iterations,
+            self.mem_cost,
+            self.key_size)?;
 
         // Write to file
         match utils::write_to_file(&hash, path::Path::new(&self.output)) {
@@ -209,8 +460,10 @@ impl Command {
         Self {
             iterations: 0,
             key_size: 4096,
+            mem_cost: DEFAULT_MEM_COST,
             password: "".to_string(),
             salt: "".to_string(),
+            generate_salt: false,
             output: "".to_string(),
         }
     }
@@ -223,53 +476,402 @@ impl Command {
 
         return Success!();
     }
+
+    /// Write random salt data to the salt file (an existing salt is never
+    /// replaced: the keys derived from it couldn't be rebuilt)
+    fn create_salt(&self) -> Result<Vec<u8>, error::Error> {
+        let path = path::Path::new(&self.salt);
+
+        if path.exists() {
+            return generic_error!(&format!(
+                "Salt `{}` already exists, it's not replaced (remove it or \
+                    use --{} to derive the key from it)",
+                &self.salt,
+                ARG_SALT));
+        }
+
+        let salt = utils::random_bytes(SALT_SIZE)?;
+
+        utils::write_to_file(&salt, path)?;
+
+        log::info!("New salt written to {}", &self.salt);
+
+        report::warning(&format!(
+            "Salt `{}` must be preserved to regenerate the same key",
+            &self.salt));
+
+        return Ok(salt);
+    }
 }
 
 // -----------------------------------------------------------------------------
 
+/// Function used to hash a password into a key (Argon2id)
+pub fn hash_password(
+    password: &[u8],
+    salt: &[u8],
+    iterations: u32,
+    mem_cost: u32,
+    key_size: u32) -> Result<Vec<u8>, error::Error> {
+
+    let hash_config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        version: argon2::Version::Version13,
+        mem_cost: mem_cost,
+        time_cost: iterations,
+        thread_mode: argon2::ThreadMode::Parallel,
+        lanes: 4,
+        secret: &[],
+        ad: &[],
+        hash_length: key_size
+    };
+
+    return match argon2::hash_raw(password, salt, &hash_config) {
+        Ok(h) => Ok(h),
+        Err(_) => generic_error!("Error while trying to hash password"),
+    };
+}
+
 /// Function used to set LUKS on a device
-pub fn format(device : &str, passphrase : &str) -> error::Return {
-    //TODO: use luks2 as soon as possible
-    utils::spawn_command(
-        "cryptsetup",
-        &[
-            "luksFormat",
-            "-c", "aes-xts-plain64",
-            "-s", "256",
-            "-h", "sha512",
-            "--type", "luks1",
-            "-q",
-            device,
-            "-"
-        ],
-        Some(passphrase.as_bytes()))?;
+pub fn format(
+    device : &str,
+    passphrase : &str,
+    config: &Config) -> error::Return {
+
+    if !config.is_valid() {
+        return generic_error!(&format!(
+            "Unsupported LUKS parameters: {} ({} bits) with {}, keyslot {:?}",
+            config.cipher,
+            config.key_size,
+            config.hash,
+            config.key_slot));
+    }
+
+    let args = format_args(device, config);
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+
+    utils::spawn_command("cryptsetup", &args, Some(passphrase.as_bytes()))?;
 
     log::info!("LUKS setup on device `{}`", device);
 
     return Success!();
 }
 
-/// Function used to add a key file to a LUKS device
+/// Build the arguments of `cryptsetup luksFormat`
+fn format_args(device: &str, config: &Config) -> Vec<String> {
+    let mut args = vec!["luksFormat".to_string()];
+
+    args.extend(header_args(config));
+
+    // The passphrase goes to the first keyslot, unless the key file takes it
+    if config.key_slot == Some(0) {
+        args.push("--key-slot".to_string());
+        args.push("1".to_string());
+    }
+
+    args.push("-q".to_string());
+    args.push(device.to_string());
+    args.push("-".to_string());
+
+    return args;
+}
+
+/// Build the arguments of `cryptsetup luksFormat` describing the header
+/// (cipher, key size, version and key derivation)
+pub fn header_args(config: &Config) -> Vec<String> {
+    let mut args = vec![
+        "-c".to_string(), config.cipher.clone(),
+        "-s".to_string(), config.key_size.to_string(),
+        "--type".to_string(), config.version().to_string(),
+    ];
+
+    match config.version() {
+        LuksVersion::Luks1 => {
+            args.push("-h".to_string());
+            args.push(config.hash.clone());
+        },
+
+        LuksVersion::Luks2 => {
+            args.push("--pbkdf".to_string());
+            args.push("argon2id".to_string());
+        },
+    }
+
+    match config.iter_time {
+        Some(t) => {
+            args.push("--iter-time".to_string());
+            args.push(t.to_string());
+        },
+
+        None => (),
+    }
+
+    return args;
+}
+
+/// Function used to add a key file to a LUKS device, in the given keyslot
+/// if any (an occupied keyslot is only wiped if `kill_slot` is set)
 pub fn add_key(
     device : &str,
     passphrase : &str,
-    key_file : &str) -> error::Return {
+    key_file : &str,
+    key_slot: Option<u32>,
+    kill_slot: bool) -> error::Return {
+
+    let slot = match key_slot {
+        Some(s) => s,
+        None => {
+            utils::spawn_command(
+                "cryptsetup",
+                &["luksAddKey", device, key_file, "-"],
+                Some(passphrase.as_bytes()))?;
+
+            return Success!();
+        },
+    };
+
+    if !utils::is_dry_run() {
+        let header = read_header(device)?;
+        let version = header.version;
+
+        if slot >= version.key_slots() {
+            return generic_error!(&format!(
+                "Keyslot {} out of range for {} `{}` (0 to {})",
+                slot,
+                version.to_string(),
+                device,
+                version.key_slots() - 1));
+        }
+
+        if header.slots.iter().any(|s| s.id == slot) {
+            kill_key(device, passphrase, &slot.to_string(), kill_slot)?;
+        }
+    }
+
+    let slot = slot.to_string();
 
     utils::spawn_command(
+        "cryptsetup",
+        &["luksAddKey", "--key-slot", &slot, device, key_file, "-"],
+        Some(passphrase.as_bytes()))?;
+
+    log::info!("Key file added to keyslot {} of `{}`", slot, device);
+
+    return Success!();
+}
+
+/// Wipe an occupied keyslot (if confirmed and if it doesn't hold the
+/// passphrase still needed to add the key file)
+fn kill_key(
+    device: &str,
+    passphrase: &str,
+    slot: &str,
+    confirmed: bool) -> error::Return {
+
+    if !confirmed {
+        return generic_error!(&format!(
+            "Keyslot {} of `{}` is occupied, use --kill-key-slot to wipe it",
+            slot,
+            device));
+    }
+
+    let unlocks_slot = utils::spawn_command(
+        "cryptsetup",
+        &["open", "--test-passphrase", "--key-slot", slot, device, "-"],
+        Some(passphrase.as_bytes())).is_ok();
+
+    if unlocks_slot {
+        return generic_error!(&format!(
+            "Keyslot {} of `{}` holds the passphrase and cannot be wiped",
+            slot,
+            device));
+    }
+
+    utils::command_output("cryptsetup", &["luksKillSlot", "-q", device, slot])?;
+
+    report::warning(&format!("Keyslot {} of `{}` wiped", slot, device));
+
+    return Success!();
+}
+
+/// Read the header of a LUKS device
+fn read_header(device: &str) -> Result<Header, error::Error> {
+    let output = utils::command_output("cryptsetup", &["luksDump", device])?;
+
+    return parse_dump(&utils::command_stdout_to_string(&output)?);
+}
+
+/// Parse the output of `cryptsetup luksDump` (LUKS1 or LUKS2)
+fn parse_dump(dump: &str) -> Result<Header, error::Error> {
+    let mut version = None;
+    let mut hash = String::new();
+    let mut slots: Vec<KeySlot> = Vec::new();
+    let mut tokens: Vec<(String, Vec<u32>)> = Vec::new();
+
+    // LUKS2 section (`Keyslots:`, `Tokens:`, ...) and entry being read
+    let mut section = "";
+    let mut entry = DumpEntry::None;
+
+    for line in dump.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => (line.trim(), ""),
+        };
+
+        // Fields of an entry
+        if line.starts_with('\t') {
+            match entry {
+                DumpEntry::Slot => match slots.last_mut() {
+                    Some(s) => s.set_field(key, value),
+                    None => (),
+                },
+
+                DumpEntry::Token => match (tokens.last_mut(), key) {
+                    (Some(t), "Keyslot") => match value.parse::<u32>() {
+                        Ok(n) => t.1.push(n),
+                        Err(_) => (),
+                    },
+                    _ => (),
+                },
+
+                DumpEntry::None => (),
+            }
+
+            continue;
+        }
+
+        // Entries of a LUKS2 section: `  0: luks2`
+        if line.starts_with("  ") {
+            let id = match key.parse::<u32>() {
+                Ok(n) => n,
+                Err(_) => continue,
+            };
+
+            entry = match section {
+                "Keyslots" => {
+                    slots.push(KeySlot::new(id, ""));
+                    DumpEntry::Slot
+                },
+
+                "Tokens" => {
+                    tokens.push((value.to_string(), Vec::new()));
+                    DumpEntry::Token
+                },
+
+                _ => DumpEntry::None,
+            };
+
+            continue;
+        }
+
+        entry = DumpEntry::None;
+
+        if value.is_empty() {
+            section = key;
+        }
+
+        match key {
+            "Version" => version = match value {
+                "1" => Some(LuksVersion::Luks1),
+                "2" => Some(LuksVersion::Luks2),
+                _ => None,
+            },
+
+            "Hash spec" => hash = value.to_string(),
+
+            _ => (),
+        }
+
+        // LUKS1: `Key Slot 0: ENABLED` (PBKDF2 with the hash of the header)
+        match key.strip_prefix("Key Slot ").map(|n| n.parse::<u32>()) {
+            Some(Ok(n)) if value == "ENABLED" => {
+                let mut slot = KeySlot::new(n, "pbkdf2");
+                slot.set_field("Hash", &hash);
+
+                slots.push(slot);
+                entry = DumpEntry::Slot;
+            },
+            _ => (),
+        }
+    }
+
+    for (token, ids) in tokens.iter() {
+        for slot in slots.iter_mut().filter(|s| ids.contains(&s.id)) {
+            slot.tokens.push(token.clone());
+        }
+    }
+
+    return match version {
+        Some(v) => Ok(Header { version: v, slots: slots }),
+        None => generic_error!("No LUKS version in the header dump"),
+    };
+}
+
+/// Print the keyslots of a LUKS device (key derivation and tokens)
+fn print_slots(device: &str) -> error::Return {
+    let header = read_header(device)?;
+
+    log::info!(
+        "Keyslots of `{}` ({}): {} of {} used",
+        device,
+        header.version.to_string(),
+        header.slots.len(),
+        header.version.key_slots());
+
+    log::info!(
+        "{:<5} {:<9} {:<48} {}",
+        "slot",
+        "pbkdf",
+        "parameters",
+        "tokens");
+
+    for slot in header.slots.iter() {
+        let parameters: Vec<String> = slot.parameters
+            .iter()
+            .map(|p| format!("{} {}", p.0.to_lowercase(), p.1))
+            .collect();
+
+        let tokens = match slot.tokens.is_empty() {
+            true => "-".to_string(),
+            false => slot.tokens.join(", "),
+        };
+
+        log::info!(
+            "{:<5} {:<9} {:<48} {}",
+            slot.id,
+            slot.pbkdf,
+            parameters.join(", "),
+            tokens);
+    }
+
+    return Success!();
+}
+
+/// Function used to check that a key file unlocks a LUKS device
+pub fn verify_key(device : &str, key_file : &str) -> error::Return {
+    match utils::command_output(
         "cryptsetup",
         &[
-            "luksAddKey",
+            "open",
+            "--test-passphrase",
+            "--key-file", key_file,
             device,
+        ]) {
+        Ok(_) => (),
+        Err(e) => return generic_error!(&format!(
+            "Key file `{}` does not unlock `{}`: {}",
             key_file,
-            "-"
-        ],
-        Some(passphrase.as_bytes()))?;
+            device,
+            e)),
+    }
+
+    log::info!("Key file `{}` unlocks `{}`", key_file, device);
 
     return Success!();
 }
 
 /// Function used to know if a LUKS device is opened
-fn is_opened(label: &str) -> bool {
+pub fn is_opened(label: &str) -> bool {
     let output = match utils::command_output(
         "cryptsetup",
         &[
@@ -285,10 +887,7 @@ fn is_opened(label: &str) -> bool {
         Err(_) => return false,
     };
 
-    return match stdout.find("is active") {
-        Some(_) => true,
-        None => false,
-    };
+    return stdout.contains("is active");
 }
 
 /// Function used to open a LUKS device
@@ -312,19 +911,227 @@ pub fn open(device : &str, passphrase : &str, label: &str) -> error::Return {
     return Success!();
 }
 
+/// Function used to resize an opened LUKS device to its backing device
+pub fn resize(label: &str) -> error::Return {
+    if !is_opened(label) {
+        return generic_error!(
+            &format!("LUKS `{}` must be opened to be resized", label));
+    }
+
+    utils::command_output("cryptsetup", &["resize", label])?;
+
+    log::info!("LUKS `{}` resized", label);
+
+    return Success!();
+}
+
 /// Function used to close a LUKS device
 pub fn close(label: &str) -> error::Return {
-    match utils::command_output(
+    utils::command_output(
         "cryptsetup",
         &[
             "luksClose",
             &format!("/dev/mapper/{}", label),
-        ]) {
-        Ok(o) => o,
-        Err(e) => return Err(e),
-    };
+        ])?;
 
     log::info!("LUKS `{}` closed", label);
 
     return Success!();
 }
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::sync;
+
+    use super::*;
+
+    #[test]
+    fn format_runs_cryptsetup_with_custom_parameters() {
+        let runner = sync::Arc::new(utils::MockRunner::new(Vec::new()));
+
+        utils::set_runner(runner.clone());
+
+        let config = Config {
+            cipher: "serpent-xts-plain64".to_string(),
+            key_size: 512,
+            hash: "sha256".to_string(),
+            ..Config::default()
+        };
+
+        format("/dev/sda2", "passphrase", &config).unwrap();
+
+        let calls = runner.calls.lock().unwrap();
+
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].0, "cryptsetup");
+        assert_eq!(calls[0].1, [
+            "luksFormat",
+            "-c", "serpent-xts-plain64",
+            "-s", "512",
+            "--type", "luks1",
+            "-h", "sha256",
+            "-q", "/dev/sda2", "-",
+        ]);
+    }
+
+    #[test]
+    fn format_rejects_unsupported_parameters() {
+        let runner = sync::Arc::new(utils::MockRunner::new(Vec::new()));
+
+        utils::set_runner(runner.clone());
+
+        let unsupported = [
+            Config {
+                cipher: "aes-ecb".to_string(),
+                ..Config::default()
+            },
+            Config {
+                key_size: 384,
+                ..Config::default()
+            },
+            Config {
+                hash: "md5".to_string(),
+                ..Config::default()
+            },
+        ];
+
+        for config in unsupported.iter() {
+            assert!(format("/dev/sda2", "passphrase", config).is_err());
+        }
+
+        assert!(runner.calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn luks2_uses_argon2id_without_hash() {
+        let config = Config {
+            version: Some(LuksVersion::Luks2),
+            ..Config::default()
+        };
+
+        let args = format_args("/dev/sda2", &config);
+
+        assert_eq!(
+            args,
+            [
+                "luksFormat",
+                "-c", "aes-xts-plain64",
+                "-s", "256",
+                "--type", "luks2",
+                "--pbkdf", "argon2id",
+                "-q", "/dev/sda2", "-",
+            ]);
+    }
+
+    #[test]
+    fn key_file_in_slot_0_moves_the_passphrase() {
+        let config = Config {
+            key_slot: Some(0),
+            ..Config::default()
+        };
+
+        let args = format_args("/dev/sda2", &config);
+
+        assert!(args.windows(2).any(|a| a == ["--key-slot", "1"]));
+
+        let config = Config {
+            key_slot: Some(8),
+            ..Config::default()
+        };
+
+        assert!(!config.is_valid());
+    }
+
+    #[test]
+    fn parses_luks1_keyslots() {
+        let dump = "LUKS header information for /dev/sda2\n\n\
+            Version:       \t1\n\
+            Cipher name:   \taes\n\
+            Hash spec:     \tsha512\n\
+            MK digest:     \t3c 2f 9a\n\
+            \t\t3c 2f 9a\n\
+            Key Slot 0: ENABLED\n\
+            \tIterations:         \t2000000\n\
+            \tKey material offset:\t8\n\
+            Key Slot 1: DISABLED\n\
+            Key Slot 2: ENABLED\n\
+            \tIterations:         \t1000000\n";
+
+        let header = parse_dump(dump).unwrap();
+
+        assert_eq!(header.version, LuksVersion::Luks1);
+        assert_eq!(
+            header.slots.iter().map(|s| s.id).collect::<Vec<u32>>(),
+            [0, 2]);
+
+        assert_eq!(header.slots[1].pbkdf, "pbkdf2");
+        assert_eq!(
+            header.slots[1].parameters,
+            [
+                ("Hash".to_string(), "sha512".to_string()),
+                ("Iterations".to_string(), "1000000".to_string()),
+            ]);
+
+        assert!(parse_dump("").is_err());
+    }
+
+    #[test]
+    fn parses_luks2_keyslots_and_tokens() {
+        let dump = "LUKS header information\n\
+            Version:       \t2\n\
+            Epoch:         \t4\n\
+            \n\
+            Data segments:\n\
+            \x20 0: crypt\n\
+            \toffset: 16777216 [bytes]\n\
+            Keyslots:\n\
+            \x20 0: luks2\n\
+            \tKey:        512 bits\n\
+            \tPBKDF:      argon2id\n\
+            \tTime cost:  4\n\
+            \tMemory:     1048576\n\
+            \tThreads:    4\n\
+            \x20 3: luks2\n\
+            \tPBKDF:      pbkdf2\n\
+            \tHash:       sha512\n\
+            \tIterations: 1000\n\
+            Tokens:\n\
+            \x20 0: systemd-tpm2\n\
+            \ttpm2-pcrs:  7\n\
+            \tKeyslot:    3\n\
+            Digests:\n\
+            \x20 0: pbkdf2\n\
+            \tKeyslot:    0\n";
+
+        let header = parse_dump(dump).unwrap();
+
+        assert_eq!(header.version, LuksVersion::Luks2);
+        assert_eq!(header.slots.len(), 2);
+
+        assert_eq!(header.slots[0].id, 0);
+        assert_eq!(header.slots[0].pbkdf, "argon2id");
+        assert_eq!(header.slots[0].parameters.len(), 3);
+        assert!(header.slots[0].tokens.is_empty());
+
+        assert_eq!(header.slots[1].id, 3);
+        assert_eq!(header.slots[1].pbkdf, "pbkdf2");
+        assert_eq!(header.slots[1].tokens, ["systemd-tpm2"]);
+    }
+
+    #[test]
+    fn existing_salt_is_not_replaced() {
+        let salt = mktemp::Temp::new_file().unwrap();
+
+        fs::write(&salt, b"salt").unwrap();
+
+        let mut command = Command::new();
+
+        command.salt = salt.to_string_lossy().to_string();
+        command.generate_salt = true;
+
+        assert!(command.create_salt().is_err());
+        assert_eq!(fs::read(&salt).unwrap(), b"salt");
+    }
+}
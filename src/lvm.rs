@@ -1,5 +1,6 @@
 // -----------------------------------------------------------------------------
 
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::path;
 
@@ -10,6 +11,15 @@ use super::utils;
 
 // -----------------------------------------------------------------------------
 
+/// Allocation of a `0`-size volume when no extent is provided
+const DEFAULT_EXTENT: &str = "100%FREE";
+
+/// Setting discarding the space of removed or reduced volumes (in the
+/// generated `lvm.conf`: the volumes are only created here)
+pub const ISSUE_DISCARDS: &str = "devices/issue_discards=1";
+
+// -----------------------------------------------------------------------------
+
 /// Json configuration of a LVM volume
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
@@ -23,6 +33,7 @@ pub struct Config {
     pub volume_type: String,
 
     /// Whether the volume is encrypted or not
+    #[serde(default)]
     pub encrypted: bool,
 
     /// Filesystem type of the volume
@@ -32,10 +43,46 @@ pub struct Config {
     pub label: String,
 
     /// Wether the volume is the root filesystem
+    #[serde(default)]
     pub is_root: bool,
 
     /// Block device of the volume
+    #[serde(default)]
     pub device: Option<String>,
+
+    /// Allocation of a `0`-size volume (e.g. `100%FREE`, `50%VG`)
+    #[serde(default)]
+    pub extent: Option<String>,
+
+    /// Whether the filesystem is grown to the volume size at boot
+    #[serde(default)]
+    pub auto_resize: bool,
+
+    /// Whether this swap volume is used to resume from hibernation
+    #[serde(default)]
+    pub is_resume: bool,
+
+    /// Options appended to the command formatting the volume
+    #[serde(default)]
+    pub mkfs_options: Vec<String>,
+
+    /// Options of the mount of the volume
+    #[serde(default)]
+    pub mount_options: Vec<String>,
+}
+
+impl Config {
+    /// Extent expression passed to `lvcreate -l` for a `0`-size volume
+    pub fn extent(&self) -> &str {
+        return self.extent.as_deref().unwrap_or(DEFAULT_EXTENT);
+    }
+
+    /// Whether the volume takes the rest of the volume group
+    fn is_rest(&self) -> bool {
+        return
+            self.size.is_null() &&
+            extent_percent(self.extent()) == Some(100);
+    }
 }
 
 // -----------------------------------------------------------------------------
@@ -51,11 +98,23 @@ pub struct Lvm {
 
     /// List of logical volumes
     pub volumes: Vec<Volume>,
+
+    /// Whether the space of removed or reduced volumes is discarded (SSD)
+    discard: bool,
 }
 
 impl Lvm {
+    /// Check if the volume group is activated
+    pub fn is_opened(&self) -> bool {
+        return self.opened;
+    }
+
     /// Create a LVM from Json configuration
-    pub fn from_config(lvms : &Vec<Config>, partition_label: &str) -> Self {
+    pub fn from_config(
+        lvms : &Vec<Config>,
+        partition_label: &str,
+        discard: bool) -> Self {
+
         let mut volumes = Vec::new();
 
         for lvm in lvms.iter() {
@@ -66,6 +125,7 @@ impl Lvm {
             volumes: volumes,
             partition_label: partition_label.to_string(),
             opened: false,
+            discard: discard,
         }
     }
 
@@ -86,15 +146,29 @@ impl Lvm {
             return Success!();
         }
 
+        self.check_sizes(gpt::device_size(device)?)?;
+        self.check_discard(device)?;
+
         self.pv_create(device)?;
         self.vg_create(device, label)?;
-        self.volumes_create(label)?;
 
+        // Activated once created: deactivated on close even if a volume fails
         self.opened = true;
 
+        self.volumes_create(label)?;
+
         return Success!();
     }
 
+    /// Identify the volumes created by a previous run
+    pub fn identify_volumes(&mut self) {
+        for volume in self.volumes.iter_mut() {
+            volume.config.device = Some(volume_device(
+                &self.partition_label,
+                &volume.config.label));
+        }
+    }
+
     /// Format volumes of the LVM
     pub fn format_volumes(&self) -> error::Return {
         for volume in self.volumes.iter() {
@@ -104,6 +178,67 @@ impl Lvm {
         return Success!();
     }
 
+    /// Resize the physical volume to the size of its device
+    pub fn pv_resize(&self, device: &str) -> error::Return {
+        utils::command_output("pvresize", &[device])?;
+
+        log::info!("Physical volume resized on `{}`", device);
+
+        return Success!();
+    }
+
+    /// Check that the volumes fit in `available` bytes (fixed sizes are summed
+    /// and only one volume can take the rest of the space)
+    pub fn check_sizes(&self, available: u64) -> error::Return {
+        check_volumes(&self.config()?, &self.partition_label)?;
+
+        let fixed: Vec<&Volume> = self.volumes
+            .iter()
+            .filter(|v| !v.config.size.is_null())
+            .collect();
+
+        let total = fixed
+            .iter()
+            .fold(0u64, |t, v| t.saturating_add(v.config.size.to_bytes()));
+
+        if total > available {
+            let labels: Vec<String> = fixed
+                .iter()
+                .map(|v| format!(
+                    "{} ({})",
+                    v.config.label,
+                    v.config.size.to_string()))
+                .collect();
+
+            return generic_error!(&format!(
+                "Volumes of `{}` exceed its size by {} bytes: {}",
+                self.partition_label,
+                total - available,
+                labels.join(", ")));
+        }
+
+        return Success!();
+    }
+
+    /// Check that discards are only issued on a non-rotational disk (when
+    /// it can be known)
+    fn check_discard(&self, device: &str) -> error::Return {
+        // Nothing has been created
+        if !self.discard || utils::is_dry_run() {
+            return Success!();
+        }
+
+        if gpt::is_rotational(device) == Some(true) {
+            return generic_error!(&format!(
+                "Discards are enabled on `vg-{}` but `{}` is on a rotational \
+                    disk",
+                self.partition_label,
+                device));
+        }
+
+        return Success!();
+    }
+
     /// Create a physical volume
     fn pv_create(&self, device: &str) -> error::Return {
         utils::command_output(
@@ -210,8 +345,8 @@ impl Volume {
         };
 
         let size = match self.config.size.is_null() {
-            false => self.config.size.to_string(),
-            true => "100%FREE".to_string(),
+            false => self.config.size.to_unit_string(),
+            true => self.config.extent().to_string(),
         };
 
         // Create name of the logical volume
@@ -225,7 +360,8 @@ impl Volume {
                 &vg,
             ])?;
 
-        self.config.device = Some(format!("/dev/{}/{}", vg, self.config.label));
+        self.config.device =
+            Some(volume_device(partition_label, &self.config.label));
 
         log::info!(
             "Logical volume created: `{}`",
@@ -234,6 +370,20 @@ impl Volume {
         return Success!();
     }
 
+    /// Extend logical volume to all the free space of its volume group
+    pub fn extend(&self) -> error::Return {
+        let device = match &self.config.device {
+            Some(d) => d,
+            None => return generic_error!("No volume device"),
+        };
+
+        utils::command_output("lvextend", &["-l", "+100%FREE", device])?;
+
+        log::info!("Logical volume extended: `{}`", device);
+
+        return Success!();
+    }
+
     /// Format logical volume
     pub fn format(&self) -> error::Return {
         let device = match &self.config.device {
@@ -242,9 +392,10 @@ impl Volume {
         };
 
         return gpt::format_partition(
-            &device,
+            device,
             &self.config.fs_type,
-            &self.config.label);
+            &self.config.label,
+            &self.config.mkfs_options);
     }
 }
 
@@ -278,7 +429,17 @@ impl Mountable for Volume {
             None => return generic_error!("No mountpoint"),
         };
 
-        utils::command_output("mount", &[device, mountpoint])?;
+        let options = self.config.mount_options.join(",");
+
+        let mut args = Vec::new();
+
+        if !options.is_empty() {
+            args.extend(["-o", &options]);
+        }
+
+        args.extend([device.as_str(), mountpoint]);
+
+        utils::mount(&args)?;
 
         self.mounted = true;
 
@@ -307,3 +468,117 @@ impl Mountable for Volume {
         return Success!();
     }
 }
+
+// -----------------------------------------------------------------------------
+
+/// Get the device of a logical volume
+fn volume_device(partition_label: &str, label: &str) -> String {
+    return format!("/dev/vg-{}/{}", partition_label, label);
+}
+
+/// Check the allocation of the volumes of a volume group (extents are only
+/// allowed for `0`-size volumes and only one volume can take the rest)
+pub fn check_volumes(
+    volumes: &[Config],
+    partition_label: &str) -> error::Return {
+
+    for volume in volumes.iter() {
+        let extent = match &volume.extent {
+            Some(e) => e,
+            None => continue,
+        };
+
+        if !volume.size.is_null() {
+            return generic_error!(&format!(
+                "Volume `{}` has both a size and an extent",
+                volume.label));
+        }
+
+        if extent_percent(extent).is_none() {
+            return generic_error!(&format!(
+                "Invalid extent `{}` of volume `{}` (expected \
+                    <1-100>%FREE|VG|PVS)",
+                extent,
+                volume.label));
+        }
+    }
+
+    let rest: Vec<&str> = volumes
+        .iter()
+        .filter(|v| v.is_rest())
+        .map(|v| v.label.as_str())
+        .collect();
+
+    if rest.len() > 1 {
+        return generic_error!(&format!(
+            "Only one volume can take the rest of `{}`: {}",
+            partition_label,
+            rest.join(", ")));
+    }
+
+    return Success!();
+}
+
+/// Get the percentage of an extent expression (`<percent>%FREE|VG|PVS`)
+fn extent_percent(extent: &str) -> Option<u64> {
+    let re = match Regex::new(r"^([0-9]{1,3})%(FREE|VG|PVS)$") {
+        Ok(r) => r,
+        Err(_) => return None,
+    };
+
+    let percent = match re.captures(extent) {
+        Some(c) => c[1].parse::<u64>().ok()?,
+        None => return None,
+    };
+
+    return match percent {
+        1..=100 => Some(percent),
+        _ => None,
+    };
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn volume(label: &str, size: &str, extent: Option<&str>) -> Config {
+        return Config {
+            id: 0,
+            size: gpt::Bytesize::from(size),
+            volume_type: "linux".to_string(),
+            encrypted: false,
+            fs_type: "ext4".to_string(),
+            label: label.to_string(),
+            is_root: false,
+            device: None,
+            extent: extent.map(|e| e.to_string()),
+            auto_resize: false,
+            is_resume: false,
+            mkfs_options: Vec::new(),
+            mount_options: Vec::new(),
+        };
+    }
+
+    #[test]
+    fn rejects_multiple_rest_volumes() {
+        let volumes = vec![
+            volume("swap", "8G", None),
+            volume("root", "0", None),
+            volume("home", "0", Some("100%FREE")),
+        ];
+
+        let error = check_volumes(&volumes, "system").unwrap_err();
+
+        assert!(error.to_string().ends_with(
+            "Only one volume can take the rest of `system`: root, home"));
+
+        let volumes = vec![
+            volume("root", "0", Some("40%VG")),
+            volume("home", "0", None),
+        ];
+
+        assert!(check_volumes(&volumes, "system").is_ok());
+    }
+}
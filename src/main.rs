@@ -1,41 +1,42 @@
 // -----------------------------------------------------------------------------
 
-use env_logger;
-
-#[macro_use]
-mod error;
-
-mod cli;
-mod disk;
-mod env;
-mod filesystem;
-mod filesystems;
-mod gpt;
-mod hardware;
-//mod initramfs;
-mod install;
-mod luks;
-mod lvm;
-mod partition;
-mod partitioning;
-mod secrets;
-mod traits;
-mod utils;
-mod zfs;
+use std::process;
+
+use nixos_setup::{cli, logger, report, signals};
 
 // -----------------------------------------------------------------------------
 
 fn main() {
+    // Parse command line interface
+    let mut cli = cli::Cli::new();
+
     // Configure logs
-    env_logger::Builder::new()
-        .filter(None, log::LevelFilter::Trace)
-        .format_timestamp(None)
-        .format_module_path(false)
-        .init();
+    match logger::init(cli.log_level(), cli.log_file(), cli.max_log_file()) {
+        Ok(_) => (),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        },
+    }
 
-    // Parse command line interface
-    match cli::parse() {
+    // Handle interruptions
+    signals::install();
+
+    // Execute command
+    let result = cli.process();
+
+    report::warnings();
+
+    match result {
         Ok(_) => log::info!("Finished!"),
-        Err(e) => log::error!("{}", e)
+
+        Err(e) => {
+            match cli.json_errors() {
+                true => eprintln!("{}", e.to_json()),
+                false => log::error!("{}", e),
+            }
+
+            process::exit(1);
+        },
     }
 }
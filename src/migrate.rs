@@ -0,0 +1,310 @@
+// -----------------------------------------------------------------------------
+
+use clap;
+use std::path;
+
+use super::env;
+use super::error;
+use super::filesystem;
+use super::gpt;
+use super::traits::{CliCommand, Validate};
+use super::utils;
+
+// -----------------------------------------------------------------------------
+
+const ARG_HOST: &str = "host";
+
+/// Field of the layout holding its schema version
+const SCHEMA_VERSION_FIELD: &str = "schema_version";
+
+/// Transform upgrading a layout to the next schema version
+type Migration = fn(&mut serde_json::Value);
+
+/// Migrations in version order (the one at index `i` upgrades a layout from
+/// version `i` to version `i + 1`)
+const MIGRATIONS: [Migration; filesystem::SCHEMA_VERSION as usize] = [
+    rest_sizes,
+];
+
+// -----------------------------------------------------------------------------
+
+/// Command structure for upgrading the layouts of a host to the current schema
+#[derive(Debug)]
+pub struct Command {
+    /// Host name
+    host: String,
+}
+
+impl Validate for Command {
+    fn is_valid(&self) -> bool {
+        return !self.host.is_empty();
+    }
+}
+
+impl CliCommand for Command {
+    /// Get the name of the command
+    fn name(&self) -> &'static str {
+        return "migrate";
+    }
+
+    /// Get command and its arguments
+    fn get<'a, 'b>(
+        &self,
+        version: &'b str,
+        author: &'b str) -> clap::App<'a, 'b> {
+
+        return clap::App::new(self.name())
+            .about("Upgrade the layouts of a host (`<host>.in` and `<host>`) \
+                to the current schema, the changes are printed and only \
+                written without --dry-run")
+            .version(version)
+            .author(author)
+            // Host argument
+            .arg(clap::Arg::with_name(ARG_HOST)
+                .long(ARG_HOST)
+                .help("Host name (optional if a .env file is present)")
+                .takes_value(true));
+    }
+
+    /// Process command line arguments
+    fn process(&mut self, matches: &clap::ArgMatches) -> error::Return {
+        // Parse arguments
+        for arg in matches.args.iter() {
+            match arg.0 {
+                &ARG_HOST => {
+                    self.host = match matches.value_of(arg.0) {
+                        Some(s) => s.to_owned(),
+                        None => return inval_error!(&ARG_HOST),
+                    };
+                },
+
+                _ => {
+                    return inval_error!(arg.0);
+                }
+            }
+        }
+
+        if !self.is_valid() {
+            self.fill_with_env()?;
+        }
+
+        log::debug!("{:#?}", self);
+
+        // Check validity
+        if !self.is_valid() {
+            return generic_error!("Invalid configuration");
+        }
+
+        let mut migrated = 0;
+
+        for name in [format!("{}.in", self.host), self.host.clone()].iter() {
+            let layout = filesystem::layout(name)?;
+
+            if !layout.exists() {
+                continue;
+            }
+
+            migrate_file(&layout)?;
+            migrated += 1;
+        }
+
+        if migrated == 0 {
+            return generic_error!(&format!(
+                "No layout found for host `{}`",
+                self.host));
+        }
+
+        return Success!();
+    }
+}
+
+impl Command {
+    /// Create an instance of Command
+    pub fn new() -> Self {
+        Self {
+            host: "".to_string(),
+        }
+    }
+
+    /// Use environment file to get needed values
+    fn fill_with_env(&mut self) -> error::Return {
+        env::read_for_host(&mut self.host)?;
+
+        return Success!();
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Upgrade a layout file (Json or Toml) and print the changes
+fn migrate_file(layout: &path::Path) -> error::Return {
+    let old: serde_json::Value = match utils::is_toml(layout) {
+        true => utils::load_toml(layout)?,
+        false => utils::load_json(layout)?,
+    };
+
+    let new = migrate(&old)?;
+    let changes = utils::json_diff(&old, &new);
+
+    if changes.is_empty() {
+        log::info!("{:?} is already at schema version {}",
+            layout,
+            filesystem::SCHEMA_VERSION);
+
+        return Success!();
+    }
+
+    log::info!("Layout changes in {:?}", layout);
+
+    for change in changes.iter() {
+        log::info!("  {}", change);
+    }
+
+    let content = match utils::is_toml(layout) {
+        true => utils::toml_to_string(&new)?,
+        false => utils::json_to_string(&new)?,
+    };
+
+    utils::write_to_file(content.as_bytes(), layout)?;
+
+    return Success!();
+}
+
+/// Get the schema version of a layout
+fn schema_version(layout: &serde_json::Value) -> Result<u64, error::Error> {
+    let version = match layout.get(SCHEMA_VERSION_FIELD) {
+        Some(v) => v,
+        None => return Ok(0),
+    };
+
+    let version = match version.as_u64() {
+        Some(v) => v,
+        None => return generic_error!(&format!(
+            "Invalid schema version: {}",
+            version)),
+    };
+
+    if version > filesystem::SCHEMA_VERSION {
+        return generic_error!(&format!(
+            "Schema version {} is newer than the supported one ({}), \
+                please upgrade nixos-setup",
+            version,
+            filesystem::SCHEMA_VERSION));
+    }
+
+    return Ok(version);
+}
+
+/// Apply the migrations a layout misses and stamp the current schema version
+pub fn migrate(layout: &serde_json::Value)
+    -> Result<serde_json::Value, error::Error> {
+
+    if !layout.is_object() {
+        return generic_error!("The layout is not an object");
+    }
+
+    let mut layout = layout.clone();
+
+    for migration in MIGRATIONS[schema_version(&layout)? as usize..].iter() {
+        migration(&mut layout);
+    }
+
+    layout[SCHEMA_VERSION_FIELD] =
+        serde_json::Value::from(filesystem::SCHEMA_VERSION);
+
+    return Ok(layout);
+}
+
+// -----------------------------------------------------------------------------
+
+/// Version 1: partitions and volumes taking the rest of the space are sized
+/// `rest` instead of `0`
+fn rest_sizes(layout: &mut serde_json::Value) {
+    let disks = match layout.get_mut("disks").and_then(|d| d.as_array_mut()) {
+        Some(d) => d,
+        None => return,
+    };
+
+    for disk in disks.iter_mut() {
+        let partitions = disk
+            .get_mut("partitions")
+            .and_then(|p| p.as_array_mut());
+
+        let partitions = match partitions {
+            Some(p) => p,
+            None => continue,
+        };
+
+        for partition in partitions.iter_mut() {
+            rest_size(partition);
+
+            let volumes = partition
+                .get_mut("lvm")
+                .and_then(|v| v.as_array_mut());
+
+            let volumes = match volumes {
+                Some(v) => v,
+                None => continue,
+            };
+
+            for volume in volumes.iter_mut() {
+                rest_size(volume);
+            }
+        }
+    }
+}
+
+/// Write a null size (e.g. `0`, `0M`) of a partition or a volume as `rest`
+fn rest_size(entry: &mut serde_json::Value) {
+    let size = match entry.get("size").and_then(|s| s.as_str()) {
+        Some(s) => s,
+        None => return,
+    };
+
+    if size.starts_with('0') && gpt::Bytesize::from(size).is_null() {
+        entry["size"] = serde_json::Value::from(gpt::SIZE_REST);
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn old_layouts_are_migrated_once() {
+        let old = serde_json::json!({
+            "disks": [{
+                "device": "/dev/sda",
+                "partitions": [
+                    { "id": 1, "size": "512M" },
+                    {
+                        "id": 2,
+                        "size": "0",
+                        "lvm": [
+                            { "id": 1, "size": "8G" },
+                            { "id": 2, "size": "0M" }
+                        ]
+                    }
+                ]
+            }]
+        });
+
+        let new = migrate(&old).unwrap();
+
+        assert_eq!(utils::json_diff(&old, &new), [
+            "~ disks[0].partitions[1].size: \"0\" -> \"rest\"",
+            "~ disks[0].partitions[1].lvm[1].size: \"0M\" -> \"rest\"",
+            "+ schema_version: 1",
+        ]);
+
+        assert!(utils::json_diff(&new, &migrate(&new).unwrap()).is_empty());
+
+        let mut newer = new.clone();
+        newer["schema_version"] = serde_json::Value::from(
+            filesystem::SCHEMA_VERSION + 1);
+
+        assert!(migrate(&newer).is_err());
+    }
+}
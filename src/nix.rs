@@ -0,0 +1,349 @@
+// -----------------------------------------------------------------------------
+
+use super::disk;
+use super::error;
+use super::filesystem;
+use super::partition;
+use super::raid;
+
+// -----------------------------------------------------------------------------
+
+/// Header of the generated files
+const HEADER: &str = "# Auto-generated, do not edit !";
+
+/// Default indentation width of the generated files
+pub const DEFAULT_INDENT: usize = 2;
+
+/// Supported formatters of the generated files
+pub const FORMATTERS: &[&str] = &["alejandra", "nixfmt"];
+
+// -----------------------------------------------------------------------------
+
+/// Nix value
+#[derive(Clone, Debug)]
+pub enum Value {
+    /// Boolean
+    Bool(bool),
+
+    /// Integer
+    Int(i64),
+
+    /// String (escaped when rendered)
+    String(String),
+
+    /// Expression rendered as is (paths, function calls, ...)
+    Raw(String),
+
+    /// List (strings, booleans and integers are rendered on one line)
+    List(Vec<Value>),
+
+    /// Attribute set
+    AttrSet(AttrSet),
+
+    /// Concatenation of lists (`++`)
+    Concat(Vec<Value>),
+}
+
+impl Value {
+    /// Render the value at provided indentation level
+    fn render(&self, level: usize, indent: &str) -> String {
+        return match self {
+            Value::Bool(b) => b.to_string(),
+            Value::Int(i) => i.to_string(),
+            Value::String(s) => quote(s),
+            Value::Raw(r) => r.clone(),
+            Value::List(l) => render_list(l, level, indent),
+            Value::AttrSet(a) => a.render(level, indent),
+            Value::Concat(c) => c
+                .iter()
+                .map(|v| v.render(level, indent))
+                .collect::<Vec<String>>()
+                .join(" ++ "),
+        };
+    }
+
+    /// Check if the value is rendered on one line
+    fn is_inline(&self) -> bool {
+        return matches!(
+            self,
+            Value::Bool(_) | Value::Int(_) | Value::String(_));
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        return Value::Bool(b);
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        return Value::Int(i);
+    }
+}
+
+impl From<i32> for Value {
+    fn from(i: i32) -> Self {
+        return Value::Int(i as i64);
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        return Value::String(s.to_string());
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        return Value::String(s);
+    }
+}
+
+impl From<AttrSet> for Value {
+    fn from(a: AttrSet) -> Self {
+        return Value::AttrSet(a);
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(l: Vec<Value>) -> Self {
+        return Value::List(l);
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Entry of an attribute set
+#[derive(Clone, Debug)]
+enum Entry {
+    /// Attribute (path and value)
+    Attr(String, Value),
+
+    /// Empty line separating groups of attributes
+    Blank,
+}
+
+/// Attribute set builder
+#[derive(Clone, Debug, Default)]
+pub struct AttrSet {
+    /// Entries in insertion order
+    entries: Vec<Entry>,
+}
+
+impl AttrSet {
+    /// Create an empty attribute set
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Add an attribute (the path is inserted as is, see `quote` for keys)
+    pub fn set(mut self, path: &str, value: impl Into<Value>) -> Self {
+        self.push(path, value);
+        return self;
+    }
+
+    /// Add an empty line
+    pub fn blank(mut self) -> Self {
+        self.push_blank();
+        return self;
+    }
+
+    /// Add an attribute (the path is inserted as is, see `quote` for keys)
+    pub fn push(&mut self, path: &str, value: impl Into<Value>) {
+        self.entries.push(Entry::Attr(path.to_string(), value.into()));
+    }
+
+    /// Add an empty line
+    pub fn push_blank(&mut self) {
+        self.entries.push(Entry::Blank);
+    }
+
+    /// Check if the attribute set has no attribute
+    pub fn is_empty(&self) -> bool {
+        return !self.entries.iter().any(|e| matches!(e, Entry::Attr(..)));
+    }
+
+    /// Render the attribute set at provided indentation level (empty lines at
+    /// the boundaries or repeated are dropped)
+    fn render(&self, level: usize, indent: &str) -> String {
+        let prefix = indent.repeat(level + 1);
+
+        let mut lines: Vec<String> = Vec::new();
+        let mut blank = false;
+
+        for entry in self.entries.iter() {
+            match entry {
+                Entry::Attr(path, value) => {
+                    if blank && !lines.is_empty() {
+                        lines.push("".to_string());
+                    }
+
+                    blank = false;
+
+                    lines.push(format!(
+                        "{}{} = {};",
+                        prefix,
+                        path,
+                        value.render(level + 1, indent)));
+                },
+
+                Entry::Blank => blank = true,
+            }
+        }
+
+        if lines.is_empty() {
+            return "{ }".to_string();
+        }
+
+        return format!(
+            "{{\n{}\n{}}}",
+            lines.join("\n"),
+            indent.repeat(level));
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Backend emitting Nix code from a layout (the disks, partitions and arrays
+/// are provided by `traverse`)
+pub trait Backend {
+    /// Emit a disk (called before its partitions)
+    fn disk(&mut self, disk: &disk::Disk) -> error::Return;
+
+    /// Emit a partition of a disk
+    fn partition(
+        &mut self,
+        disk: &disk::Disk,
+        partition: &partition::Partition) -> error::Return;
+
+    /// Emit a software RAID array (called after the disks)
+    fn array(&mut self, array: &raid::Array) -> error::Return;
+}
+
+/// Provide the disks, their partitions and the arrays of a layout to a
+/// backend (in the order of the layout)
+pub fn traverse(
+    fs: &filesystem::Filesystem,
+    backend: &mut impl Backend) -> error::Return {
+
+    for disk in fs.disks.iter() {
+        backend.disk(disk)?;
+
+        for partition in disk.partitions.iter() {
+            backend.partition(disk, partition)?;
+        }
+    }
+
+    for array in fs.raids.iter() {
+        backend.array(array)?;
+    }
+
+    return Success!();
+}
+
+// -----------------------------------------------------------------------------
+
+/// Render a NixOS module (with the auto-generated header) indented with
+/// provided number of spaces
+pub fn module(args: &[&str], body: &AttrSet, indent: usize) -> String {
+    let mut args = args.to_vec();
+    args.push("...");
+
+    return format!(
+        "{}\n{{ {} }}:\n\n{}\n",
+        HEADER,
+        args.join(", "),
+        body.render(0, &" ".repeat(indent)));
+}
+
+/// Create a list of strings
+pub fn strings(items: &[&str]) -> Value {
+    return Value::List(items.iter().map(|i| Value::from(*i)).collect());
+}
+
+/// Quote an attribute name (always quoted, so that any label, device or
+/// mount point is a valid name)
+pub fn attr_name(name: &str) -> String {
+    return quote(name);
+}
+
+/// Quote and escape a string
+fn quote(s: &str) -> String {
+    return format!("\"{}\"", escape(s));
+}
+
+/// Escape a string for a double-quoted Nix string (quotes, backslashes,
+/// control characters and interpolations)
+fn escape(s: &str) -> String {
+    let mut escaped = String::new();
+
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => escaped += "\\\"",
+            '\\' => escaped += "\\\\",
+            '\n' => escaped += "\\n",
+            '\r' => escaped += "\\r",
+            '\t' => escaped += "\\t",
+            '$' if chars.peek() == Some(&'{') => escaped += "\\$",
+            _ => escaped.push(c),
+        }
+    }
+
+    return escaped;
+}
+
+/// Render a list (on one line if it only contains simple values)
+fn render_list(list: &[Value], level: usize, indent: &str) -> String {
+    if list.is_empty() {
+        return "[ ]".to_string();
+    }
+
+    if list.iter().all(|v| v.is_inline()) {
+        let items = list
+            .iter()
+            .map(|v| v.render(0, indent))
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        return format!("[ {} ]", items);
+    }
+
+    let prefix = indent.repeat(level + 1);
+
+    let items = list
+        .iter()
+        .map(|v| format!("{}{}", prefix, v.render(level + 1, indent)))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    return format!("[\n{}\n{}]", items, indent.repeat(level));
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_strings_and_attribute_names() {
+        assert_eq!(escape("my\"disk"), "my\\\"disk");
+        assert_eq!(escape("a${b}"), "a\\${b}");
+        assert_eq!(escape("a$b \\ c\n"), "a$b \\\\ c\\n");
+
+        assert_eq!(attr_name("my\"disk"), "\"my\\\"disk\"");
+        assert_eq!(attr_name("/mnt/a${b}"), "\"/mnt/a\\${b}\"");
+
+        let content = AttrSet::new().set(
+            &format!("fileSystems.{}", attr_name("/a${b}")),
+            AttrSet::new().set("label", "my\"disk"));
+
+        assert_eq!(
+            content.render(0, "  "),
+            "{\n  fileSystems.\"/a\\${b}\" = {\n    \
+            label = \"my\\\"disk\";\n  };\n}");
+    }
+}
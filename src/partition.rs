@@ -4,17 +4,50 @@ use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::path;
 use std::str::FromStr;
+use std::time;
 
+use super::btrfs;
 use super::error;
 use super::gpt;
 use super::luks;
 use super::lvm;
+use super::plan;
+use super::report;
 use super::traits::{Configurable, Mountable, Openable, Validate};
 use super::utils;
 use super::zfs;
 
 // -----------------------------------------------------------------------------
 
+/// Minimal recommended size of an EFI partition
+const EFI_MIN_SIZE: u64 = 256 << 20;
+
+/// Default boundary the created partitions are checked to start on
+pub const DEFAULT_ALIGNMENT_BOUNDARY: u64 = 1 << 20;
+
+/// Default time to wait for the device of a created partition (in seconds)
+pub const DEFAULT_DISK_TIMEOUT: u64 = 10;
+
+/// Mount point of the EFI partition of the system disk (by default)
+pub const EFI_MOUNTPOINT: &str = "/boot/efi";
+
+// -----------------------------------------------------------------------------
+
+/// Get the number of bytes an offset is past an alignment boundary
+fn misalignment(offset: u64, boundary: u64) -> u64 {
+    return match boundary {
+        0 => 0,
+        b => offset % b,
+    };
+}
+
+/// Get the boot directory of a mirrored EFI partition (mounted in `efi`)
+pub fn mirrored_boot_path(label: &str) -> String {
+    return format!("/boot-{}", label);
+}
+
+// -----------------------------------------------------------------------------
+
 /// Json configuration of a partition
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config{
@@ -28,6 +61,7 @@ pub struct Config{
     pub partition_type: String,
 
     /// Whether the partition is encrypted or not
+    #[serde(default)]
     pub encrypted: bool,
 
     /// Type of filesystem of the partition
@@ -37,31 +71,128 @@ pub struct Config{
     pub label: String,
 
     /// Whether this partition hosts the Linux system
+    #[serde(default)]
     pub is_system: bool,
 
     /// Whether this partition is the root mount point
+    #[serde(default)]
     pub is_root: bool,
 
     /// LVM configuration
+    #[serde(default)]
     pub lvm: Vec<lvm::Config>,
 
     /// ZFS filesystems
+    #[serde(default)]
     pub zfs: Vec<zfs::Config>,
 
     /// Block device of this partition
+    #[serde(default)]
     pub device: Option<String>,
 
     /// Name of the block device
+    #[serde(default)]
     pub device_name: Option<String>,
 
     /// Block device of this partition (by id)
+    #[serde(default)]
     pub device_by_id: Option<String>,
     
     /// Block device of this partition (by partlabel)
+    #[serde(default)]
     pub device_by_partlabel: Option<String>,
 
     /// Mapper device for LUKS partition
+    #[serde(default)]
     pub luks_mapper: Option<String>,
+
+    /// LUKS format parameters (defaults are used if not provided)
+    #[serde(default)]
+    pub luks: Option<luks::Config>,
+
+    /// ZFS pool compatibility (`grub2` by default if the pool holds the root)
+    #[serde(default)]
+    pub zfs_compatibility: Option<String>,
+
+    /// Sector size exponent of the ZFS pool (`12` by default)
+    #[serde(default)]
+    pub zfs_ashift: Option<u32>,
+
+    /// Whether the ZFS pool is natively encrypted (with the passphrase,
+    /// instead of LUKS)
+    #[serde(default)]
+    pub zfs_encrypted: bool,
+
+    /// Btrfs subvolumes
+    #[serde(default)]
+    pub btrfs: Vec<btrfs::Config>,
+
+    /// Whether the filesystem is grown to the partition size at boot
+    #[serde(default)]
+    pub auto_resize: bool,
+
+    /// Whether this swap partition is used to resume from hibernation
+    #[serde(default)]
+    pub is_resume: bool,
+
+    /// Mount point of an EFI partition (`/boot/efi` on the system disk and
+    /// `/boot-<label>/efi` for the mirrors by default)
+    #[serde(default)]
+    pub efi_mountpoint: Option<String>,
+
+    /// Whether the volume group discards the space of removed or reduced
+    /// volumes (`issue_discards`, SSD only)
+    #[serde(default)]
+    pub lvm_discard: bool,
+
+    /// Options appended to the command formatting the partition (e.g. ext4
+    /// `-O` features)
+    #[serde(default)]
+    pub mkfs_options: Vec<String>,
+
+    /// Options of the mount of the partition (e.g. `noatime`)
+    #[serde(default)]
+    pub mount_options: Vec<String>,
+}
+
+impl Config {
+    /// Get the mount point of an EFI partition (on the system disk or not)
+    pub fn efi_mountpoint(&self, is_system: bool) -> String {
+        return match (&self.efi_mountpoint, is_system) {
+            (Some(m), _) => m.clone(),
+            (None, true) => EFI_MOUNTPOINT.to_string(),
+            (None, false) => {
+                format!("{}/efi", mirrored_boot_path(&self.label))
+            },
+        };
+    }
+
+    /// Check if the partition only holds data, not needed to boot (no root,
+    /// system, swap, volumes, pool or array member)
+    pub fn is_data(&self) -> bool {
+        let holds_root =
+            self.is_root ||
+            self.zfs.iter().any(|f| f.is_root) ||
+            self.btrfs.iter().any(|s| s.is_root);
+
+        if self.is_system || holds_root {
+            return false;
+        }
+
+        match gpt::PartitionType::from_str(&self.partition_type) {
+            Ok(gpt::PartitionType::Linux) => (),
+            _ => return false,
+        }
+
+        return match gpt::FsType::from_str(&self.fs_type) {
+            Ok(gpt::FsType::Lvm) |
+            Ok(gpt::FsType::Raid) |
+            Ok(gpt::FsType::Swap) |
+            Ok(gpt::FsType::Zfs) => false,
+            Ok(_) => true,
+            Err(_) => false,
+        };
+    }
 }
 
 impl Validate for Config{
@@ -84,10 +215,190 @@ impl Validate for Config{
             return false;
         }
 
+        // Checked before partitioning as mkfs.xfs would fail afterwards
+        if self.fs_type == "xfs" && self.label.len() > 12 {
+            log::error!(
+                "XFS label `{}` is longer than 12 characters",
+                self.label);
+
+            return false;
+        }
+
+        match &self.luks {
+            Some(l) if !l.is_valid() => return false,
+            _ => (),
+        }
+
+        match &self.zfs_compatibility {
+            Some(c) if c.is_empty() || self.fs_type != "zfs" => return false,
+            _ => (),
+        }
+
+        if self.zfs_ashift.is_some() && self.fs_type != "zfs" {
+            log::error!(
+                "`{}` can only set `zfs_ashift` as a ZFS pool",
+                self.label);
+
+            return false;
+        }
+
+        if self.zfs_encrypted && (self.fs_type != "zfs" || self.encrypted) {
+            log::error!(
+                "`{}` can only use ZFS native encryption as a pool not \
+                encrypted with LUKS",
+                self.label);
+
+            return false;
+        }
+
+        // Pools, volume groups and members aren't formatted by mkfs
+        if !self.mkfs_options.is_empty() &&
+            ["lvm", "raid", "zfs"].contains(&self.fs_type.as_str()) {
+
+            log::error!(
+                "`{}` cannot have mkfs options as a {} partition",
+                self.label,
+                self.fs_type);
+
+            return false;
+        }
+
+        if self.lvm_discard && self.fs_type != "lvm" {
+            log::error!(
+                "`{}` can only discard as a LVM volume group",
+                self.label);
+
+            return false;
+        }
+
+        if !self.btrfs.is_empty() && self.fs_type != "btrfs" {
+            return false;
+        }
+
+        for subvolume in self.btrfs.iter() {
+            if !subvolume.is_valid() {
+                return false;
+            }
+        }
+
+        match &self.efi_mountpoint {
+            Some(m) if self.partition_type != "efi" ||
+                !m.starts_with('/') ||
+                m.split('/').any(|c| c == "..") ||
+                m == "/" ||
+                m == "/boot" => {
+
+                log::error!(
+                    "Invalid EFI mount point `{}` of `{}`",
+                    m,
+                    self.label);
+
+                return false;
+            },
+
+            _ => (),
+        }
+
         return true;
     }
 }
 
+/// Check that the filesystem of a partition makes sense for its type and its
+/// role (rejected at load, before anything is destroyed)
+pub fn check_types(config: &Config) -> error::Return {
+    let partition_type = gpt::PartitionType::from_str(&config.partition_type)?;
+    let fs_type = gpt::FsType::from_str(&config.fs_type)?;
+
+    let holds_system = config.is_root || config.is_system;
+
+    // Only one of them would be created on the partition
+    if !config.lvm.is_empty() && !config.zfs.is_empty() {
+        return generic_error!(&format!(
+            "`{}` declares both LVM volumes and ZFS filesystems (at most one \
+            of them)",
+            config.label));
+    }
+
+    // Volumes are encrypted through the LUKS device of their partition
+    match config.lvm.iter().find(|v| v.encrypted) {
+        Some(v) => return generic_error!(&format!(
+            "Volume `{}` of `{}` cannot be encrypted on its own (encrypt the \
+            partition instead)",
+            v.label,
+            config.label)),
+        None => (),
+    }
+
+    let reason = match (partition_type, fs_type) {
+        (gpt::PartitionType::Efi, gpt::FsType::Fat32) if config.encrypted => {
+            Some("an EFI partition is read by the firmware, it cannot be \
+                encrypted")
+        },
+
+        (gpt::PartitionType::Efi, gpt::FsType::Fat32) if holds_system => {
+            Some("an EFI partition cannot hold the system")
+        },
+
+        (gpt::PartitionType::Efi, gpt::FsType::Fat32) => None,
+
+        (gpt::PartitionType::Efi, _) => {
+            Some("an EFI partition must be formatted as fat32")
+        },
+
+        (_, gpt::FsType::Fat32) if holds_system => {
+            Some("fat32 cannot hold the system (the ESP must be of type efi)")
+        },
+
+        (_, gpt::FsType::Zfs) if config.zfs.is_empty() => {
+            Some("its ZFS pool has no filesystem")
+        },
+
+        (_, gpt::FsType::Lvm) if config.lvm.is_empty() => {
+            Some("its volume group has no volume")
+        },
+
+        (_, gpt::FsType::Lvm) |
+        (_, gpt::FsType::Raid) |
+        (_, gpt::FsType::Swap) |
+        (_, gpt::FsType::Zfs) if config.is_root => {
+            Some("it cannot be mounted as the root (set it on the volume, \
+                the filesystem or the array instead)")
+        },
+
+        (_, gpt::FsType::Swap) if config.is_system => {
+            Some("a swap cannot hold the system")
+        },
+
+        _ => None,
+    };
+
+    match reason {
+        Some(r) => return generic_error!(&format!(
+            "Invalid partition `{}` (type {}, fs_type {}): {}",
+            config.label,
+            config.partition_type,
+            config.fs_type,
+            r)),
+        None => (),
+    }
+
+    if !config.zfs.is_empty() && config.fs_type != "zfs" {
+        return generic_error!(&format!(
+            "`{}` has ZFS filesystems but fs_type {}",
+            config.label,
+            config.fs_type));
+    }
+
+    if !config.lvm.is_empty() && config.fs_type != "lvm" {
+        return generic_error!(&format!(
+            "`{}` has LVM volumes but fs_type {}",
+            config.label,
+            config.fs_type));
+    }
+
+    return Success!();
+}
+
 // -----------------------------------------------------------------------------
 
 /// Partition representation
@@ -107,23 +418,120 @@ pub struct Partition {
 
     /// ZFS filesystems
     pub zfs: zfs::Filesystems,
+
+    /// Btrfs subvolumes
+    pub btrfs: btrfs::Subvolumes,
+
+    /// Whether an occupied keyslot of the key file can be wiped
+    pub kill_key_slot: bool,
+
+    /// Time to wait for the device nodes of the partition (in seconds)
+    pub disk_timeout: u64,
+
+    /// Boundary the start of the created partition is checked against (in
+    /// bytes, not checked if none)
+    pub alignment_boundary: Option<u64>,
+
+    /// Whether the existing partition has been kept (see `--resume`): it's
+    /// not formatted again
+    pub kept: bool,
 }
 
 impl Partition {
+    /// Check if an existing partition of the disk matches the layout (label,
+    /// type and size, any size for a partition taking the rest of the disk)
+    pub fn matches(
+        &self,
+        device: &str,
+        number: u32) -> Result<bool, error::Error> {
+
+        let info = match gpt::partition_info(device, number)? {
+            Some(i) => i,
+            None => return Ok(false),
+        };
+
+        let partition_type =
+            gpt::PartitionType::from_str(&self.config.partition_type)?;
+
+        return Ok(
+            info.name == self.config.label &&
+            info.type_guid == partition_type.to_gpt_guid() &&
+            (self.config.size.is_null() ||
+                info.size == self.config.size.to_bytes()));
+    }
+
+    /// Keep the existing partition matching the layout (identified with its
+    /// content but not created nor formatted)
+    pub fn keep(&mut self, device: &str) -> error::Return {
+        log::info!("Partition `{}` kept", self.config.label);
+
+        self.kept = true;
+
+        self.identify_created(device)?;
+        self.identify_content();
+
+        return Success!();
+    }
+
+    /// Identify the content formatted by a previous run (ZFS datasets are
+    /// only named after their pool)
+    fn identify_content(&mut self) {
+        if self.lvm.is_valid() {
+            self.lvm.identify_volumes();
+        }
+
+        match filesystem_device(&self.config) {
+            Some(d) => self.btrfs.identify(d),
+            None => (),
+        }
+    }
+
+    /// Identify the partition created by a previous run (its formatting
+    /// steps are run unless completed too)
+    pub fn identify_existing(&mut self, device: &str) -> error::Return {
+        return self.identify_created(device);
+    }
+
     /// Create partition
     pub fn create(&mut self, device: &str) -> error::Return {
+        let partition_type =
+            gpt::PartitionType::from_str(&self.config.partition_type)?;
+
+        self.check_efi_size(&partition_type);
+
+        // Align ZFS partitions on the pool sectors
+        let alignment = match gpt::FsType::from_str(&self.config.fs_type)? {
+            gpt::FsType::Zfs => zfs::partition_alignment(self.zfs_ashift())?,
+            _ => gpt::DEFAULT_ALIGNMENT,
+        };
+
+        log::info!(
+            "Partition `{}` aligned on {} sectors",
+            self.config.label,
+            alignment);
+
         // Create
         gpt::create_partition(
             device,
             &self.config.size,
-            &gpt::PartitionType::from_str(&self.config.partition_type)?,
-            &self.config.label)?;
+            &partition_type,
+            &self.config.label,
+            alignment)?;
+
+        self.identify_created(device)?;
+        self.check_region();
+        self.check_zfs_start()?;
+
+        return Success!();
+    }
 
-        // Identify partition device
-        self.identify(device)?;
+    /// Identify the devices of the partition once it exists
+    fn identify_created(&mut self, device: &str) -> error::Return {
+        // Identify partition device (nodes can take time to appear)
+        self.retry_identify(|p| p.identify(device))?;
 
         // Identify partition id
-        self.identify_id()?;
+        self.retry_identify(|p| p.identify_id())?;
 
         // Set LUKS mapper (if needed)
         if self.config.encrypted {
@@ -134,15 +542,82 @@ impl Partition {
         return Success!();
     }
 
-    /// Format partition
-    pub fn format(
+    /// Get the action formatting the partition (none for RAID members, which
+    /// are formatted by mdadm when the array is created)
+    pub fn format_action(&self) -> Option<plan::Action> {
+        if self.lvm.is_valid() {
+            return Some(plan::Action::CreateVolumeGroup);
+        }
+
+        return match self.config.fs_type.as_str() {
+            "zfs" => Some(plan::Action::CreatePool),
+            "raid" => None,
+            _ => Some(plan::Action::Format),
+        };
+    }
+
+    /// Run a formatting step of the plan
+    pub fn run(
         &mut self,
+        action: plan::Action,
         key_file: &str,
         passphrase: &str) -> error::Return {
 
-        // LUKS initialize
-        self.luks_format(passphrase, key_file)?;
+        // Formatted by a previous run
+        if self.kept {
+            return Success!();
+        }
+
+        return match action {
+            plan::Action::LuksFormat => self.luks_format(passphrase, key_file),
+            plan::Action::CreateVolumeGroup |
+            plan::Action::CreatePool |
+            plan::Action::Format => {
+                // Initialized by a previous run
+                self.open_luks(passphrase)?;
+                self.format(passphrase)
+            },
+            _ => generic_error!(&format!(
+                "Invalid step for partition `{}`",
+                self.config.label)),
+        };
+    }
+
+    /// Skip a formatting step of the plan completed by a previous run (the
+    /// created volumes are identified)
+    pub fn skip(&mut self, action: plan::Action) -> error::Return {
+        log::info!(
+            "Step `{}` of `{}` already completed",
+            action.name(),
+            self.config.label);
+
+        if action != plan::Action::LuksFormat {
+            self.identify_content();
+        }
+
+        return Success!();
+    }
+
+    /// Open the LUKS device initialized by a previous run
+    fn open_luks(&mut self, passphrase: &str) -> error::Return {
+        if !self.config.encrypted || self.opened {
+            return Success!();
+        }
 
+        let device = match &self.config.device_by_id {
+            Some(d) => d,
+            None => return generic_error!("No device for partition"),
+        };
+
+        luks::open(device, passphrase, &self.config.label)?;
+
+        self.opened = true;
+
+        return Success!();
+    }
+
+    /// Format partition (once its LUKS device is initialized)
+    fn format(&mut self, passphrase: &str) -> error::Return {
         // Get device regarding encryption
         let device = match self.config.encrypted {
             false => self.config.device_by_id.as_ref().unwrap().clone(),
@@ -156,11 +631,38 @@ impl Partition {
                 self.lvm.format_volumes()?;
             },
 
-            false => {
-                gpt::format_partition(
-                    &device,
-                    &self.config.fs_type,
-                    &self.config.label)?;
+            false => match gpt::FsType::from_str(&self.config.fs_type)? {
+                gpt::FsType::Zfs => {
+                    let zfs_passphrase = match self.config.zfs_encrypted {
+                        true => Some(passphrase),
+                        false => None,
+                    };
+
+                    // GRUB reads `/boot` from the pool holding the root
+                    if self.config.zfs_encrypted &&
+                        self.config.zfs.iter().any(|fs| fs.is_root) {
+
+                        report::warning(&format!(
+                            "Pool `{}` holds the root but is natively \
+                            encrypted, GRUB cannot read it",
+                            self.config.label));
+                    }
+
+                    gpt::format_zfs(
+                        &device,
+                        &self.config.label,
+                        self.zfs_ashift(),
+                        self.zfs_compatibility().as_deref(),
+                        zfs_passphrase)?;
+                },
+
+                _ => {
+                    gpt::format_partition(
+                        &device,
+                        &self.config.fs_type,
+                        &self.config.label,
+                        &self.config.mkfs_options)?;
+                },
             },
         }
 
@@ -169,38 +671,178 @@ impl Partition {
             self.zfs.create()?;
         }
 
+        // Btrfs subvolumes
+        if self.btrfs.is_valid() {
+            self.btrfs.create(&device)?;
+        }
+
         return Success!();
     }
 
-    /// Identify the block device of this partition
-    fn identify(&mut self, device: &str) -> error::Return {
-        // Run command
-        let output = utils::command_output("fdisk", &["-l", device])?;
+    /// Get the sector size exponent of the ZFS pool
+    pub fn zfs_ashift(&self) -> u32 {
+        return self.config.zfs_ashift.unwrap_or(zfs::ASHIFT);
+    }
 
-        let stdout = utils::command_stdout_to_string(&output)?;
+    /// Get the compatibility of the ZFS pool: GRUB must be able to read the
+    /// pool holding the root (and thus `/boot`)
+    pub fn zfs_compatibility(&self) -> Option<String> {
+        let is_root_pool = self.config.zfs.iter().any(|fs| fs.is_root);
 
-        // Search partition
-        let pattern = format!(r"({}[^ ]*{})", device, self.config.id);
+        return match (&self.config.zfs_compatibility, is_root_pool) {
+            (Some(c), true) if c != zfs::GRUB_COMPATIBILITY => {
+                report::warning(&format!(
+                    "Pool `{}` holds the root but uses compatibility `{}`, \
+                    GRUB may not be able to read it",
+                    self.config.label,
+                    c));
 
-        let re = match Regex::new(&pattern) {
+                Some(c.clone())
+            },
+
+            (Some(c), _) => Some(c.clone()),
+            (None, true) => Some(zfs::GRUB_COMPATIBILITY.to_string()),
+            (None, false) => None,
+        };
+    }
+
+    /// Warn if the EFI partition is too small
+    fn check_efi_size(&self, partition_type: &gpt::PartitionType) {
+        match partition_type {
+            gpt::PartitionType::Efi => (),
+            _ => return,
+        }
+
+        if self.config.size.is_null() {
+            return;
+        }
+
+        if self.config.size.to_bytes() < EFI_MIN_SIZE {
+            report::warning(&format!(
+                "EFI partition `{}` is smaller than 256M ({})",
+                self.config.label,
+                self.config.size.to_string()));
+        }
+    }
+
+    /// Log the region of the disk the created partition actually occupies
+    /// and warn if it doesn't start on the alignment boundary (only logged: an
+    /// unknown region doesn't prevent the partitioning)
+    fn check_region(&self) {
+        // Nothing has been created
+        if utils::is_dry_run() {
+            return;
+        }
+
+        let device = match &self.config.device {
+            Some(d) => d,
+            None => return,
+        };
+
+        let (offset, length) = match gpt::partition_region(device) {
             Ok(r) => r,
-            Err(e) => return generic_error!(
-                &format!("Cannot build regex: {}", e.to_string())),
+            Err(e) => {
+                log::warn!(
+                    "Region of partition `{}` unknown: {}",
+                    self.config.label,
+                    e);
+
+                return;
+            },
         };
 
-        let captures = match re.captures(&stdout) {
-            Some(c) => c,
-            None => return generic_error!("Cannot identify partition"),
+        log::info!(
+            "Partition `{}` created at offset {} ({}) for {} bytes ({})",
+            self.config.label,
+            offset,
+            gpt::Bytesize::from_bytes(offset).to_human(),
+            length,
+            gpt::Bytesize::from_bytes(length).to_human());
+
+        match self.alignment_boundary {
+            Some(b) if misalignment(offset, b) != 0 => log::warn!(
+                "Partition `{}` is misaligned: starts at offset {} ({} bytes \
+                past a {} boundary)",
+                self.config.label,
+                offset,
+                misalignment(offset, b),
+                gpt::Bytesize::from_bytes(b).to_human()),
+            _ => (),
+        }
+    }
+
+    /// Fail if a created ZFS partition doesn't start on a pool sector (its
+    /// region is only warned about when unknown)
+    fn check_zfs_start(&self) -> error::Return {
+        if utils::is_dry_run() || self.config.fs_type != "zfs" {
+            return Success!();
+        }
+
+        let device = match &self.config.device {
+            Some(d) => d,
+            None => return Success!(),
         };
 
-        let partition_device = captures.get(0).map_or("", |m| m.as_str());
+        let offset = match gpt::partition_region(device) {
+            Ok((offset, _)) => offset,
+            Err(_) => return Success!(),
+        };
+
+        return zfs::check_partition_start(
+            &self.config.label,
+            offset,
+            self.zfs_ashift());
+    }
+
+    /// Run an identification until it succeeds or the disk timeout expires
+    fn retry_identify<F>(&mut self, identify: F) -> error::Return
+        where
+            F: Fn(&mut Self) -> error::Return {
+
+        let operation =
+            format!("Identification of partition `{}`", self.config.label);
+
+        return utils::retry_timeout(
+            &operation,
+            time::Duration::from_secs(self.disk_timeout),
+            || identify(self));
+    }
+
+    /// Identify the block device of this partition
+    fn identify(&mut self, device: &str) -> error::Return {
+        // Nothing has been created: use the kernel naming
+        if utils::is_dry_run() {
+            let separator = match device.ends_with(char::is_numeric) {
+                true => "p",
+                false => "",
+            };
+
+            let partition_device =
+                format!("{}{}{}", device, separator, self.config.id);
+
+            self.config.device_name =
+                Some(partition_device.replace("/dev/", ""));
+            self.config.device = Some(partition_device);
 
-        if partition_device.is_empty() {
-            return generic_error!("No partition found");
+            return Success!();
         }
 
+        // Run command (children are listed whatever their naming scheme)
+        let output = utils::command_output(
+            "lsblk",
+            &["-J", "-l", "-p", "-o", "NAME,KNAME,PKNAME,TYPE", device])?;
+
+        let stdout = utils::command_stdout_to_string(&output)?;
+
+        // Search partition
+        let partition_device =
+            find_child_device(&stdout, device, self.config.id)?;
+        let partition_device = partition_device.as_str();
+
         self.config.device = Some(partition_device.to_string());
 
+        report::device(partition_device);
+
         self.config.device_name =
             Some(partition_device.to_string().replace("/dev/", ""));
 
@@ -214,6 +856,22 @@ impl Partition {
 
     /// Identify ID of this partition
     fn identify_id(&mut self) -> error::Return {
+        // Nothing has been created: use a placeholder id
+        if utils::is_dry_run() {
+            let device = match &self.config.device_name {
+                Some(d) => d,
+                None => return generic_error!("No device name for partition"),
+            };
+
+            self.config.device_by_id =
+                Some(format!("/dev/disk/by-id/dry-run-{}", device));
+
+            self.config.device_by_partlabel =
+                Some(format!("/dev/disk/by-partlabel/{}", &self.config.label));
+
+            return Success!();
+        }
+
         // Run command
         let output = utils::command_output("ls", &["-l", "/dev/disk/by-id"])?;
         let output = utils::command_stdout_to_string(&output)?;
@@ -226,11 +884,11 @@ impl Partition {
         let re = match Regex::new(&pattern) {
             Ok(r) => r,
             Err(e) => return generic_error!(
-                &format!("Cannot build regex: {}", e.to_string())),
+                &format!("Cannot build regex: {}", e)),
         };
 
         for line in output.lines() {
-            let captures = match re.captures(&line) {
+            let captures = match re.captures(line) {
                 Some(c) => c,
                 None => continue,
             };
@@ -241,10 +899,17 @@ impl Partition {
                 return generic_error!("No partition id");
             }
 
+            let by_partlabel =
+                format!("/dev/disk/by-partlabel/{}", &self.config.label);
+
+            if !path::Path::new(&by_partlabel).exists() {
+                return generic_error!(
+                    &format!("`{}` does not exist", by_partlabel));
+            }
+
             self.config.device_by_id = Some(format!("/dev/disk/by-id/{}", &id));
 
-            self.config.device_by_partlabel =
-                Some(format!("/dev/disk/by-partlabel/{}", &self.config.label));
+            self.config.device_by_partlabel = Some(by_partlabel);
 
             log::info!(
                 "Partition `{}` identified on device `{}`",
@@ -259,7 +924,7 @@ impl Partition {
 
     /// Format this partition using LUKS
     fn luks_format(&mut self, passphrase: &str, key_file: &str) -> error::Return {
-        if self.config.encrypted == false {
+        if !self.config.encrypted {
             return Success!();
         }
 
@@ -267,10 +932,34 @@ impl Partition {
         let device = self.config.device_by_id.as_ref().unwrap();
 
         // Format
-        luks::format(device, passphrase)?;
+        let config = match &self.config.luks {
+            Some(c) => c.clone(),
+            None => luks::Config::default(),
+        };
+
+        // GRUB reads `/boot` from the system partition but cannot derive
+        // argon2id keys
+        let is_luks2 = config.version() == luks::LuksVersion::Luks2;
+
+        if self.config.is_system && is_luks2 {
+            report::warning(&format!(
+                "Partition `{}` uses LUKS2 (argon2id), GRUB may not be able to \
+                unlock it",
+                self.config.label));
+        }
+
+        luks::format(device, passphrase, &config)?;
 
         // Add key file
-        luks::add_key(device, passphrase, key_file)?;
+        luks::add_key(
+            device,
+            passphrase,
+            key_file,
+            config.key_slot,
+            self.kill_key_slot)?;
+
+        // Check that the key file unlocks the device
+        luks::verify_key(device, key_file)?;
 
         // Open
         luks::open(
@@ -282,6 +971,43 @@ impl Partition {
 
         return Success!();
     }
+
+    /// Tear down what has been set up (mount, volume group, LUKS device),
+    /// going on whatever fails: get what has been cleaned up (errors are
+    /// logged)
+    pub fn rollback(&mut self) -> Vec<String> {
+        let mut cleaned = Vec::new();
+        let label = self.config.label.clone();
+
+        if self.mounted {
+            match self.unmount() {
+                Ok(_) => cleaned.push(format!("mount of `{}`", label)),
+                Err(e) => log::error!("{}", e),
+            }
+        }
+
+        if !self.opened {
+            return cleaned;
+        }
+
+        if self.lvm.is_opened() {
+            match self.lvm.close() {
+                Ok(_) => cleaned.push(format!("volume group `vg-{}`", label)),
+                Err(e) => log::error!("{}", e),
+            }
+        }
+
+        if self.config.encrypted {
+            match luks::close(&label) {
+                Ok(_) => cleaned.push(format!("LUKS device `{}`", label)),
+                Err(e) => log::error!("{}", e),
+            }
+        }
+
+        self.opened = false;
+
+        return cleaned;
+    }
 }
 
 impl Mountable for Partition {
@@ -298,7 +1024,17 @@ impl Mountable for Partition {
             None => return generic_error!("No mountpoint"),
         };
 
-        utils::command_output("mount", &[device, mountpoint])?;
+        let options = self.config.mount_options.join(",");
+
+        let mut args = Vec::new();
+
+        if !options.is_empty() {
+            args.extend(["-o", &options]);
+        }
+
+        args.extend([device.as_str(), mountpoint]);
+
+        utils::mount(&args)?;
 
         self.mounted = true;
 
@@ -383,8 +1119,18 @@ impl Configurable<Config> for Partition {
             config: config.clone(),
             opened: false,
             mounted: false,
-            lvm: lvm::Lvm::from_config(&config.lvm, &config.label),
+            lvm: lvm::Lvm::from_config(
+                &config.lvm,
+                &config.label,
+                config.lvm_discard),
             zfs: zfs::Filesystems::from_config(&config.label, &config.zfs),
+            btrfs: btrfs::Subvolumes::from_config(
+                filesystem_device(config),
+                &config.btrfs),
+            kill_key_slot: false,
+            disk_timeout: DEFAULT_DISK_TIMEOUT,
+            alignment_boundary: None,
+            kept: false,
         }
     }
 
@@ -405,6 +1151,244 @@ impl Configurable<Config> for Partition {
             device_by_id: self.config.device_by_id.clone(),
             device_by_partlabel: self.config.device_by_partlabel.clone(),
             luks_mapper: self.config.luks_mapper.clone(),
+            luks: self.config.luks.clone(),
+            zfs_compatibility: self.config.zfs_compatibility.clone(),
+            zfs_ashift: self.config.zfs_ashift,
+            zfs_encrypted: self.config.zfs_encrypted,
+            btrfs: self.btrfs.config()?,
+            auto_resize: self.config.auto_resize,
+            is_resume: self.config.is_resume,
+            efi_mountpoint: self.config.efi_mountpoint.clone(),
+            lvm_discard: self.config.lvm_discard,
+            mkfs_options: self.config.mkfs_options.clone(),
+            mount_options: self.config.mount_options.clone(),
         });
     }
 }
+
+// -----------------------------------------------------------------------------
+
+/// Get the device holding the filesystem of a partition (the LUKS mapper if
+/// encrypted)
+pub fn filesystem_device(config: &Config) -> Option<&str> {
+    return match config.encrypted {
+        true => config.luks_mapper.as_deref(),
+        false => config.device_by_id.as_deref(),
+    };
+}
+
+/// Block device listed by `lsblk`
+#[derive(Deserialize)]
+struct BlockDevice {
+    /// Path of the device (mapper name for device-mapper nodes)
+    name: String,
+
+    /// Path of the kernel device
+    kname: String,
+
+    /// Path of the kernel device of the parent
+    pkname: Option<String>,
+}
+
+/// Devices listed by `lsblk -J`
+#[derive(Deserialize)]
+struct BlockDevices {
+    /// Device and its children
+    blockdevices: Vec<BlockDevice>,
+}
+
+/// Find the partition of a device in a `lsblk` listing from the partition
+/// number ending the name of the children (`sda1`, `nvme0n1p1`, `loop0p1`,
+/// `mapper/loop0p1`, `-part1`)
+fn find_child_device(
+    lsblk: &str,
+    device: &str,
+    id: u32) -> Result<String, error::Error> {
+
+    let devices: BlockDevices = match serde_json::from_str(lsblk) {
+        Ok(d) => d,
+        Err(e) => return json_error!("lsblk", e),
+    };
+
+    // The device itself is listed first
+    let kname = match devices.blockdevices.first() {
+        Some(d) => d.kname.clone(),
+        None => return generic_error!(
+            &format!("Device `{}` not listed", device)),
+    };
+
+    let number = id.to_string();
+
+    let mut children = devices.blockdevices
+        .iter()
+        .filter(|d| d.pkname.as_deref() == Some(kname.as_str()))
+        .filter(|d| {
+            let prefix = d.name.trim_end_matches(char::is_numeric);
+
+            return
+                prefix.len() < d.name.len() &&
+                d.name[prefix.len()..] == number;
+        });
+
+    let child = match children.next() {
+        Some(c) => c,
+        None => return generic_error!(&format!(
+            "Cannot identify partition {} of `{}`",
+            id,
+            device)),
+    };
+
+    match children.next() {
+        Some(c) => return generic_error!(&format!(
+            "Partition {} of `{}` is ambiguous (`{}` and `{}`)",
+            id,
+            device,
+            child.name,
+            c.name)),
+
+        None => return Ok(child.name.clone()),
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::sync;
+
+    use super::*;
+
+    #[test]
+    fn misalignment_is_measured_from_the_boundary() {
+        assert_eq!(misalignment(2048 * 512, DEFAULT_ALIGNMENT_BOUNDARY), 0);
+        assert_eq!(misalignment(34 * 512, DEFAULT_ALIGNMENT_BOUNDARY), 17408);
+        assert_eq!(misalignment(34 * 512, 0), 0);
+    }
+
+    #[test]
+    fn rollback_closes_luks_when_lvm_fails() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "id": 2,
+            "size": "0",
+            "partition_type": "linux",
+            "encrypted": true,
+            "fs_type": "lvm",
+            "label": "system",
+            "is_system": true,
+            "is_root": false,
+            "lvm": [{
+                "id": 0,
+                "size": "0",
+                "volume_type": "linux",
+                "encrypted": false,
+                "fs_type": "ext4",
+                "label": "root",
+                "is_root": true,
+                "device": null,
+            }],
+            "zfs": [],
+            "device": null,
+            "device_name": null,
+            "device_by_id": null,
+            "device_by_partlabel": null,
+            "luks_mapper": null,
+            "luks": null,
+            "zfs_compatibility": null,
+        })).unwrap();
+
+        let runner = sync::Arc::new(utils::MockRunner::new(vec![
+            // vgchange -a y
+            utils::MockRunner::output(0, ""),
+            // vgchange -a n (volumes still in use)
+            utils::MockRunner::output(5, ""),
+        ]));
+
+        utils::set_runner(runner.clone());
+
+        let mut partition = Partition::from_config(&config);
+        partition.lvm.open("").unwrap();
+        partition.opened = true;
+
+        assert_eq!(partition.rollback(), ["LUKS device `system`"]);
+
+        let calls = runner.calls.lock().unwrap();
+
+        assert_eq!(calls.last().unwrap().0, "cryptsetup");
+        assert_eq!(
+            calls.last().unwrap().1,
+            ["luksClose", "/dev/mapper/system"]);
+        assert!(!partition.opened);
+    }
+
+    #[test]
+    fn kept_partitions_identify_their_lvm_volumes() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "id": 2,
+            "size": "0",
+            "partition_type": "linux",
+            "encrypted": true,
+            "fs_type": "lvm",
+            "label": "system",
+            "lvm": [{
+                "id": 1,
+                "size": "0",
+                "volume_type": "linux",
+                "fs_type": "ext4",
+                "label": "root",
+                "is_root": true,
+            }],
+        })).unwrap();
+
+        let mut partition = Partition::from_config(&config);
+
+        utils::set_test_dry_run(true);
+        let result = partition.keep("/dev/nvme0n1");
+        utils::set_test_dry_run(false);
+
+        result.unwrap();
+
+        let config = partition.config().unwrap();
+
+        assert!(partition.kept);
+        assert_eq!(config.device.as_deref(), Some("/dev/nvme0n1p2"));
+        assert_eq!(
+            config.lvm[0].device.as_deref(),
+            Some("/dev/vg-system/root"));
+    }
+
+    #[test]
+    fn identifies_partitions_whatever_their_naming() {
+        let fixture = |name: &str| {
+            let path = path::Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests")
+                .join("lsblk")
+                .join(format!("{}.json", name));
+
+            return std::fs::read_to_string(path).unwrap();
+        };
+
+        let dm = fixture("dm");
+
+        assert_eq!(
+            find_child_device(&dm, "/dev/loop0", 2).unwrap(),
+            "/dev/mapper/loop0p2");
+        assert!(find_child_device(&dm, "/dev/loop0", 3).is_err());
+
+        let looped = fixture("loop");
+
+        assert_eq!(
+            find_child_device(&looped, "/dev/loop1", 1).unwrap(),
+            "/dev/loop1p1");
+        assert_eq!(
+            find_child_device(&looped, "/dev/loop1", 12).unwrap(),
+            "/dev/loop1p12");
+
+        // Boot partitions are not children of the eMMC
+        let mmc = fixture("mmc");
+
+        assert_eq!(
+            find_child_device(&mmc, "/dev/mmcblk0", 1).unwrap(),
+            "/dev/mmcblk0p1");
+        assert!(find_child_device(&mmc, "/dev/mmcblk0", 0).is_err());
+    }
+}
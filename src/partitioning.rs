@@ -2,18 +2,41 @@
 
 use clap;
 use std::collections::HashMap;
+use std::path;
+use std::str::FromStr;
 
+use super::disk;
 use super::env;
 use super::filesystem;
 use super::error;
+use super::gpt;
+use super::logger;
+use super::luks;
+use super::partition;
+use super::plan;
+use super::report;
 use super::traits::{CliCommand, Openable, Validate};
 use super::utils;
 
 // -----------------------------------------------------------------------------
 
+const ARG_ALIGNMENT_BOUNDARY: &str = "alignment-boundary";
+const ARG_CHECK_ALIGNMENT: &str = "check-alignment";
 const ARG_DEVICE: &str = "device";
+const ARG_DIFF: &str = "diff";
+const ARG_DISK_TIMEOUT: &str = "disk-timeout";
+const ARG_FORCE_GPT: &str = "force-gpt";
 const ARG_HOST: &str = "host";
+const ARG_KEY_SLOT: &str = "key-slot";
+const ARG_KILL_KEY_SLOT: &str = "kill-key-slot";
+const ARG_LABEL_PREFIX: &str = "label-prefix";
+const ARG_LUKS_ITER_TIME: &str = "luks-iter-time";
+const ARG_LUKS_VERSION: &str = "luks-version";
+const ARG_ONLY_DISK: &str = "only-disk";
 const ARG_PASSWORD: &str = "password";
+const ARG_RESUME: &str = "resume";
+#[cfg(debug_assertions)]
+const ARG_SIMULATE_FAILURE: &str = "simulate-failure";
 
 // -----------------------------------------------------------------------------
 
@@ -30,7 +53,48 @@ pub struct Command {
     key_file: String,
 
     /// Filesystem description
+    #[allow(dead_code)]
     fs_config: Option<filesystem::Config>,
+
+    /// LUKS keyslot iteration time in milliseconds
+    luks_iter_time: Option<u32>,
+
+    /// Prefix of all the labels
+    label_prefix: Option<String>,
+
+    /// LUKS header version
+    luks_version: Option<luks::LuksVersion>,
+
+    /// Disks to partition (all if empty)
+    only_disks: Vec<String>,
+
+    /// Whether the changes of the layout must be printed
+    diff: bool,
+
+    /// Whether existing non-GPT partition tables can be converted
+    force_gpt: bool,
+
+    /// LUKS keyslot of the key file
+    key_slot: Option<u32>,
+
+    /// Whether an occupied keyslot of the key file can be wiped
+    kill_key_slot: bool,
+
+    /// Time to wait for the devices of the created partitions (in seconds)
+    disk_timeout: Option<u64>,
+
+    /// Whether the start of the created partitions must be checked
+    check_alignment: bool,
+
+    /// Boundary the created partitions must start on (in bytes)
+    alignment_boundary: Option<u64>,
+
+    /// Whether the existing partitions matching the layout are kept
+    resume: bool,
+
+    /// Step of the plan failing on purpose (1-based, debug builds only)
+    #[cfg(debug_assertions)]
+    simulate_failure: Option<usize>,
 }
 
 impl Validate for Command {
@@ -53,27 +117,114 @@ impl CliCommand for Command {
         version: &'b str,
         author: &'b str) -> clap::App<'a, 'b> {
 
-        return clap::App::new(self.name())
+        let app = clap::App::new(self.name())
             .about("Create partitions")
             .version(version)
             .author(author)
+            // Alignment boundary argument
+            .arg(clap::Arg::with_name(ARG_ALIGNMENT_BOUNDARY)
+                .long(ARG_ALIGNMENT_BOUNDARY)
+                .help("Boundary checked by --check-alignment (1M by default)")
+                .requires(ARG_CHECK_ALIGNMENT)
+                .takes_value(true))
+            // Check alignment argument
+            .arg(clap::Arg::with_name(ARG_CHECK_ALIGNMENT)
+                .long(ARG_CHECK_ALIGNMENT)
+                .help("Warn about created partitions not starting on the \
+                    alignment boundary (read from sysfs)"))
             // Device argument
             .arg(clap::Arg::with_name(ARG_DEVICE)
                 .long(ARG_DEVICE)
                 .help("Device mapping (value must be \"NAME=REPLACEMENT\")")
                 .multiple(true)
                 .takes_value(true))
+            // Diff argument
+            .arg(clap::Arg::with_name(ARG_DIFF)
+                .long(ARG_DIFF)
+                .help("Print the fields of the layout populated by the run \
+                    (<host>.in.json compared to <host>.json)"))
+            // Disk timeout argument
+            .arg(clap::Arg::with_name(ARG_DISK_TIMEOUT)
+                .long(ARG_DISK_TIMEOUT)
+                .help("Seconds to wait for the devices of a created partition \
+                    (slow USB enclosures, 10 by default)")
+                .takes_value(true))
+            // Force GPT argument
+            .arg(clap::Arg::with_name(ARG_FORCE_GPT)
+                .long(ARG_FORCE_GPT)
+                .help("Convert disks having an existing MBR (or other non-GPT) \
+                    partition table"))
             // Host argument
             .arg(clap::Arg::with_name(ARG_HOST)
                 .long(ARG_HOST)
                 .help("Host name (optional if a .env file is present)")
                 .takes_value(true))
+            // Key slot argument
+            .arg(clap::Arg::with_name(ARG_KEY_SLOT)
+                .long(ARG_KEY_SLOT)
+                .help("LUKS keyslot of the key file (next free one by default, \
+                    the passphrase moves to slot 1 if 0 is taken)")
+                .takes_value(true))
+            // Kill key slot argument
+            .arg(clap::Arg::with_name(ARG_KILL_KEY_SLOT)
+                .long(ARG_KILL_KEY_SLOT)
+                .help("Wipe the keyslot of the key file if it's occupied")
+                .requires(ARG_KEY_SLOT))
+            // Label prefix argument
+            .arg(clap::Arg::with_name(ARG_LABEL_PREFIX)
+                .long(ARG_LABEL_PREFIX)
+                .help("Prefix of all the partition, volume and pool labels")
+                .takes_value(true))
+            // LUKS iteration time argument
+            .arg(clap::Arg::with_name(ARG_LUKS_ITER_TIME)
+                .long(ARG_LUKS_ITER_TIME)
+                .help("LUKS keyslot iteration time in ms (lower is faster to \
+                    unlock but weaker against brute-force)")
+                .takes_value(true))
+            // LUKS version argument
+            .arg(clap::Arg::with_name(ARG_LUKS_VERSION)
+                .long(ARG_LUKS_VERSION)
+                .help("LUKS header version of encrypted partitions not \
+                    providing one (luks2 uses argon2id)")
+                .possible_values(&["luks1", "luks2"])
+                .takes_value(true))
+            // Only disk argument
+            .arg(clap::Arg::with_name(ARG_ONLY_DISK)
+                .long(ARG_ONLY_DISK)
+                .help("Only partition this disk (device or mapping name)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true))
             // Password argument
             .arg(clap::Arg::with_name(ARG_PASSWORD)
                 .long(ARG_PASSWORD)
                 .help("Password to be used to create encrypted partitions")
-                .required(true)
+                .required_unless_one(&[
+                    utils::ARG_PASSWORD_AGE,
+                    utils::ARG_PASSWORD_SOPS,
+                ])
+                .takes_value(true))
+            // Password age and sops arguments
+            .args(&utils::password_file_args(ARG_PASSWORD))
+            // Resume argument
+            .arg(clap::Arg::with_name(ARG_RESUME)
+                .long(ARG_RESUME)
+                .help("Skip the steps completed by a previous run (see \
+                    <host>.steps.json) and keep the existing partitions \
+                    matching the layout (label, type and size) instead of \
+                    wiping the disks, only the partitions created again are \
+                    formatted"));
+
+        // Simulate failure argument (test hook of the rollback and the resume)
+        #[cfg(debug_assertions)]
+        let app = app
+            .arg(clap::Arg::with_name(ARG_SIMULATE_FAILURE)
+                .long(ARG_SIMULATE_FAILURE)
+                .help("Fail before running this step of the plan (1-based)")
+                .hidden(true)
                 .takes_value(true));
+
+        return app;
     }
 
     fn process(&mut self, matches: &clap::ArgMatches) -> error::Return {
@@ -100,6 +251,42 @@ impl CliCommand for Command {
                     }
                 },
 
+                &ARG_ALIGNMENT_BOUNDARY => {
+                    let value = match matches.value_of(arg.0) {
+                        Some(s) => gpt::Bytesize::from(s),
+                        None => return inval_error!(&ARG_ALIGNMENT_BOUNDARY),
+                    };
+
+                    self.alignment_boundary = match value.is_null() {
+                        true => return inval_error!(&ARG_ALIGNMENT_BOUNDARY),
+                        false => Some(value.to_bytes()),
+                    };
+                },
+
+                &ARG_CHECK_ALIGNMENT => {
+                    self.check_alignment = true;
+                },
+
+                &ARG_DIFF => {
+                    self.diff = true;
+                },
+
+                &ARG_DISK_TIMEOUT => {
+                    let value = match matches.value_of(arg.0) {
+                        Some(s) => s.to_string(),
+                        None => return inval_error!(&ARG_DISK_TIMEOUT),
+                    };
+
+                    self.disk_timeout = match value.parse::<u64>() {
+                        Ok(t) => Some(t),
+                        _ => return inval_error!(&ARG_DISK_TIMEOUT),
+                    };
+                },
+
+                &ARG_FORCE_GPT => {
+                    self.force_gpt = true;
+                },
+
                 &ARG_HOST => {
                     self.host = match matches.value_of(arg.0) {
                         Some(s) => s.to_string(),
@@ -107,11 +294,86 @@ impl CliCommand for Command {
                     };
                 },
 
+                &ARG_KEY_SLOT => {
+                    let value = match matches.value_of(arg.0) {
+                        Some(s) => s.to_string(),
+                        None => return inval_error!(&ARG_KEY_SLOT),
+                    };
+
+                    self.key_slot = match value.parse::<u32>() {
+                        Ok(s) => Some(s),
+                        _ => return inval_error!(&ARG_KEY_SLOT),
+                    };
+                },
+
+                &ARG_KILL_KEY_SLOT => {
+                    self.kill_key_slot = true;
+                },
+
+                &ARG_LABEL_PREFIX => {
+                    self.label_prefix = match matches.value_of(arg.0) {
+                        Some(s) if !s.is_empty() => Some(s.to_string()),
+                        _ => return inval_error!(&ARG_LABEL_PREFIX),
+                    };
+                },
+
+                &ARG_LUKS_ITER_TIME => {
+                    let value = match matches.value_of(arg.0) {
+                        Some(s) => s.to_string(),
+                        None => return inval_error!(&ARG_LUKS_ITER_TIME),
+                    };
+
+                    self.luks_iter_time = match value.parse::<u32>() {
+                        Ok(i) if i > 0 => Some(i),
+                        _ => return inval_error!(&ARG_LUKS_ITER_TIME),
+                    };
+                },
+
+                &ARG_LUKS_VERSION => {
+                    let value = match matches.value_of(arg.0) {
+                        Some(s) => s,
+                        None => return inval_error!(&ARG_LUKS_VERSION),
+                    };
+
+                    self.luks_version =
+                        Some(luks::LuksVersion::from_str(value)?);
+                },
+
+                &ARG_ONLY_DISK => {
+                    self.only_disks = match matches.values_of(arg.0) {
+                        Some(v) => v.map(|s| s.to_string()).collect(),
+                        None => return inval_error!(&ARG_ONLY_DISK),
+                    };
+                },
+
                 &ARG_PASSWORD => {
                     self.password = match matches.value_of(arg.0) {
                         Some(s) => s.to_string(),
                         None => return inval_error!(&ARG_PASSWORD),
                     };
+
+                    logger::add_secret(&self.password);
+                },
+
+                &utils::ARG_PASSWORD_AGE | &utils::ARG_PASSWORD_SOPS => {
+                    self.password = utils::decrypt_password(arg.0, matches)?;
+                },
+
+                &ARG_RESUME => {
+                    self.resume = true;
+                },
+
+                #[cfg(debug_assertions)]
+                &ARG_SIMULATE_FAILURE => {
+                    let value = match matches.value_of(arg.0) {
+                        Some(s) => s.to_string(),
+                        None => return inval_error!(&ARG_SIMULATE_FAILURE),
+                    };
+
+                    self.simulate_failure = match value.parse::<usize>() {
+                        Ok(i) if i > 0 => Some(i),
+                        _ => return inval_error!(&ARG_SIMULATE_FAILURE),
+                    };
                 },
 
                 _ => {
@@ -127,32 +389,123 @@ impl CliCommand for Command {
         log::debug!("{:#?}", self);
 
         // Check validity
-        if !self.is_valid() {
-            return generic_error!("Invalid configuration");
-        }
+        env::check_values(&[
+            (ARG_HOST, &self.host),
+            ("key_file", &self.key_file),
+        ])?;
+
+        report::begin();
 
         // Create filesystem
-        let path = utils::current_dir()?
-            .join("layouts")
-            .join(format!("{}.in.json", self.host));
+        let path = filesystem::layout(&format!("{}.in", self.host))?;
 
-        let mut fs = filesystem::Filesystem::from_json(&path)?;
+        let mut fs = filesystem::Filesystem::from_file(&path)?;
+
+        // Prefix labels
+        match &self.label_prefix {
+            Some(p) => fs.set_label_prefix(p)?,
+            None => (),
+        }
 
         // Give device mapping
         log::debug!("{:#?}", device_mapping);
 
         fs.set_device_mapping(&device_mapping);
 
-        // Create partitioning
-        fs.create(&self.key_file, &self.password)?;
+        // Find disks given by serial number or model
+        fs.resolve_devices()?;
+
+        // Select disks to partition
+        if !self.only_disks.is_empty() {
+            let mut devices: Vec<String> = Vec::new();
+
+            for disk in self.only_disks.iter() {
+                devices.push(match disk::is_device_spec(disk) {
+                    true => disk::resolve_device(disk)?,
+                    false => device_mapping.get(disk).unwrap_or(disk).clone(),
+                });
+            }
+
+            fs.select_disks(&devices)?;
+        }
+
+        // Allow partition tables conversion
+        fs.set_force_gpt(self.force_gpt);
+        fs.set_resume(self.resume);
+
+        // Give LUKS iteration time
+        match self.luks_iter_time {
+            Some(t) => fs.set_luks_iter_time(t),
+            None => (),
+        }
+
+        // Give LUKS version
+        match self.luks_version {
+            Some(v) => fs.set_luks_version(v),
+            None => (),
+        }
+
+        // Give LUKS keyslot of the key file (after the version it depends on)
+        match self.key_slot {
+            Some(s) => fs.set_luks_key_slot(s)?,
+            None => (),
+        }
+
+        fs.set_kill_key_slot(self.kill_key_slot);
+
+        match self.disk_timeout {
+            Some(t) => fs.set_disk_timeout(t),
+            None => (),
+        }
+
+        if self.check_alignment {
+            fs.set_alignment_boundary(
+                self.alignment_boundary
+                    .unwrap_or(partition::DEFAULT_ALIGNMENT_BOUNDARY));
+        }
+
+        // Steps completed by the runs (next to the layout)
+        let progress_path =
+            path.with_file_name(format!("{}.steps.json", self.host));
+
+        let progress = match self.resume {
+            true => plan::Progress::load(&progress_path)?,
+            false => plan::Progress::new(&progress_path),
+        };
+
+        #[cfg(debug_assertions)]
+        let progress = self.simulate_failure(&fs, progress)?;
+
+        // Create partitioning (close what has been opened on failure)
+        match fs.create(&self.key_file, &self.password, &progress) {
+            Ok(_) => (),
+            Err(e) => {
+                log::warn!("Partitioning failed, cleaning up");
+
+                fs.rollback();
+
+                return Err(e);
+            },
+        }
+
         fs.close()?;
 
-        // Save back to json file
-        let path = utils::current_dir()?
-            .join("layouts")
-            .join(format!("{}.json", self.host));
+        // Everything is done, nothing to resume
+        progress.clear()?;
+
+        // Save back to the layout (in the format of the input one)
+        let output = path.with_file_name(match utils::is_toml(&path) {
+            true => format!("{}.toml", self.host),
+            false => format!("{}.json", self.host),
+        });
+
+        fs.to_file(&output)?;
+
+        if self.diff {
+            print_diff(&path, &fs)?;
+        }
 
-        fs.to_json(&path)?;
+        report::summary();
 
         return Success!();
     }
@@ -166,16 +519,90 @@ impl Command {
             password: "".to_string(),
             key_file: "".to_string(),
             fs_config: None,
+            luks_iter_time: None,
+            label_prefix: None,
+            luks_version: None,
+            only_disks: Vec::new(),
+            diff: false,
+            force_gpt: false,
+            key_slot: None,
+            kill_key_slot: false,
+            disk_timeout: None,
+            check_alignment: false,
+            alignment_boundary: None,
+            resume: false,
+            #[cfg(debug_assertions)]
+            simulate_failure: None,
         }
     }
 
+    /// Make the step given by --simulate-failure fail (debug builds only)
+    #[cfg(debug_assertions)]
+    fn simulate_failure(
+        &self,
+        fs: &filesystem::Filesystem,
+        mut progress: plan::Progress)
+        -> Result<plan::Progress, error::Error> {
+
+        let i = match self.simulate_failure {
+            Some(i) => i,
+            None => return Ok(progress),
+        };
+
+        let plan = fs.plan();
+
+        match plan.get(i - 1) {
+            Some(step) => progress.simulate_failure(step),
+            None => return generic_error!(&format!(
+                "No step {} in the plan ({} steps)",
+                i,
+                plan.len())),
+        }
+
+        return Ok(progress);
+    }
+
     /// Use environment file to get needed values
     fn fill_with_env(&mut self) -> error::Return {
-        let config = env::read()?;
+        let config = match env::read_for_host(&mut self.host)? {
+            Some(c) => c,
+            None => return Success!(),
+        };
 
-        self.host = config.nixos.host;
         self.key_file = config.nixos.key_file;
 
         return Success!();
     }
 }
+
+// -----------------------------------------------------------------------------
+
+/// Print the differences between the input layout and the resulting one
+fn print_diff(
+    input: &path::Path,
+    fs: &filesystem::Filesystem) -> error::Return {
+
+    let old: serde_json::Value = match utils::is_toml(input) {
+        true => utils::load_toml(input)?,
+        false => utils::load_json(input)?,
+    };
+
+    let new = match serde_json::to_value(fs.to_config()?) {
+        Ok(v) => v,
+        Err(e) => return json_error!("Cannot serialize layout", e),
+    };
+
+    let changes = utils::json_diff(&old, &new);
+
+    log::info!("Layout changes from {:?}", input);
+
+    if changes.is_empty() {
+        log::info!("  none");
+    }
+
+    for change in changes.iter() {
+        log::info!("  {}", change);
+    }
+
+    return Success!();
+}
@@ -0,0 +1,337 @@
+// -----------------------------------------------------------------------------
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path;
+use std::sync::Mutex;
+
+use super::error;
+use super::utils;
+
+// -----------------------------------------------------------------------------
+
+/// Action of a step of the partitioning
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// Destroy the ZFS pools of the machine
+    WipePools,
+
+    /// Wipe the partition table of a disk
+    WipeDisk,
+
+    /// Create a partition
+    CreatePartition,
+
+    /// Initialize the LUKS header of a partition (and open it)
+    LuksFormat,
+
+    /// Create the volume group of a partition and its volumes
+    CreateVolumeGroup,
+
+    /// Create the ZFS pool of a partition and its filesystems
+    CreatePool,
+
+    /// Format the filesystem of a partition (and create its subvolumes)
+    Format,
+
+    /// Create a software RAID array
+    CreateArray,
+}
+
+impl Action {
+    /// Get the name of the action (in the identity of the steps)
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Action::WipePools => "wipe-pools",
+            Action::WipeDisk => "wipe-disk",
+            Action::CreatePartition => "create-partition",
+            Action::LuksFormat => "luks-format",
+            Action::CreateVolumeGroup => "create-volume-group",
+            Action::CreatePool => "create-pool",
+            Action::Format => "format",
+            Action::CreateArray => "create-array",
+        };
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Step of the partitioning, derived from the layout
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlanStep {
+    /// Action of the step
+    pub action: Action,
+
+    /// Device of the disk (none for the pools and the arrays)
+    pub disk: Option<String>,
+
+    /// Label of the partition or name of the array (none for the disks)
+    pub target: Option<String>,
+
+    /// Details of the target (size, filesystem)
+    pub detail: Option<String>,
+}
+
+impl PlanStep {
+    /// Create a step
+    pub fn new(action: Action, disk: Option<&str>, target: Option<&str>)
+        -> Self {
+
+        Self {
+            action: action,
+            disk: disk.map(|d| d.to_string()),
+            target: target.map(|t| t.to_string()),
+            detail: None,
+        }
+    }
+
+    /// Set the details of the target
+    pub fn with_detail(mut self, detail: &str) -> Self {
+        self.detail = Some(detail.to_string());
+        return self;
+    }
+
+    /// Get the identity of the step, stable across runs of the same layout
+    /// (the details are part of it: a step of an edited layout is run again)
+    pub fn id(&self) -> String {
+        return format!(
+            "{}:{}:{}:{}",
+            self.action.name(),
+            self.disk.as_deref().unwrap_or("-"),
+            self.target.as_deref().unwrap_or("-"),
+            self.detail.as_deref().unwrap_or("-"));
+    }
+}
+
+impl fmt::Display for PlanStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let disk = self.disk.as_deref().unwrap_or("-");
+        let target = self.target.as_deref().unwrap_or("-");
+
+        match self.action {
+            Action::WipePools => write!(f, "destroy the ZFS pools")?,
+            Action::WipeDisk => write!(f, "wipe `{}`", disk)?,
+            Action::CreatePartition => {
+                write!(f, "create partition `{}` on `{}`", target, disk)?
+            },
+            Action::LuksFormat => write!(f, "luksFormat `{}`", target)?,
+            Action::CreateVolumeGroup => {
+                write!(f, "create volume group `vg-{}`", target)?
+            },
+            Action::CreatePool => write!(f, "create ZFS pool `{}`", target)?,
+            Action::Format => write!(f, "format `{}`", target)?,
+            Action::CreateArray => {
+                write!(f, "create RAID array `{}`", target)?
+            },
+        }
+
+        return match &self.detail {
+            Some(d) => write!(f, " ({})", d),
+            None => Ok(()),
+        };
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Log the plan as a numbered list (printed in dry-run)
+pub fn log(plan: &[PlanStep]) {
+    log::info!("Plan of {} steps:", plan.len());
+
+    for (i, step) in plan.iter().enumerate() {
+        log::info!("{:>3}. {}", i + 1, step);
+
+        if utils::is_dry_run() {
+            println!("{:>3}. {}", i + 1, step);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Json content of the progress file
+#[derive(Debug, Serialize, Deserialize)]
+struct ProgressFile {
+    /// Identities of the completed steps, in order of completion
+    completed: Vec<String>,
+}
+
+/// Steps of the plan completed by the runs of the partitioning of a host
+/// (saved after each step, shared by the threads creating the disks)
+#[derive(Debug)]
+pub struct Progress {
+    /// File where the progress is saved
+    path: path::PathBuf,
+
+    /// Identities of the completed steps
+    completed: Mutex<Vec<String>>,
+
+    /// Identity of the step failing on purpose (test hook, debug builds only)
+    #[cfg(debug_assertions)]
+    failure: Option<String>,
+}
+
+impl Progress {
+    /// Create an empty progress (the file is overwritten once a step
+    /// completes)
+    pub fn new(path: &path::Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            completed: Mutex::new(Vec::new()),
+            #[cfg(debug_assertions)]
+            failure: None,
+        }
+    }
+
+    /// Load the progress of a previous run (empty if there's none)
+    pub fn load(path: &path::Path) -> Result<Self, error::Error> {
+        if !path.exists() {
+            log::info!("No progress in {:?}, starting from scratch", path);
+            return Ok(Self::new(path));
+        }
+
+        let content = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) => return fs_error!(path.to_path_buf(), e),
+        };
+
+        let file: ProgressFile = match serde_json::from_str(&content) {
+            Ok(f) => f,
+            Err(e) => return json_error!(&path.to_string_lossy(), e),
+        };
+
+        log::info!(
+            "{} steps completed by a previous run (see {:?})",
+            file.completed.len(),
+            path);
+
+        return Ok(Self {
+            path: path.to_path_buf(),
+            completed: Mutex::new(file.completed),
+            #[cfg(debug_assertions)]
+            failure: None,
+        });
+    }
+
+    /// Check if a step has been completed
+    pub fn is_completed(&self, step: &PlanStep) -> bool {
+        return self.completed.lock().unwrap().contains(&step.id());
+    }
+
+    /// Make a step fail before running it, to exercise the rollback and the
+    /// resume without damaging anything
+    #[cfg(debug_assertions)]
+    pub fn simulate_failure(&mut self, step: &PlanStep) {
+        log::warn!("Step `{}` will fail on purpose", step);
+
+        self.failure = Some(step.id());
+    }
+
+    /// Signal that a step is about to run (fails if it's the step set by
+    /// `simulate_failure`)
+    pub fn start(&self, step: &PlanStep) -> error::Return {
+        log::debug!("Starting step `{}`", step);
+
+        #[cfg(debug_assertions)]
+        if self.failure.as_ref() == Some(&step.id()) {
+            return generic_error!(
+                &format!("Simulated failure of step `{}`", step));
+        }
+
+        return Success!();
+    }
+
+    /// Record a completed step
+    pub fn complete(&self, step: &PlanStep) -> error::Return {
+        let mut completed = self.completed.lock().unwrap();
+
+        completed.push(step.id());
+
+        let file = ProgressFile {
+            completed: completed.clone(),
+        };
+
+        let content = match serde_json::to_string_pretty(&file) {
+            Ok(c) => c,
+            Err(e) => return json_error!("Cannot serialize progress", e),
+        };
+
+        return utils::write_to_file(content.as_bytes(), &self.path);
+    }
+
+    /// Remove the progress file once every step has completed (a next run
+    /// starts from scratch)
+    pub fn clear(&self) -> error::Return {
+        if utils::is_dry_run() || !self.path.exists() {
+            return Success!();
+        }
+
+        match fs::remove_file(&self.path) {
+            Ok(_) => return Success!(),
+            Err(e) => return fs_error!(self.path.clone(), e),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_survives_runs() {
+        let file = mktemp::Temp::new_file().unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        let wipe = PlanStep::new(Action::WipeDisk, Some("/dev/sda"), None);
+        let create = PlanStep::new(
+            Action::CreatePartition,
+            Some("/dev/sda"),
+            Some("uefi"));
+
+        assert_eq!(create.id(), "create-partition:/dev/sda:uefi:-");
+
+        let progress = Progress::load(&file).unwrap();
+
+        assert!(!progress.is_completed(&wipe));
+
+        progress.complete(&wipe).unwrap();
+        progress.complete(&create.clone().with_detail("1G")).unwrap();
+
+        let progress = Progress::load(&file).unwrap();
+
+        assert!(progress.is_completed(&wipe));
+        assert!(progress.is_completed(&create.clone().with_detail("1G")));
+
+        // A step whose details changed in the layout isn't completed
+        assert!(!progress.is_completed(&create.clone().with_detail("2G")));
+
+        progress.clear().unwrap();
+
+        assert!(!file.exists());
+
+        // Nothing left to remove
+        file.release();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn simulated_failure_stops_at_its_step() {
+        let file = mktemp::Temp::new_file().unwrap();
+
+        let wipe = PlanStep::new(Action::WipeDisk, Some("/dev/sda"), None);
+        let create = PlanStep::new(
+            Action::CreatePartition,
+            Some("/dev/sda"),
+            Some("uefi"));
+
+        let mut progress = Progress::new(&file);
+
+        progress.simulate_failure(&create);
+
+        assert!(progress.start(&wipe).is_ok());
+        assert!(progress.start(&create).is_err());
+    }
+}
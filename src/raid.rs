@@ -0,0 +1,482 @@
+// -----------------------------------------------------------------------------
+
+use serde::{Deserialize, Serialize};
+use std::path;
+use std::str::FromStr;
+
+use super::error;
+use super::gpt;
+use super::luks;
+use super::report;
+use super::traits::{Configurable, Mountable, Openable, Validate};
+use super::utils;
+
+// -----------------------------------------------------------------------------
+
+/// Metadata format of the arrays (superblock at the start of the members)
+pub const METADATA: &str = "1.2";
+
+/// Supported RAID levels and their minimal number of members
+const LEVELS: &[(&str, usize)] = &[
+    ("0", 2),
+    ("1", 2),
+    ("4", 3),
+    ("5", 3),
+    ("6", 4),
+    ("10", 2),
+];
+
+// -----------------------------------------------------------------------------
+
+/// Json configuration of a software RAID array
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// Name of the array (device is `/dev/md/<name>`)
+    pub name: String,
+
+    /// RAID level (`0`, `1`, `4`, `5`, `6` or `10`)
+    pub level: String,
+
+    /// Chunk size (striped levels only, mdadm default if not provided)
+    #[serde(default)]
+    pub chunk: Option<gpt::Bytesize>,
+
+    /// Labels of the member partitions
+    pub members: Vec<String>,
+
+    /// Type of filesystem of the array
+    pub fs_type: String,
+
+    /// Label of the filesystem
+    pub label: String,
+
+    /// Mountpoint of the array
+    pub mountpoint: String,
+
+    /// Whether the array is the root mount point
+    #[serde(default)]
+    pub is_root: bool,
+
+    /// Whether the array is encrypted with LUKS (its filesystem is created on
+    /// the LUKS device named after the label)
+    #[serde(default)]
+    pub encrypted: bool,
+
+    /// LUKS format parameters (defaults are used if not provided)
+    #[serde(default)]
+    pub luks: Option<luks::Config>,
+
+    /// UUID of the array (set once created)
+    #[serde(default)]
+    pub uuid: Option<String>,
+}
+
+impl Config {
+    /// Get the block device of the array
+    pub fn device(&self) -> String {
+        return format!("/dev/md/{}", self.name);
+    }
+
+    /// Get the device holding the filesystem of the array (its LUKS device
+    /// if encrypted)
+    pub fn filesystem_device(&self) -> String {
+        return match self.encrypted {
+            true => format!("/dev/mapper/{}", self.label),
+            false => self.device(),
+        };
+    }
+}
+
+impl Validate for Config {
+    fn is_valid(&self) -> bool {
+        if self.name.is_empty() || self.name.contains('/') {
+            return false;
+        }
+
+        // Only mountable filesystems formatted directly on the array
+        match gpt::FsType::from_str(&self.fs_type) {
+            Ok(gpt::FsType::Btrfs) | Ok(gpt::FsType::Ext4) => (),
+            _ => return false,
+        }
+
+        match &self.luks {
+            Some(l) if !self.encrypted || !l.is_valid() => return false,
+            _ => (),
+        }
+
+        return !self.label.is_empty() && !self.mountpoint.is_empty();
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Software RAID array representation
+#[derive(Debug)]
+pub struct Array {
+    /// Json configuration
+    pub config: Config,
+
+    /// Whether the array is assembled or not
+    opened: bool,
+
+    /// Whether the LUKS device of the array is opened or not
+    luks_opened: bool,
+
+    /// Whether the array is mounted or not
+    mounted: bool,
+}
+
+impl Array {
+    /// Check if the array is assembled
+    pub fn is_opened(&self) -> bool {
+        return self.opened;
+    }
+
+    /// Create the array from its member devices, initialize its LUKS device
+    /// (if encrypted) and format it
+    pub fn create(
+        &mut self,
+        members: &[String],
+        key_file: &str,
+        passphrase: &str) -> error::Return {
+
+        let device = self.config.device();
+
+        let args = create_args(&self.config, members);
+
+        utils::command_output(
+            "mdadm",
+            &args.iter().map(|a| a.as_str()).collect::<Vec<&str>>())?;
+
+        self.opened = true;
+
+        report::device(&device);
+
+        log::info!(
+            "RAID{} array `{}` created with {}",
+            self.config.level,
+            device,
+            members.join(", "));
+
+        self.config.uuid = Some(uuid(&device)?);
+
+        self.luks_format(key_file, passphrase)?;
+
+        return gpt::format_partition(
+            &self.config.filesystem_device(),
+            &self.config.fs_type,
+            &self.config.label,
+            &[]);
+    }
+
+    /// Assemble the array created by a previous run, identify it and open
+    /// its LUKS device
+    pub fn reuse(
+        &mut self,
+        members: &[String],
+        passphrase: &str) -> error::Return {
+
+        self.assemble(members)?;
+
+        self.config.uuid = Some(uuid(&self.config.device())?);
+
+        return self.open_luks(passphrase);
+    }
+
+    /// Initialize the LUKS device of the array with the passphrase and the
+    /// key file (in the next free keyslot), then open it
+    fn luks_format(
+        &mut self,
+        key_file: &str,
+        passphrase: &str) -> error::Return {
+
+        if !self.config.encrypted {
+            return Success!();
+        }
+
+        let device = self.config.device();
+
+        let config = match &self.config.luks {
+            Some(c) => c.clone(),
+            None => luks::Config::default(),
+        };
+
+        luks::format(&device, passphrase, &config)?;
+        luks::add_key(&device, passphrase, key_file, None, false)?;
+        luks::verify_key(&device, key_file)?;
+
+        return self.open_luks(passphrase);
+    }
+
+    /// Open the LUKS device of the array
+    fn open_luks(&mut self, passphrase: &str) -> error::Return {
+        if !self.config.encrypted || self.luks_opened {
+            return Success!();
+        }
+
+        luks::open(&self.config.device(), passphrase, &self.config.label)?;
+
+        self.luks_opened = true;
+
+        return Success!();
+    }
+
+    /// Assemble the array from its member devices
+    pub fn assemble(&mut self, members: &[String]) -> error::Return {
+        if self.opened {
+            return Success!();
+        }
+
+        let device = self.config.device();
+
+        let mut args = vec!["--assemble", device.as_str()];
+        args.extend(members.iter().map(|m| m.as_str()));
+
+        utils::command_output("mdadm", &args)?;
+
+        self.opened = true;
+
+        log::info!("RAID array `{}` assembled", device);
+
+        return Success!();
+    }
+}
+
+impl Openable for Array {
+    /// Open the LUKS device of the array (arrays are assembled with their
+    /// members, see `assemble`)
+    fn open(&mut self, passphrase: &str) -> error::Return {
+        if !self.opened {
+            return generic_error!(&format!(
+                "RAID array `{}` must be assembled from its members",
+                self.config.name));
+        }
+
+        return self.open_luks(passphrase);
+    }
+
+    fn close(&mut self) -> error::Return {
+        if !self.opened {
+            return Success!();
+        }
+
+        if self.luks_opened {
+            luks::close(&self.config.label)?;
+
+            self.luks_opened = false;
+        }
+
+        let device = self.config.device();
+
+        utils::command_output("mdadm", &["--stop", &device])?;
+
+        self.opened = false;
+
+        log::info!("RAID array `{}` stopped", device);
+
+        return Success!();
+    }
+}
+
+impl Mountable for Array {
+    /// Mount the array
+    fn mount(&mut self, mountpoint: &path::PathBuf) -> error::Return {
+        if self.mounted {
+            return Success!();
+        }
+
+        let device = self.config.filesystem_device();
+
+        let mountpoint = match mountpoint.to_str() {
+            Some(m) => m,
+            None => return generic_error!("No mountpoint"),
+        };
+
+        utils::mount(&[&device, mountpoint])?;
+
+        self.mounted = true;
+
+        log::info!("`{}` mounted to `{}`", device, mountpoint);
+
+        return Success!();
+    }
+
+    /// Unmount the array
+    fn unmount(&mut self) -> error::Return {
+        if !self.mounted {
+            return Success!();
+        }
+
+        let device = self.config.filesystem_device();
+
+        utils::command_output("umount", &[&device])?;
+
+        self.mounted = false;
+
+        log::info!("{} unmounted", device);
+
+        return Success!();
+    }
+}
+
+impl Configurable<Config> for Array {
+    fn from_config(config: &Config) -> Self {
+        Self {
+            config: config.clone(),
+            opened: false,
+            luks_opened: false,
+            mounted: false,
+        }
+    }
+
+    fn config(&self) -> Result<Config, error::Error> {
+        return Ok(self.config.clone());
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Check the arrays of a layout: level, number of members and members (given
+/// as label and filesystem type of every partition of the layout)
+pub fn check_arrays(
+    arrays: &[Config],
+    partitions: &[(String, String)]) -> error::Return {
+
+    let mut used: Vec<&str> = Vec::new();
+
+    for array in arrays.iter() {
+        let min_members = match LEVELS.iter().find(|l| l.0 == array.level) {
+            Some(l) => l.1,
+            None => return generic_error!(&format!(
+                "Invalid RAID level `{}` of array `{}`",
+                array.level,
+                array.name)),
+        };
+
+        if array.members.len() < min_members {
+            return generic_error!(&format!(
+                "RAID{} array `{}` needs at least {} members ({} given)",
+                array.level,
+                array.name,
+                min_members,
+                array.members.len()));
+        }
+
+        if array.chunk.is_some() && array.level == "1" {
+            return generic_error!(&format!(
+                "RAID1 array `{}` cannot have a chunk size",
+                array.name));
+        }
+
+        for member in array.members.iter() {
+            match partitions.iter().find(|p| &p.0 == member) {
+                Some(p) if p.1 == "raid" => (),
+                Some(_) => return generic_error!(&format!(
+                    "Member `{}` of array `{}` must have fs_type `raid`",
+                    member,
+                    array.name)),
+                None => return generic_error!(&format!(
+                    "Member `{}` of array `{}` not found",
+                    member,
+                    array.name)),
+            }
+
+            if used.contains(&member.as_str()) {
+                return generic_error!(&format!(
+                    "Partition `{}` is a member of several arrays",
+                    member));
+            }
+
+            used.push(member);
+        }
+
+        // The LUKS device is named after the label
+        if array.encrypted && partitions.iter().any(|p| p.0 == array.label) {
+            return generic_error!(&format!(
+                "Label `{}` of encrypted array `{}` is used by a partition",
+                array.label,
+                array.name));
+        }
+    }
+
+    return Success!();
+}
+
+/// Get the arguments of `mdadm --create`
+fn create_args(config: &Config, members: &[String]) -> Vec<String> {
+    let mut args = vec![
+        "--create".to_string(), config.device(),
+        "--run".to_string(),
+        format!("--metadata={}", METADATA),
+        format!("--level={}", config.level),
+        format!("--raid-devices={}", members.len()),
+    ];
+
+    match &config.chunk {
+        Some(c) => args.push(format!("--chunk={}", c.to_bytes() >> 10)),
+        None => (),
+    }
+
+    args.extend(members.iter().cloned());
+
+    return args;
+}
+
+/// Get the UUID of an array
+fn uuid(device: &str) -> Result<String, error::Error> {
+    // Nothing has been created: use a placeholder UUID
+    if utils::is_dry_run() {
+        return Ok("00000000:00000000:00000000:00000000".to_string());
+    }
+
+    let output =
+        utils::command_output("mdadm", &["--detail", "--export", device])?;
+    let output = utils::command_stdout_to_string(&output)?;
+
+    for line in output.lines() {
+        match line.strip_prefix("MD_UUID=") {
+            Some(u) => return Ok(u.trim().to_string()),
+            None => continue,
+        }
+    }
+
+    return generic_error!(&format!("Cannot get UUID of `{}`", device));
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_too_few_members() {
+        let partitions = vec![
+            ("data1".to_string(), "raid".to_string()),
+            ("data2".to_string(), "raid".to_string()),
+        ];
+
+        let mut array = Config {
+            name: "data".to_string(),
+            level: "5".to_string(),
+            chunk: None,
+            members: vec!["data1".to_string(), "data2".to_string()],
+            fs_type: "ext4".to_string(),
+            label: "data".to_string(),
+            mountpoint: "/data".to_string(),
+            is_root: false,
+            encrypted: false,
+            luks: None,
+            uuid: None,
+        };
+
+        let error = check_arrays(&[array.clone()], &partitions).unwrap_err();
+
+        assert!(error.to_string().ends_with(
+            "RAID5 array `data` needs at least 3 members (2 given)"));
+
+        array.level = "1".to_string();
+
+        assert!(check_arrays(&[array], &partitions).is_ok());
+    }
+}
@@ -0,0 +1,171 @@
+// -----------------------------------------------------------------------------
+
+use clap;
+
+use super::env;
+use super::error;
+use super::filesystem;
+use super::gpt;
+use super::report;
+use super::traits::{CliCommand, Validate};
+
+// -----------------------------------------------------------------------------
+
+const ARG_CONFIRM: &str = "confirm";
+const ARG_HOST: &str = "host";
+
+// -----------------------------------------------------------------------------
+
+/// Command structure for repairing the GPT of the disks
+#[derive(Debug)]
+pub struct Command {
+    /// Host name
+    host: String,
+
+    /// Whether the problems found must be fixed
+    confirm: bool,
+}
+
+impl Validate for Command {
+    fn is_valid(&self) -> bool {
+        return !self.host.is_empty();
+    }
+}
+
+impl CliCommand for Command {
+    /// Get the name of the command
+    fn name(&self) -> &'static str {
+        return "repair";
+    }
+
+    /// Get command and its arguments
+    fn get<'a, 'b>(
+        &self,
+        version: &'b str,
+        author: &'b str) -> clap::App<'a, 'b> {
+
+        return clap::App::new(self.name())
+            .about("Detect and fix GPT inconsistencies (e.g. after a resize)")
+            .version(version)
+            .author(author)
+            // Confirm argument
+            .arg(clap::Arg::with_name(ARG_CONFIRM)
+                .long(ARG_CONFIRM)
+                .help("Fix the problems found (only report them otherwise)"))
+            // Host argument
+            .arg(clap::Arg::with_name(ARG_HOST)
+                .long(ARG_HOST)
+                .help("Host name (optional if a .env file is present)")
+                .takes_value(true));
+    }
+
+    /// Process command line arguments
+    fn process(&mut self, matches: &clap::ArgMatches) -> error::Return {
+        // Parse arguments
+        for arg in matches.args.iter() {
+            match arg.0 {
+                &ARG_CONFIRM => {
+                    self.confirm = true;
+                },
+
+                &ARG_HOST => {
+                    self.host = match matches.value_of(arg.0) {
+                        Some(s) => s.to_owned(),
+                        None => return inval_error!(&ARG_HOST),
+                    };
+                },
+
+                _ => {
+                    return inval_error!(arg.0);
+                }
+            }
+        }
+
+        if !self.is_valid() {
+            self.fill_with_env()?;
+        }
+
+        log::info!("{:#?}", self);
+
+        // Check validity
+        if !self.is_valid() {
+            return generic_error!("Invalid configuration");
+        }
+
+        // Create filesystem
+        let layout = filesystem::layout(&self.host)?;
+
+        let fs = filesystem::Filesystem::from_file(&layout)?;
+
+        // Check disks
+        let mut unfixed = 0;
+
+        for disk in fs.disks.iter() {
+            if disk.read_only() {
+                log::info!("Disk `{}` skipped (read-only)", disk.config.device);
+                continue;
+            }
+
+            if !self.repair(&disk.config.device)? {
+                unfixed += 1;
+            }
+        }
+
+        if unfixed > 0 {
+            return match self.confirm {
+                true => generic_error!("Some GPT problems cannot be fixed"),
+                false => generic_error!(
+                    "GPT problems found, use --confirm to fix them"),
+            };
+        }
+
+        return Success!();
+    }
+}
+
+impl Command {
+    /// Create an instance of Command
+    pub fn new() -> Self {
+        Self {
+            host: "".to_string(),
+            confirm: false,
+        }
+    }
+
+    /// Use environment file to get needed values
+    fn fill_with_env(&mut self) -> error::Return {
+        env::read_for_host(&mut self.host)?;
+
+        return Success!();
+    }
+
+    /// Verify the GPT of a disk and fix it if confirmed (returns false if
+    /// problems remain)
+    fn repair(&self, device: &str) -> Result<bool, error::Error> {
+        let problems = gpt::verify(device)?;
+
+        if problems.is_empty() {
+            log::info!("No GPT problem found on `{}`", device);
+            return Ok(true);
+        }
+
+        for problem in problems.iter() {
+            report::warning(&format!("`{}`: {}", device, problem));
+        }
+
+        if !self.confirm {
+            return Ok(false);
+        }
+
+        gpt::fix_backup_header(device)?;
+
+        // Check again
+        let problems = gpt::verify(device)?;
+
+        for problem in problems.iter() {
+            log::error!("`{}`: {}", device, problem);
+        }
+
+        return Ok(problems.is_empty());
+    }
+}
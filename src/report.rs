@@ -0,0 +1,133 @@
+// -----------------------------------------------------------------------------
+
+use std::sync::Mutex;
+use std::time;
+
+// -----------------------------------------------------------------------------
+
+static REPORT: Mutex<Report> = Mutex::new(Report::new());
+
+// -----------------------------------------------------------------------------
+
+/// Summary of a run (timings, devices, warnings)
+#[derive(Debug)]
+struct Report {
+    /// Instant at which the run started
+    start: Option<time::Instant>,
+
+    /// Accumulated duration of each phase (in order of first appearance)
+    phases: Vec<(String, time::Duration)>,
+
+    /// List of devices that have been touched
+    devices: Vec<String>,
+
+    /// List of warnings encountered
+    warnings: Vec<String>,
+}
+
+impl Report {
+    /// Create an empty report
+    const fn new() -> Self {
+        Self {
+            start: None,
+            phases: Vec::new(),
+            devices: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Add a duration to a phase
+    fn add_phase(&mut self, name: &str, duration: time::Duration) {
+        for phase in self.phases.iter_mut() {
+            if phase.0 == name {
+                phase.1 += duration;
+                return;
+            }
+        }
+
+        self.phases.push((name.to_string(), duration));
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Start a new report (previous timings and devices are dropped, warnings are
+/// kept until the end of the run)
+pub fn begin() {
+    let mut report = REPORT.lock().unwrap();
+
+    report.phases.clear();
+    report.devices.clear();
+    report.start = Some(time::Instant::now());
+}
+
+/// Run a phase and accumulate its duration
+pub fn phase<T, F>(name: &str, f: F) -> T
+    where
+        F: FnOnce() -> T {
+
+    let start = time::Instant::now();
+
+    let result = f();
+
+    REPORT.lock().unwrap().add_phase(name, start.elapsed());
+
+    return result;
+}
+
+/// Register a device that has been touched
+pub fn device(device: &str) {
+    let mut report = REPORT.lock().unwrap();
+
+    if !report.devices.iter().any(|d| d == device) {
+        report.devices.push(device.to_string());
+    }
+}
+
+/// Register a warning (logged now and re-printed at the end of the run)
+pub fn warning(message: &str) {
+    log::warn!("{}", message);
+
+    REPORT.lock().unwrap().warnings.push(message.to_string());
+}
+
+/// Print the warnings encountered during the run
+pub fn warnings() {
+    let report = REPORT.lock().unwrap();
+
+    if report.warnings.is_empty() {
+        return;
+    }
+
+    log::warn!("Warnings");
+
+    for warning in report.warnings.iter() {
+        log::warn!("  - {}", warning);
+    }
+}
+
+/// Print the summary of the run
+pub fn summary() {
+    let report = REPORT.lock().unwrap();
+
+    let total = match report.start {
+        Some(s) => s.elapsed(),
+        None => time::Duration::from_secs(0),
+    };
+
+    log::info!("Summary");
+    log::info!("  {:<16} {:>10}", "Phase", "Duration");
+
+    for phase in report.phases.iter() {
+        log::info!("  {:<16} {:>9.1}s", phase.0, phase.1.as_secs_f64());
+    }
+
+    log::info!("  {:<16} {:>9.1}s", "total", total.as_secs_f64());
+
+    match report.devices.is_empty() {
+        true => log::info!("Devices: none"),
+        false => log::info!("Devices: {}", report.devices.join(", ")),
+    }
+
+    log::info!("Warnings: {}", report.warnings.len());
+}
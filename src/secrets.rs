@@ -3,12 +3,11 @@
 use clap;
 use std::fs;
 use std::path;
-use std::thread;
-use std::time;
 
 use super::env;
 use super::filesystem;
 use super::error;
+use super::logger;
 use super::lvm;
 use super::partition;
 use super::traits::{CliCommand, Mountable, Openable, Validate};
@@ -17,6 +16,7 @@ use super::zfs;
 
 // -----------------------------------------------------------------------------
 
+const ARG_FORCE_UNMOUNT: &str = "force-unmount";
 const ARG_HOST: &str = "host";
 const ARG_PASSWORD: &str = "password";
 
@@ -36,6 +36,12 @@ pub struct Command {
 
     /// File name of the key
     key_filename: String,
+
+    /// Directory of the key on the installed system
+    secrets_dir: String,
+
+    /// Whether existing mounts under the target are unmounted first
+    force_unmount: bool,
 }
 
 impl Validate for Command {
@@ -63,6 +69,11 @@ impl CliCommand for Command {
             .about("Install secrets")
             .version(version)
             .author(author)
+            // Force unmount argument
+            .arg(clap::Arg::with_name(ARG_FORCE_UNMOUNT)
+                .long(ARG_FORCE_UNMOUNT)
+                .help("Unmount what is already mounted under /mnt/root \
+                    instead of refusing to install the secrets"))
             // Host argument
             .arg(clap::Arg::with_name(ARG_HOST)
                 .long(ARG_HOST)
@@ -72,7 +83,9 @@ impl CliCommand for Command {
             .arg(clap::Arg::with_name(ARG_PASSWORD)
                 .long(ARG_PASSWORD)
                 .help("Password used to decrypt filesystems")
-                .takes_value(true));
+                .takes_value(true))
+            // Password age and sops arguments
+            .args(&utils::password_file_args(ARG_PASSWORD));
     }
 
     /// Process command line arguments
@@ -80,6 +93,10 @@ impl CliCommand for Command {
         // Parse arguments
         for arg in matches.args.iter() {
             match arg.0 {
+                &ARG_FORCE_UNMOUNT => {
+                    self.force_unmount = true;
+                },
+
                 &ARG_HOST => {
                     self.host = match matches.value_of(arg.0) {
                         Some(s) => s.to_owned(),
@@ -92,6 +109,12 @@ impl CliCommand for Command {
                         Some(s) => s.to_owned(),
                         None => return inval_error!(&ARG_PASSWORD),
                     };
+
+                    logger::add_secret(&self.password);
+                },
+
+                &utils::ARG_PASSWORD_AGE | &utils::ARG_PASSWORD_SOPS => {
+                    self.password = utils::decrypt_password(arg.0, matches)?;
                 },
 
                 _ => {
@@ -107,9 +130,11 @@ impl CliCommand for Command {
         log::info!("{:#?}", self);
 
         // Check validity
-        if !self.is_valid() {
-            return generic_error!("Invalid configuration");
-        }
+        env::check_values(&[
+            (ARG_HOST, &self.host),
+            ("key_file", &self.key_file),
+            ("key_filename", &self.key_filename),
+        ])?;
 
         // Create root directory
         let root = path::Path::new("/").join("mnt").join("root");
@@ -119,18 +144,17 @@ impl CliCommand for Command {
             Err(e) => return io_error!("Error creating directory", e),
         }
 
+        // Refuse to mount over a previous (interrupted) run
+        utils::check_unmounted(&root, self.force_unmount)?;
+
         // Create filesystem
-        let json = utils::current_dir()?
-            .join("layouts")
-            .join(format!("{}.json", self.host));
+        let layout = filesystem::layout(&self.host)?;
 
-        let mut fs = filesystem::Filesystem::from_json(&json)?;
+        let mut fs = filesystem::Filesystem::from_file(&layout)?;
 
         // Open filesystem
         fs.open(&self.password)?;
 
-        thread::sleep(time::Duration::from_secs(1));
-
         // Install key file
         self.install_keyfile(&root, &mut fs)?;
 
@@ -149,14 +173,20 @@ impl Command {
             password: "".to_string(),
             key_file: "".to_string(),
             key_filename: "".to_string(),
+            secrets_dir: env::DEFAULT_SECRETS_DIR.to_string(),
+            force_unmount: false,
         }
     }
 
     /// Use environment file to get needed values
     fn fill_with_env(&mut self) -> error::Return {
-        let config = env::read()?;
+        let config = match env::read_for_host(&mut self.host)? {
+            Some(c) => c,
+            None => return Success!(),
+        };
+
+        self.secrets_dir = config.nixos.secrets_dir()?;
 
-        self.host = config.nixos.host;
         self.key_file = config.nixos.key_file;
         self.key_filename = config.nixos.key_filename;
 
@@ -170,7 +200,7 @@ impl Command {
         fs: &mut filesystem::Filesystem) -> error::Return {
 
         for disk in fs.disks.iter_mut() {
-            if !disk.config.contains_system {
+            if !disk.config.contains_system() {
                 continue;
             }
 
@@ -248,7 +278,8 @@ impl Command {
     /// Install the key file to the given path
     fn install_keyfile_to(&self, root: &path::PathBuf) -> error::Return {
         // Create diretory
-        let install_path = root.join("etc").join("secrets").join("disks");
+        let install_path =
+            root.join(self.secrets_dir.trim_start_matches('/'));
 
         match fs::create_dir_all(&install_path) {
             Ok(_) => (),
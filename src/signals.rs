@@ -0,0 +1,89 @@
+// -----------------------------------------------------------------------------
+
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+use super::error;
+
+// -----------------------------------------------------------------------------
+
+/// Whether an interruption signal has been received
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Maximal number of child processes tracked at the same time (disks are
+/// partitioned in parallel)
+const MAX_CHILDREN: usize = 64;
+
+/// PIDs of the child processes currently running, which are also the IDs of
+/// their process groups (0 for a free slot)
+static CHILDREN: [AtomicI32; MAX_CHILDREN] =
+    [const { AtomicI32::new(0) }; MAX_CHILDREN];
+
+// -----------------------------------------------------------------------------
+
+/// Handler of SIGINT/SIGTERM (must only do async-signal-safe operations)
+extern "C" fn handler(_signal: libc::c_int) {
+    CANCELLED.store(true, Ordering::SeqCst);
+
+    // Kill the whole groups so that no grand-child keeps using the disks
+    for slot in CHILDREN.iter() {
+        let child = slot.load(Ordering::SeqCst);
+
+        if child > 0 {
+            unsafe {
+                libc::kill(-child, libc::SIGTERM);
+            }
+        }
+    }
+}
+
+/// Install the handler of interruption signals
+pub fn install() {
+    let handler = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// Check whether the run has been interrupted (to be called at safe points)
+pub fn check() -> error::Return {
+    if CANCELLED.load(Ordering::SeqCst) {
+        return generic_error!("Interrupted by signal");
+    }
+
+    return Success!();
+}
+
+/// Register a child process currently running (it must have been spawned in
+/// its own process group)
+pub fn set_child(pid: u32) {
+    for slot in CHILDREN.iter() {
+        let free = slot.compare_exchange(
+            0,
+            pid as i32,
+            Ordering::SeqCst,
+            Ordering::SeqCst);
+
+        if free.is_ok() {
+            return;
+        }
+    }
+
+    log::warn!("Too many child processes, {} won't be killed", pid);
+}
+
+/// Unregister a child process that has finished
+pub fn clear_child(pid: u32) {
+    for slot in CHILDREN.iter() {
+        let found = slot.compare_exchange(
+            pid as i32,
+            0,
+            Ordering::SeqCst,
+            Ordering::SeqCst);
+
+        if found.is_ok() {
+            return;
+        }
+    }
+}
@@ -143,17 +143,13 @@ impl CliCommand for Command {
         }
 
         // Create filesystem
-        let json = utils::current_dir()?
-            .join("layouts")
-            .join(format!("{}.json", self.host));
+        let layout = filesystem::layout(&self.host)?;
 
-        let mut fs = filesystem::Filesystem::from_json(&json)?;
+        let mut fs = filesystem::Filesystem::from_file(&layout)?;
 
         // Open filesystem
         fs.open(&self.password)?;
 
-        thread::sleep(time::Duration::from_secs(1));
-
         // Install NixOS
         self.install_nixos(&self.host, &self.repo, &mut fs)?;
 
@@ -198,7 +194,7 @@ impl Command {
         let etc = root.join("etc");
 
         // Root partition
-        fs.find_system_disk()?.find_root_partition()?.mount(&root)?;
+        fs.find_root()?.mount(&root)?;
 
         match fs::create_dir_all(&etc) {
             Ok(_) => log::info!("`{:?}` created", etc),
@@ -221,7 +217,7 @@ impl Command {
 
         // Unmount partitions
         fs.find_system_disk()?.find_efi_partition()?.unmount()?;
-        fs.find_system_disk()?.find_root_partition()?.unmount()?;
+        fs.find_root()?.unmount()?;
 
         return Success!();
     }
@@ -246,7 +242,9 @@ impl Command {
 
             log::info!("Cloning {} to {}", repo, local_repo);
 
-            utils::command_output("git", &["clone", repo, local_repo])?;
+            utils::without_timeout(|| {
+                utils::command_output("git", &["clone", repo, local_repo])
+            })?;
 
             log::info!("{} cloned to {}", repo, local_repo);
 
@@ -287,7 +285,9 @@ impl Command {
             None => return generic_error!("No root"),
         };
 
-        utils::command_output("nixos-install", &["--root", root])?;
+        utils::without_timeout(|| {
+            utils::command_output("nixos-install", &["--root", root])
+        })?;
 
         return Success!();
     }
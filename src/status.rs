@@ -0,0 +1,203 @@
+// -----------------------------------------------------------------------------
+
+use clap;
+
+use super::env;
+use super::error;
+use super::filesystem;
+use super::luks;
+use super::traits::{CliCommand, Validate};
+use super::utils;
+use super::zfs;
+
+// -----------------------------------------------------------------------------
+
+const ARG_HOST: &str = "host";
+
+// -----------------------------------------------------------------------------
+
+/// Command structure for inspecting the state of the devices of a layout
+#[derive(Debug)]
+pub struct Command {
+    /// Host name
+    host: String,
+}
+
+impl Validate for Command {
+    fn is_valid(&self) -> bool {
+        return !self.host.is_empty();
+    }
+}
+
+impl CliCommand for Command {
+    /// Get the name of the command
+    fn name(&self) -> &'static str {
+        return "status";
+    }
+
+    /// Get command and its arguments
+    fn get<'a, 'b>(
+        &self,
+        version: &'b str,
+        author: &'b str) -> clap::App<'a, 'b> {
+
+        return clap::App::new(self.name())
+            .about("Report whether the devices of the layout exist, are \
+                opened and mounted (read-only)")
+            .version(version)
+            .author(author)
+            // Host argument
+            .arg(clap::Arg::with_name(ARG_HOST)
+                .long(ARG_HOST)
+                .help("Host name (optional if a .env file is present)")
+                .takes_value(true));
+    }
+
+    /// Process command line arguments
+    fn process(&mut self, matches: &clap::ArgMatches) -> error::Return {
+        // Parse arguments
+        for arg in matches.args.iter() {
+            match arg.0 {
+                &ARG_HOST => {
+                    self.host = match matches.value_of(arg.0) {
+                        Some(s) => s.to_owned(),
+                        None => return inval_error!(&ARG_HOST),
+                    };
+                },
+
+                _ => {
+                    return inval_error!(arg.0);
+                }
+            }
+        }
+
+        if !self.is_valid() {
+            self.fill_with_env()?;
+        }
+
+        log::debug!("{:#?}", self);
+
+        // Check validity
+        if !self.is_valid() {
+            return generic_error!("Invalid configuration");
+        }
+
+        // Create filesystem
+        let layout = filesystem::layout(&self.host)?;
+
+        let fs = filesystem::Filesystem::from_file(&layout)?;
+
+        log::info!(
+            "{:<16} {:<16} {:<7} {:<7} {:<7} {}",
+            "label",
+            "device",
+            "exists",
+            "luks",
+            "pool",
+            "mounted");
+
+        for disk in fs.disks.iter() {
+            for partition in disk.partitions.iter() {
+                let config = &partition.config;
+
+                let device = match &config.device {
+                    Some(d) => d.as_str(),
+                    None => "-",
+                };
+
+                let exists = device_exists(device);
+
+                let luks = match config.encrypted {
+                    true => yes_no(luks::is_opened(&config.label)),
+                    false => "-",
+                };
+
+                let pool = match config.fs_type.as_str() {
+                    "zfs" => yes_no(zfs::pool_exists(&config.label)),
+                    _ => "-",
+                };
+
+                // Mapper and volumes are listed as children of the partition
+                let mounted = exists && is_mounted(device);
+
+                log::info!(
+                    "{:<16} {:<16} {:<7} {:<7} {:<7} {}",
+                    config.label,
+                    device,
+                    yes_no(exists),
+                    luks,
+                    pool,
+                    yes_no(mounted));
+            }
+        }
+
+        for array in fs.raids.iter() {
+            let device = array.config.device();
+            let exists = device_exists(&device);
+
+            let luks = match array.config.encrypted {
+                true => yes_no(luks::is_opened(&array.config.label)),
+                false => "-",
+            };
+
+            log::info!(
+                "{:<16} {:<16} {:<7} {:<7} {:<7} {}",
+                array.config.label,
+                device,
+                yes_no(exists),
+                luks,
+                "-",
+                yes_no(exists && is_mounted(&device)));
+        }
+
+        return Success!();
+    }
+}
+
+impl Command {
+    /// Create an instance of Command
+    pub fn new() -> Self {
+        Self {
+            host: "".to_string(),
+        }
+    }
+
+    /// Use environment file to get needed values
+    fn fill_with_env(&mut self) -> error::Return {
+        env::read_for_host(&mut self.host)?;
+
+        return Success!();
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Check if a block device exists
+fn device_exists(device: &str) -> bool {
+    return utils::command_output("lsblk", &["-n", "-d", device]).is_ok();
+}
+
+/// Check if a block device or one of its children (LUKS mapper, logical
+/// volumes) is mounted
+fn is_mounted(device: &str) -> bool {
+    let output = match utils::command_output(
+        "lsblk",
+        &["-n", "-r", "-o", "MOUNTPOINT", device]) {
+
+        Ok(o) => o,
+        Err(_) => return false,
+    };
+
+    return match utils::command_stdout_to_string(&output) {
+        Ok(s) => s.lines().any(|l| !l.trim().is_empty()),
+        Err(_) => false,
+    };
+}
+
+/// Get the table representation of a boolean
+fn yes_no(value: bool) -> &'static str {
+    return match value {
+        true => "yes",
+        false => "no",
+    };
+}
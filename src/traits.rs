@@ -2,6 +2,7 @@
 
 use clap;
 use std::path;
+use std::process;
 
 use super::error;
 
@@ -47,3 +48,10 @@ pub trait Configurable<T> {
 
     fn config(&self) -> Result<T, error::Error>;
 }
+
+// -----------------------------------------------------------------------------
+
+pub trait CommandRunner {
+    fn run(&self, command: &str, args: &[&str], stdin: Option<&[u8]>)
+        -> Result<process::Output, error::Error>;
+}
@@ -0,0 +1,96 @@
+// -----------------------------------------------------------------------------
+
+use clap;
+use std::path;
+
+use super::error;
+use super::traits::{CliCommand, Validate};
+use super::utils;
+
+// -----------------------------------------------------------------------------
+
+const ARG_TARGET: &str = "target";
+
+/// Directory where the commands mount the system of a host
+const DEFAULT_TARGET: &str = "/mnt/root";
+
+// -----------------------------------------------------------------------------
+
+/// Command structure for unmounting what a previous (interrupted) run left
+/// mounted
+#[derive(Debug)]
+pub struct Command {
+    /// Directory under which everything is unmounted
+    target: String,
+}
+
+impl Validate for Command {
+    fn is_valid(&self) -> bool {
+        return path::Path::new(&self.target).is_absolute();
+    }
+}
+
+impl CliCommand for Command {
+    /// Get the name of the command
+    fn name(&self) -> &'static str {
+        return "unmount";
+    }
+
+    /// Get command and its arguments
+    fn get<'a, 'b>(
+        &self,
+        version: &'b str,
+        author: &'b str) -> clap::App<'a, 'b> {
+
+        return clap::App::new(self.name())
+            .about("Unmount everything mounted under the target directory, \
+                the deepest mount points first")
+            .version(version)
+            .author(author)
+            // Target argument
+            .arg(clap::Arg::with_name(ARG_TARGET)
+                .long(ARG_TARGET)
+                .help("Directory to unmount")
+                .default_value(DEFAULT_TARGET)
+                .takes_value(true));
+    }
+
+    /// Process command line arguments
+    fn process(&mut self, matches: &clap::ArgMatches) -> error::Return {
+        // Parse arguments
+        for arg in matches.args.iter() {
+            match arg.0 {
+                &ARG_TARGET => {
+                    self.target = match matches.value_of(arg.0) {
+                        Some(s) => s.to_owned(),
+                        None => return inval_error!(&ARG_TARGET),
+                    };
+                },
+
+                _ => {
+                    return inval_error!(arg.0);
+                }
+            }
+        }
+
+        log::info!("{:#?}", self);
+
+        // Check validity
+        if !self.is_valid() {
+            return generic_error!(&format!(
+                "Target `{}` is not an absolute path",
+                self.target));
+        }
+
+        return utils::unmount_all(path::Path::new(&self.target));
+    }
+}
+
+impl Command {
+    /// Create an instance of Command
+    pub fn new() -> Self {
+        Self {
+            target: DEFAULT_TARGET.to_string(),
+        }
+    }
+}
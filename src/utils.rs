@@ -1,20 +1,269 @@
 // -----------------------------------------------------------------------------
 
+use clap;
+use regex::Regex;
 use serde::{Serialize};
+use std::cell;
+use std::cmp;
+use std::collections;
 use std::env;
 use std::fs;
+use std::io;
+use std::io::BufRead;
 use std::io::BufReader;
+use std::io::Read;
 use std::io::Write;
+use std::os::unix::process::CommandExt;
 use std::path;
 use std::process;
 use std::str;
+use std::sync;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time;
 
 use super::error;
+use super::logger;
+use super::signals;
+use super::traits::CommandRunner;
 
 // -----------------------------------------------------------------------------
 
+/// Runner of the external commands (shared with the spawned threads)
+pub type Runner = sync::Arc<dyn CommandRunner + Send + Sync>;
+
+// -----------------------------------------------------------------------------
+
+/// Argument giving the password in an age encrypted file
+pub const ARG_PASSWORD_AGE: &str = "password-age";
+
+/// Argument giving the password in a sops encrypted file
+pub const ARG_PASSWORD_SOPS: &str = "password-sops";
+
+/// Number of lines of output kept to describe a failed command
+const ERROR_OUTPUT_LINES: usize = 20;
+
+/// Default time after which a command is killed (in seconds)
+pub const DEFAULT_COMMAND_TIMEOUT: u64 = 300;
+
+/// Default number of attempts of the operations failing on transient errors
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// Delay before the second attempt of an operation (doubled after each one)
+const RETRY_DELAY: time::Duration = time::Duration::from_millis(500);
+
+/// Environment making the tools fail instead of prompting (values already set
+/// by the user are kept)
+const NON_INTERACTIVE_ENV: [(&str, &str); 2] = [
+    ("GIT_TERMINAL_PROMPT", "0"),
+    ("GIT_SSH_COMMAND", "ssh -o BatchMode=yes"),
+];
+
+/// Set when network operations are forbidden
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Set when commands and files must only be logged
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Time after which a command is killed (in seconds, 0 for none)
+static COMMAND_TIMEOUT: AtomicU64 = AtomicU64::new(DEFAULT_COMMAND_TIMEOUT);
+
+/// Number of attempts of the operations failing on transient errors
+static RETRIES: AtomicU32 = AtomicU32::new(DEFAULT_RETRIES);
+
+thread_local! {
+    /// Set while running commands allowed to take any time
+    static NO_TIMEOUT: cell::Cell<bool> = const { cell::Cell::new(false) };
+
+    /// Runner of the external commands (spawned threads use the system one
+    /// unless the `ThreadContext` of their parent is installed)
+    static RUNNER: cell::RefCell<Runner> =
+        cell::RefCell::new(sync::Arc::new(SystemRunner));
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Dry run mode of the current test only (tests run in parallel)
+    static TEST_DRY_RUN: cell::Cell<bool> = const { cell::Cell::new(false) };
+}
+
+// -----------------------------------------------------------------------------
+
+/// Forbid (or allow) network operations
+pub fn set_offline(offline: bool) {
+    OFFLINE.store(offline, Ordering::SeqCst);
+}
+
+/// Whether network operations are forbidden
+pub fn is_offline() -> bool {
+    return OFFLINE.load(Ordering::SeqCst);
+}
+
+/// Fail if a network operation is attempted while offline
+pub fn require_network(operation: &str) -> error::Return {
+    if is_offline() {
+        return generic_error!(&format!(
+            "{} requires network access (--offline is set)",
+            operation));
+    }
+
+    return Success!();
+}
+
+/// Only log the commands and written files instead of running/writing them
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::SeqCst);
+}
+
+/// Whether commands and files must only be logged
+pub fn is_dry_run() -> bool {
+    #[cfg(test)]
+    if TEST_DRY_RUN.with(|d| d.get()) {
+        return true;
+    }
+
+    return DRY_RUN.load(Ordering::SeqCst);
+}
+
+/// Enable (or disable) the dry run mode in the current test only
+#[cfg(test)]
+pub fn set_test_dry_run(dry_run: bool) {
+    TEST_DRY_RUN.with(|d| d.set(dry_run));
+}
+
+/// Set the time after which a command is killed (in seconds, 0 for none)
+pub fn set_command_timeout(seconds: u64) {
+    COMMAND_TIMEOUT.store(seconds, Ordering::SeqCst);
+}
+
+/// Get the time after which a command is killed (if any)
+fn command_timeout() -> Option<time::Duration> {
+    if NO_TIMEOUT.with(|n| n.get()) {
+        return None;
+    }
+
+    return match COMMAND_TIMEOUT.load(Ordering::SeqCst) {
+        0 => None,
+        s => Some(time::Duration::from_secs(s)),
+    };
+}
+
+/// Run commands without timeout (builds, copies of closures, clones)
+pub fn without_timeout<T, F>(f: F) -> T
+    where
+        F: FnOnce() -> T {
+
+    let previous = NO_TIMEOUT.with(|n| n.replace(true));
+
+    let result = f();
+
+    NO_TIMEOUT.with(|n| n.set(previous));
+
+    return result;
+}
+
+/// Set the number of attempts of the operations failing on transient errors
+pub fn set_retries(retries: u32) {
+    RETRIES.store(retries.max(1), Ordering::SeqCst);
+}
+
+/// Run an operation until it succeeds or has been attempted as many times as
+/// configured, waiting longer after each failure (nothing is retried in dry
+/// run mode since commands are not run)
+pub fn retry<T, F>(operation: &str, f: F) -> Result<T, error::Error>
+    where
+        F: FnMut() -> Result<T, error::Error> {
+
+    let attempts = RETRIES.load(Ordering::SeqCst);
+
+    return retry_while(operation, f, |attempt, _| {
+        return match attempt < attempts {
+            true => Some(RETRY_DELAY * 2u32.pow((attempt - 1).min(16))),
+            false => None,
+        };
+    });
+}
+
+/// Run an operation until it succeeds or the timeout expires, waiting longer
+/// after each failure (nothing is retried in dry run mode)
+pub fn retry_timeout<T, F>(
+    operation: &str,
+    timeout: time::Duration,
+    f: F) -> Result<T, error::Error>
+    where
+        F: FnMut() -> Result<T, error::Error> {
+
+    return retry_while(operation, f, |attempt, elapsed| {
+        let remaining = timeout.checked_sub(elapsed)?;
+        let delay = RETRY_DELAY * 2u32.pow((attempt - 1).min(16));
+
+        return match remaining.is_zero() {
+            true => None,
+            false => Some(delay.min(remaining)),
+        };
+    });
+}
+
+/// Run an operation until it succeeds or `delay` (given the attempt and the
+/// time elapsed since the first one) gives no delay before the next attempt
+fn retry_while<T, F, D>(
+    operation: &str,
+    mut f: F,
+    delay: D) -> Result<T, error::Error>
+    where
+        F: FnMut() -> Result<T, error::Error>,
+        D: Fn(u32, time::Duration) -> Option<time::Duration> {
+
+    let start = time::Instant::now();
+
+    let mut attempt = 1;
+
+    loop {
+        let error = match f() {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
+
+        if is_dry_run() {
+            return Err(error);
+        }
+
+        let delay = match delay(attempt, start.elapsed()) {
+            Some(d) => d,
+            None => return Err(error),
+        };
+
+        signals::check()?;
+
+        log::warn!(
+            "{} failed (attempt {}), retrying in {:?}: {}",
+            operation,
+            attempt,
+            delay,
+            error);
+
+        thread::sleep(delay);
+
+        attempt += 1;
+    }
+}
+
+/// Mount a device (retried while it appears, e.g. after its LUKS container or
+/// its pool has been opened)
+pub fn mount(args: &[&str]) -> error::Return {
+    retry("Mount", || command_output("mount", args))?;
+
+    return Success!();
+}
+
 /// Write bytes to a file
 pub fn write_to_file(content: &[u8], filepath: &path::Path) -> error::Return {
+    if is_dry_run() {
+        log::info!("[dry-run] write {:?} ({} bytes)", filepath, content.len());
+        return Success!();
+    }
+
     let mut file = match fs::File::create(filepath) {
         Ok(f) => f,
         Err(e) => return fs_error!(filepath.to_path_buf(), e),
@@ -53,7 +302,7 @@ pub fn load_json<T>(filepath : &path::Path) -> Result<T, error::Error>
         T: serde::de::DeserializeOwned {
 
     // Open the file in read-only mode
-    let file = match fs::File::open(&filepath) {
+    let file = match fs::File::open(filepath) {
         Ok(f) => f,
         Err(e) => return fs_error!(filepath.to_path_buf(), e)
     };
@@ -68,6 +317,116 @@ pub fn load_json<T>(filepath : &path::Path) -> Result<T, error::Error>
             e)
     };
 }
+
+/// Convert an object to a printable Toml string
+pub fn toml_to_string(data: &impl Serialize) -> Result<String, error::Error> {
+    // Going through a value writes the plain fields before the tables
+    let value = match toml::Value::try_from(data) {
+        Ok(v) => v,
+        Err(e) => return toml_error!("Cannot serialize data", e),
+    };
+
+    match toml::to_string_pretty(&value) {
+        Ok(s) => return Ok(s),
+        Err(e) => return toml_error!("Cannot serialize data", e),
+    }
+}
+
+/// Load Toml data from file
+pub fn load_toml<T>(filepath : &path::Path) -> Result<T, error::Error>
+    where
+        T: serde::de::DeserializeOwned {
+
+    let content = match fs::read_to_string(filepath) {
+        Ok(c) => c,
+        Err(e) => return fs_error!(filepath.to_path_buf(), e)
+    };
+
+    match toml::from_str(&content) {
+        Ok(c) => return Ok(c),
+        Err(e) => return toml_error!(
+            filepath.to_path_buf().to_str().unwrap(),
+            e)
+    };
+}
+
+/// Check if a file is a Toml file (Json otherwise)
+pub fn is_toml(filepath: &path::Path) -> bool {
+    return filepath.extension().is_some_and(|e| e == "toml");
+}
+
+/// Structural diff of two Json values (one line per added `+`, removed `-`
+/// or changed `~` field, arrays are compared index by index)
+pub fn json_diff(old: &serde_json::Value, new: &serde_json::Value)
+    -> Vec<String> {
+
+    let mut changes = Vec::new();
+
+    json_diff_at("", old, new, &mut changes);
+
+    return changes;
+}
+
+/// Diff two Json values located at `path`
+fn json_diff_at(
+    path: &str,
+    old: &serde_json::Value,
+    new: &serde_json::Value,
+    changes: &mut Vec<String>) {
+
+    match (old, new) {
+        (serde_json::Value::Object(o), serde_json::Value::Object(n)) => {
+            for (key, value) in o.iter() {
+                let child = match path.is_empty() {
+                    true => key.clone(),
+                    false => format!("{}.{}", path, key),
+                };
+
+                match n.get(key) {
+                    Some(v) => json_diff_at(&child, value, v, changes),
+                    None => changes.push(format!("- {}: {}", child, value)),
+                }
+            }
+
+            for (key, value) in n.iter() {
+                if o.contains_key(key) {
+                    continue;
+                }
+
+                let child = match path.is_empty() {
+                    true => key.clone(),
+                    false => format!("{}.{}", path, key),
+                };
+
+                changes.push(format!("+ {}: {}", child, value));
+            }
+        },
+
+        (serde_json::Value::Array(o), serde_json::Value::Array(n)) => {
+            for i in 0..o.len().max(n.len()) {
+                let child = format!("{}[{}]", path, i);
+
+                match (o.get(i), n.get(i)) {
+                    (Some(a), Some(b)) => json_diff_at(&child, a, b, changes),
+                    (Some(a), None) => {
+                        changes.push(format!("- {}: {}", child, a))
+                    },
+                    (None, Some(b)) => {
+                        changes.push(format!("+ {}: {}", child, b))
+                    },
+                    (None, None) => (),
+                }
+            }
+        },
+
+        (o, n) => {
+            if o != n {
+                changes.push(format!("~ {}: {} -> {}", path, o, n));
+            }
+        },
+    }
+}
+
 /// Get current directory path
 pub fn current_dir() -> Result<path::PathBuf, error::Error> {
     match env::current_dir() {
@@ -76,25 +435,255 @@ pub fn current_dir() -> Result<path::PathBuf, error::Error> {
     }
 }
 
+/// Read bytes from the OS random number generator
+pub fn random_bytes(size: usize) -> Result<Vec<u8>, error::Error> {
+    let path = path::Path::new("/dev/urandom");
+
+    let mut file = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) => return fs_error!(path.to_path_buf(), e),
+    };
+
+    let mut bytes = vec![0u8; size];
+
+    match file.read_exact(&mut bytes) {
+        Ok(_) => return Ok(bytes),
+        Err(e) => return fs_error!(path.to_path_buf(), e),
+    }
+}
+
+/// Get the arguments giving the password in an encrypted file (age or sops),
+/// instead of the plain `password` argument
+pub fn password_file_args<'a, 'b>(password: &'a str)
+    -> [clap::Arg<'a, 'b>; 2] {
+
+    return [
+        clap::Arg::with_name(ARG_PASSWORD_AGE)
+            .long(ARG_PASSWORD_AGE)
+            .help("age encrypted file containing the password")
+            .conflicts_with_all(&[password, ARG_PASSWORD_SOPS])
+            .takes_value(true),
+        clap::Arg::with_name(ARG_PASSWORD_SOPS)
+            .long(ARG_PASSWORD_SOPS)
+            .help("sops encrypted file containing the password")
+            .conflicts_with_all(&[password, ARG_PASSWORD_AGE])
+            .takes_value(true),
+    ];
+}
+
+/// Get the password from the encrypted file given by one of the
+/// `password_file_args`
+pub fn decrypt_password(arg: &str, matches: &clap::ArgMatches)
+    -> Result<String, error::Error> {
+
+    let file = match matches.value_of(arg) {
+        Some(f) => f,
+        None => return inval_error!(arg),
+    };
+
+    let decryptor = match arg {
+        ARG_PASSWORD_AGE => "age",
+        _ => "sops",
+    };
+
+    return decrypt_secret(decryptor, file);
+}
+
+/// Decrypt a secret file with `sops` or `age` (using the ambient key), the
+/// secret is registered to be redacted from logs
+pub fn decrypt_secret(decryptor: &str, file: &str)
+    -> Result<String, error::Error> {
+
+    if !find_program(decryptor) {
+        return generic_error!(&format!("`{}` not found", decryptor));
+    }
+
+    if !path::Path::new(file).is_file() {
+        return generic_error!(&format!("Secret file `{}` not found", file));
+    }
+
+    let identity = match env::var("SOPS_AGE_KEY_FILE") {
+        Ok(i) => i,
+        Err(_) => match env::var("HOME") {
+            Ok(h) => format!("{}/.config/sops/age/keys.txt", h),
+            Err(_) => "".to_string(),
+        },
+    };
+
+    let args = match decryptor {
+        "age" => vec!["--decrypt", "--identity", &identity, file],
+        _ => vec!["--decrypt", file],
+    };
+
+    let output = match command_output(decryptor, &args) {
+        Ok(o) => o,
+        Err(e) => return generic_error!(&format!(
+            "Cannot decrypt `{}` with {}: {}",
+            file,
+            decryptor,
+            e)),
+    };
+
+    let secret = match String::from_utf8(output.stdout) {
+        Ok(s) => s.trim_end_matches('\n').to_string(),
+        Err(_) => return generic_error!("Decrypted secret is not UTF-8"),
+    };
+
+    if secret.is_empty() {
+        return generic_error!(&format!("Secret file `{}` is empty", file));
+    }
+
+    logger::add_secret(&secret);
+
+    return Ok(secret);
+}
+
+/// Check if a program is available in the `PATH`
+pub fn find_program(name: &str) -> bool {
+    let paths = match env::var_os("PATH") {
+        Some(p) => p,
+        None => return false,
+    };
+
+    return env::split_paths(&paths).any(|p| p.join(name).is_file());
+}
+
+/// Get the mount points at or under a directory (from `/proc/mounts`)
+pub fn mounts_under(dir: &path::Path) -> Result<Vec<String>, error::Error> {
+    let path = path::Path::new("/proc/mounts");
+
+    let content = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => return fs_error!(path.to_path_buf(), e),
+    };
+
+    return Ok(parse_mounts(&content, dir));
+}
+
+/// Get the mount points at or under a directory from the content of
+/// `/proc/mounts` (octal escapes of the mount points are decoded)
+fn parse_mounts(content: &str, dir: &path::Path) -> Vec<String> {
+    let mut mounts: Vec<String> = content
+        .lines()
+        .filter_map(|l| l.split_whitespace().nth(1))
+        .map(unescape_mount)
+        .filter(|m| path::Path::new(m).starts_with(dir))
+        .collect();
+
+    mounts.dedup();
+
+    return mounts;
+}
+
+/// Decode the octal escapes (`\040` for a space...) of a mount point
+fn unescape_mount(mount: &str) -> String {
+    let mut result = String::new();
+    let mut chars = mount.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        let code: String = chars.clone().take(3).collect();
+
+        match u8::from_str_radix(&code, 8) {
+            Ok(b) if code.len() == 3 => {
+                result.push(b as char);
+                chars.nth(2);
+            },
+            _ => result.push(c),
+        }
+    }
+
+    return result;
+}
+
+/// Sort mount points in the order they can be unmounted: the deepest first,
+/// the last mounted first at the same depth
+fn unmount_order(mut mounts: Vec<String>) -> Vec<String> {
+    mounts.reverse();
+    mounts.sort_by_key(|m| {
+        cmp::Reverse(path::Path::new(m).components().count())
+    });
+
+    return mounts;
+}
+
+/// Unmount everything mounted at or under a directory (the directory itself
+/// doesn't need to be a mount point)
+pub fn unmount_all(dir: &path::Path) -> error::Return {
+    for mount in unmount_order(mounts_under(dir)?).iter() {
+        command_output("umount", &[mount])?;
+
+        log::info!("`{}` unmounted", mount);
+    }
+
+    return Success!();
+}
+
+/// Make sure nothing is mounted at or under a directory: refuse if something
+/// is, or unmount it if forced
+pub fn check_unmounted(dir: &path::Path, force: bool) -> error::Return {
+    let mounts = mounts_under(dir)?;
+
+    if mounts.is_empty() {
+        return Success!();
+    }
+
+    let target = dir.to_string_lossy().to_string();
+
+    if !force {
+        return generic_error!(&format!(
+            "Already mounted under `{}`: {} (run `nixos-setup unmount \
+                --target {}` or use --force-unmount)",
+            target,
+            mounts.join(", "),
+            target));
+    }
+
+    log::warn!("Unmounting {}", mounts.join(", "));
+
+    return unmount_all(dir);
+}
+
 /// Get output of a command
 pub fn command_output(command: &str, args: &[&str])
     -> Result<process::Output, error::Error> {
 
     log::debug!("Running command: {} {:?}", command, args);
 
-    let output = match process::Command::new(command).args(args).output() {
-        Ok(o) => o,
-        Err(e) => return io_error!(&format!("`{}` command", command), e),
-    };
+    let output = runner().run(command, args, None)?;
 
     if !output.status.success() {
-        return generic_error!(
-            &format!("`{}` command returned an error", command));
+        return Err(command_error(command, &output));
     }
 
     return Ok(output);
 }
 
+/// Build the error of a failed command from the last lines of its stderr (or
+/// of its stdout for the tools reporting errors there, e.g. sgdisk)
+pub fn command_error(command: &str, output: &process::Output) -> error::Error {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    let text = match stderr.trim().is_empty() {
+        true => String::from_utf8_lossy(&output.stdout),
+        false => stderr,
+    };
+
+    let lines: Vec<&str> = text
+        .lines()
+        .map(|l| l.trim_end())
+        .filter(|l| !l.is_empty())
+        .collect();
+
+    let last = &lines[lines.len().saturating_sub(ERROR_OUTPUT_LINES)..];
+
+    return error::Error::process(output.status, command, &last.join("\n"));
+}
+
 /// Convert command output to string
 pub fn command_stdout_to_string(output: &process::Output)
     -> Result<String, error::Error> {
@@ -106,54 +695,533 @@ pub fn command_stdout_to_string(output: &process::Output)
     }
 }
 
-/// Spawn a command with stdout and stderr in pipes
+/// Spawn a command with stdin, stdout and stderr in pipes
 pub fn spawn_command(command: &str, args: &[&str], stdin: Option<&[u8]>)
     -> Result<process::Output, error::Error> {
 
     log::debug!("Running command: {} {:?}", command, args);
 
-    // Create process
-    let mut process = match process::Command::new(command)
-        .args(args)
-        .stdin(process::Stdio::piped())
+    // The input is never logged (it usually holds a passphrase)
+    match stdin {
+        Some(s) => log::trace!("...with input: <{} bytes>", s.len()),
+        None => (),
+    }
+
+    let output = runner().run(command, args, stdin)?;
+
+    if !output.status.success() {
+        return Err(command_error(command, &output));
+    }
+
+    return Ok(output);
+}
+
+/// Run a command logging its output (stdout and stderr) line by line as it
+/// arrives, each line being also given to `on_line`
+pub fn stream_command(
+    command: &str,
+    args: &[&str],
+    on_line: &mut dyn FnMut(&str)) -> error::Return {
+
+    log::debug!("Running command: {} {:?}", command, args);
+
+    if is_dry_run() {
+        log::info!("[dry-run] {} {}", command, args.join(" "));
+        return Success!();
+    }
+
+    let mut process = match system_command(command, args)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
         .spawn() {
             Ok(p) => p,
             Err(e) => return cmd_error!(&command, e),
         };
 
-    // Inject stdin if needed
-    match stdin {
-        Some(s) => {
-            log::debug!("...with input: `{}`", str::from_utf8(s).unwrap());
+    let pid = process.id();
+
+    signals::set_child(pid);
+
+    // Both streams are read in their own thread (the end of stderr is kept to
+    // describe a failure)
+    let (sender, receiver) = mpsc::channel();
+
+    let mut readers = Vec::new();
+
+    match process.stdout.take() {
+        Some(s) => readers.push(forward_lines(s, false, sender.clone())),
+        None => (),
+    }
+
+    match process.stderr.take() {
+        Some(s) => readers.push(forward_lines(s, true, sender.clone())),
+        None => (),
+    }
+
+    drop(sender);
+
+    let mut stderr: collections::VecDeque<String> =
+        collections::VecDeque::new();
+
+    for (is_stderr, line) in receiver.iter() {
+        log::info!("{}", line);
+
+        on_line(&line);
 
-            let mut stream = match process.stdin.take() {
-                Some(s) => s,
-                None => return generic_error!("Cannot obtain access to stdin"),
+        if is_stderr {
+            if stderr.len() == ERROR_OUTPUT_LINES {
+                stderr.pop_front();
+            }
+
+            stderr.push_back(line);
+        }
+    }
+
+    for reader in readers.into_iter() {
+        let _ = reader.join();
+    }
+
+    let status = process.wait();
+
+    signals::clear_child(pid);
+
+    let status = match status {
+        Ok(s) => s,
+        Err(e) => return io_error!(&format!("`{}` command", command), e),
+    };
+
+    if !status.success() {
+        return process_error!(
+            command,
+            status,
+            &Vec::from(stderr).join("\n"));
+    }
+
+    return Success!();
+}
+
+/// Send the lines of a stream (flagged as coming from stderr or not) from a
+/// thread
+fn forward_lines(
+    stream: impl Read + Send + 'static,
+    is_stderr: bool,
+    sender: mpsc::Sender<(bool, String)>) -> thread::JoinHandle<()> {
+
+    return thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => return,
             };
 
-            match stream.write_all(s) {
+            match sender.send((is_stderr, line)) {
                 Ok(_) => (),
-                Err(_) => {
-                    return generic_error!("Cannot write passphrase to stdin");
-                },
+                Err(_) => return,
             }
+        }
+    });
+}
 
-            drop(stream);
-        },
+/// Replace the runner of the commands (of the current thread)
+pub fn set_runner(runner: Runner) {
+    RUNNER.with(|r| *r.borrow_mut() = runner);
+}
 
-        None => (),
+/// Get the runner of the commands (of the current thread)
+fn runner() -> Runner {
+    if is_dry_run() {
+        return sync::Arc::new(DryRunRunner);
+    }
+
+    return RUNNER.with(|r| r.borrow().clone());
+}
+
+/// Settings of a thread running commands (its runner) to be installed in the
+/// threads it spawns, so that they run commands the same way
+#[derive(Clone)]
+pub struct ThreadContext {
+    /// Runner of the commands
+    runner: Runner,
+
+    /// Dry run mode of the current test
+    #[cfg(test)]
+    dry_run: bool,
+}
+
+impl ThreadContext {
+    /// Capture the settings of the current thread
+    pub fn current() -> Self {
+        Self {
+            runner: RUNNER.with(|r| r.borrow().clone()),
+            #[cfg(test)]
+            dry_run: TEST_DRY_RUN.with(|d| d.get()),
+        }
+    }
+
+    /// Install the settings in the current thread
+    pub fn install(&self) {
+        set_runner(self.runner.clone());
+
+        #[cfg(test)]
+        set_test_dry_run(self.dry_run);
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Runner only logging the commands (a success without output is returned)
+pub struct DryRunRunner;
+
+impl CommandRunner for DryRunRunner {
+    fn run(&self, command: &str, args: &[&str], stdin: Option<&[u8]>)
+        -> Result<process::Output, error::Error> {
+
+        use std::os::unix::process::ExitStatusExt;
+
+        let input = match stdin {
+            Some(_) => " (with input)",
+            None => "",
+        };
+
+        log::info!("[dry-run] {} {}{}", command, args.join(" "), input);
+
+        return Ok(process::Output {
+            status: process::ExitStatus::from_raw(0),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        });
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Prepare an external command: it runs in its own session, so that it can be
+/// killed with its group on interruption or timeout, without a controlling
+/// terminal, so that the tools prompting there (ssh, age) fail instead of
+/// being stopped
+fn system_command(command: &str, args: &[&str]) -> process::Command {
+    let mut process = process::Command::new(command);
+
+    process.args(args);
+
+    for (name, value) in NON_INTERACTIVE_ENV.iter() {
+        if env::var_os(name).is_none() {
+            process.env(name, value);
+        }
+    }
+
+    unsafe {
+        process.pre_exec(|| {
+            match libc::setsid() {
+                -1 => return Err(io::Error::last_os_error()),
+                _ => return Ok(()),
+            }
+        });
     }
 
-    // Wait for process to finish
-    let output = match process.wait_with_output() {
+    return process;
+}
+
+/// Runner executing the commands on the system
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, command: &str, args: &[&str], stdin: Option<&[u8]>)
+        -> Result<process::Output, error::Error> {
+
+        let stdin_mode = match stdin {
+            Some(_) => process::Stdio::piped(),
+            None => process::Stdio::null(),
+        };
+
+        // Create process
+        let mut process = match system_command(command, args)
+            .stdin(stdin_mode)
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .spawn() {
+                Ok(p) => p,
+                Err(e) => return cmd_error!(&command, e),
+            };
+
+        // Inject stdin if needed
+        match stdin {
+            Some(s) => {
+                let mut stream = match process.stdin.take() {
+                    Some(s) => s,
+                    None => return generic_error!(
+                        "Cannot obtain access to stdin"),
+                };
+
+                match stream.write_all(s) {
+                    Ok(_) => (),
+                    Err(_) => {
+                        return generic_error!(
+                            "Cannot write passphrase to stdin");
+                    },
+                }
+
+                drop(stream);
+            },
+
+            None => (),
+        }
+
+        // Wait for process to finish (it can be killed on interruption)
+        let pid = process.id();
+
+        signals::set_child(pid);
+
+        let output = match command_timeout() {
+            Some(t) => wait_with_timeout(process, command, t),
+            None => match process.wait_with_output() {
+                Ok(o) => Ok(o),
+                Err(e) => io_error!(&format!("`{}` command", command), e),
+            },
+        };
+
+        signals::clear_child(pid);
+
+        return output;
+    }
+}
+
+/// Wait for a process and collect its output, killing it (and its group) if
+/// it runs longer than the timeout
+fn wait_with_timeout(
+    process: process::Child,
+    command: &str,
+    timeout: time::Duration) -> Result<process::Output, error::Error> {
+
+    let pid = process.id() as i32;
+
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let _ = sender.send(process.wait_with_output());
+    });
+
+    let output = match receiver.recv_timeout(timeout) {
         Ok(o) => o,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            log::error!(
+                "`{}` timed out after {}s, killing it",
+                command,
+                timeout.as_secs());
+
+            unsafe {
+                libc::kill(-pid, libc::SIGKILL);
+            }
+
+            let output = match receiver.recv() {
+                Ok(Ok(o)) => o,
+                _ => return generic_error!(&format!(
+                    "`{}` timed out after {}s",
+                    command,
+                    timeout.as_secs())),
+            };
+
+            return process_error!(
+                command,
+                output.status,
+                &format!(
+                    "timed out after {}s (see --command-timeout)",
+                    timeout.as_secs()));
+        },
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            return generic_error!(&format!("`{}` was not waited", command));
+        },
+    };
+
+    match output {
+        Ok(o) => return Ok(o),
         Err(e) => return io_error!(&format!("`{}` command", command), e),
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Runner recording the commands and returning canned outputs
+#[cfg(test)]
+pub struct MockRunner {
+    /// Commands run (name and arguments)
+    pub calls: sync::Mutex<Vec<(String, Vec<String>)>>,
+
+    /// Outputs returned (in order), a success without output afterwards
+    pub outputs: sync::Mutex<Vec<process::Output>>,
+}
+
+#[cfg(test)]
+impl MockRunner {
+    /// Create a runner returning the provided outputs
+    pub fn new(outputs: Vec<process::Output>) -> Self {
+        Self {
+            calls: sync::Mutex::new(Vec::new()),
+            outputs: sync::Mutex::new(outputs),
+        }
+    }
+
+    /// Create an output of a command
+    pub fn output(code: i32, stdout: &str) -> process::Output {
+        use std::os::unix::process::ExitStatusExt;
+
+        return process::Output {
+            status: process::ExitStatus::from_raw(code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: Vec::new(),
+        };
+    }
+
+    /// Create an output of a failed command
+    pub fn failure(code: i32, stderr: &str) -> process::Output {
+        let mut output = Self::output(code, "");
+
+        output.stderr = stderr.as_bytes().to_vec();
+
+        return output;
+    }
+}
+
+#[cfg(test)]
+impl CommandRunner for MockRunner {
+    fn run(&self, command: &str, args: &[&str], _stdin: Option<&[u8]>)
+        -> Result<process::Output, error::Error> {
+
+        self.calls.lock().unwrap().push((
+            command.to_string(),
+            args.iter().map(|a| a.to_string()).collect()));
+
+        let mut outputs = self.outputs.lock().unwrap();
+
+        return match outputs.is_empty() {
+            true => Ok(Self::output(0, "")),
+            false => Ok(outputs.remove(0)),
+        };
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Check that a user or group name is valid
+pub fn check_name(name: &str) -> error::Return {
+    let re = match Regex::new(r"^[a-z_][a-z0-9_-]{0,31}$") {
+        Ok(r) => r,
+        Err(e) => return generic_error!(
+            &format!("Cannot build regex: {}", e)),
     };
 
-    if !output.status.success() {
-        return generic_error!(
-            &format!("`{}` command returned an error", command));
+    if !re.is_match(name) {
+        return generic_error!(&format!("Invalid user or group `{}`", name));
     }
 
-    return Ok(output);
+    return Success!();
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_escaped_mounts_under_target() {
+        let content = "\
+            /dev/sda2 / ext4 rw 0 0\n\
+            /dev/mapper/root /mnt/root ext4 rw 0 0\n\
+            /dev/sda1 /mnt/root/boot vfat rw 0 0\n\
+            /dev/sdb1 /mnt/root/my\\040data ext4 rw 0 0\n\
+            /dev/sdc1 /mnt/rootfs ext4 rw 0 0\n";
+
+        let mounts = parse_mounts(content, path::Path::new("/mnt/root"));
+
+        assert_eq!(mounts, vec![
+            "/mnt/root".to_string(),
+            "/mnt/root/boot".to_string(),
+            "/mnt/root/my data".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn mounts_are_unmounted_deepest_first() {
+        let mounts = vec![
+            "/mnt/root/boot".to_string(),
+            "/mnt/root/home".to_string(),
+            "/mnt/root/home/user/data".to_string(),
+            "/mnt/root/nix".to_string(),
+        ];
+
+        // The target itself isn't mounted
+        assert_eq!(unmount_order(mounts), vec![
+            "/mnt/root/home/user/data".to_string(),
+            "/mnt/root/nix".to_string(),
+            "/mnt/root/home".to_string(),
+            "/mnt/root/boot".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn failed_commands_are_described_by_their_output() {
+        let mut output = MockRunner::output(4, "Could not create partition\n");
+
+        let error = command_error("sgdisk", &output).to_string();
+
+        assert_eq!(
+            error,
+            "(PROCESS) `sgdisk` returned 4 => Could not create partition");
+
+        output.stderr = b"first\n\nsecond\n".to_vec();
+
+        let error = command_error("sgdisk", &output).to_string();
+
+        assert_eq!(
+            error,
+            "(PROCESS) `sgdisk` returned 4 =>\n    first\n    second");
+    }
+
+    #[test]
+    fn system_commands_cannot_prompt() {
+        let output = SystemRunner.run(
+            "sh",
+            &["-c", "cat; echo \"$GIT_TERMINAL_PROMPT\"; exec 3</dev/tty"],
+            None).unwrap();
+
+        // Stdin is empty, the variable is set and there is no terminal
+        assert!(!output.status.success());
+        assert!(!String::from_utf8_lossy(&output.stdout).trim().is_empty());
+    }
+
+    #[test]
+    fn operations_are_retried_until_they_succeed() {
+        let mut attempts = 0;
+
+        let result = retry("Operation", || {
+            attempts += 1;
+
+            match attempts {
+                DEFAULT_RETRIES => return Ok(attempts),
+                _ => return generic_error!("Not ready"),
+            }
+        });
+
+        assert_eq!(result.unwrap(), DEFAULT_RETRIES);
+    }
+
+    #[test]
+    fn operations_are_retried_until_the_timeout() {
+        let mut attempts = 0;
+
+        let result: Result<(), error::Error> = retry_timeout(
+            "Operation",
+            time::Duration::from_millis(700),
+            || {
+                attempts += 1;
+
+                return generic_error!("Not ready");
+            });
+
+        // Attempts after 0, 500ms and 700ms (the last delay is shortened)
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
 }
@@ -0,0 +1,255 @@
+// -----------------------------------------------------------------------------
+
+use clap;
+use regex::Regex;
+use std::fs;
+use std::path;
+
+use super::env;
+use super::error;
+use super::traits::{CliCommand, Validate};
+use super::utils;
+
+// -----------------------------------------------------------------------------
+
+const ARG_HOST: &str = "host";
+
+/// Program checking the syntax of the generated files
+const PARSER: &str = "nix-instantiate";
+
+// -----------------------------------------------------------------------------
+
+/// Command structure for checking the generated NixOS configuration of a host
+#[derive(Debug)]
+pub struct Command {
+    /// Host name
+    host: String,
+}
+
+impl Validate for Command {
+    fn is_valid(&self) -> bool {
+        return !self.host.is_empty();
+    }
+}
+
+impl CliCommand for Command {
+    /// Get the name of the command
+    fn name(&self) -> &'static str {
+        return "verify";
+    }
+
+    /// Get command and its arguments
+    fn get<'a, 'b>(
+        &self,
+        version: &'b str,
+        author: &'b str) -> clap::App<'a, 'b> {
+
+        return clap::App::new(self.name())
+            .about("Check that the files generated by `filesystems` parse \
+                (nix-instantiate --parse) and that the imports of \
+                default.nix exist")
+            .version(version)
+            .author(author)
+            // Host argument
+            .arg(clap::Arg::with_name(ARG_HOST)
+                .long(ARG_HOST)
+                .help("Host name (optional if a .env file is present)")
+                .takes_value(true));
+    }
+
+    /// Process command line arguments
+    fn process(&mut self, matches: &clap::ArgMatches) -> error::Return {
+        // Parse arguments
+        for arg in matches.args.iter() {
+            match arg.0 {
+                &ARG_HOST => {
+                    self.host = match matches.value_of(arg.0) {
+                        Some(s) => s.to_owned(),
+                        None => return inval_error!(&ARG_HOST),
+                    };
+                },
+
+                _ => {
+                    return inval_error!(arg.0);
+                }
+            }
+        }
+
+        if !self.is_valid() {
+            self.fill_with_env()?;
+        }
+
+        log::debug!("{:#?}", self);
+
+        // Check validity
+        if !self.is_valid() {
+            return generic_error!("Invalid configuration");
+        }
+
+        if !utils::find_program(PARSER) {
+            return generic_error!(
+                &format!("`{}` is needed to verify the files", PARSER));
+        }
+
+        let dir = utils::current_dir()?
+            .join("filesystems")
+            .join(&self.host);
+
+        let files = nix_files(&dir)?;
+
+        if files.is_empty() {
+            return generic_error!(&format!(
+                "No generated file in {:?}, run `filesystems` first",
+                dir));
+        }
+
+        let mut errors = 0;
+
+        for file in files.iter() {
+            match parse(file) {
+                Ok(_) => log::info!("{:?} parses", file),
+                Err(e) => {
+                    log::error!("{:?} doesn't parse: {}", file, e);
+                    errors += 1;
+                },
+            }
+        }
+
+        // Disko layouts don't have a default.nix
+        let default = dir.join("default.nix");
+
+        if default.exists() {
+            let content = match fs::read_to_string(&default) {
+                Ok(c) => c,
+                Err(e) => return fs_error!(default, e),
+            };
+
+            for import in missing_imports(&dir, &content)? {
+                log::error!("{:?} imports missing `{}`", default, import);
+                errors += 1;
+            }
+        }
+
+        if errors > 0 {
+            return generic_error!(&format!(
+                "{} errors in the files of {:?}",
+                errors,
+                dir));
+        }
+
+        log::info!("{} files verified in {:?}", files.len(), dir);
+
+        return Success!();
+    }
+}
+
+impl Command {
+    /// Create an instance of Command
+    pub fn new() -> Self {
+        Self {
+            host: "".to_string(),
+        }
+    }
+
+    /// Use environment file to get needed values
+    fn fill_with_env(&mut self) -> error::Return {
+        env::read_for_host(&mut self.host)?;
+
+        return Success!();
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Get the `.nix` files of a directory (sorted by name)
+fn nix_files(dir: &path::Path) -> Result<Vec<path::PathBuf>, error::Error> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => return fs_error!(dir.to_path_buf(), e),
+    };
+
+    let mut files = Vec::new();
+
+    for entry in entries {
+        let path = match entry {
+            Ok(e) => e.path(),
+            Err(e) => return fs_error!(dir.to_path_buf(), e),
+        };
+
+        if path.is_file() && path.extension().is_some_and(|e| e == "nix") {
+            files.push(path);
+        }
+    }
+
+    files.sort();
+
+    return Ok(files);
+}
+
+/// Check the syntax of a Nix file
+fn parse(file: &path::Path) -> error::Return {
+    let file = match file.to_str() {
+        Some(f) => f,
+        None => return generic_error!("Invalid path"),
+    };
+
+    utils::command_output(PARSER, &["--parse", file])?;
+
+    return Success!();
+}
+
+/// Get the relative imports of `default.nix` missing from its directory (the
+/// ones guarded by `builtins.pathExists` are optional)
+fn missing_imports(dir: &path::Path, content: &str)
+    -> Result<Vec<String>, error::Error> {
+
+    let re = match Regex::new(r"\./[A-Za-z0-9._-]+\.nix") {
+        Ok(r) => r,
+        Err(e) => return generic_error!(
+            &format!("Cannot build regex: {}", e)),
+    };
+
+    let mut missing = Vec::new();
+
+    for m in re.find_iter(content) {
+        let import = m.as_str();
+
+        if content.contains(&format!("pathExists {}", import)) ||
+            missing.iter().any(|i| i == import) {
+
+            continue;
+        }
+
+        if !dir.join(import).exists() {
+            missing.push(import.to_string());
+        }
+    }
+
+    return Ok(missing);
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_missing_imports() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+
+        fs::write(dir.join("base.nix"), "{ }").unwrap();
+
+        let content = "{ lib, ... }:\n\
+            {\n  \
+              imports = [\n    \
+                ./base.nix\n    \
+                ./devices.nix\n  \
+              ] ++ lib.optional (builtins.pathExists ./ssh.nix) ./ssh.nix;\n\
+            }\n";
+
+        assert_eq!(
+            missing_imports(&dir, content).unwrap(),
+            ["./devices.nix"]);
+    }
+}
@@ -0,0 +1,293 @@
+// -----------------------------------------------------------------------------
+
+use clap;
+use std::io;
+use std::io::Write;
+use std::path;
+
+use super::disk;
+use super::env;
+use super::error;
+use super::filesystem;
+use super::luks;
+use super::traits::{CliCommand, Validate};
+use super::utils;
+use super::zfs;
+
+// -----------------------------------------------------------------------------
+
+const ARG_CONFIRM: &str = "confirm";
+const ARG_HOST: &str = "host";
+
+// -----------------------------------------------------------------------------
+
+/// Command structure for destroying the layout of a host (before installing
+/// it again)
+#[derive(Debug)]
+pub struct Command {
+    /// Host name
+    host: String,
+
+    /// Whether the destruction is confirmed (asked otherwise)
+    confirm: bool,
+}
+
+impl Validate for Command {
+    fn is_valid(&self) -> bool {
+        return !self.host.is_empty();
+    }
+}
+
+impl CliCommand for Command {
+    /// Get the name of the command
+    fn name(&self) -> &'static str {
+        return "wipe";
+    }
+
+    /// Get command and its arguments
+    fn get<'a, 'b>(
+        &self,
+        version: &'b str,
+        author: &'b str) -> clap::App<'a, 'b> {
+
+        return clap::App::new(self.name())
+            .about("Close the devices of the layout, destroy its ZFS pools \
+                and wipe the partition tables of its disks (read-only disks \
+                are skipped)")
+            .version(version)
+            .author(author)
+            // Confirm argument
+            .arg(clap::Arg::with_name(ARG_CONFIRM)
+                .long(ARG_CONFIRM)
+                .help("Don't ask to type the host name to confirm"))
+            // Host argument
+            .arg(clap::Arg::with_name(ARG_HOST)
+                .long(ARG_HOST)
+                .help("Host name (optional if a .env file is present)")
+                .takes_value(true));
+    }
+
+    /// Process command line arguments
+    fn process(&mut self, matches: &clap::ArgMatches) -> error::Return {
+        // Parse arguments
+        for arg in matches.args.iter() {
+            match arg.0 {
+                &ARG_CONFIRM => {
+                    self.confirm = true;
+                },
+
+                &ARG_HOST => {
+                    self.host = match matches.value_of(arg.0) {
+                        Some(s) => s.to_owned(),
+                        None => return inval_error!(&ARG_HOST),
+                    };
+                },
+
+                _ => {
+                    return inval_error!(arg.0);
+                }
+            }
+        }
+
+        if !self.is_valid() {
+            self.fill_with_env()?;
+        }
+
+        log::info!("{:#?}", self);
+
+        // Check validity
+        if !self.is_valid() {
+            return generic_error!("Invalid configuration");
+        }
+
+        // Create filesystem
+        let layout = filesystem::layout(&self.host)?;
+
+        let fs = filesystem::Filesystem::from_file(&layout)?;
+
+        // Nothing is destroyed in dry-run
+        if !self.confirm && !utils::is_dry_run() {
+            self.ask_confirmation(&fs)?;
+        }
+
+        return wipe(&fs);
+    }
+}
+
+impl Command {
+    /// Create an instance of Command
+    pub fn new() -> Self {
+        Self {
+            host: "".to_string(),
+            confirm: false,
+        }
+    }
+
+    /// Use environment file to get needed values
+    fn fill_with_env(&mut self) -> error::Return {
+        env::read_for_host(&mut self.host)?;
+
+        return Success!();
+    }
+
+    /// Ask to type the host name before destroying anything
+    fn ask_confirmation(&self, fs: &filesystem::Filesystem) -> error::Return {
+        let devices: Vec<&str> = writable_disks(fs)
+            .map(|d| d.config.device.as_str())
+            .collect();
+
+        eprint!(
+            "All the data of {} will be lost, type the host name (`{}`) to \
+            confirm: ",
+            devices.join(", "),
+            self.host);
+
+        match io::stderr().flush() {
+            Ok(_) => (),
+            Err(e) => return io_error!("Cannot write to stderr", e),
+        }
+
+        let mut answer = String::new();
+
+        match io::stdin().read_line(&mut answer) {
+            Ok(_) => (),
+            Err(e) => return io_error!("Cannot read from stdin", e),
+        }
+
+        if answer.trim() != self.host {
+            return generic_error!("Wipe not confirmed");
+        }
+
+        return Success!();
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+/// Get the disks that can be wiped (not read-only)
+fn writable_disks(
+    fs: &filesystem::Filesystem) -> impl Iterator<Item = &disk::Disk> {
+
+    return fs.disks.iter().filter(|d| !d.read_only());
+}
+
+/// Close what a previous run left opened on the writable disks, destroy their
+/// ZFS pools and wipe their partition tables
+fn wipe(fs: &filesystem::Filesystem) -> error::Return {
+    let labels: Vec<&str> = writable_disks(fs)
+        .flat_map(|d| d.partitions.iter())
+        .map(|p| p.config.label.as_str())
+        .collect();
+
+    // Arrays having a member to wipe
+    for array in fs.raids.iter() {
+        let config = &array.config;
+
+        if !config.members.iter().any(|m| labels.contains(&m.as_str())) {
+            continue;
+        }
+
+        let device = config.device();
+
+        if !path::Path::new(&device).exists() {
+            continue;
+        }
+
+        if config.encrypted && luks::is_opened(&config.label) {
+            luks::close(&config.label)?;
+        }
+
+        utils::command_output("mdadm", &["--stop", &device])?;
+
+        log::info!("RAID array `{}` stopped", device);
+    }
+
+    for disk in writable_disks(fs) {
+        for partition in disk.partitions.iter() {
+            let config = &partition.config;
+
+            // Pools of other disks are kept (unlike `zfs::wipeout`)
+            if config.fs_type == "zfs" && zfs::pool_exists(&config.label) {
+                zfs::pool_destroy(&config.label)?;
+
+                log::info!("ZFS pool `{}` destroyed", config.label);
+            }
+
+            if config.fs_type == "lvm" {
+                let vg = format!("vg-{}", config.label);
+
+                if utils::command_output("vgs", &[&vg]).is_ok() {
+                    utils::command_output("vgchange", &["-a", "n", &vg])?;
+
+                    log::info!("Volume group `{}` deactivated", vg);
+                }
+            }
+
+            if config.encrypted && luks::is_opened(&config.label) {
+                luks::close(&config.label)?;
+            }
+        }
+    }
+
+    for disk in fs.disks.iter() {
+        if disk.read_only() {
+            log::info!("Disk `{}` skipped (read-only)", disk.config.device);
+            continue;
+        }
+
+        disk.wipeout()?;
+    }
+
+    return Success!();
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::sync;
+
+    use super::*;
+    use super::super::filesystems::tests::golden_layout;
+
+    #[test]
+    fn wipes_writable_disks_only() {
+        let fs = golden_layout("ext4.json", |value| {
+            let mut data = value["disks"][0].clone();
+
+            data["device"] = serde_json::Value::from("/dev/sdb");
+            data["read_only"] = serde_json::Value::Bool(true);
+            data["contains_system"] = serde_json::Value::Bool(false);
+            data["partitions"] = serde_json::Value::from(Vec::<u8>::new());
+
+            value["disks"].as_array_mut().unwrap().push(data);
+        }).unwrap();
+
+        let runner = sync::Arc::new(utils::MockRunner::new(vec![
+            // cryptsetup status (data)
+            utils::MockRunner::output(0, "/dev/mapper/data is active."),
+        ]));
+
+        utils::set_runner(runner.clone());
+
+        wipe(&fs).unwrap();
+
+        let calls = runner.calls.lock().unwrap();
+        let commands: Vec<String> = calls
+            .iter()
+            .map(|c| format!("{} {}", c.0, c.1.join(" ")))
+            .collect();
+
+        assert_eq!(
+            commands,
+            [
+                "cryptsetup status /dev/mapper/data",
+                "cryptsetup luksClose /dev/mapper/data",
+                "vgs vg-system",
+                "vgchange -a n vg-system",
+                "cryptsetup status /dev/mapper/system",
+                "blockdev --getsize64 /dev/sda",
+                "sgdisk -Z /dev/sda",
+            ]);
+    }
+}
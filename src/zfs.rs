@@ -4,11 +4,38 @@ use serde::{Deserialize, Serialize};
 use std::path;
 
 use super::error;
+use super::gpt;
 use super::traits::{Mountable, Validate};
 use super::utils;
 
 // -----------------------------------------------------------------------------
 
+/// Sector size exponent of the pools (2^12 = 4K) unless configured
+pub const ASHIFT: u32 = 12;
+
+/// Sector size exponents supported by `zpool create` (512 bytes to 64K)
+const ASHIFTS: std::ops::RangeInclusive<u32> = 9..=16;
+
+/// Pool compatibility restricting features to the ones GRUB can read
+pub const GRUB_COMPATIBILITY: &str = "grub2";
+
+/// Directory searched for pool devices (stable across device renaming)
+pub const DEV_NODES: &str = "/dev/disk/by-id";
+
+/// Cipher of the natively encrypted pools
+pub const ENCRYPTION: &str = "aes-256-gcm";
+
+/// Minimal length of a ZFS passphrase
+const MIN_PASSPHRASE_LENGTH: usize = 8;
+
+/// Error of `zpool import -a` when no pool is found
+const NO_POOLS: &str = "no pools available to import";
+
+/// Error of `zpool list` when the pool doesn't exist
+const NO_SUCH_POOL: &str = "no such pool";
+
+// -----------------------------------------------------------------------------
+
 /// Json configuration of a ZFS filesystem
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config{
@@ -20,6 +47,10 @@ pub struct Config{
 
     /// Whether this partition is the root mount point
     pub is_root: bool,
+
+    /// Options of the mount of the filesystem
+    #[serde(default)]
+    pub mount_options: Vec<String>,
 }
 
 impl Validate for Config{
@@ -92,6 +123,7 @@ pub struct Filesystem {
     pub pool: String,
 
     /// Whether the filesystem is opened or not
+    #[allow(dead_code)]
     opened: bool,
 
     /// Wether the filesystem is mounted or not
@@ -115,6 +147,7 @@ impl Filesystem {
             name: self.config.name.clone(),
             mountpoint: self.config.mountpoint.clone(),
             is_root: self.config.is_root.clone(),
+            mount_options: self.config.mount_options.clone(),
         });
     }
 
@@ -141,7 +174,17 @@ impl Mountable for Filesystem {
             None => return generic_error!("No mountpoint"),
         };
 
-        utils::command_output("mount", &["-t", "zfs", &device, mountpoint])?;
+        let options = self.config.mount_options.join(",");
+
+        let mut args = vec!["-t", "zfs"];
+
+        if !options.is_empty() {
+            args.extend(["-o", &options]);
+        }
+
+        args.extend([&device, mountpoint]);
+
+        utils::mount(&args)?;
 
         self.mounted = true;
 
@@ -170,7 +213,15 @@ impl Mountable for Filesystem {
 
 // -----------------------------------------------------------------------------
 
-pub fn pool_create(name : &str, device : &str) -> error::Return {
+/// Create a pool (natively encrypted with the passphrase if any), or add the
+/// device to it if it already exists
+pub fn pool_create(
+    name : &str,
+    device : &str,
+    ashift: u32,
+    compatibility: Option<&str>,
+    passphrase: Option<&str>) -> error::Return {
+
     pool_import_all()?;
 
     if pool_exists(name) {
@@ -179,16 +230,113 @@ pub fn pool_create(name : &str, device : &str) -> error::Return {
 
     pool_export_all()?;
 
-    utils::command_output(
+    match passphrase {
+        Some(p) if p.len() < MIN_PASSPHRASE_LENGTH => {
+            return generic_error!(&format!(
+                "The passphrase of encrypted pool `{}` must be at least {} \
+                characters long",
+                name,
+                MIN_PASSPHRASE_LENGTH));
+        },
+        _ => (),
+    }
+
+    let args = pool_create_args(
+        name,
+        device,
+        ashift,
+        compatibility,
+        passphrase.is_some());
+
+    // The prompted passphrase is read from stdin
+    utils::spawn_command(
         "zpool",
-        &[
-            "create",
-            "-o", "ashift=12",
-            "-O", "compression=lz4",
-            "-m", "none",
-            name,
-            device,
-        ])?;
+        &args.iter().map(|a| a.as_str()).collect::<Vec<&str>>(),
+        passphrase.map(|p| p.as_bytes()))?;
+
+    return Success!();
+}
+
+/// Get the arguments of `zpool create` (pools are imported by id, without
+/// cache file)
+fn pool_create_args(
+    name: &str,
+    device: &str,
+    ashift: u32,
+    compatibility: Option<&str>,
+    encrypted: bool) -> Vec<String> {
+
+    let mut args = vec![
+        "create".to_string(),
+        "-o".to_string(), format!("ashift={}", ashift),
+        "-o".to_string(), "cachefile=none".to_string(),
+        "-O".to_string(), "compression=lz4".to_string(),
+        "-m".to_string(), "none".to_string(),
+    ];
+
+    match compatibility {
+        Some(c) => {
+            args.push("-o".to_string());
+            args.push(format!("compatibility={}", c));
+        },
+
+        None => (),
+    }
+
+    if encrypted {
+        args.extend([
+            "-O".to_string(), format!("encryption={}", ENCRYPTION),
+            "-O".to_string(), "keyformat=passphrase".to_string(),
+            "-O".to_string(), "keylocation=prompt".to_string(),
+        ]);
+    }
+
+    args.push(name.to_string());
+    args.push(device.to_string());
+
+    return args;
+}
+
+/// Get the alignment (in sectors) of partitions hosting a pool, so that they
+/// start on an `ashift` boundary
+pub fn partition_alignment(ashift: u32) -> Result<u64, error::Error> {
+    if !ASHIFTS.contains(&ashift) {
+        return generic_error!(&format!(
+            "Invalid ashift {} (from {} to {})",
+            ashift,
+            ASHIFTS.start(),
+            ASHIFTS.end()));
+    }
+
+    let block_size: u64 = 1 << ashift;
+
+    // The default alignment is kept when it's a multiple of the block size
+    let alignment = match gpt::DEFAULT_ALIGNMENT * gpt::SECTOR_SIZE {
+        a if a.is_multiple_of(block_size) => gpt::DEFAULT_ALIGNMENT,
+        _ => block_size / gpt::SECTOR_SIZE,
+    };
+
+    return Ok(alignment);
+}
+
+/// Check that a partition hosting a pool starts on an `ashift` boundary
+/// (offset in bytes)
+pub fn check_partition_start(
+    label: &str,
+    offset: u64,
+    ashift: u32) -> error::Return {
+
+    let block_size: u64 = 1 << ashift;
+
+    if !offset.is_multiple_of(block_size) {
+        return generic_error!(&format!(
+            "Partition `{}` starts at offset {}, which is not a multiple of \
+            the pool sectors ({} bytes, ashift {})",
+            label,
+            offset,
+            block_size,
+            ashift));
+    }
 
     return Success!();
 }
@@ -199,16 +347,35 @@ pub fn pool_add(name : &str, device : &str) -> error::Return {
     return Success!();
 }
 
+pub fn pool_expand(name : &str, device : &str) -> error::Return {
+    utils::command_output("zpool", &["online", "-e", name, device])?;
+
+    log::info!("ZFS pool `{}` expanded on `{}`", name, device);
+
+    return Success!();
+}
+
 pub fn pool_destroy(name : &str) -> error::Return {
     utils::command_output("zpool", &["destroy", "-f", name])?;
 
     return Success!();
 }
 
+/// Import all the pools found (retried while their devices appear, finding no
+/// pool at all is not an error)
 pub fn pool_import_all() -> error::Return {
-    utils::command_output("zpool", &["import", "-a"])?;
-
-    return Success!();
+    return utils::retry("ZFS pools import", || {
+        let args = ["import", "-a", "-d", DEV_NODES];
+
+        match utils::command_output("zpool", &args) {
+            Ok(_) => return Success!(),
+            Err(e) if e.stderr().is_some_and(|s| s.contains(NO_POOLS)) => {
+                log::info!("No ZFS pool to import");
+                return Success!();
+            },
+            Err(e) => return Err(e),
+        }
+    });
 }
 
 //pub fn pool_export(pool : &str) -> error::Return {
@@ -240,6 +407,52 @@ pub fn zfs_create(pool : &str, name : &str) -> error::Return {
     return Success!();
 }
 
+/// Load the key of a natively encrypted pool (the prompted passphrase is read
+/// from stdin)
+pub fn load_key(pool: &str, passphrase: &str) -> error::Return {
+    if is_key_loaded(pool) {
+        return Success!();
+    }
+
+    utils::spawn_command(
+        "zfs",
+        &["load-key", pool],
+        Some(passphrase.as_bytes()))?;
+
+    log::info!("Key of ZFS pool `{}` loaded", pool);
+
+    return Success!();
+}
+
+/// Unload the key of a natively encrypted pool
+pub fn unload_key(pool: &str) -> error::Return {
+    if !is_key_loaded(pool) {
+        return Success!();
+    }
+
+    utils::command_output("zfs", &["unload-key", pool])?;
+
+    log::info!("Key of ZFS pool `{}` unloaded", pool);
+
+    return Success!();
+}
+
+/// Check if the key of a natively encrypted pool is loaded
+fn is_key_loaded(pool: &str) -> bool {
+    let output = match utils::command_output(
+        "zfs",
+        &["get", "-H", "-o", "value", "keystatus", pool]) {
+
+        Ok(o) => o,
+        Err(_) => return false,
+    };
+
+    return match utils::command_stdout_to_string(&output) {
+        Ok(s) => s.trim() == "available",
+        Err(_) => false,
+    };
+}
+
 pub fn wipeout() -> error::Return {
     let output = utils::command_output("zpool", &["list", "-H", "-o", "name"])?;
     let output = utils::command_stdout_to_string(&output)?;
@@ -253,9 +466,183 @@ pub fn wipeout() -> error::Return {
     return Success!();
 }
 
-pub fn pool_exists(name : &str) -> bool {
-    return match utils::command_output("zpool", &["list", name]) {
-        Ok(_) => true,
-        Err(_) => false,
+/// Get the devices of the imported pools (name of the pool and resolved path
+/// of the device), none if ZFS isn't available
+pub fn pool_devices() -> Vec<(String, String)> {
+    let output = match utils::command_output(
+        "zpool",
+        &["list", "-v", "-H", "-P", "-L", "-o", "name"]) {
+
+        Ok(o) => o,
+        Err(_) => return Vec::new(),
+    };
+
+    let output = match utils::command_stdout_to_string(&output) {
+        Ok(o) => o,
+        Err(_) => return Vec::new(),
     };
+
+    let mut devices = Vec::new();
+    let mut pool = String::new();
+
+    for line in output.lines() {
+        // Vdevs are indented below their pool
+        if !line.starts_with(char::is_whitespace) {
+            pool = line.trim().to_string();
+            continue;
+        }
+
+        match line.split_whitespace().next() {
+            Some(d) if d.starts_with("/dev/") => {
+                devices.push((pool.clone(), d.to_string()));
+            },
+            _ => (),
+        }
+    }
+
+    return devices;
+}
+
+pub fn pool_exists(name : &str) -> bool {
+    // Nothing has been created: pools never exist
+    if utils::is_dry_run() {
+        return false;
+    }
+
+    // Only an unknown pool is a definitive answer
+    let exists = utils::retry(&format!("ZFS pool `{}` lookup", name), || {
+        match utils::command_output("zpool", &["list", name]) {
+            Ok(_) => return Ok(true),
+            Err(e) if e.stderr().is_some_and(|s| s.contains(NO_SUCH_POOL)) => {
+                return Ok(false);
+            },
+            Err(e) => return Err(e),
+        }
+    });
+
+    return exists.unwrap_or(false);
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::sync;
+
+    use super::super::utils;
+
+    #[test]
+    fn pool_create_runs_zpool_with_import_options() {
+        let runner = sync::Arc::new(utils::MockRunner::new(vec![
+            // zpool import
+            utils::MockRunner::output(0, ""),
+            // zpool list (pool doesn't exist)
+            utils::MockRunner::failure(
+                1,
+                "cannot open 'rpool': no such pool\n"),
+        ]));
+
+        utils::set_runner(runner.clone());
+
+        super::pool_create(
+            "rpool",
+            "/dev/sda2",
+            super::ASHIFT,
+            Some("grub2"),
+            None).unwrap();
+
+        let calls = runner.calls.lock().unwrap();
+
+        assert_eq!(calls[0].1, ["import", "-a", "-d", "/dev/disk/by-id"]);
+        assert_eq!(calls.last().unwrap().0, "zpool");
+        assert_eq!(calls.last().unwrap().1, [
+            "create",
+            "-o", "ashift=12",
+            "-o", "cachefile=none",
+            "-O", "compression=lz4",
+            "-m", "none",
+            "-o", "compatibility=grub2",
+            "rpool",
+            "/dev/sda2",
+        ]);
+    }
+
+    #[test]
+    fn finding_no_pool_to_import_is_not_an_error() {
+        let runner = sync::Arc::new(utils::MockRunner::new(vec![
+            utils::MockRunner::failure(1, "no pools available to import\n"),
+        ]));
+
+        utils::set_runner(runner.clone());
+
+        super::pool_import_all().unwrap();
+
+        assert_eq!(runner.calls.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn pool_devices_are_listed_with_their_pool() {
+        let runner = sync::Arc::new(utils::MockRunner::new(vec![
+            utils::MockRunner::output(
+                0,
+                "rpool\n\tmirror-0\n\t/dev/sda2\n\t/dev/sdb2\n\
+                tank\n\t/dev/nvme0n1p3\n"),
+        ]));
+
+        utils::set_runner(runner.clone());
+
+        assert_eq!(super::pool_devices(), [
+            ("rpool".to_string(), "/dev/sda2".to_string()),
+            ("rpool".to_string(), "/dev/sdb2".to_string()),
+            ("tank".to_string(), "/dev/nvme0n1p3".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn encrypted_pool_uses_native_encryption() {
+        let args = super::pool_create_args(
+            "rpool",
+            "/dev/sda2",
+            super::ASHIFT,
+            None,
+            true);
+
+        assert!(args.windows(2).any(|a| a == ["-O", "encryption=aes-256-gcm"]));
+        assert!(args.windows(2).any(|a| a == ["-O", "keylocation=prompt"]));
+        assert_eq!(args[args.len() - 2..], ["rpool", "/dev/sda2"]);
+
+        let runner = sync::Arc::new(utils::MockRunner::new(vec![
+            // zpool import
+            utils::MockRunner::output(0, ""),
+            // zpool list (pool doesn't exist)
+            utils::MockRunner::failure(
+                1,
+                "cannot open 'rpool': no such pool\n"),
+        ]));
+
+        utils::set_runner(runner.clone());
+
+        assert!(super::pool_create(
+            "rpool",
+            "/dev/sda2",
+            super::ASHIFT,
+            None,
+            Some("short")).is_err());
+    }
+
+    #[test]
+    fn partitions_are_aligned_on_the_pool_ashift() {
+        let args =
+            super::pool_create_args("tank", "/dev/sda3", 13, None, false);
+
+        assert!(args.windows(2).any(|a| a == ["-o", "ashift=13"]));
+
+        // 1M (2048 sectors) is a multiple of 8K
+        assert_eq!(super::partition_alignment(13).unwrap(), 2048);
+        assert!(super::partition_alignment(8).is_err());
+        assert!(super::partition_alignment(17).is_err());
+
+        assert!(super::check_partition_start("tank", 1 << 20, 13).is_ok());
+        assert!(super::check_partition_start("tank", 3 << 12, 13).is_err());
+    }
 }
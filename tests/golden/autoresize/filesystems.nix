@@ -0,0 +1,45 @@
+# Auto-generated, do not edit !
+{ config, ... }:
+
+{
+  networking.hostId = "01234567";
+
+  fileSystems."/" = {
+    device = "/dev/vg-system/root";
+    fsType = "ext4";
+    autoResize = true;
+  };
+
+  fileSystems."/boot/efi" = {
+    device = "/dev/disk/by-partlabel/uefi";
+    fsType = "vfat";
+  };
+
+  fileSystems."data" = {
+    device = "/dev/mapper/data";
+
+    encrypted = {
+      enable = true;
+      blkdev = "/dev/disk/by-partlabel/data";
+      label = "data";
+      keyFile = "/etc/secrets/disks/key_file";
+    };
+  };
+
+  fileSystems."system" = {
+    device = "/dev/mapper/system";
+
+    encrypted = {
+      enable = true;
+      blkdev = "/dev/disk/by-partlabel/system";
+      label = "system";
+      keyFile = "/etc/secrets/disks/key_file";
+    };
+  };
+
+  swapDevices = [
+    {
+      device = "/dev/vg-system/swap";
+    }
+  ];
+}
@@ -0,0 +1,23 @@
+# Auto-generated, do not edit !
+{ config, ... }:
+
+{
+  boot = {
+    supportedFilesystems = [ "btrfs" ];
+
+    initrd = {
+      supportedFilesystems = [ "btrfs" ];
+
+      luks.devices."system" = {
+        device = "/dev/disk/by-partlabel/system";
+        keyFile = "/key_file";
+        allowDiscards = true;
+        preLVM = true;
+      };
+
+      secrets = {
+        "/key_file" = "/etc/secrets/disks/key_file";
+      };
+    };
+  };
+}
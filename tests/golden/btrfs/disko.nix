@@ -0,0 +1,56 @@
+# Auto-generated, do not edit !
+{ ... }:
+
+{
+  disko.devices = {
+    disk."nvme0n1" = {
+      type = "disk";
+      device = "/dev/nvme0n1";
+      content = {
+        type = "gpt";
+        partitions = {
+          "uefi" = {
+            priority = 1;
+            size = "512M";
+            type = "EF00";
+            content = {
+              type = "filesystem";
+              format = "vfat";
+              extraArgs = [ "-n" "uefi" ];
+              mountpoint = "/boot/efi";
+            };
+          };
+          "system" = {
+            priority = 2;
+            size = "100%";
+            type = "8300";
+            content = {
+              type = "luks";
+              name = "system";
+              extraFormatArgs = [ "-c" "aes-xts-plain64" "-s" "256" "--type" "luks1" "-h" "sha512" ];
+              settings = {
+                keyFile = "/key_file";
+                allowDiscards = true;
+              };
+              content = {
+                type = "btrfs";
+                extraArgs = [ "-L" "system" "-f" ];
+                subvolumes = {
+                  "@" = {
+                    mountpoint = "/";
+                  };
+                  "@home" = {
+                    mountpoint = "/home";
+                  };
+                  "@nix" = {
+                    mountpoint = "/nix";
+                  };
+                };
+              };
+            };
+          };
+        };
+      };
+    };
+  };
+}
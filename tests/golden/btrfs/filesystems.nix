@@ -0,0 +1,29 @@
+# Auto-generated, do not edit !
+{ config, ... }:
+
+{
+  networking.hostId = "01234567";
+
+  fileSystems."/" = {
+    device = "/dev/mapper/system";
+    fsType = "btrfs";
+    options = [ "subvol=@" ];
+  };
+
+  fileSystems."/boot/efi" = {
+    device = "/dev/disk/by-partlabel/uefi";
+    fsType = "vfat";
+  };
+
+  fileSystems."/home" = {
+    device = "/dev/mapper/system";
+    fsType = "btrfs";
+    options = [ "subvol=@home" ];
+  };
+
+  fileSystems."/nix" = {
+    device = "/dev/mapper/system";
+    fsType = "btrfs";
+    options = [ "subvol=@nix" ];
+  };
+}
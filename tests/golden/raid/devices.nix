@@ -0,0 +1,17 @@
+# Auto-generated, do not edit !
+{ config, ... }:
+
+{
+  boot = {
+    initrd = {
+      secrets = {
+        "/key_file" = "/etc/secrets/disks/key_file";
+      };
+    };
+
+    swraid = {
+      enable = true;
+      mdadmConf = "ARRAY /dev/md/system metadata=1.2 UUID=4b1f8e1c:5e2a9d3f:8c7b6a5d:1e2f3a4b\nMAILADDR root";
+    };
+  };
+}
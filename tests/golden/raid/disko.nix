@@ -0,0 +1,80 @@
+# Auto-generated, do not edit !
+{ ... }:
+
+{
+  disko.devices = {
+    disk."sda" = {
+      type = "disk";
+      device = "/dev/sda";
+      content = {
+        type = "gpt";
+        partitions = {
+          "uefi" = {
+            priority = 1;
+            size = "512M";
+            type = "EF00";
+            content = {
+              type = "filesystem";
+              format = "vfat";
+              extraArgs = [ "-n" "uefi" ];
+              mountpoint = "/boot/efi";
+            };
+          };
+          "system-a" = {
+            priority = 2;
+            size = "100%";
+            type = "8300";
+            content = {
+              type = "mdraid";
+              name = "system";
+            };
+          };
+        };
+      };
+    };
+
+    disk."sdb" = {
+      type = "disk";
+      device = "/dev/sdb";
+      content = {
+        type = "gpt";
+        partitions = {
+          "uefi-b" = {
+            priority = 1;
+            size = "512M";
+            type = "EF00";
+            content = {
+              type = "filesystem";
+              format = "vfat";
+              extraArgs = [ "-n" "uefi-b" ];
+              mountpoint = "/boot-uefi-b/efi";
+            };
+          };
+          "system-b" = {
+            priority = 2;
+            size = "100%";
+            type = "8300";
+            content = {
+              type = "mdraid";
+              name = "system";
+            };
+          };
+        };
+      };
+    };
+
+    mdadm = {
+      "system" = {
+        type = "mdadm";
+        level = 1;
+        metadata = "1.2";
+        content = {
+          type = "filesystem";
+          format = "ext4";
+          extraArgs = [ "-L" "nixos" ];
+          mountpoint = "/";
+        };
+      };
+    };
+  };
+}
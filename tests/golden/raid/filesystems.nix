@@ -0,0 +1,22 @@
+# Auto-generated, do not edit !
+{ config, ... }:
+
+{
+  networking.hostId = "01234567";
+
+  fileSystems."/" = {
+    device = "/dev/md/system";
+    fsType = "ext4";
+  };
+
+  fileSystems."/boot-uefi-b/efi" = {
+    device = "/dev/disk/by-partlabel/uefi-b";
+    fsType = "vfat";
+    options = [ "nofail" ];
+  };
+
+  fileSystems."/boot/efi" = {
+    device = "/dev/disk/by-partlabel/uefi";
+    fsType = "vfat";
+  };
+}
@@ -0,0 +1,26 @@
+# Auto-generated, do not edit !
+{ config, ... }:
+
+{
+  boot = {
+    initrd = {
+      luks.devices."swap" = {
+        device = "/dev/disk/by-partlabel/swap";
+        keyFile = "/key_file";
+        allowDiscards = true;
+        preLVM = true;
+      };
+
+      luks.devices."system" = {
+        device = "/dev/disk/by-partlabel/system";
+        keyFile = "/key_file";
+        allowDiscards = true;
+        preLVM = true;
+      };
+
+      secrets = {
+        "/key_file" = "/etc/secrets/disks/key_file";
+      };
+    };
+  };
+}
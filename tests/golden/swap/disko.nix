@@ -0,0 +1,88 @@
+# Auto-generated, do not edit !
+{ ... }:
+
+{
+  disko.devices = {
+    disk."sda" = {
+      type = "disk";
+      device = "/dev/sda";
+      content = {
+        type = "gpt";
+        partitions = {
+          "uefi" = {
+            priority = 1;
+            size = "512M";
+            type = "EF00";
+            content = {
+              type = "filesystem";
+              format = "vfat";
+              extraArgs = [ "-n" "uefi" ];
+              mountpoint = "/boot/efi";
+            };
+          };
+          "swap" = {
+            priority = 2;
+            size = "1G";
+            type = "8300";
+            content = {
+              type = "luks";
+              name = "swap";
+              extraFormatArgs = [ "-c" "aes-xts-plain64" "-s" "256" "--type" "luks1" "-h" "sha512" ];
+              settings = {
+                keyFile = "/key_file";
+                allowDiscards = true;
+              };
+              content = {
+                type = "swap";
+                extraArgs = [ "-L" "swap" ];
+                resumeDevice = true;
+              };
+            };
+          };
+          "system" = {
+            priority = 3;
+            size = "100%";
+            type = "8300";
+            content = {
+              type = "luks";
+              name = "system";
+              extraFormatArgs = [ "-c" "aes-xts-plain64" "-s" "256" "--type" "luks1" "-h" "sha512" ];
+              settings = {
+                keyFile = "/key_file";
+                allowDiscards = true;
+              };
+              content = {
+                type = "lvm_pv";
+                vg = "vg-system";
+              };
+            };
+          };
+        };
+      };
+    };
+
+    lvm_vg = {
+      "vg-system" = {
+        type = "lvm_vg";
+        lvs = {
+          "swap-lvm" = {
+            size = "1G";
+            content = {
+              type = "swap";
+              extraArgs = [ "-L" "swap-lvm" ];
+            };
+          };
+          "root" = {
+            size = "100%FREE";
+            content = {
+              type = "filesystem";
+              format = "ext4";
+              extraArgs = [ "-L" "root" ];
+              mountpoint = "/";
+            };
+          };
+        };
+      };
+    };
+  };
+}
@@ -0,0 +1,33 @@
+# Auto-generated, do not edit !
+{ config, ... }:
+
+{
+  networking.hostId = "01234567";
+
+  fileSystems."/boot/efi" = {
+    device = "/dev/disk/by-partlabel/uefi";
+    fsType = "vfat";
+  };
+
+  fileSystems."system" = {
+    device = "/dev/mapper/system";
+
+    encrypted = {
+      enable = true;
+      blkdev = "/dev/disk/by-partlabel/system";
+      label = "system";
+      keyFile = "/etc/secrets/disks/key_file";
+    };
+  };
+
+  swapDevices = [
+    {
+      device = "/dev/mapper/swap";
+    }
+    {
+      device = "/dev/vg-system/swap-lvm";
+    }
+  ];
+
+  boot.resumeDevice = "/dev/mapper/swap";
+}
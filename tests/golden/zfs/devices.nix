@@ -0,0 +1,24 @@
+# Auto-generated, do not edit !
+{ config, ... }:
+
+{
+  boot = {
+    supportedFilesystems = [ "zfs" ];
+    zfs.devNodes = "/dev/disk/by-id";
+
+    initrd = {
+      supportedFilesystems = [ "zfs" ];
+
+      luks.devices."rpool" = {
+        device = "/dev/disk/by-partlabel/rpool";
+        keyFile = "/key_file";
+        allowDiscards = true;
+        preLVM = true;
+      };
+
+      secrets = {
+        "/key_file" = "/etc/secrets/disks/key_file";
+      };
+    };
+  };
+}
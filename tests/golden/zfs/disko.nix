@@ -0,0 +1,72 @@
+# Auto-generated, do not edit !
+{ ... }:
+
+{
+  disko.devices = {
+    disk."nvme0n1" = {
+      type = "disk";
+      device = "/dev/nvme0n1";
+      content = {
+        type = "gpt";
+        partitions = {
+          "uefi" = {
+            priority = 1;
+            size = "512M";
+            type = "EF00";
+            content = {
+              type = "filesystem";
+              format = "vfat";
+              extraArgs = [ "-n" "uefi" ];
+              mountpoint = "/boot/efi";
+            };
+          };
+          "rpool" = {
+            priority = 2;
+            size = "100%";
+            type = "8300";
+            content = {
+              type = "luks";
+              name = "rpool";
+              extraFormatArgs = [ "-c" "aes-xts-plain64" "-s" "256" "--type" "luks1" "-h" "sha512" ];
+              settings = {
+                keyFile = "/key_file";
+                allowDiscards = true;
+              };
+              content = {
+                type = "zfs";
+                pool = "rpool";
+              };
+            };
+          };
+        };
+      };
+    };
+
+    zpool = {
+      "rpool" = {
+        type = "zpool";
+        options = {
+          ashift = "12";
+          cachefile = "none";
+          compatibility = "grub2";
+        };
+        rootFsOptions = {
+          compression = "lz4";
+          mountpoint = "none";
+        };
+        datasets = {
+          "root" = {
+            type = "zfs_fs";
+            mountpoint = "/";
+            options.mountpoint = "legacy";
+          };
+          "home" = {
+            type = "zfs_fs";
+            mountpoint = "/home";
+            options.mountpoint = "legacy";
+          };
+        };
+      };
+    };
+  };
+}